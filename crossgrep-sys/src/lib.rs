@@ -1,9 +1,11 @@
 use anyhow::{anyhow, Error, Result};
+use itertools::Itertools;
 use std::str::FromStr;
-use strum::{IntoEnumIterator, VariantNames};
-use strum_macros::{Display, EnumIter, EnumVariantNames, FromRepr};
+use std::sync::OnceLock;
+use strum::IntoEnumIterator;
+use strum_macros::{Display, EnumIter, IntoStaticStr};
 
-#[derive(Display, FromRepr, EnumIter, EnumVariantNames, PartialEq, Eq, Hash, Debug)]
+#[derive(Display, EnumIter, IntoStaticStr, Clone, Copy, PartialEq, Eq, Hash, Debug)]
 #[strum(serialize_all = "lowercase")]
 pub enum Language {
     C,
@@ -24,8 +26,17 @@ pub enum Language {
 }
 
 impl Language {
-    pub fn all() -> Vec<Language> {
-        Language::iter().collect()
+    /// Every language we have a grammar for, sorted by display name. Use
+    /// this instead of listing languages by hand so that adding a grammar
+    /// shows up everywhere automatically.
+    pub fn all() -> &'static [Language] {
+        static ALL: OnceLock<Vec<Language>> = OnceLock::new();
+
+        ALL.get_or_init(|| {
+            let mut all: Vec<Language> = Language::iter().collect();
+            all.sort_by_key(|lang| <&str>::from(*lang));
+            all
+        })
     }
 
     pub fn language(&self) -> tree_sitter::Language {
@@ -50,8 +61,11 @@ impl Language {
         }
     }
 
+    /// Compile a query against this language's grammar. If that fails, the
+    /// error wraps a `tree_sitter::QueryError`, so you can downcast to it to
+    /// get the row, column, byte offset, and kind of the problem.
     pub fn parse_query(&self, raw: &str) -> Result<tree_sitter::Query> {
-        tree_sitter::Query::new(self.language(), raw).map_err(|err| anyhow!("{}", err))
+        tree_sitter::Query::new(self.language(), raw).map_err(Error::from)
     }
 
     pub fn name_for_types_builder(&self) -> &str {
@@ -73,27 +87,244 @@ impl Language {
             Language::TypeScript => "ts",
         }
     }
+
+    /// The kinds of nodes that declare a named scope (like functions,
+    /// classes, and modules) in this language, each with the field its name
+    /// is in. Languages without any here never report a scope.
+    pub fn scope_kinds(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            // C and C++ function names are inside (possibly several layers
+            // of) declarators.
+            Language::C => &[
+                ("function_definition", "declarator"),
+                ("struct_specifier", "name"),
+                ("union_specifier", "name"),
+                ("enum_specifier", "name"),
+            ],
+            Language::Cpp => &[
+                ("function_definition", "declarator"),
+                ("class_specifier", "name"),
+                ("struct_specifier", "name"),
+                ("union_specifier", "name"),
+                ("enum_specifier", "name"),
+                ("namespace_definition", "name"),
+            ],
+            Language::Go => &[
+                ("function_declaration", "name"),
+                ("method_declaration", "name"),
+                ("type_spec", "name"),
+            ],
+            Language::Java => &[
+                ("class_declaration", "name"),
+                ("interface_declaration", "name"),
+                ("enum_declaration", "name"),
+                ("method_declaration", "name"),
+                ("constructor_declaration", "name"),
+            ],
+            Language::JavaScript => &[
+                ("function_declaration", "name"),
+                ("generator_function_declaration", "name"),
+                ("class_declaration", "name"),
+                ("method_definition", "name"),
+            ],
+            Language::Php => &[
+                ("function_definition", "name"),
+                ("class_declaration", "name"),
+                ("interface_declaration", "name"),
+                ("trait_declaration", "name"),
+                ("method_declaration", "name"),
+            ],
+            Language::Python => &[
+                ("function_definition", "name"),
+                ("class_definition", "name"),
+            ],
+            Language::Ruby => &[
+                ("method", "name"),
+                ("singleton_method", "name"),
+                ("class", "name"),
+                ("module", "name"),
+            ],
+            Language::Rust => &[
+                ("function_item", "name"),
+                ("impl_item", "type"),
+                ("trait_item", "name"),
+                ("mod_item", "name"),
+                ("struct_item", "name"),
+                ("enum_item", "name"),
+            ],
+            Language::TypeScript => &[
+                ("function_declaration", "name"),
+                ("generator_function_declaration", "name"),
+                ("class_declaration", "name"),
+                ("abstract_class_declaration", "name"),
+                ("interface_declaration", "name"),
+                ("method_definition", "name"),
+                ("module", "name"),
+                ("internal_module", "name"),
+            ],
+            Language::Elixir
+            | Language::Elm
+            | Language::Haskell
+            | Language::Markdown
+            | Language::Nix => &[],
+        }
+    }
+
+    /// The kinds of nodes that are comments in this language.
+    pub fn comment_kinds(&self) -> &'static [&'static str] {
+        match self {
+            Language::Elm | Language::Java | Language::Rust => &["line_comment", "block_comment"],
+            Language::Markdown => &[],
+            Language::C
+            | Language::Cpp
+            | Language::Elixir
+            | Language::Go
+            | Language::Haskell
+            | Language::JavaScript
+            | Language::Nix
+            | Language::Php
+            | Language::Python
+            | Language::Ruby
+            | Language::TypeScript => &["comment"],
+        }
+    }
+
+    /// A query that finds this language's local scopes, the variables defined
+    /// in them, and references to variables, in the same `@local.scope`,
+    /// `@local.definition`, and `@local.reference` captures as tree-sitter's
+    /// `locals.scm` files. This is what `#is? local` in a query is checked
+    /// with. Only JavaScript, Python, Rust, and TypeScript have one so far.
+    pub fn locals_query(&self) -> Option<&'static str> {
+        match self {
+            Language::JavaScript => Some(JAVASCRIPT_LOCALS),
+            Language::Python => Some(PYTHON_LOCALS),
+            Language::Rust => Some(RUST_LOCALS),
+            Language::TypeScript => Some(TYPESCRIPT_LOCALS),
+            _ => None,
+        }
+    }
+
+    /// Other names we accept for this language, like the short ones ripgrep
+    /// uses for its file types.
+    pub fn aliases(&self) -> &'static [&'static str] {
+        match self {
+            Language::Cpp => &["c++"],
+            Language::JavaScript => &["js"],
+            Language::Python => &["py"],
+            Language::TypeScript => &["ts"],
+            _ => &[],
+        }
+    }
 }
 
 impl FromStr for Language {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        let languages = Language::VARIANTS;
+        let languages = Language::all();
         languages
-            .binary_search(&s)
-            .map(|idx| Language::from_repr(idx).unwrap())
+            .binary_search_by(|lang| <&str>::from(*lang).cmp(s))
+            .map(|idx| languages[idx])
+            .or_else(|_| {
+                languages
+                    .iter()
+                    .find(|lang| lang.aliases().contains(&s))
+                    .copied()
+                    .ok_or(())
+            })
             .map_err(|_| {
                 anyhow!(
                     "unknown language {}. Try one of: {}",
                     s,
-                    languages.join(", ")
+                    languages.iter().join(", ")
                 )
             })
     }
 }
 
+// Function and class names aren't definitions here: they'd land in the scope
+// the function or class starts, not the one around it.
+const JAVASCRIPT_LOCALS: &str = r#"
+[
+  (statement_block)
+  (function_expression)
+  (arrow_function)
+  (function_declaration)
+  (method_definition)
+  (for_statement)
+  (for_in_statement)
+] @local.scope
+
+(formal_parameters (identifier) @local.definition)
+(formal_parameters (assignment_pattern left: (identifier) @local.definition))
+(arrow_function parameter: (identifier) @local.definition)
+(variable_declarator name: (identifier) @local.definition)
+
+(identifier) @local.reference
+"#;
+
+const TYPESCRIPT_LOCALS: &str = r#"
+[
+  (statement_block)
+  (function_expression)
+  (arrow_function)
+  (function_declaration)
+  (method_definition)
+  (for_statement)
+  (for_in_statement)
+] @local.scope
+
+(required_parameter pattern: (identifier) @local.definition)
+(optional_parameter pattern: (identifier) @local.definition)
+(arrow_function parameter: (identifier) @local.definition)
+(variable_declarator name: (identifier) @local.definition)
+
+(identifier) @local.reference
+"#;
+
+const PYTHON_LOCALS: &str = r#"
+[
+  (function_definition)
+  (lambda)
+  (list_comprehension)
+  (dictionary_comprehension)
+  (set_comprehension)
+  (generator_expression)
+] @local.scope
+
+(parameters (identifier) @local.definition)
+(default_parameter name: (identifier) @local.definition)
+(typed_parameter (identifier) @local.definition)
+(typed_default_parameter name: (identifier) @local.definition)
+(lambda_parameters (identifier) @local.definition)
+(assignment left: (identifier) @local.definition)
+(for_statement left: (identifier) @local.definition)
+(for_in_clause left: (identifier) @local.definition)
+
+(identifier) @local.reference
+"#;
+
+const RUST_LOCALS: &str = r#"
+[
+  (block)
+  (function_item)
+  (closure_expression)
+  (for_expression)
+  (match_arm)
+] @local.scope
+
+(parameter pattern: (identifier) @local.definition)
+(closure_parameters (identifier) @local.definition)
+(let_declaration pattern: (identifier) @local.definition)
+(let_declaration pattern: (tuple_pattern (identifier) @local.definition))
+(for_expression pattern: (identifier) @local.definition)
+
+(identifier) @local.reference
+"#;
+
+// The grammar bindings stay at the end of the file, below the tests.
 #[cfg(test)]
+#[allow(clippy::items_after_test_module)]
 mod tests {
     use super::*;
 
@@ -106,6 +337,17 @@ mod tests {
             .for_each(|lang| assert_eq!(Language::from_str(&lang.to_string()).unwrap(), lang))
     }
 
+    #[test]
+    fn aliases_are_unique_and_parse() {
+        let names: Vec<String> = Language::iter().map(|lang| lang.to_string()).collect();
+        for lang in Language::iter() {
+            for alias in lang.aliases() {
+                assert!(!names.iter().any(|name| name == alias), "{}", alias);
+                assert_eq!(Language::from_str(alias).unwrap(), lang);
+            }
+        }
+    }
+
     #[test]
     fn parse_query_smoke_test() {
         Language::iter().for_each(|lang| assert!(lang.parse_query("(_)").is_ok()));
@@ -113,15 +355,74 @@ mod tests {
 
     #[test]
     fn language_list_should_be_sorted() {
-        use itertools::Itertools;
         // sorted elements needed by binary_search in FromStr
         // TODO: use is_sorted: https://github.com/rust-lang/rust/issues/53485
-        assert!(Language::VARIANTS
+        assert!(Language::all()
             .iter()
+            .map(|lang| lang.to_string())
             .tuple_windows()
             .all(|(a, b)| a <= b));
     }
 
+    #[test]
+    fn all_has_every_language() {
+        assert_eq!(Language::all().len(), Language::iter().count());
+    }
+
+    #[test]
+    fn scope_kinds_are_in_the_grammar() {
+        for lang in Language::iter() {
+            let language = lang.language();
+            for (kind, field) in lang.scope_kinds() {
+                assert_ne!(
+                    language.id_for_node_kind(kind, true),
+                    0,
+                    "{} {}",
+                    lang,
+                    kind
+                );
+                assert!(
+                    language.field_id_for_name(field).is_some(),
+                    "{} {}",
+                    lang,
+                    field
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn locals_queries_parse() {
+        for lang in Language::iter() {
+            if let Some(query) = lang.locals_query() {
+                let query = lang.parse_query(query).unwrap();
+                for capture in ["local.scope", "local.definition", "local.reference"] {
+                    assert!(
+                        query.capture_index_for_name(capture).is_some(),
+                        "{} {}",
+                        lang,
+                        capture
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn comment_kinds_are_in_the_grammar() {
+        for lang in Language::iter() {
+            for kind in lang.comment_kinds() {
+                assert_ne!(
+                    lang.language().id_for_node_kind(kind, true),
+                    0,
+                    "{} {}",
+                    lang,
+                    kind
+                );
+            }
+        }
+    }
+
     #[test]
     fn parse_query_problem() {
         // tree-grepper 1.0 just printed the error struct when problems like
@@ -135,6 +436,19 @@ mod tests {
                 .to_string(),
         )
     }
+
+    #[test]
+    fn parse_query_problem_has_position() {
+        let err = Language::Elm
+            .parse_query("(import_clause)\n(node_that_doesnt_exist)")
+            .unwrap_err();
+        let query_err = err.downcast_ref::<tree_sitter::QueryError>().unwrap();
+
+        assert_eq!(query_err.row, 1);
+        assert_eq!(query_err.column, 1);
+        assert_eq!(query_err.offset, 17);
+        assert_eq!(query_err.kind, tree_sitter::QueryErrorKind::NodeType);
+    }
 }
 
 extern "C" {
@@ -0,0 +1,143 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use crossgrep::chunker::Chunker;
+use crossgrep::extractor::Extractor;
+use crossgrep::model::Model;
+use crossgrep::parser_pool::ParserPool;
+use crossgrep_sys::Language;
+use tree_sitter::Parser;
+
+// Each target is a language, a query like you'd give with -t, and a source
+// text with a realistic mix of things to match and things to skip.
+fn targets() -> Vec<(Language, &'static str, String)> {
+    vec![
+        (
+            Language::Rust,
+            "(function_item name: (identifier) @name) (call_expression) @call",
+            "fn greet(name: &str) -> usize {\n    let message = format!(\"Hello, {}!\", name);\n    println!(\"{}\", message);\n    message.len()\n}\n\n"
+                .repeat(200),
+        ),
+        (
+            Language::JavaScript,
+            "(function_declaration) @function (string) @string",
+            "function greet(name) {\n  const message = `Hello, ${name}!`;\n  console.log(message);\n  return message.length;\n}\n\n"
+                .repeat(200),
+        ),
+        (
+            Language::Python,
+            "(function_definition name: (identifier) @name) (call) @call",
+            "def greet(name):\n    message = f\"Hello, {name}!\"\n    print(message)\n    return len(message)\n\n\n"
+                .repeat(200),
+        ),
+    ]
+}
+
+fn extract_from_text(c: &mut Criterion) {
+    let mut group = c.benchmark_group("extract_from_text");
+
+    for (language, query, source) in targets() {
+        let extractor = Extractor::new(
+            language,
+            language.parse_query(query).expect("could not parse query"),
+            Model::Noop,
+        )
+        .expect("could not build extractor");
+        let mut parser = Parser::new();
+
+        group.throughput(Throughput::Bytes(source.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(language),
+            source.as_bytes(),
+            |b, source| {
+                b.iter(|| {
+                    extractor
+                        .extract_from_text(None, source, &mut parser)
+                        .expect("could not extract")
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+// A directory's worth of files in a few languages, grouped by language the
+// way a walk tends to find them, searched with a new parser for each file and
+// with parsers from a pool.
+fn mixed_languages(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mixed_languages");
+
+    let extractors: Vec<(Extractor, String)> = targets()
+        .into_iter()
+        .map(|(language, query, source)| {
+            let extractor = Extractor::new(
+                language,
+                language.parse_query(query).expect("could not parse query"),
+                Model::Noop,
+            )
+            .expect("could not build extractor");
+            // small files, so setting up parsers is a real part of the work
+            (
+                extractor,
+                source.lines().take(20).collect::<Vec<_>>().join("\n"),
+            )
+        })
+        .collect();
+    let files: Vec<&(Extractor, String)> = extractors
+        .iter()
+        .flat_map(|target| std::iter::repeat_n(target, 50))
+        .collect();
+
+    group.bench_function("new_parser_per_file", |b| {
+        b.iter(|| {
+            for (extractor, source) in &files {
+                extractor
+                    .extract_from_text(None, source.as_bytes(), &mut Parser::new())
+                    .expect("could not extract");
+            }
+        })
+    });
+
+    let pool = ParserPool::new();
+    group.bench_function("pooled_parser", |b| {
+        b.iter(|| {
+            for (extractor, source) in &files {
+                extractor
+                    .extract_from_text(None, source.as_bytes(), &mut pool.get())
+                    .expect("could not extract");
+            }
+        })
+    });
+
+    group.finish();
+}
+
+fn chunk_node(c: &mut Criterion) {
+    let mut group = c.benchmark_group("chunk_node");
+
+    // CodeBERT has a real chunk size, so bigger nodes get split into more
+    // chunks instead of all fitting into one like they would with Noop.
+    let chunker = Chunker::from_model(Model::CodeBert).expect("could not build chunker");
+    let mut parser = Parser::new();
+    parser
+        .set_language(Language::JavaScript.language())
+        .expect("could not set language");
+
+    for (size, repeats) in [("small", 1), ("medium", 50), ("large", 1_000)] {
+        let source = "let total = add(first, second) * scale;\n".repeat(repeats);
+        let tree = parser.parse(&source, None).expect("could not parse");
+
+        group.throughput(Throughput::Bytes(source.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &source, |b, source| {
+            b.iter(|| {
+                chunker
+                    .chunk_node(source.as_bytes(), &tree.root_node())
+                    .expect("could not chunk")
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, extract_from_text, mixed_languages, chunk_node);
+criterion_main!(benches);
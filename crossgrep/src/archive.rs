@@ -0,0 +1,171 @@
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// The kinds of archives we know how to look inside of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveKind {
+    pub fn for_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+
+        if name.ends_with(".zip") {
+            Some(ArchiveKind::Zip)
+        } else if name.ends_with(".tar") {
+            Some(ArchiveKind::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveKind::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+/// The path we show for a file inside an archive, like
+/// `archive.zip!src/lib.rs`.
+pub fn virtual_path(archive: &Path, inner: &Path) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push("!");
+    path.push(inner.as_os_str());
+
+    PathBuf::from(path)
+}
+
+/// Call `visit` with the path and contents of each regular file in an
+/// archive, without extracting anything to disk.
+pub fn for_each_file(
+    archive: &Path,
+    kind: ArchiveKind,
+    mut visit: impl FnMut(&Path, &mut dyn Read) -> Result<()>,
+) -> Result<()> {
+    let file = File::open(archive).context("could not open archive")?;
+
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = zip::ZipArchive::new(file).context("could not read zip archive")?;
+            for index in 0..zip.len() {
+                let mut entry = zip
+                    .by_index(index)
+                    .context("could not read zip archive entry")?;
+                if !entry.is_file() {
+                    continue;
+                }
+
+                // `enclosed_name` refuses names that would escape the
+                // archive (like `../../etc/passwd`.) We never write these
+                // anywhere, but they'd make for confusing output.
+                let inner = match entry.enclosed_name() {
+                    Some(inner) => inner.to_owned(),
+                    None => continue,
+                };
+
+                visit(&inner, &mut entry)?;
+            }
+        }
+
+        ArchiveKind::Tar => for_each_tar_file(file, visit)?,
+
+        ArchiveKind::TarGz => for_each_tar_file(GzDecoder::new(file), visit)?,
+    }
+
+    Ok(())
+}
+
+fn for_each_tar_file(
+    reader: impl Read,
+    mut visit: impl FnMut(&Path, &mut dyn Read) -> Result<()>,
+) -> Result<()> {
+    let mut tar = tar::Archive::new(reader);
+
+    for entry in tar.entries().context("could not read tar archive")? {
+        let mut entry = entry.context("could not read tar archive entry")?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let inner = entry
+            .path()
+            .context("could not read the path of a tar archive entry")?
+            .into_owned();
+
+        visit(&inner, &mut entry)?;
+    }
+
+    Ok(())
+}
+
+/// Read all of `reader`, unless it's longer than `limit` bytes. We can't trust
+/// the sizes archives claim for their files (that's how zip bombs work) so we
+/// check how much we actually got instead.
+pub fn read_limited(reader: &mut dyn Read, limit: Option<u64>) -> io::Result<Option<Vec<u8>>> {
+    let mut contents = Vec::new();
+
+    match limit {
+        Some(limit) => {
+            reader.take(limit + 1).read_to_end(&mut contents)?;
+            if contents.len() as u64 > limit {
+                return Ok(None);
+            }
+        }
+        None => {
+            reader.read_to_end(&mut contents)?;
+        }
+    }
+
+    Ok(Some(contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_kinds_come_from_names() {
+        assert_eq!(
+            ArchiveKind::for_path(Path::new("deps/foo-1.0.ZIP")),
+            Some(ArchiveKind::Zip)
+        );
+        assert_eq!(
+            ArchiveKind::for_path(Path::new("foo.tar")),
+            Some(ArchiveKind::Tar)
+        );
+        assert_eq!(
+            ArchiveKind::for_path(Path::new("foo.tar.gz")),
+            Some(ArchiveKind::TarGz)
+        );
+        assert_eq!(
+            ArchiveKind::for_path(Path::new("foo.tgz")),
+            Some(ArchiveKind::TarGz)
+        );
+        assert_eq!(ArchiveKind::for_path(Path::new("foo.gz")), None);
+        assert_eq!(ArchiveKind::for_path(Path::new("src/lib.rs")), None);
+    }
+
+    #[test]
+    fn virtual_paths_point_inside_the_archive() {
+        assert_eq!(
+            virtual_path(Path::new("deps/foo.zip"), Path::new("src/lib.rs")),
+            PathBuf::from("deps/foo.zip!src/lib.rs")
+        );
+    }
+
+    #[test]
+    fn read_limited_refuses_too_much() {
+        assert_eq!(
+            read_limited(&mut &b"12345"[..], Some(5)).unwrap(),
+            Some(b"12345".to_vec())
+        );
+        assert_eq!(read_limited(&mut &b"123456"[..], Some(5)).unwrap(), None);
+        assert_eq!(
+            read_limited(&mut &b"123456"[..], None).unwrap(),
+            Some(b"123456".to_vec())
+        );
+    }
+}
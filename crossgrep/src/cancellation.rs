@@ -0,0 +1,30 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A flag that can be flipped from any thread to ask an in-flight search to
+/// stop. Clones share the same flag, so hand one to each `Extractor` and keep
+/// one around to call `cancel` on.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    // tree-sitter checks an `AtomicUsize` for its cancellation flag, so we use
+    // one here instead of an `AtomicBool` so the parser can watch it directly.
+    flag: Arc<AtomicUsize>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.flag.store(1, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed) != 0
+    }
+
+    pub(crate) fn flag(&self) -> &AtomicUsize {
+        &self.flag
+    }
+}
@@ -1,88 +1,782 @@
+use crate::error::{Error, Result};
 use crate::model::Model;
-use anyhow::{bail, Result};
-use serde::Serialize;
-use tokenizers::tokenizer::Tokenizer;
-use tree_sitter::Node;
+use serde::{Serialize, Serializer};
+use std::cmp::{max, min};
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::str::FromStr;
+use tokenizers::tokenizer::{Encoding, Tokenizer};
+use tree_sitter::{Node, Point};
+
+/// How to split a match that's too long for the model into chunks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChunkStrategy {
+    /// Windows that end at line breaks where possible, only cutting mid-line
+    /// when a line is too long for a chunk by itself.
+    #[default]
+    Line,
+    /// Windows that start and end between the matched node's children where
+    /// possible (descending into children too big to fit in one chunk) so
+    /// chunks line up with statements, items, and so on.
+    Node,
+}
+
+impl FromStr for ChunkStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "line" => Ok(ChunkStrategy::Line),
+            "node" => Ok(ChunkStrategy::Node),
+            _ => anyhow::bail!("unknown chunk strategy {}. Try line or node.", s),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Chunker {
     tokenizer: Tokenizer,
-    model: Model,
     chunk_size: usize,
     chunk_overlap: usize,
+    strategy: ChunkStrategy,
+    add_special_tokens: bool,
+    decode: bool,
+    overlaps: bool,
+    lookbehind: Option<usize>,
 }
 
 impl Chunker {
-    pub fn from_model(model: Model) -> Self {
-        Chunker {
-            model: model.clone(),
-            tokenizer: model.tokenizer(),
-            chunk_size: model.chunk_size() - model.special_tokens(),
+    pub fn from_model(model: Model) -> Result<Self> {
+        Ok(Chunker {
+            tokenizer: model.tokenizer()?,
+            chunk_size: content_size(
+                model.chunk_size(),
+                model.chunk_overlap(),
+                model.special_tokens(),
+            )?,
             chunk_overlap: model.chunk_overlap(),
-        }
+            strategy: ChunkStrategy::default(),
+            add_special_tokens: model.tokenizer_options().add_special_tokens,
+            decode: false,
+            overlaps: false,
+            lookbehind: None,
+        })
+    }
+
+    pub fn set_strategy(&mut self, strategy: ChunkStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// Decode each chunk's ids back into text (without special tokens) and
+    /// keep it in the chunk's `text`, to check where chunk boundaries fell.
+    pub fn set_decode(&mut self, decode: bool) {
+        self.decode = decode;
+    }
+
+    /// Count how many tokens each chunk shares with the chunks before and
+    /// after it, in its `overlap_start` and `overlap_end`. This is for
+    /// checking that chunks overlap by as much as the model says they should.
+    pub fn set_overlaps(&mut self, overlaps: bool) {
+        self.overlaps = overlaps;
+    }
+
+    /// How many tokens back from where a window has to end we look for a
+    /// boundary (a line break, or a node for `ChunkStrategy::Node`) to end it
+    /// at instead. If there isn't one that close, the window is cut where it
+    /// has to be. Without this, we look back as far as the start of the
+    /// window, which keeps syntax together but can leave chunks short.
+    pub fn set_lookbehind(&mut self, lookbehind: Option<usize>) {
+        self.lookbehind = lookbehind;
+    }
+
+    /// How the tokenizer sees `source`, token by token, with the same
+    /// encoding and offsets we use for chunking. This is for debugging
+    /// chunks that come out looking wrong.
+    pub fn tokens(&self, source: &str) -> Result<Vec<Token>> {
+        let encoding = self
+            .tokenizer
+            .encode(source, self.add_special_tokens)
+            .map_err(Error::Tokenizer)?;
+
+        encoding
+            .get_ids()
+            .iter()
+            .enumerate()
+            .map(|(index, id)| {
+                Ok(Token {
+                    id: *id,
+                    text: self
+                        .tokenizer
+                        .decode(&[*id], false)
+                        .map_err(Error::Tokenizer)?,
+                    start_byte: token_start(&encoding, index),
+                    end_byte: token_end(&encoding, index),
+                })
+            })
+            .collect()
     }
 
     pub fn chunk_node(&self, source: &[u8], node: &Node) -> Result<Vec<ExtractedChunk>> {
         assert!(source.len() == node.end_byte() - node.start_byte());
 
-        let source_str = std::str::from_utf8(source).expect("invalid utf-8");
-        let encoding = match self.tokenizer.encode(source_str, false) {
-            Ok(encoding) => encoding,
-            Err(err) => bail!("Could not encode source: {}", err),
-        };
+        self.chunk_source(source, node.start_byte(), node.start_position(), *node)
+    }
+
+    /// Chunk text that didn't come from a tree, like snippets extracted some
+    /// other way. Without nodes to line chunks up with, windows end at line
+    /// breaks where they can (whatever the strategy) and only cut mid-line
+    /// when a line is too long for a chunk by itself. Positions are relative
+    /// to the start of `source`.
+    pub fn chunk_text(&self, source: &[u8]) -> Result<Vec<ExtractedChunk>> {
+        self.chunk_text_at(source, 0, Point::new(0, 0))
+    }
+
+    /// Like `chunk_text`, but for text that starts at `start_byte` and `start`
+    /// in a file, so chunks get file-absolute positions.
+    pub(crate) fn chunk_text_at(
+        &self,
+        source: &[u8],
+        start_byte: usize,
+        start: Point,
+    ) -> Result<Vec<ExtractedChunk>> {
+        self.chunk_with(source, start_byte, start, |encoding| {
+            self.line_windows(source, encoding)
+        })
+    }
+
+    /// Chunk `source`, which starts at `start_byte` and `start` in the file it
+    /// came from. Those are used to give chunks file-absolute positions.
+    /// `node` is the smallest node containing all of `source`, which we use to
+    /// find boundaries for `ChunkStrategy::Node`.
+    pub(crate) fn chunk_source(
+        &self,
+        source: &[u8],
+        start_byte: usize,
+        start: Point,
+        node: Node,
+    ) -> Result<Vec<ExtractedChunk>> {
+        self.chunk_with(source, start_byte, start, |encoding| match self.strategy {
+            ChunkStrategy::Line => self.line_windows(source, encoding),
+            ChunkStrategy::Node => self.node_windows(encoding, start_byte, node),
+        })
+    }
+
+    /// Tokenize `source` and, if it's too big for one chunk, split it into the
+    /// token ranges `windows` picks.
+    fn chunk_with(
+        &self,
+        source: &[u8],
+        start_byte: usize,
+        start: Point,
+        windows: impl FnOnce(&Encoding) -> Vec<Range<usize>>,
+    ) -> Result<Vec<ExtractedChunk>> {
+        let source_str = std::str::from_utf8(source)
+            .map_err(|err| Error::Chunk(format!("source isn't valid UTF-8: {}", err)))?;
+        let encoding = self
+            .tokenizer
+            .encode(source_str, self.add_special_tokens)
+            .map_err(Error::Tokenizer)?;
         let ids = encoding.get_ids();
 
-        if ids.len() < self.chunk_size - self.model.special_tokens() {
-            let mut tokens = vec![0; ids.len()];
-            tokens.clone_from_slice(ids);
+        let mut chunks = if ids.len() <= self.chunk_size {
+            let mut chunk =
+                ExtractedChunk::new(ids.to_vec(), source, start_byte, start, 0, source.len());
+            if self.overlaps {
+                chunk.overlap_start = Some(0);
+                chunk.overlap_end = Some(0);
+            }
 
-            return Ok(vec![ExtractedChunk {
-                ids: tokens,
-                start_byte: 0,
-                end_byte: source.len(),
-            }]);
+            vec![chunk]
+        } else {
+            let windows = windows(&encoding);
+            let mut chunks: Vec<ExtractedChunk> = windows
+                .iter()
+                .map(|window| {
+                    ExtractedChunk::new(
+                        ids[window.clone()].to_vec(),
+                        source,
+                        start_byte,
+                        start,
+                        token_start(&encoding, window.start),
+                        token_end(&encoding, window.end - 1),
+                    )
+                })
+                .collect();
+
+            if self.overlaps {
+                for (index, chunk) in chunks.iter_mut().enumerate() {
+                    let window = &windows[index];
+                    chunk.overlap_start = Some(match index.checked_sub(1) {
+                        Some(before) => windows[before].end.saturating_sub(window.start),
+                        None => 0,
+                    });
+                    chunk.overlap_end = Some(match windows.get(index + 1) {
+                        Some(after) => window.end.saturating_sub(after.start),
+                        None => 0,
+                    });
+                }
+            }
+
+            chunks
+        };
+
+        if self.decode {
+            for chunk in &mut chunks {
+                chunk.text = Some(
+                    self.tokenizer
+                        .decode(&chunk.ids, true)
+                        .map_err(Error::Tokenizer)?,
+                );
+            }
         }
 
-        let mut chunk_start = 0;
-        let mut chunk_end = 0;
-        let mut chunks = Vec::new();
+        Ok(chunks)
+    }
 
-        loop {
-            chunk_end += 1;
-
-            if chunk_end - chunk_start > self.chunk_size {
-                chunk_end = std::cmp::min(ids.len(), chunk_end + self.chunk_overlap);
-                let mut chunk_ids: Vec<u32> = vec![0; chunk_end - chunk_start];
-                chunk_ids.clone_from_slice(&ids[chunk_start..chunk_end]);
-                chunks.push(ExtractedChunk {
-                    ids: chunk_ids,
-                    start_byte: encoding
-                        .token_to_chars(chunk_start)
-                        .expect("token out of range")
-                        .1
-                         .0,
-                    end_byte: encoding
-                        .token_to_chars(chunk_start)
-                        .expect("token out of range")
-                        .1
-                         .1,
-                });
-
-                chunk_start = chunk_end - self.chunk_overlap;
+    /// Windows of up to `chunk_size` tokens that end at the last line break
+    /// that fits, with overlaps that start at a line break too (or are
+    /// skipped if there isn't one close enough.)
+    fn line_windows(&self, source: &[u8], encoding: &Encoding) -> Vec<Range<usize>> {
+        let boundaries = source
+            .iter()
+            .enumerate()
+            .filter(|(_, byte)| **byte == b'\n')
+            .map(|(newline, _)| token_containing(encoding, newline + 1))
+            .collect();
+
+        self.boundary_windows(encoding.get_ids().len(), &boundaries)
+    }
+
+    /// Like `line_windows`, but each window ends at the last child boundary
+    /// that fits, and the overlap starts at a child boundary too (or is
+    /// skipped if there isn't one close enough.) We only cut mid-node when a
+    /// single node is too big for a chunk all by itself.
+    fn node_windows(
+        &self,
+        encoding: &Encoding,
+        start_byte: usize,
+        node: Node,
+    ) -> Vec<Range<usize>> {
+        let len = encoding.get_ids().len();
+        let token_at = |byte: usize| first_token_at(encoding, byte.saturating_sub(start_byte));
+
+        let mut boundaries = BTreeSet::new();
+        let mut too_big = vec![node];
+        while let Some(parent) = too_big.pop() {
+            let mut cursor = parent.walk();
+            for child in parent.children(&mut cursor) {
+                let (first, last) = (token_at(child.start_byte()), token_at(child.end_byte()));
+                boundaries.insert(first);
+                boundaries.insert(last);
+
+                if last - first > self.chunk_size {
+                    too_big.push(child);
+                }
             }
+        }
+
+        self.boundary_windows(len, &boundaries)
+    }
+
+    /// Windows over `len` tokens that end at the last of `boundaries` (token
+    /// indexes) that fits and is within `lookbehind`, with overlaps that
+    /// start at one too.
+    fn boundary_windows(&self, len: usize, boundaries: &BTreeSet<usize>) -> Vec<Range<usize>> {
+        let mut windows = Vec::new();
+        let mut window_start = 0;
+
+        loop {
+            let limit = min(window_start + self.chunk_size, len);
+            let window_end = if limit == len {
+                len
+            } else {
+                let earliest = match self.lookbehind {
+                    Some(lookbehind) => max(window_start + 1, limit.saturating_sub(lookbehind)),
+                    None => window_start + 1,
+                };
+                boundaries
+                    .range(earliest..=limit)
+                    .next_back()
+                    .copied()
+                    .unwrap_or(limit)
+            };
+            windows.push(window_start..window_end);
 
-            if chunk_end > ids.len() {
-                break;
+            if window_end == len {
+                return windows;
             }
+
+            let overlap_start = max(
+                window_end.saturating_sub(self.chunk_overlap),
+                window_start + 1,
+            );
+            window_start = boundaries
+                .range(overlap_start..window_end)
+                .next()
+                .copied()
+                .unwrap_or(window_end);
         }
+    }
+}
 
-        Ok(chunks)
+/// The index of the first token at or after `byte` in the encoded source.
+fn first_token_at(encoding: &Encoding, byte: usize) -> usize {
+    encoding
+        .get_offsets()
+        .partition_point(|(token_start, _)| *token_start < byte)
+}
+
+/// The index of the token `byte` is in, or the first one after it if it's
+/// between tokens. Tokenizers can merge a newline with what comes after it
+/// (like `\n` and the indentation on the next line), and a chunk starting
+/// at that token keeps the whole line instead of cutting off its start.
+fn token_containing(encoding: &Encoding, byte: usize) -> usize {
+    encoding
+        .get_offsets()
+        .partition_point(|(_, token_end)| *token_end <= byte)
+}
+
+/// How many tokens of source fit in a chunk once the model's special tokens
+/// are added. There has to be room for at least one token more than the
+/// overlap, or we'd never make progress through the source.
+fn content_size(chunk_size: usize, chunk_overlap: usize, special_tokens: usize) -> Result<usize> {
+    match chunk_size.checked_sub(special_tokens) {
+        Some(content_size) if content_size > chunk_overlap => Ok(content_size),
+        _ => Err(Error::ChunkSize {
+            chunk_size,
+            chunk_overlap,
+            special_tokens,
+        }),
+    }
+}
+
+// note: `encode` gives us byte offsets (not character offsets) into the source
+// we passed it, which is what we want here.
+fn token_start(encoding: &Encoding, token: usize) -> usize {
+    token_bytes(encoding, token).0
+}
+
+fn token_end(encoding: &Encoding, token: usize) -> usize {
+    token_bytes(encoding, token).1
+}
+
+// special tokens the tokenizer added itself (with `add_special_tokens`) don't
+// come from any of the source, so we put them right after the token before
+// them, taking up no room.
+fn token_bytes(encoding: &Encoding, token: usize) -> (usize, usize) {
+    assert!(token < encoding.len(), "token out of range");
+
+    match encoding.token_to_chars(token) {
+        Some((_, bytes)) => bytes,
+        None => {
+            let at = (0..token)
+                .rev()
+                .find_map(|before| encoding.token_to_chars(before))
+                .map(|(_, (_, end))| end)
+                .unwrap_or(0);
+
+            (at, at)
+        }
+    }
+}
+
+/// One token of source, from `Chunker::tokens`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Token {
+    pub id: u32,
+    /// The token decoded back into text on its own.
+    pub text: String,
+    /// Byte offset of the start of the token in the source it came from.
+    pub start_byte: usize,
+    /// Byte offset of the end of the token in the source it came from.
+    pub end_byte: usize,
+}
+
+/// The chunks for a match. With one model this is a plain list; with more,
+/// it's a list for each model, which serializes as an object keyed by model
+/// name (in the order the models were given.)
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Chunks {
+    One(Vec<ExtractedChunk>),
+    PerModel(Vec<(String, Vec<ExtractedChunk>)>),
+}
+
+impl Chunks {
+    /// The chunks for the model called `name`. A single model's chunks are
+    /// returned whatever the name.
+    pub fn for_model(&self, name: &str) -> Option<&[ExtractedChunk]> {
+        match self {
+            Chunks::One(chunks) => Some(chunks),
+            Chunks::PerModel(per_model) => per_model
+                .iter()
+                .find(|(model, _)| model == name)
+                .map(|(_, chunks)| chunks.as_slice()),
+        }
+    }
+
+    /// Change every chunk's byte offsets with `f`, like when the source they
+    /// were chunked from was decoded from some other encoding.
+    pub(crate) fn map_bytes(&mut self, f: impl Fn(usize) -> usize) {
+        let all = match self {
+            Chunks::One(chunks) => vec![chunks],
+            Chunks::PerModel(per_model) => per_model.iter_mut().map(|(_, chunks)| chunks).collect(),
+        };
+
+        for chunk in all.into_iter().flatten() {
+            chunk.start_byte = f(chunk.start_byte);
+            chunk.end_byte = f(chunk.end_byte);
+        }
+    }
+}
+
+impl Serialize for Chunks {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Chunks::One(chunks) => chunks.serialize(serializer),
+            Chunks::PerModel(per_model) => {
+                serializer.collect_map(per_model.iter().map(|(model, chunks)| (model, chunks)))
+            }
+        }
     }
 }
 
 #[derive(Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ExtractedChunk {
     pub ids: Vec<u32>,
+    /// Byte offset of the start of the chunk in the file (not the node.)
     pub start_byte: usize,
+    /// Byte offset of the end of the chunk in the file (not the node.)
     pub end_byte: usize,
+    /// The 0-based row in the file the chunk starts on. This is serialized
+    /// as a 1-based line number, same as match positions.
+    #[serde(serialize_with = "serialize_line")]
+    pub start_line: usize,
+    /// The 0-based row in the file the chunk ends on. This is serialized as a
+    /// 1-based line number, same as match positions.
+    #[serde(serialize_with = "serialize_line")]
+    pub end_line: usize,
+    /// The chunk's ids decoded back into text, without special tokens. Only
+    /// set if the chunker was asked to with `set_decode`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// How many of the chunk's first tokens the chunk before it ends with.
+    /// Only set if the chunker was asked to with `set_overlaps`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overlap_start: Option<usize>,
+    /// How many of the chunk's last tokens the chunk after it starts with.
+    /// Only set if the chunker was asked to with `set_overlaps`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overlap_end: Option<usize>,
+}
+
+impl ExtractedChunk {
+    /// Make a chunk covering `chunk_start..chunk_end` of `source`, which itself
+    /// starts at `start_byte` and `start` in the file.
+    fn new(
+        ids: Vec<u32>,
+        source: &[u8],
+        start_byte: usize,
+        start: Point,
+        chunk_start: usize,
+        chunk_end: usize,
+    ) -> ExtractedChunk {
+        let rows_before = |offset: usize| {
+            source[..offset]
+                .iter()
+                .filter(|byte| **byte == b'\n')
+                .count()
+        };
+
+        ExtractedChunk {
+            ids,
+            start_byte: start_byte + chunk_start,
+            end_byte: start_byte + chunk_end,
+            start_line: start.row + rows_before(chunk_start),
+            // the last byte of the chunk is at `chunk_end - 1`, so a chunk
+            // ending in a newline ends on that line, not the next one.
+            end_line: start.row + rows_before(chunk_end.saturating_sub(1).max(chunk_start)),
+            text: None,
+            overlap_start: None,
+            overlap_end: None,
+        }
+    }
+}
+
+fn serialize_line<S>(line: &usize, sz: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    sz.serialize_u64(*line as u64 + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossgrep_sys::Language;
+    use tokenizers::models::bpe::BPE;
+    use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+    use tree_sitter::{Parser, Tree};
+
+    const SOURCE: &str = "function f() {\n  let alpha = one(two, three);\n  let beta = four(five, six);\n  let gamma = seven(eight, nine);\n  let delta = ten(eleven, twelve);\n}";
+
+    fn chunker(strategy: ChunkStrategy) -> Chunker {
+        Chunker {
+            tokenizer: Model::Noop.tokenizer().unwrap(),
+            chunk_size: 16,
+            chunk_overlap: 4,
+            strategy,
+            add_special_tokens: false,
+            decode: false,
+            overlaps: false,
+            lookbehind: None,
+        }
+    }
+
+    fn parse() -> Tree {
+        let mut parser = Parser::new();
+        parser
+            .set_language(Language::JavaScript.language())
+            .unwrap();
+        parser.parse(SOURCE, None).unwrap()
+    }
+
+    fn node_starts(node: Node, starts: &mut Vec<usize>) {
+        starts.push(node.start_byte());
+        let mut cursor = node.walk();
+        for child in node.children(&mut cursor) {
+            node_starts(child, starts);
+        }
+    }
+
+    #[test]
+    fn line_chunks_cover_the_whole_source() {
+        let tree = parse();
+        let chunks = chunker(ChunkStrategy::Line)
+            .chunk_node(SOURCE.as_bytes(), &tree.root_node())
+            .unwrap();
+
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.ids.len() <= 16));
+        assert_eq!(chunks.first().unwrap().start_byte, 0);
+        assert_eq!(chunks.last().unwrap().end_byte, SOURCE.len());
+    }
+
+    #[test]
+    fn node_chunks_start_at_nodes() {
+        let tree = parse();
+        let chunks = chunker(ChunkStrategy::Node)
+            .chunk_node(SOURCE.as_bytes(), &tree.root_node())
+            .unwrap();
+
+        let mut starts = Vec::new();
+        node_starts(tree.root_node(), &mut starts);
+
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.ids.len() <= 16));
+        assert_eq!(chunks.first().unwrap().start_byte, 0);
+        assert_eq!(chunks.last().unwrap().end_byte, SOURCE.len());
+        for chunk in &chunks {
+            assert!(
+                starts.contains(&chunk.start_byte),
+                "chunk starting at {} isn't at a node",
+                chunk.start_byte
+            );
+        }
+    }
+
+    #[test]
+    fn chunks_can_count_their_overlaps() {
+        // lines are 2 tokens each, so there's always a line break right where
+        // the overlap wants to start.
+        let text = "a\n".repeat(20);
+        let mut chunker = chunker(ChunkStrategy::Line);
+        let overlaps = |chunker: &Chunker| {
+            chunker
+                .chunk_text(text.as_bytes())
+                .unwrap()
+                .into_iter()
+                .map(|chunk| (chunk.overlap_start, chunk.overlap_end))
+                .collect::<Vec<_>>()
+        };
+
+        assert!(overlaps(&chunker)
+            .iter()
+            .all(|overlap| *overlap == (None, None)));
+
+        // every window overlaps the next by the chunker's 4 tokens
+        chunker.set_overlaps(true);
+        let counted = overlaps(&chunker);
+        assert!(counted.len() > 2);
+        assert_eq!(counted.first(), Some(&(Some(0), Some(4))));
+        assert_eq!(counted.last(), Some(&(Some(4), Some(0))));
+        assert!(counted[1..counted.len() - 1]
+            .iter()
+            .all(|overlap| *overlap == (Some(4), Some(4))));
+    }
+
+    #[test]
+    fn text_chunks_keep_lines_whole_when_newlines_merge_into_tokens() {
+        // like the bytes tokenizer, but each newline merges with the `a`
+        // starting the line after it, so no token starts where a line does.
+        let alphabet = ByteLevel::alphabet();
+        let vocab = alphabet
+            .iter()
+            .map(char::to_string)
+            .chain(["Ċa".to_string()])
+            .enumerate()
+            .map(|(id, token)| (token, id as u32))
+            .collect();
+        let mut tokenizer = Tokenizer::new(
+            BPE::builder()
+                .vocab_and_merges(vocab, vec![("Ċ".to_string(), "a".to_string())])
+                .build()
+                .unwrap(),
+        );
+        tokenizer.with_pre_tokenizer(ByteLevel::new(false, true, false));
+        let chunker = Chunker {
+            tokenizer,
+            ..chunker(ChunkStrategy::Line)
+        };
+
+        let text = "abcde\n".repeat(10);
+        let chunks = chunker.chunk_text(text.as_bytes()).unwrap();
+
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.last().unwrap().end_byte, text.len());
+        for chunk in &chunks[1..] {
+            assert!(text[chunk.start_byte..].starts_with("\nabcde"));
+        }
+    }
+
+    #[test]
+    fn text_chunks_end_at_line_breaks() {
+        // the noop tokenizer has a token for every character, so these lines
+        // are 6 tokens each and two fit in a chunk.
+        let text = "abcde\n".repeat(10);
+        let chunks = chunker(ChunkStrategy::Line)
+            .chunk_text(text.as_bytes())
+            .unwrap();
+
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.first().unwrap().start_byte, 0);
+        assert_eq!(chunks.last().unwrap().end_byte, text.len());
+        for chunk in &chunks {
+            assert!(text[..chunk.end_byte].ends_with('\n'));
+            assert!(
+                text[..chunk.start_byte].is_empty() || text[..chunk.start_byte].ends_with('\n')
+            );
+        }
+    }
+
+    #[test]
+    fn longer_lookbehinds_end_chunks_at_earlier_line_breaks() {
+        // lines are 6 tokens each, so the first window has to end 4 tokens
+        // into the third line.
+        let text = "abcde\n".repeat(10);
+        let first_chunk_end = |lookbehind| {
+            let mut chunker = chunker(ChunkStrategy::Line);
+            chunker.set_lookbehind(Some(lookbehind));
+            chunker.chunk_text(text.as_bytes()).unwrap()[0].end_byte
+        };
+
+        assert_eq!(first_chunk_end(2), 16);
+        assert_eq!(first_chunk_end(4), 12);
+    }
+
+    #[test]
+    fn noop_chunks_of_big_sources_can_be_prepared() {
+        let source = "let foo = bar(baz);\n".repeat(2_000);
+        let mut parser = Parser::new();
+        parser
+            .set_language(Language::JavaScript.language())
+            .unwrap();
+        let tree = parser.parse(&source, None).unwrap();
+
+        let model = Model::Noop;
+        let chunks = Chunker::from_model(model.clone())
+            .unwrap()
+            .chunk_node(source.as_bytes(), &tree.root_node())
+            .unwrap();
+
+        assert_eq!(chunks.len(), 1);
+        for chunk in chunks {
+            let mut input_ids = Vec::new();
+            model.prepare_input_ids(&mut input_ids, &chunk.ids).unwrap();
+
+            assert_eq!(input_ids, chunk.ids);
+            assert_eq!(model.attention_mask(chunk.ids.len()).len(), input_ids.len());
+        }
+    }
+
+    #[test]
+    fn tokens_have_byte_offsets_into_the_source() {
+        let source = "let café = 1;";
+        let tokens = chunker(ChunkStrategy::Line).tokens(source).unwrap();
+
+        assert_eq!(tokens.first().unwrap().start_byte, 0);
+        assert_eq!(tokens.last().unwrap().end_byte, source.len());
+        for pair in tokens.windows(2) {
+            assert!(pair[0].start_byte <= pair[1].start_byte);
+        }
+
+        // é is two bytes, and a token for each. Neither decodes to anything
+        // on its own, and both point at the whole character.
+        let accented: Vec<&Token> = tokens
+            .iter()
+            .filter(|token| token.start_byte == source.find('é').unwrap())
+            .collect();
+        assert_eq!(accented.len(), 2);
+        for token in accented {
+            assert_eq!(token.end_byte, token.start_byte + 'é'.len_utf8());
+            assert_eq!(token.text, "\u{FFFD}");
+        }
+    }
+
+    #[test]
+    fn tiny_chunk_sizes_are_rejected() {
+        assert_eq!(content_size(512, 64, 2).unwrap(), 510);
+        assert_eq!(content_size(4, 1, 2).unwrap(), 2);
+
+        // no room for anything but special tokens
+        assert!(matches!(
+            content_size(2, 0, 2),
+            Err(Error::ChunkSize { .. })
+        ));
+        // would underflow
+        assert!(matches!(
+            content_size(1, 0, 2),
+            Err(Error::ChunkSize { .. })
+        ));
+        assert!(matches!(
+            content_size(0, 0, 0),
+            Err(Error::ChunkSize { .. })
+        ));
+        // the overlap would eat the whole chunk
+        assert!(matches!(
+            content_size(66, 64, 2),
+            Err(Error::ChunkSize { .. })
+        ));
+    }
+
+    #[test]
+    fn decoded_chunks_match_their_source() {
+        let mut chunker = chunker(ChunkStrategy::Line);
+        chunker.set_decode(true);
+
+        let chunks = chunker.chunk_text(SOURCE.as_bytes()).unwrap();
+        assert!(chunks.len() > 1);
+        for chunk in chunks {
+            // offsets leave out the whitespace a token starts with, but
+            // decoding keeps it.
+            assert!(chunk
+                .text
+                .unwrap()
+                .contains(&SOURCE[chunk.start_byte..chunk.end_byte]));
+        }
+
+        chunker.set_decode(false);
+        assert!(chunker.chunk_text(SOURCE.as_bytes()).unwrap()[0]
+            .text
+            .is_none());
+    }
 }
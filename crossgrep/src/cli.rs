@@ -1,42 +1,261 @@
-use crate::extractor::Extractor;
-use crate::extractor_chooser::ExtractorChooser;
-use crate::model::Model;
+use crate::exec::ExecCommand;
+use crate::library::Library;
+use crate::logging;
+use crate::presets::Presets;
+use crate::remote::{Checkout, Remote};
+use crate::template::Template;
 use anyhow::{bail, Context, Error, Result};
+use clap::builder::{PossibleValue, PossibleValuesParser, TypedValueParser};
+use clap::parser::ValueSource;
 use clap::{crate_authors, crate_version, Arg, ArgAction, ArgMatches, Command};
-use itertools::Itertools;
+use crossgrep::chunker::{ChunkStrategy, Chunker};
+use crossgrep::diagnostics::{Diagnostic, DiagnosticFormat};
+use crossgrep::extractor::{ColumnUnit, ExtractedMatch, Extractor};
+use crossgrep::extractor_chooser::ExtractorChooser;
+use crossgrep::model::Model;
+use crossgrep::parser_pool::{ParserPool, PooledParser};
+use crossgrep::regex_extractor::RegexExtractor;
 use crossgrep_sys::Language;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE};
+use itertools::Itertools;
+use regex::{bytes, Regex, RegexBuilder};
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Once;
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+use tree_sitter::{Parser, Query, QueryError, QueryErrorKind};
 
 pub enum Invocation {
-    DoQuery(QueryOpts),
-    ShowLanguages,
+    DoQuery(Box<QueryOpts>),
+    ShowLanguages(QueryFormat),
     ShowTree(TreeOpts),
+    CheckQueries(Vec<(String, String)>),
+    /// Say why a file would or wouldn't be searched, from `--explain`.
+    Explain(Box<QueryOpts>, PathBuf),
 }
 
 #[derive(Debug)]
 pub struct QueryOpts {
     pub extractors: Vec<Extractor>,
+    /// For the files none of `extractors` claim, from `-t regex`.
+    pub regex: Option<RegexExtractor>,
+    /// Languages we have queries for but couldn't load the grammar of, so
+    /// they aren't in `extractors`.
+    pub failed_grammars: Vec<Language>,
+    /// Whether `extractors` can have more than one extractor for a language,
+    /// from `--separate-queries`.
+    pub separate_queries: bool,
     pub paths: Vec<PathBuf>,
+    pub files_from: Option<PathBuf>,
+    /// Source to search instead of any files, from `--text`.
+    pub text: Option<String>,
+    pub remote: Option<Remote>,
+    /// Where `remote` got cloned to, once it has been. `paths` are inside
+    /// this.
+    pub checkout: Option<Checkout>,
+    pub relative_to: Option<RelativeTo>,
+    /// Whether we've warned about a path outside `relative_to` yet. We only
+    /// do it for the first one, since every match in every file outside it
+    /// would get the same warning otherwise.
+    pub outside_relative_to_warning: Once,
+    pub path_regex: Option<Regex>,
+    pub line_ranges: Vec<LineRange>,
     pub git_ignore: bool,
+    pub gitattributes: bool,
+    pub ignore_parents: bool,
+    /// Names of directories to skip (with everything in them) wherever they
+    /// turn up in the walk.
+    pub exclude_dirs: Vec<OsString>,
+    pub follow_links: bool,
     pub format: QueryFormat,
+    pub dot_scope_edges: bool,
+    pub group_by: Option<GroupBy>,
+    pub template: Option<Template>,
+    /// A command to run for matches instead of printing them.
+    pub exec: Option<ExecCommand>,
+    pub heading: Option<bool>,
+    /// Show match text in the lines format with line numbers, from
+    /// `--number-lines`.
+    pub number_lines: bool,
+    /// Parsers for searching files, with the `--timeout` set.
+    pub parsers: ParserPool,
+    pub max_filesize: Option<u64>,
+    pub binary: bool,
+    /// The encoding from `--encoding`, if one was given.
+    pub encoding: Option<&'static Encoding>,
+    pub archives: bool,
+    pub stats: bool,
+    pub report_unmatched_captures: bool,
+    pub diagnostics: DiagnosticFormat,
+    pub count_matches: bool,
+    pub count_by_kind: bool,
+    pub max_total: Option<usize>,
+    /// Tokenizers (one per model, by name) to show matches through instead
+    /// of printing them, for `--dump-tokens`.
+    pub dump_tokens: Vec<(String, Chunker)>,
+    pub only_matching: bool,
+    pub only_matching_trim: bool,
+    pub quiet: bool,
+    pub watch: bool,
+    pub progress: bool,
+    pub watch_clear: bool,
 }
 
 impl QueryOpts {
-    pub fn extractor_chooser(&self) -> Result<ExtractorChooser> {
-        ExtractorChooser::from_extractors(&self.extractors)
+    pub fn extractor_chooser(&self) -> Result<ExtractorChooser<'_>> {
+        let mut chooser = if self.separate_queries {
+            ExtractorChooser::from_separate_extractors(&self.extractors)?
+        } else {
+            ExtractorChooser::from_extractors(&self.extractors)?
+        };
+        chooser.set_gitattributes(self.gitattributes);
+        if let Some(regex) = &self.regex {
+            chooser.set_fallback(regex);
+        }
+
+        // one warning for each set of languages, rather than for every glob
+        // they share
+        let mut overlaps: Vec<(&[Language], Vec<&str>)> = Vec::new();
+        for overlap in chooser.overlaps() {
+            match overlaps
+                .iter_mut()
+                .find(|(languages, _)| *languages == overlap.languages.as_slice())
+            {
+                Some((_, globs)) => globs.push(&overlap.glob),
+                None => overlaps.push((&overlap.languages, vec![&overlap.glob])),
+            }
+        }
+        for (languages, globs) in overlaps {
+            let languages: Vec<String> = languages.iter().map(|l| l.to_string()).collect();
+            tracing::warn!(
+                "{} files could be {}, but only the {} queries will search them",
+                globs.join(", "),
+                languages.join(" or "),
+                languages[0]
+            );
+        }
+
+        Ok(chooser)
+    }
+
+    /// Whether to print matches under a heading for each file. Unless we were
+    /// told either way, we do this when a person is going to be reading the
+    /// output, like ripgrep does.
+    pub fn heading(&self, out_is_terminal: bool) -> bool {
+        self.heading.unwrap_or(out_is_terminal)
+    }
+
+    /// A parser for searching files, from `parsers`.
+    pub fn parser(&self) -> PooledParser<'_> {
+        self.parsers.get()
+    }
+
+    /// How to show `path` in results. Inside a `--git-url` checkout that's
+    /// relative to the checkout, since where we cloned it to doesn't mean
+    /// anything to anyone. Otherwise it's relative to `--relative-to`, if
+    /// that was given and `path` is inside it.
+    pub fn shown_path<'path>(&self, path: &'path Path) -> Cow<'path, Path> {
+        let (shown, outside_relative_to) = self.shown_path_quietly(path);
+
+        if outside_relative_to {
+            self.outside_relative_to_warning.call_once(|| {
+                self.diagnostics.report(Diagnostic {
+                    file: Some(path),
+                    code: "outside-relative-to",
+                    message: format!(
+                        "{} isn't inside --relative-to, so it's shown as an absolute path (as are any others like it)",
+                        path.display()
+                    ),
+                })
+            });
+        }
+
+        shown
+    }
+
+    /// `shown_path` without the warning, plus whether it would have warned.
+    fn shown_path_quietly<'path>(&self, path: &'path Path) -> (Cow<'path, Path>, bool) {
+        if let Some(checkout) = &self.checkout {
+            return (
+                Cow::Borrowed(path.strip_prefix(checkout.dir()).unwrap_or(path)),
+                false,
+            );
+        }
+
+        match self.relative_to.as_ref().map(|base| base.apply(path)) {
+            Some(Ok(relative)) => (Cow::Owned(relative), false),
+            Some(Err(absolute)) => (Cow::Owned(absolute), true),
+            None => (Cow::Borrowed(path), false),
+        }
+    }
+
+    /// Does this path pass `--path-regex`? Everything passes if it wasn't
+    /// given.
+    pub fn path_matches(&self, path: &Path) -> bool {
+        match &self.path_regex {
+            Some(regex) => regex.is_match(&path.to_string_lossy()),
+            None => true,
+        }
+    }
+
+    /// Should files that look binary be skipped? They are unless we're told
+    /// to search them, or that files are UTF-16, where every ASCII character
+    /// comes with a NUL byte that would make them all look binary.
+    pub fn skips_binary(&self) -> bool {
+        let utf16 = self
+            .encoding
+            .is_some_and(|encoding| encoding == UTF_16LE || encoding == UTF_16BE);
+
+        !self.binary && !utf16
+    }
+
+    /// Like `path_matches`, but for a path on disk that hasn't been through
+    /// `shown_path` yet. This is for filtering files before we open them, and
+    /// it doesn't warn about paths outside `--relative-to` since we might not
+    /// end up showing them.
+    pub fn file_path_matches(&self, path: &Path) -> bool {
+        self.path_regex.is_none() || self.path_matches(&self.shown_path_quietly(path).0)
+    }
+
+    /// Is this match inside one of the `--line-range`s for its file? Every
+    /// match is if none were given.
+    pub fn in_line_ranges(&self, file: Option<&Path>, extracted: &ExtractedMatch) -> bool {
+        self.line_ranges.is_empty()
+            || self.line_ranges.iter().any(|range| {
+                range.overlaps(file, extracted.start().row + 1, extracted.end().row + 1)
+            })
     }
 }
 
 #[derive(Debug)]
 pub struct TreeOpts {
     pub language: Language,
-    pub path: PathBuf,
+    pub input: TreeInput,
+}
+
+/// What `--show-tree` shows the tree of.
+#[derive(Debug)]
+pub enum TreeInput {
+    Path(PathBuf),
+    /// Source given with `--text`.
+    Text(String),
 }
 
 impl Invocation {
+    /// Parse the command line and build what it asks for in one go. `try_main`
+    /// does these separately so it can set up logging in between.
+    #[cfg(test)]
     pub fn from_args(args: Vec<String>) -> Result<Self> {
+        Self::from_matches(Self::parse_args(args)?)
+    }
+
+    pub fn parse_args(args: Vec<String>) -> Result<ArgMatches> {
         // I'm not super happy with this! I would love for LANGUAGE and QUERY to
         // be taken positionally when there is just one so we don't always have
         // to specify `-q`. However, I also want to get working on the rest of
@@ -48,7 +267,7 @@ impl Invocation {
         // Check
         // https://users.rust-lang.org/t/grep-like-argument-parsing-with-clap/63392
         // for where I asked about this in public.
-        let matches = Command::new("tree-grepper")
+        Command::new("tree-grepper")
             .version(crate_version!())
             .author(crate_authors!())
             .arg(
@@ -56,11 +275,67 @@ impl Invocation {
                     .short('t')
                     .long("target")
                     .help("a language and tree-sitter query to restrict semantic search to")
-                    .long_help("a language and tree-sitter query to restrict semantic search to")
+                    .long_help("a language and tree-sitter query to restrict semantic search to. Give this more than once to search with several queries. If more than one language claims a file (like C and C++ both claiming `.h` files) the language of the first query given searches it. Use `regex` as the language to search with a regex instead, for files in languages we don't have a grammar for: `-t regex 'TODO\\w*'` searches every file no other -t claims, and reports each match with the capture name `match` and the kind `regex`. --text-match, --text-not-match, --min-lines, --min-bytes, and --encoding apply to regex matches too. Options for what queries find, like --expand-to, are an error when every target is a regex.")
                     .number_of_values(2)
                     .value_names(["LANGUAGE", "TARGET"])
-                    .required_unless_present("languages")
-                    .required_unless_present("show-tree")
+                    .required_unless_present_any([
+                        "languages",
+                        "show-tree",
+                        "preset",
+                        "kind",
+                        "from-library",
+                    ])
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+                    .action(ArgAction::Append),
+            )
+            .arg(
+                Arg::new("kind")
+                    .long("kind")
+                    .help("search for every node of one kind, without writing a query")
+                    .long_help("search for every node of one kind, without writing a query. `--kind rust function_item` is the same as `-t rust '(function_item) @match'`. Use --show-tree on a file to see what kinds of nodes are in it. Can be given more than once, and combined with -t and --preset.")
+                    .number_of_values(2)
+                    .value_names(["LANGUAGE", "KIND"])
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+                    .action(ArgAction::Append),
+            )
+            .arg(
+                Arg::new("preset")
+                    .long("preset")
+                    .value_name("NAME")
+                    .help("search with a named set of queries from the presets file")
+                    .long_help("search with a named set of queries from the presets file. Presets are tables mapping language names to queries, like `[imports]` followed by `javascript = \"(import_statement) @import\"`. You can give --preset more than once, and combine it with -t. If a preset and -t (or two presets) have queries for the same language, neither wins: all of them are searched, just like giving -t several times for one language.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+                    .action(ArgAction::Append),
+            )
+            .arg(
+                Arg::new("presets-file")
+                    .long("presets-file")
+                    .value_name("FILE")
+                    .help("where to find presets (default: queries.toml in crossgrep's config directory, like ~/.config/crossgrep/queries.toml)")
+                    .requires("preset")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("from-library")
+                    .long("from-library")
+                    .value_name("FILE")
+                    .help("a file of named queries to pick from with --pattern")
+                    .long_help("a file of named queries to pick from with --pattern. Name each pattern with a `; @name NAME` comment right before it, and give the language of the patterns below it with a `; @language LANGUAGE` comment (files named after a language, like rust.scm, don't need one.) Patterns that aren't picked are ignored, so a library can hold every query you use. Can be combined with -t and --preset.")
+                    .requires("pattern")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("pattern")
+                    .long("pattern")
+                    .value_name("NAMES")
+                    .value_delimiter(',')
+                    .help("the names of the patterns to search with from --from-library, like imports,exports")
+                    .requires("from-library")
                     .conflicts_with("languages")
                     .conflicts_with("show-tree")
                     .action(ArgAction::Append),
@@ -68,7 +343,47 @@ impl Invocation {
             .arg(
                 Arg::new("no-gitignore")
                     .long("no-gitignore")
+                    .action(ArgAction::SetTrue)
                     .help("don't use git's ignore and exclude files to filter files")
+                    .long_help("don't use git's ignore and exclude files to filter files. By default we skip whatever git would: files matched by .gitignore, .git/info/exclude, or the global excludes file (core.excludesFile, or ~/.config/git/ignore), in that order of precedence. This applies to every path being searched; to search some paths with their ignore files and some without, run crossgrep once for each.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("no-gitattributes")
+                    .long("no-gitattributes")
+                    .action(ArgAction::SetTrue)
+                    .help("pick languages by file name only, ignoring linguist-language in .gitattributes")
+                    .long_help("pick languages by file name only. By default a linguist-language attribute in a .gitattributes file (like `*.foo linguist-language=C`) picks the language of the files it matches, as long as there's a query for that language. Only .gitattributes files in the directories of the paths being searched are read, not ones above them.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("no-ignore-parent")
+                    .long("no-ignore-parent")
+                    .action(ArgAction::SetTrue)
+                    .help("don't use ignore files in the directories above the paths being searched")
+                    .long_help("don't use ignore files in the directories above the paths being searched. Useful for searching a subdirectory that a parent directory's .gitignore would otherwise hide.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("exclude-dir")
+                    .long("exclude-dir")
+                    .value_name("NAME")
+                    .action(ArgAction::Append)
+                    .help("skip directories named NAME, wherever they are (can be given more than once)")
+                    .long_help("skip directories named NAME (like node_modules or target) and everything in them, however deep they are. We don't look inside them at all, so this is faster than ignoring their files. This is on top of whatever ignore files skip, and doesn't apply to the paths given on the command line. Can be given more than once.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("follow")
+                    .long("follow")
+                    .short('L')
+                    .action(ArgAction::SetTrue)
+                    .help("search inside symlinked directories")
+                    .long_help("search inside symlinked directories. Matches are reported under the symlink's path, not where it points. A symlink that points back to a directory we're already inside is skipped instead of being followed around forever.")
                     .conflicts_with("languages")
                     .conflicts_with("show-tree"),
             )
@@ -76,7 +391,29 @@ impl Invocation {
                 Arg::new("MODEL")
                     .long("model")
                     .short('m')
-                    .help("model to use for embedding")
+                    .action(ArgAction::Append)
+                    .help("model to use for embedding [default: $CROSSGREP_MODEL, or codebert]")
+                    .long_help("model to use for embedding. Without this we use the model named in the CROSSGREP_MODEL environment variable, or codebert if that isn't set. Give this more than once to chunk every match for each model so you can compare them; chunks are then an object keyed by model name instead of a list. Models changed with --model-config are named like codebert:small.json.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("model-config")
+                    .long("model-config")
+                    .value_name("FILE")
+                    .action(ArgAction::Append)
+                    .help("change the model's settings with a JSON file")
+                    .long_help("change the model's settings with a JSON file. With more than one --model, this changes the --model right before it, and can be given once for each. Any of tokenizer_source (a Hugging Face tokenizer name or a path to a tokenizer.json), chunk_size, chunk_overlap, special_tokens, and framing (an object with start_token, end_token, and pad_token ids, or null for none) can be given; anything left out stays the way the model from --model has it. For example, {\"tokenizer_source\": \"my-tokenizer.json\"} keeps CodeBERT's chunking with a different vocabulary.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("tokenizer-opt")
+                    .long("tokenizer-opt")
+                    .value_name("KEY=VALUE")
+                    .action(ArgAction::Append)
+                    .help("pass an option to the tokenizer, like add_special_tokens=true")
+                    .long_help("pass an option to the tokenizer, like add_special_tokens=true. Can be given more than once, and applies to every --model (after any --model-config). VALUE is read as JSON when it can be. The only option so far is add_special_tokens, which lets the tokenizer add its own special tokens when encoding source (off by default, since chunks get framed separately). Unknown options are an error.")
                     .conflicts_with("languages")
                     .conflicts_with("show-tree"),
             )
@@ -86,151 +423,1660 @@ impl Invocation {
                     .help("places to search for matches")
                     .num_args(1..),
             )
+            .arg(
+                Arg::new("text")
+                    .long("text")
+                    .value_name("SOURCE")
+                    .help("search SOURCE itself instead of any files")
+                    .long_help("search SOURCE itself instead of any files, which is handy for trying out queries. There's no file name to pick a language with, so the queries have to be for exactly one language. Matches are shown as coming from NO FILE (or a null file in JSON). With --show-tree, shows the tree of SOURCE instead of a file.")
+                    .conflicts_with("PATHS")
+                    .conflicts_with("files-from")
+                    .conflicts_with("git-url")
+                    .conflicts_with("watch")
+                    .conflicts_with("languages"),
+            )
+            .arg(
+                Arg::new("files-from")
+                    .long("files-from")
+                    .value_name("LIST")
+                    .help("search the files listed in this file (one per line) instead of walking PATHS. Use - to read the list from stdin.")
+                    .long_help("search the files listed in this file (one per line) instead of walking PATHS. Use - to read the list from stdin. Files are still matched to languages by name, and missing files get a warning instead of stopping the search.")
+                    .conflicts_with("PATHS")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("git-url")
+                    .long("git-url")
+                    .value_name("URL")
+                    .help("search a git repository instead of local files")
+                    .long_help("search a git repository instead of local files. It's shallow-cloned into a temporary directory that's removed after the search (or kept in --clone-cache), and PATHS are searched inside it. Paths in results are relative to the repository. Needs git to be installed.")
+                    .conflicts_with("files-from")
+                    .conflicts_with("watch")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("relative-to")
+                    .long("relative-to")
+                    .value_name("BASE")
+                    .help("show paths in results relative to this directory")
+                    .long_help("show paths in results relative to this directory, like the root of a repository in CI logs, no matter where crossgrep was run from or how the paths to search were given. Paths outside BASE are shown in full, with a warning. This applies to every output format, and to what --path-regex and --line-range see.")
+                    .conflicts_with("git-url")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("ref")
+                    .long("ref")
+                    .value_name("REF")
+                    .requires("git-url")
+                    .help("the branch or tag to search with --git-url")
+                    .long_help("the branch or tag to search with --git-url, instead of the repository's default branch"),
+            )
+            .arg(
+                Arg::new("clone-cache")
+                    .long("clone-cache")
+                    .value_name("DIR")
+                    .requires("git-url")
+                    .help("keep --git-url clones in this directory and re-use them")
+                    .long_help("keep --git-url clones in this directory and re-use them instead of cloning again. Each repository and --ref gets its own clone. Cached clones aren't updated; delete one to get a fresh copy."),
+            )
+            .arg(
+                Arg::new("path-regex")
+                    .long("path-regex")
+                    .value_name("PATTERN")
+                    .value_parser(Regex::new)
+                    .help("only search files whose path matches this regex")
+                    .long_help("only search files whose path matches this regex. The regex is matched against the whole path as it would be printed in the output (so `src/.*_test\\.rs$` works), and isn't anchored unless you anchor it. Files are filtered before they're parsed, so this is a cheap way to narrow down a big search.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("text-match")
+                    .long("text-match")
+                    .value_name("REGEX")
+                    .help("only show matches whose text matches this regex")
+                    .long_help("only show matches whose text matches this regex, like `--text-match '^test_'` with a query for function names. The regex isn't anchored unless you anchor it. Use --ignore-case to match regardless of case.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("text-not-match")
+                    .long("text-not-match")
+                    .value_name("REGEX")
+                    .help("don't show matches whose text matches this regex")
+                    .long_help("don't show matches whose text matches this regex. Can be combined with --text-match to keep matches that match one regex but not another.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("min-lines")
+                    .long("min-lines")
+                    .value_name("LINES")
+                    .value_parser(clap::value_parser!(usize))
+                    .help("don't show matches that span fewer than this many lines")
+                    .long_help("don't show matches that span fewer than this many lines, counting the lines they start and end on (so a match on one line spans 1.) Handy for seeing only the substantial matches of a broad query.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("min-bytes")
+                    .long("min-bytes")
+                    .value_name("BYTES")
+                    .value_parser(clap::value_parser!(usize))
+                    .help("don't show matches shorter than this many bytes")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("ignore-case")
+                    .short('i')
+                    .long("ignore-case")
+                    .action(ArgAction::SetTrue)
+                    .help("make --text-match and --text-not-match ignore case")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("line-range")
+                    .long("line-range")
+                    .value_name("[PATH:]START:END")
+                    .value_parser(LineRange::from_str)
+                    .action(ArgAction::Append)
+                    .help("only show matches that touch these lines")
+                    .long_help("only show matches that touch these lines. Lines start at 1 and both ends are included, so 10:20 is 11 lines long. Prefix a range with a path (like src/lib.rs:10:20) to only apply it to that file; ranges without a path apply to every file. Give this more than once to keep matches in any of the ranges. Matches that are only partly inside a range are kept.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
             .arg(
                 Arg::new("FORMAT")
                     .long("format")
                     .short('f')
-                    .value_parser(["lines", "json", "json-lines", "pretty-json"])
+                    .value_parser([
+                        PossibleValue::new("lines")
+                            .help("one line per match, like `path:row:column:capture:text`"),
+                        PossibleValue::new("json").help(
+                            "a single JSON array with one object per file (`[]` if nothing matched)",
+                        ),
+                        PossibleValue::new("json-lines").help(
+                            "one JSON object per file, each on its own line (nothing if nothing matched)",
+                        ),
+                        PossibleValue::new("json-stream-array").help(
+                            "the same array as `json`, flushed after each file so it can be read as it's written",
+                        ),
+                        PossibleValue::new("json-lines-matches").help(
+                            "one JSON object per match, each on its own line, with the file's path and type in every one",
+                        ),
+                        PossibleValue::new("pretty-json").help("the same array as `json`, but indented"),
+                        PossibleValue::new("lsp").help(
+                            "a JSON array of language server protocol locations, with 0-based lines and UTF-16 characters",
+                        ),
+                        PossibleValue::new("dot").help(
+                            "a Graphviz graph with a cluster for each file and a node for each match",
+                        ),
+                    ])
                     .default_value("lines")
                     .help("what format should we output lines in?")
+                    .long_help("what format should we output lines in? With --languages, `json` and `pretty-json` print an array with each language's name, aliases, file extensions, and grammar ABI version instead of just the names.")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("dot-scope-edges")
+                    .long("dot-scope-edges")
+                    .action(ArgAction::SetTrue)
+                    .help("with --format dot, connect matches in the same function, class, or module")
+                    .long_help("with --format dot, connect matches in the same function, class, or module (the same enclosing scope as the {scope} template placeholder) with dashed edges. Each match is connected to the next one in its scope.")
                     .conflicts_with("languages")
                     .conflicts_with("show-tree"),
             )
             .arg(
-                Arg::new("languages")
-                    .long("languages")
+                Arg::new("group-by")
+                    .long("group-by")
+                    .value_name("WHAT")
+                    .value_parser([PossibleValue::new("capture")
+                        .help("put all the matches for each capture name together")])
+                    .help("group matches across all files instead of listing them file by file")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("template")
+                    .long("template")
+                    .value_name("TEMPLATE")
+                    .help("lay out each line of output like this, e.g. '{path}:{line} [{name}] {text}'")
+                    .long_help("lay out each line of output like this, e.g. '{path}:{line} [{name}] {text}'. Available placeholders are {path}, {file_type}, {line}, {column}, {end_line}, {end_column}, {byte_start}, {byte_end}, {name}, {kind}, {scope} (the name of the function, class, or module the match is in, or nothing), and {text}, plus {metadata.KEY} for whatever a `#set!` directive in the matching pattern gave KEY (or nothing.) Lines and columns start at 1 and byte offsets at 0. Use {{ and }} for literal braces. Only works with the lines format.")
+                    .conflicts_with("count-matches")
+                    .conflicts_with("quiet")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("exec")
+                    .long("exec")
+                    .value_name("COMMAND")
+                    .help("run COMMAND for each match instead of printing it, e.g. 'rustfmt --check {}'")
+                    .long_help("run COMMAND for each match instead of printing it, e.g. 'rustfmt --check {}'. COMMAND is split into words like a shell would (with quotes and backslashes, but nothing else), and each word can use the same placeholders as --template, plus {} for the path of a temporary file with the match's text in it. The file has the same extension as the one the match came from. What the commands print goes to stdout and stderr as usual. A command that fails gets a warning with the code exec-failed, and crossgrep keeps going but exits with an error at the end.")
+                    .conflicts_with("exec-batch")
+                    .conflicts_with("FORMAT")
+                    .conflicts_with("template")
+                    .conflicts_with("only-matching")
+                    .conflicts_with("count-matches")
+                    .conflicts_with("count-by-kind")
+                    .conflicts_with("quiet")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("exec-batch")
+                    .long("exec-batch")
+                    .value_name("COMMAND")
+                    .help("run COMMAND once with the arguments for every match, e.g. 'rustfmt --check {path}'")
+                    .long_help("run COMMAND once when the search is done, with the arguments for every match, instead of printing them, e.g. 'rustfmt --check {path}'. This is like --exec, but each word with a placeholder becomes one argument per match, so `{path}` passes every file with a match. Arguments that come out the same for several matches (like the path of a file with many matches) are only passed once. The command doesn't run at all if nothing matched.")
+                    .conflicts_with("FORMAT")
+                    .conflicts_with("template")
+                    .conflicts_with("only-matching")
+                    .conflicts_with("count-matches")
+                    .conflicts_with("count-by-kind")
+                    .conflicts_with("quiet")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("heading")
+                    .long("heading")
                     .action(ArgAction::SetTrue)
-                    .help("print the language names crossgrep knows about")
-                    .conflicts_with("additional-target")
+                    .overrides_with("no-heading")
+                    .help("print the filename once above its matches instead of on every line")
+                    .long_help("print the filename once above its matches instead of on every line, with a blank line between files. This is the default when printing to a terminal. Only affects the lines format without --group-by or --template.")
+                    .conflicts_with("languages")
                     .conflicts_with("show-tree"),
             )
             .arg(
-                Arg::new("show-tree")
-                    .long("show-tree")
-                    .help("Show the node names and associated text of the specified files")
-                    .value_names(["LANGUAGE"])
-                    .action(ArgAction::Append)
+                Arg::new("no-heading")
+                    .long("no-heading")
+                    .action(ArgAction::SetTrue)
+                    .overrides_with("heading")
+                    .help("print the filename on every line, even when printing to a terminal")
                     .conflicts_with("languages")
-                    .conflicts_with("additional-target"),
+                    .conflicts_with("show-tree"),
             )
             .arg(
-                Arg::new("QUERY")
-                    .last(true)
-                    .number_of_values(1)
-                    .value_name("QUERY")
-                    .required_unless_present("languages")
-                    .required_unless_present("show-tree")
+                Arg::new("timeout")
+                    .long("timeout")
+                    .value_name("MS")
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .help("give up on parsing a file after this many milliseconds")
+                    .long_help("give up on parsing a file after this many milliseconds. Files that time out are skipped with a warning instead of stopping the search.")
                     .conflicts_with("languages")
-                    .conflicts_with("show-tree")
-                    .num_args(1..)
-                    .action(ArgAction::Append),
+                    .conflicts_with("show-tree"),
             )
-            .try_get_matches_from(args)
-            .context("could not parse args")?;
-
-        if matches.get_flag("languages") {
-            Ok(Self::ShowLanguages)
-        } else if let Some(raw_lang) = matches.get_one::<String>("show-tree") {
-            let lang = Language::from_str(raw_lang).context("could not parse language")?;
-
-            let paths = Self::paths(&matches)?;
-            if paths.len() != 1 {
-                anyhow::bail!("need exactly one path to print a tree")
-            }
-
-            Ok(Self::ShowTree(TreeOpts {
-                language: lang,
-                path: paths[0].to_owned(),
-            }))
-        } else {
-            Ok(Self::DoQuery(QueryOpts {
-                extractors: Self::extractors(&matches)?,
-                paths: Self::paths(&matches)?,
-                git_ignore: !matches.contains_id("no-gitignore"),
-                format: QueryFormat::from_str(
-                    matches
-                        .get_one::<String>("FORMAT")
-                        .context("format not provided")?,
-                )
-                .context("could not set format")?,
-            }))
-        }
-    }
-
-    fn extractors(matches: &ArgMatches) -> Result<Vec<Extractor>> {
-        let values = match matches.get_many::<String>("additional-target") {
-            Some(values) => values,
-            None => bail!("queries were required but not provided. This indicates an internal error and you should report it!"),
-        };
-
-        let model_identifier = matches
-            .get_one::<String>("MODEL")
-            .context("model not provided")?;
-        let model = Model::from_pretrained(model_identifier).context("model not supported")?;
-
-        // the most common case is going to be one query, so let's allocate
-        // that immediately...
-        let mut query_strings: HashMap<Language, String> = HashMap::with_capacity(1);
-
-        // If you have two tree-sitter queries `(one)` and `(two)`, you can
-        // join them together in a single string like `(one)(two)`. In that
-        // case, the resulting query will act like an OR and match any of the
-        // queries inside. Doing this automatically gives us an advantage:
-        // for however many queries we get on the command line, we will only
-        // ever have to run one per file, since we can combine them and you
-        // can't specify queries across multiple languages! Nobody should ever
-        // notice, except that they won't see as much of a slowdown for adding
-        // new queries to an invocation as they might expect. (Well, hopefully!)
-        for (raw_lang, raw_query) in values.tuples() {
-            let lang = Language::from_str(raw_lang).context("could not parse language")?;
-
-            let mut query_out = String::from(raw_query);
-
-            let temp_query = lang
-                .parse_query(raw_query)
-                .context("could not parse query")?;
-
-            if temp_query.capture_names().is_empty() {
-                query_out.push_str("@query");
-            }
-
-            if let Some(existing) = query_strings.get_mut(&lang) {
-                existing.push_str(&query_out);
-            } else {
-                query_strings.insert(lang, query_out);
-            }
-        }
-
-        let mut out = Vec::with_capacity(query_strings.len());
-        for (lang, raw_query) in query_strings {
-            let query = lang
-                .parse_query(&raw_query)
-                .context("could not parse combined query")?;
-
-            out.push(Extractor::new(lang, query, model))
-        }
-
-        Ok(out)
-    }
-
-    fn paths(matches: &ArgMatches) -> Result<Vec<PathBuf>> {
-        match matches.get_many::<String>("PATHS") {
-            Some(values) =>
-                values
-                    .map(|raw_path| PathBuf::from_str(raw_path).with_context(|| format!("could not parse a path from {}", raw_path)))
-                    .collect(),
-
-            None => bail!("at least one path was required but not provided. This indicates an internal errors and you should report it!"),
-        }
-    }
-}
-
-#[derive(Debug)]
-pub enum QueryFormat {
-    Lines,
-    Json,
-    JsonLines,
+            .arg(
+                Arg::new("max-filesize")
+                    .long("max-filesize")
+                    .value_name("SIZE")
+                    .value_parser(parse_filesize)
+                    .default_value("10M")
+                    .help("skip files larger than this. Accepts suffixes like 500K, 10M, or 1G. Use 0 for no limit.")
+                    .long_help("skip files larger than this. Accepts suffixes like 500K, 10M, or 1G. Use 0 for no limit. The default is meant to keep things like minified bundles and data files from eating up time and memory.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("binary")
+                    .long("binary")
+                    .action(ArgAction::SetTrue)
+                    .help("search binary files instead of skipping them")
+                    .long_help("search binary files instead of skipping them. A file counts as binary if it has a NUL byte near the start.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("no-archives")
+                    .long("no-archives")
+                    .action(ArgAction::SetTrue)
+                    .help("don't search inside .zip, .tar, and .tar.gz archives")
+                    .long_help("don't search inside .zip, .tar, and .tar.gz archives. Normally we read the files inside archives without extracting them, and report them with paths like `archive.zip!src/lib.rs`. --max-filesize applies to the size of each file after decompressing it.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("coalesce-captures")
+                    .long("coalesce-captures")
+                    .action(ArgAction::SetTrue)
+                    .help("join repeated captures of the same name in one match into a single result")
+                    .long_help("join repeated captures of the same name in one match into a single result. This is useful for quantified captures like `(comment)* @docs`, which otherwise produce one result per node. The joined result spans from the start of the first node to the end of the last.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("merge-adjacent")
+                    .long("merge-adjacent")
+                    .value_name("GAP")
+                    .value_parser(clap::value_parser!(usize))
+                    .num_args(0..=1)
+                    .default_missing_value("0")
+                    .help("merge matches in a file that touch or are at most GAP lines apart into one")
+                    .long_help("merge matches in a file that overlap or have at most GAP lines between them (0 if not given, so matches on consecutive lines merge) into one match covering all of them. This turns runs of statements into blocks, which is handy for embedding. Merged matches keep the first match's capture name, and list every merged match's capture name in `names` in JSON output.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("chunk-strategy")
+                    .long("chunk-strategy")
+                    .value_name("STRATEGY")
+                    .value_parser(
+                        PossibleValuesParser::new([
+                            PossibleValue::new("line")
+                                .help("end chunks at line breaks where possible"),
+                            PossibleValue::new("node").help(
+                                "split between the matched node's children where possible, so chunks line up with syntax",
+                            ),
+                        ])
+                        .try_map(|s| s.parse::<ChunkStrategy>()),
+                    )
+                    .default_value("line")
+                    .help("how to split matches that are too long for the model into chunks")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("chunk-lookbehind")
+                    .long("chunk-lookbehind")
+                    .value_name("TOKENS")
+                    .value_parser(clap::value_parser!(usize))
+                    .help("how many tokens back from a chunk's end to look for a better place to end it")
+                    .long_help("how many tokens back from where a chunk has to end to look for a better place to end it: a line break, or a node boundary with `--chunk-strategy node`. If there isn't one that close, the chunk is cut where it has to be. By default we look back as far as the start of the chunk, which keeps lines and nodes together but can leave chunks much shorter than the model allows; a small number fills chunks up more at the cost of cutting through syntax more often.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("column-unit")
+                    .long("column-unit")
+                    .value_name("UNIT")
+                    .value_parser(
+                        PossibleValuesParser::new([
+                            PossibleValue::new("byte").help("count bytes, like tree-sitter does"),
+                            PossibleValue::new("char").help("count Unicode characters"),
+                            PossibleValue::new("utf16").help(
+                                "count UTF-16 code units, like the language server protocol does",
+                            ),
+                        ])
+                        .try_map(|s| s.parse::<ColumnUnit>()),
+                    )
+                    .default_value("byte")
+                    .hide_default_value(true)
+                    .help("how to count the columns of matches [default: char for lines, utf16 for lsp, byte otherwise]")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("diagnostics")
+                    .long("diagnostics")
+                    .value_name("FORMAT")
+                    .value_parser(
+                        PossibleValuesParser::new([
+                            PossibleValue::new("text").help("warning: lines for people to read"),
+                            PossibleValue::new("json").help(
+                                "one JSON object per line, with file, code, and message fields",
+                            ),
+                        ])
+                        .try_map(|s| s.parse::<DiagnosticFormat>()),
+                    )
+                    .default_value("text")
+                    .help("how to print warnings about files we had to skip or leave matches out of")
+                    .long_help("how to print warnings about files we had to skip or leave matches out of. Warnings always go to stderr, so they never get mixed up with results on stdout. With json, each one is an object like {\"file\": \"src/lib.rs\", \"code\": \"parse-timeout\", \"message\": \"...\"}, where file is null for warnings that aren't about one file, and code is one of tokenization-failed, parse-timeout, unreadable-path, is-a-directory, search-failed, unmatched-capture, outside-relative-to, exec-failed, or grammar-failed.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("log-level")
+                    .long("log-level")
+                    .value_name("LEVEL")
+                    .value_parser(["off", "error", "warn", "info", "debug", "trace"])
+                    .help("how much to log about what we're doing on stderr (default: warn)")
+                    .long_help("how much to log about what we're doing on stderr (default: warn), like which files we skipped and why. Logs never go to stdout, so they don't get mixed up with results. Without this, we use the level in the RUST_LOG environment variable if it has one, either on its own (like RUST_LOG=debug) or for crossgrep (like RUST_LOG=crossgrep=debug). Warnings about problems with files use --diagnostics instead."),
+            )
+            .arg(
+                Arg::new("encoding")
+                    .long("encoding")
+                    .value_name("ENCODING")
+                    .value_parser(parse_encoding)
+                    .help("the encoding of the files being searched, like shift_jis or latin1 (default: utf-8)")
+                    .long_help("the encoding of the files being searched, like shift_jis or latin1 (default: utf-8). Files are decoded to UTF-8 before parsing, so matches are printed as UTF-8, but byte offsets and byte columns still point into the original files. Anything that doesn't decode becomes U+FFFD. Takes any label from the WHATWG Encoding Standard.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("path-separator")
+                    .long("path-separator")
+                    .value_name("SEPARATOR")
+                    .value_parser(parse_path_separator)
+                    .help("print paths with this between directories, like / on Windows (default: the platform's separator)")
+                    .long_help("print paths with this between directories, like / on Windows (default: the platform's separator). Applies to every output format. Must be a single character.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("with-sexp")
+                    .long("with-sexp")
+                    .action(ArgAction::SetTrue)
+                    .help("include the S-expression of each matched node in the output")
+                    .long_help("include the S-expression of each matched node in the output, to help figure out why a query matched what it did. JSON formats get a `sexp` field, and the lines format prints it indented under each match.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("number-lines")
+                    .long("number-lines")
+                    .action(ArgAction::SetTrue)
+                    .help("print each match's text under it with line numbers, like `  12 | fn foo() {`")
+                    .long_help("print each match's text on lines of its own under the match's position, each after its line number in the file, like `  12 | fn foo() {`. This only changes the lines format (with or without --heading), and applies to single-line matches too.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("skip-errors")
+                    .long("skip-errors")
+                    .action(ArgAction::SetTrue)
+                    .help("drop matches inside parts of files that have syntax errors")
+                    .long_help("drop matches inside parts of files that have syntax errors. tree-sitter still parses files with mistakes in them, wrapping the parts it can't make sense of in ERROR nodes, and queries can match inside those. With this, matches in or under an ERROR node are left out. `--stats` counts the files that had errors either way.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("decode-chunks")
+                    .long("decode-chunks")
+                    .action(ArgAction::SetTrue)
+                    .help("include each chunk's tokens decoded back into text in JSON output")
+                    .long_help("include each chunk's tokens decoded back into text (without special tokens) as its `text` in JSON output, to check where chunk boundaries fell without mapping byte offsets by hand.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("separate-queries")
+                    .long("separate-queries")
+                    .action(ArgAction::SetTrue)
+                    .help("search with each query on its own instead of joining queries for the same language")
+                    .long_help("search with each query on its own instead of joining queries for the same language into one. Joined queries only have to run once per file, which is faster, but they act like one big query: matches of the same node and capture name from different queries are only reported once, and a query's captures can collide with another's. With this, each query's results are exactly what it would find alone, at the cost of running every query over every file separately.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("no-auto-capture")
+                    .long("no-auto-capture")
+                    .action(ArgAction::SetTrue)
+                    .help("don't add `@query` to queries without captures; reject them instead")
+                    .long_help("don't add `@query` to the end of queries without any captures, and reject them instead, since nothing could be extracted from them. Note that queries for the same language are joined into one before searching, and without this flag `@query` is added to every pattern of a query only when that query has no captures at all: in `(a) @x (b)`, `(b)` still matches but isn't extracted.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("with-leading-comments")
+                    .long("with-leading-comments")
+                    .action(ArgAction::SetTrue)
+                    .help("include the comments right before each match (like doc comments) in it")
+                    .long_help("include the comments right before each match (like doc comments) in it, so they're part of its text, position, and chunks. Only comments with no blank line between them and the match count, and a comment at the end of a line of code is left with that code.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("expand-to")
+                    .long("expand-to")
+                    .value_name("KIND")
+                    .help("extract the closest node of this kind around each match instead")
+                    .long_help("extract the closest node of this kind around each match (like `function_item`) instead of the match itself, or the match if it's already one. Matches with nothing of that kind around them are dropped, and a node around several matches is only extracted once. Use `--show-tree` to find the kind you want.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("with-pattern-index")
+                    .long("with-pattern-index")
+                    .action(ArgAction::SetTrue)
+                    .help("include the index of the query pattern that produced each match in JSON output")
+                    .long_help("include the index of the query pattern that produced each match in JSON output, as a `pattern` field. All the queries for a language are joined into one in the order they were given (presets first, then -t), so the index counts patterns across all of them, starting at 0.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("sexp-max-chars")
+                    .long("sexp-max-chars")
+                    .value_name("CHARS")
+                    .value_parser(clap::value_parser!(usize))
+                    .default_value("1000")
+                    .help("cut off S-expressions from --with-sexp after this many characters")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("no-dedup")
+                    .long("no-dedup")
+                    .action(ArgAction::SetTrue)
+                    .help("report every match, even when several queries match the same node with the same capture name")
+                    .long_help("report every match, even when several queries match the same node with the same capture name. Queries for the same language (from -t or --preset) are run together, so by default a node that more than one of them captures under the same name is only reported once.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("stats")
+                    .long("stats")
+                    .action(ArgAction::SetTrue)
+                    .help("print statistics about the search to stderr when done")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("report-unmatched-captures")
+                    .long("report-unmatched-captures")
+                    .action(ArgAction::SetTrue)
+                    .help("warn about captures that never matched once the search is done")
+                    .long_help("warn about captures that never matched anything once the search is done, to spot patterns that can't match. Captures starting with _ are left out, since they're ignored anyway. The warnings use the --diagnostics format, with the code unmatched-capture.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("max-total")
+                    .long("max-total")
+                    .value_name("N")
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .help("stop searching after N matches, across all files")
+                    .long_help("stop searching after N matches, across all files. Handy for a quick look at what a query finds in a huge tree. Files are searched in parallel, so which N matches you get can change from run to run.")
+                    .conflicts_with("quiet")
+                    .conflicts_with("watch")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("count-matches")
+                    .long("count-matches")
+                    .action(ArgAction::SetTrue)
+                    .help("print only the total number of matches across all files")
+                    .long_help("print only the total number of matches across all files, or 0 if nothing matched. Captures starting with an underscore aren't counted, same as they aren't printed. With --count-by-kind, print only the count for each kind instead.")
+                    .conflicts_with("group-by")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("count-by-kind")
+                    .long("count-by-kind")
+                    .action(ArgAction::SetTrue)
+                    .help("print how many matches there were of each node kind when done")
+                    .long_help("print how many matches there were of each node kind (like function_item or struct_item) after the results, most common first. Handy for getting a feel for what a codebase looks like. Add --count-matches to print only the counts, which is the only way to use this with other formats: with the JSON ones, the counts are a JSON array of {\"kind\", \"count\"} objects.")
+                    .conflicts_with("quiet")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("debug")
+                    .long("debug")
+                    .action(ArgAction::SetTrue)
+                    .hide(true)
+                    .help("allow options meant for debugging crossgrep itself"),
+            )
+            .arg(
+                Arg::new("dump-tokens")
+                    .long("dump-tokens")
+                    .action(ArgAction::SetTrue)
+                    .hide(true)
+                    .requires("debug")
+                    .help("print how the tokenizer sees each match instead of chunking it")
+                    .long_help("print how the tokenizer sees each match instead of chunking it: one line per token with its id, its byte range in the match's text, and what it decodes to on its own. This uses the same tokenizer and encoding as chunking, so it's handy for figuring out why a chunk came out wrong. Needs --debug.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("debug-chunks")
+                    .long("debug-chunks")
+                    .action(ArgAction::SetTrue)
+                    .hide(true)
+                    .requires("debug")
+                    .help("include how many tokens each chunk shares with its neighbors in JSON output")
+                    .long_help("include how many tokens each chunk shares with the chunk before it and the one after it as its `overlap_start` and `overlap_end` in JSON output, to check chunk boundaries against the model's chunk_overlap. Needs --debug.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("only-matching")
+                    .long("only-matching")
+                    .short('o')
+                    .action(ArgAction::SetTrue)
+                    .help("print only the text of each match, one per line")
+                    .long_help("print only the text of each match, one per line, like `grep -o`. Matches that span several lines are printed as-is, newlines and all; use --only-matching-trim to put each on one line.")
+                    .conflicts_with("FORMAT")
+                    .conflicts_with("template")
+                    .conflicts_with("count-matches")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("only-matching-trim")
+                    .long("only-matching-trim")
+                    .action(ArgAction::SetTrue)
+                    .requires("only-matching")
+                    .help("with --only-matching, collapse each run of whitespace in a match into one space")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("quiet")
+                    .long("quiet")
+                    .action(ArgAction::SetTrue)
+                    .help("don't print matches, just exit with 0 if there were any and 1 if not")
+                    .long_help("don't print matches, just exit with 0 if there were any and 1 if not. The search stops at the first match, so this is a fast way to check whether a pattern shows up anywhere in a big tree. `--stats` still prints if you ask for it.")
+                    .conflicts_with("FORMAT")
+                    .conflicts_with("group-by")
+                    .conflicts_with("count-matches")
+                    .conflicts_with("only-matching")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("progress")
+                    .long("progress")
+                    .action(ArgAction::SetTrue)
+                    .help("show how many files have been searched so far on stderr (if it's a terminal)")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("watch")
+                    .long("watch")
+                    .action(ArgAction::SetTrue)
+                    .help("keep running, and search files again when they change")
+                    .long_help("keep running, and search files again when they change. After the first search, only the files that changed are searched again, and only their matches are printed.")
+                    .conflicts_with("quiet")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("watch-clear")
+                    .long("watch-clear")
+                    .action(ArgAction::SetTrue)
+                    .help("clear the screen before printing matches for changed files in --watch mode")
+                    .requires("watch")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("languages")
+                    .long("languages")
+                    .action(ArgAction::SetTrue)
+                    .help("print the language names crossgrep knows about")
+                    .conflicts_with("additional-target")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("check")
+                    .long("check")
+                    .action(ArgAction::SetTrue)
+                    .help("check that the queries compile without searching anything")
+                    .long_help("check that the queries compile without searching anything. Each query (from -t or --preset) is reported on its own, and we exit with an error if any of them don't compile. Handy for pre-commit hooks.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("explain")
+                    .long("explain")
+                    .value_name("FILE")
+                    .help("say whether FILE would be searched, and why or why not, instead of searching")
+                    .long_help("say whether FILE would be searched, and why or why not, instead of searching. This walks toward FILE from the paths being searched with the same rules a search uses (ignore files, hidden files, --exclude-dir, and --follow) and names the rule that stopped the walk, if one did. The walker doesn't say why it skips things, so that rule is worked out again afterwards, as best we can: unusual setups can get the wrong reason, or none. Then it reports which of the queries' languages claim FILE, its size against --max-filesize, whether it looks binary, and whether it passes --path-regex. Exits with 0 if FILE would be searched and 1 if it wouldn't. Give the same options you'd search with, since they all change the answer.")
+                    .conflicts_with("text")
+                    .conflicts_with("files-from")
+                    .conflicts_with("git-url")
+                    .conflicts_with("watch")
+                    .conflicts_with("check")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree"),
+            )
+            .arg(
+                Arg::new("show-tree")
+                    .long("show-tree")
+                    .help("Show the node names and associated text of the specified files")
+                    .value_names(["LANGUAGE"])
+                    .action(ArgAction::Append)
+                    .conflicts_with("languages")
+                    .conflicts_with("additional-target"),
+            )
+            .arg(
+                Arg::new("QUERY")
+                    .last(true)
+                    .number_of_values(1)
+                    .value_name("QUERY")
+                    .required_unless_present("languages")
+                    .required_unless_present("show-tree")
+                    .required_unless_present("check")
+                    .required_unless_present("explain")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+                    .num_args(1..)
+                    .action(ArgAction::Append),
+            )
+            .try_get_matches_from(args)
+            .context("could not parse args")
+    }
+
+    /// The level to log at, from `--log-level` or else `RUST_LOG`.
+    pub fn log_level(matches: &ArgMatches) -> Result<LevelFilter> {
+        Ok(match matches.get_one::<String>("log-level") {
+            Some(level) => level.parse().context("could not set log level")?,
+            None => env::var("RUST_LOG")
+                .ok()
+                .and_then(|raw| logging::level_from_env(&raw))
+                .unwrap_or(logging::DEFAULT_LEVEL),
+        })
+    }
+
+    pub fn from_matches(matches: ArgMatches) -> Result<Self> {
+        if matches.get_flag("languages") {
+            Ok(Self::ShowLanguages(Self::format(&matches)?))
+        } else if matches.get_flag("check") {
+            let presets = Self::presets(&matches)?;
+            let targets = Self::targets(&matches, &presets)?;
+
+            Ok(Self::CheckQueries(
+                targets
+                    .into_iter()
+                    .map(|(lang, query)| (lang.to_string(), query.into_owned()))
+                    .collect(),
+            ))
+        } else if let Some(raw_lang) = matches.get_one::<String>("show-tree") {
+            let lang = Language::from_str(raw_lang).context("could not parse language")?;
+
+            let input = match matches.get_one::<String>("text") {
+                Some(text) => TreeInput::Text(text.clone()),
+                None => {
+                    let paths = Self::paths(&matches)?;
+                    if paths.len() != 1 {
+                        anyhow::bail!("need exactly one path to print a tree")
+                    }
+
+                    TreeInput::Path(paths[0].to_owned())
+                }
+            };
+
+            Ok(Self::ShowTree(TreeOpts {
+                language: lang,
+                input,
+            }))
+        } else {
+            if matches.contains_id("template")
+                && matches.get_one::<String>("FORMAT").map(|f| f.as_str()) != Some("lines")
+            {
+                bail!("--template only works with the lines format")
+            }
+            if matches.get_flag("count-matches")
+                && !matches.get_flag("count-by-kind")
+                && matches.value_source("FORMAT") == Some(ValueSource::CommandLine)
+            {
+                bail!("--count-matches prints a plain number, so it doesn't take --format (unless you add --count-by-kind)")
+            }
+            let format = Self::format(&matches)?;
+            if matches.get_flag("count-by-kind")
+                && !matches.get_flag("count-matches")
+                && !matches!(format, QueryFormat::Lines)
+            {
+                // the counts would be a second document after the first one
+                bail!("--count-by-kind only goes after results in the lines format. Add --count-matches to print only the counts.")
+            }
+            if let QueryFormat::Dot = format {
+                if matches.contains_id("group-by") {
+                    bail!("--group-by doesn't work with the dot format")
+                }
+            } else if matches.get_flag("dot-scope-edges") {
+                bail!("--dot-scope-edges only works with the dot format")
+            }
+
+            let Extractors {
+                queries: extractors,
+                regex,
+                failed_grammars,
+            } = Self::extractors(&matches)?;
+
+            let opts = Box::new(QueryOpts {
+                extractors,
+                regex,
+                failed_grammars,
+                separate_queries: matches.get_flag("separate-queries"),
+                paths: Self::paths(&matches)?,
+                files_from: matches.get_one::<String>("files-from").map(PathBuf::from),
+                text: matches.get_one::<String>("text").cloned(),
+                remote: matches.get_one::<String>("git-url").map(|url| Remote {
+                    url: url.clone(),
+                    git_ref: matches.get_one::<String>("ref").cloned(),
+                    cache: matches.get_one::<String>("clone-cache").map(PathBuf::from),
+                }),
+                checkout: None,
+                relative_to: matches
+                    .get_one::<String>("relative-to")
+                    .map(|base| RelativeTo::new(Path::new(base)))
+                    .transpose()?,
+                outside_relative_to_warning: Once::new(),
+                path_regex: matches.get_one::<Regex>("path-regex").cloned(),
+                line_ranges: matches
+                    .get_many::<LineRange>("line-range")
+                    .map(|ranges| ranges.cloned().collect())
+                    .unwrap_or_default(),
+                git_ignore: !matches.get_flag("no-gitignore"),
+                gitattributes: !matches.get_flag("no-gitattributes"),
+                ignore_parents: !matches.get_flag("no-ignore-parent"),
+                exclude_dirs: matches
+                    .get_many::<String>("exclude-dir")
+                    .map(|names| names.map(OsString::from).collect())
+                    .unwrap_or_default(),
+                follow_links: matches.get_flag("follow"),
+                format,
+                dot_scope_edges: matches.get_flag("dot-scope-edges"),
+                group_by: matches
+                    .get_one::<String>("group-by")
+                    .map(|raw| GroupBy::from_str(raw))
+                    .transpose()
+                    .context("could not set grouping")?,
+                exec: match (
+                    matches.get_one::<String>("exec"),
+                    matches.get_one::<String>("exec-batch"),
+                ) {
+                    (Some(raw), _) => Some(ExecCommand::parse(raw, false)),
+                    (None, Some(raw)) => Some(ExecCommand::parse(raw, true)),
+                    (None, None) => None,
+                }
+                .transpose()
+                .context("could not parse the command to run")?,
+                template: matches
+                    .get_one::<String>("template")
+                    .map(|raw| Template::from_str(raw))
+                    .transpose()
+                    .context("could not parse template")?,
+                heading: if matches.get_flag("heading") {
+                    Some(true)
+                } else if matches.get_flag("no-heading") {
+                    Some(false)
+                } else {
+                    None
+                },
+                number_lines: matches.get_flag("number-lines"),
+                parsers: ParserPool::with_timeout(
+                    matches
+                        .get_one::<u64>("timeout")
+                        .map(|ms| Duration::from_millis(*ms)),
+                ),
+                max_filesize: matches
+                    .get_one::<u64>("max-filesize")
+                    .copied()
+                    .filter(|size| *size > 0),
+                binary: matches.get_flag("binary"),
+                encoding: matches.get_one::<&'static Encoding>("encoding").copied(),
+                archives: !matches.get_flag("no-archives"),
+                stats: matches.get_flag("stats"),
+                report_unmatched_captures: matches.get_flag("report-unmatched-captures"),
+                diagnostics: Self::diagnostic_format(&matches),
+                count_matches: matches.get_flag("count-matches"),
+                count_by_kind: matches.get_flag("count-by-kind"),
+                max_total: matches.get_one::<u64>("max-total").map(|max| *max as usize),
+                dump_tokens: if matches.get_flag("dump-tokens") {
+                    Self::models(&matches)?
+                        .into_iter()
+                        .map(|(name, model)| Ok((name, Chunker::from_model(model)?)))
+                        .collect::<Result<_>>()
+                        .context("could not set up tokenizers")?
+                } else {
+                    Vec::new()
+                },
+                only_matching: matches.get_flag("only-matching"),
+                only_matching_trim: matches.get_flag("only-matching-trim"),
+                quiet: matches.get_flag("quiet"),
+                watch: matches.get_flag("watch"),
+                progress: matches.get_flag("progress"),
+                watch_clear: matches.get_flag("watch-clear"),
+            });
+
+            match matches.get_one::<String>("explain") {
+                Some(path) => Ok(Self::Explain(opts, PathBuf::from(path))),
+                None => Ok(Self::DoQuery(opts)),
+            }
+        }
+    }
+
+    fn format(matches: &ArgMatches) -> Result<QueryFormat> {
+        QueryFormat::from_str(
+            matches
+                .get_one::<String>("FORMAT")
+                .context("format not provided")?,
+        )
+        .context("could not set format")
+    }
+
+    fn presets(matches: &ArgMatches) -> Result<Option<Presets>> {
+        match matches.get_one::<String>("presets-file") {
+            Some(path) => Ok(Some(Presets::load(Path::new(path))?)),
+            None if matches.contains_id("preset") => {
+                let path = Presets::default_path()
+                    .context("could not find your config directory to look for presets in. Try --presets-file.")?;
+                Ok(Some(Presets::load(&path)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// All the language and query pairs we were asked for, from presets, `-t`,
+    /// `--kind`, and the query library.
+    fn targets<'a>(
+        matches: &'a ArgMatches,
+        presets: &'a Option<Presets>,
+    ) -> Result<Vec<(Cow<'a, str>, Cow<'a, str>)>> {
+        let mut targets: Vec<(Cow<str>, Cow<str>)> = Vec::new();
+        if let (Some(presets), Some(names)) = (presets, matches.get_many::<String>("preset")) {
+            for name in names {
+                targets.extend(
+                    presets
+                        .targets(name)?
+                        .map(|(lang, query)| (Cow::Borrowed(lang), Cow::Borrowed(query))),
+                );
+            }
+        }
+        if let Some(values) = matches.get_many::<String>("additional-target") {
+            targets.extend(values.tuples().map(|(lang, query): (&String, &String)| {
+                (Cow::Borrowed(lang.as_str()), Cow::Borrowed(query.as_str()))
+            }));
+        }
+        if let Some(values) = matches.get_many::<String>("kind") {
+            for (lang, kind) in values.tuples() {
+                targets.push((Cow::Borrowed(lang), Cow::Owned(kind_query(lang, kind)?)));
+            }
+        }
+        if let (Some(path), Some(names)) = (
+            matches.get_one::<String>("from-library"),
+            matches.get_many::<String>("pattern"),
+        ) {
+            let library = Library::load(Path::new(path))?;
+            let names: Vec<&str> = names.map(|name| name.as_str()).collect();
+            targets.extend(library.targets(&names)?.into_iter().map(|(lang, query)| {
+                (Cow::Owned(lang.to_string()), Cow::Owned(query.to_string()))
+            }));
+        }
+
+        if targets.is_empty() && presets.is_some() {
+            bail!("the presets you asked for don't have any queries in them")
+        } else if targets.is_empty() {
+            bail!("queries were required but not provided. This indicates an internal error and you should report it!")
+        }
+
+        Ok(targets)
+    }
+
+    fn diagnostic_format(matches: &ArgMatches) -> DiagnosticFormat {
+        matches
+            .get_one::<DiagnosticFormat>("diagnostics")
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The models from `--model`, each with its `--model-config` and any
+    /// `--tokenizer-opt`s applied and named for comparing them.
+    fn models(matches: &ArgMatches) -> Result<Vec<(String, Model)>> {
+        let given: Vec<(usize, String)> = match (
+            matches.indices_of("MODEL"),
+            matches.get_many::<String>("MODEL"),
+        ) {
+            (Some(indices), Some(identifiers)) => indices.zip(identifiers.cloned()).collect(),
+            _ => vec![(0, default_model(env::var(MODEL_ENV).ok()))],
+        };
+
+        let mut models = Vec::with_capacity(1);
+        for (index, identifier) in given {
+            let model = Model::from_pretrained(&identifier).context("model not supported")?;
+            models.push((index, identifier, model, None));
+        }
+
+        if let (Some(paths), Some(indices)) = (
+            matches.get_many::<String>("model-config"),
+            matches.indices_of("model-config"),
+        ) {
+            for (index, path) in indices.zip(paths) {
+                // with one model there's no question which one a config is
+                // for, so it can go anywhere.
+                let owner = if models.len() == 1 {
+                    models.first_mut()
+                } else {
+                    models
+                        .iter_mut()
+                        .rev()
+                        .find(|(model_index, ..)| *model_index < index)
+                }
+                .context("--model-config has to come after the --model it changes")?;
+
+                if owner.3.is_some() {
+                    bail!("each --model can only have one --model-config");
+                }
+
+                let overrides: serde_json::Value = serde_json::from_str(
+                    &fs::read_to_string(path).context("could not read model config")?,
+                )
+                .context("could not parse model config")?;
+
+                owner.2 = Model::Custom(
+                    owner
+                        .2
+                        .config()
+                        .with_overrides(overrides)
+                        .with_context(|| format!("could not use the model config in {}", path))?,
+                );
+                owner.3 = Some(path);
+            }
+        }
+
+        let options = matches
+            .get_many::<String>("tokenizer-opt")
+            .unwrap_or_default()
+            .map(|option| {
+                option.split_once('=').with_context(|| {
+                    format!("--tokenizer-opt {} should look like KEY=VALUE", option)
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut named: Vec<(String, Model)> = Vec::with_capacity(models.len());
+        for (_, identifier, mut model, config) in models {
+            if !options.is_empty() {
+                let mut custom = model.config();
+                for (key, value) in &options {
+                    custom = custom.with_tokenizer_option(key, value).with_context(|| {
+                        format!("could not use --tokenizer-opt {}={}", key, value)
+                    })?;
+                }
+                model = Model::Custom(custom);
+            }
+
+            let name = match config {
+                Some(path) => format!("{}:{}", identifier, path),
+                None => identifier,
+            };
+            if named.iter().any(|(existing, _)| *existing == name) {
+                bail!("--model {} was given more than once", name);
+            }
+
+            named.push((name, model));
+        }
+
+        Ok(named)
+    }
+
+    /// The extractors for every target whose grammar loads, and the languages
+    /// whose grammars didn't (which we've warned about.)
+    fn extractors(matches: &ArgMatches) -> Result<Extractors> {
+        let presets = Self::presets(matches)?;
+        let mut targets = Self::targets(matches, &presets)?;
+
+        let failed_grammars = drop_unloadable_grammars(&mut targets, |lang| {
+            Parser::new()
+                .set_language(lang.language())
+                .map_err(|err| err.to_string())
+        })?;
+        for (lang, problem) in &failed_grammars {
+            Self::diagnostic_format(matches).report(Diagnostic {
+                file: None,
+                code: "grammar-failed",
+                message: format!(
+                    "skipping the {} queries, since the grammar couldn't be loaded: {}",
+                    lang, problem
+                ),
+            });
+        }
+
+        // regexes don't have a grammar, so they get an extractor of their own.
+        let mut regexes: Vec<String> = Vec::new();
+        targets.retain(|(raw_lang, raw_query)| {
+            if raw_lang == REGEX_TARGET {
+                regexes.push(raw_query.to_string());
+            }
+            raw_lang != REGEX_TARGET
+        });
+        if targets.is_empty() && !regexes.is_empty() {
+            if let Some(arg) = QUERY_ONLY_ARGS.iter().find(|arg| {
+                matches
+                    .value_source(arg)
+                    .is_some_and(|source| source != ValueSource::DefaultValue)
+            }) {
+                bail!(
+                    "--{} only changes what tree-sitter queries find, and there are only -t regex targets",
+                    arg
+                );
+            }
+        }
+
+        let models = Self::models(matches)?;
+        let text_match = Self::text_regex(matches, "text-match")?;
+        let text_not_match = Self::text_regex(matches, "text-not-match")?;
+
+        // the most common case is going to be one query, so let's allocate
+        // that immediately...
+        // ...and keep languages in the order their first query was given,
+        // since that decides which gets files that more than one claims.
+        let mut query_strings: Vec<(Language, String)> = Vec::with_capacity(1);
+
+        // If you have two tree-sitter queries `(one)` and `(two)`, you can
+        // join them together in a single string like `(one)\n(two)`. In that
+        // case, the resulting query will act like an OR and match any of the
+        // queries inside. Doing this automatically gives us an advantage:
+        // for however many queries we get on the command line, we will only
+        // ever have to run one per file, since we can combine them and you
+        // can't specify queries across multiple languages! Nobody should ever
+        // notice, except that they won't see as much of a slowdown for adding
+        // new queries to an invocation as they might expect. (Well, hopefully!)
+        for (raw_lang, raw_query) in targets {
+            let (lang, temp_query) = compile_target(&raw_lang, &raw_query)?;
+
+            // we add the capture to each query before joining them, since
+            // afterwards a capture in one would hide that another has none.
+            let query_out = if temp_query.capture_names().is_empty() {
+                if matches.get_flag("no-auto-capture") {
+                    bail!(
+                        "the {} query {:?} doesn't capture anything, so there would be nothing to extract. Add a capture like `@match`, or drop --no-auto-capture.",
+                        lang,
+                        raw_query
+                    );
+                }
+                capture_every_pattern(&raw_query, &temp_query, "query")
+            } else {
+                raw_query.into_owned()
+            };
+
+            // a query can end in a comment, so joining on the same line
+            // would comment out whatever comes next.
+            if matches.get_flag("separate-queries") {
+                query_strings.push((lang, query_out));
+            } else if let Some((_, existing)) = query_strings
+                .iter_mut()
+                .find(|(existing_lang, _)| *existing_lang == lang)
+            {
+                existing.push('\n');
+                existing.push_str(&query_out);
+            } else {
+                query_strings.push((lang, query_out));
+            }
+        }
+
+        // LSP counts in UTF-16, and people reading lines compare them to their
+        // editor, which counts characters. So that's what makes sense for
+        // those formats unless we're told otherwise.
+        let column_unit = if matches.value_source("column-unit") == Some(ValueSource::DefaultValue)
+        {
+            match matches.get_one::<String>("FORMAT").map(|f| f.as_str()) {
+                Some("lsp") => ColumnUnit::Utf16,
+                Some("lines") => ColumnUnit::Char,
+                _ => ColumnUnit::Byte,
+            }
+        } else {
+            matches
+                .get_one::<ColumnUnit>("column-unit")
+                .copied()
+                .unwrap_or_default()
+        };
+
+        let mut out = Vec::with_capacity(query_strings.len());
+        for (lang, raw_query) in query_strings {
+            let query = lang.parse_query(&raw_query).map_err(|err| {
+                point_at_query_error(err, &raw_query, "could not parse combined query")
+            })?;
+
+            let mut extractor = match models.as_slice() {
+                [(_, model)] => Extractor::new(lang, query, model.clone()),
+                _ => Extractor::with_models(lang, query, models.clone()),
+            }
+            .context("could not set up extractor")?;
+            extractor.set_coalesce_captures(matches.get_flag("coalesce-captures"));
+            extractor.set_merge_adjacent(matches.get_one::<usize>("merge-adjacent").copied());
+            extractor.set_dedup(!matches.get_flag("no-dedup"));
+            extractor.set_pattern_index(matches.get_flag("with-pattern-index"));
+            extractor.set_skip_errors(matches.get_flag("skip-errors"));
+            extractor.set_leading_comments(matches.get_flag("with-leading-comments"));
+            extractor.set_expand_to(matches.get_one::<String>("expand-to").cloned());
+            extractor.set_decode_chunks(matches.get_flag("decode-chunks"));
+            extractor.set_chunk_overlaps(matches.get_flag("debug-chunks"));
+            if let Some(regex) = &text_match {
+                extractor.set_text_match(regex.clone());
+            }
+            if let Some(regex) = &text_not_match {
+                extractor.set_text_not_match(regex.clone());
+            }
+            if let Some(min_lines) = matches.get_one::<usize>("min-lines") {
+                extractor.set_min_lines(*min_lines);
+            }
+            if let Some(min_bytes) = matches.get_one::<usize>("min-bytes") {
+                extractor.set_min_bytes(*min_bytes);
+            }
+            // watch mode searches the same files again and again, so it's
+            // worth keeping their trees around to re-parse only what changed.
+            extractor.set_incremental(matches.get_flag("watch"));
+            if matches.get_flag("with-sexp") {
+                extractor.set_sexp_limit(matches.get_one::<usize>("sexp-max-chars").copied());
+            }
+            extractor.set_chunk_strategy(
+                matches
+                    .get_one::<ChunkStrategy>("chunk-strategy")
+                    .copied()
+                    .unwrap_or_default(),
+            );
+            extractor.set_chunk_lookbehind(matches.get_one::<usize>("chunk-lookbehind").copied());
+            extractor.set_column_unit(column_unit);
+            extractor.set_diagnostic_format(Self::diagnostic_format(matches));
+            if let Some(encoding) = matches.get_one::<&'static Encoding>("encoding") {
+                extractor.set_encoding(encoding);
+            }
+            if let Some(separator) = matches.get_one::<char>("path-separator") {
+                extractor.set_path_separator(*separator);
+            }
+
+            out.push(extractor)
+        }
+
+        let regex = if regexes.is_empty() {
+            None
+        } else {
+            let mut extractor = match models.as_slice() {
+                [(_, model)] => RegexExtractor::new(join_regexes(&regexes)?, model.clone()),
+                _ => RegexExtractor::with_models(join_regexes(&regexes)?, models.clone()),
+            }
+            .context("could not set up the regex extractor")?;
+            if let Some(regex) = text_match {
+                extractor.set_text_match(regex);
+            }
+            if let Some(regex) = text_not_match {
+                extractor.set_text_not_match(regex);
+            }
+            if let Some(min_lines) = matches.get_one::<usize>("min-lines") {
+                extractor.set_min_lines(*min_lines);
+            }
+            if let Some(min_bytes) = matches.get_one::<usize>("min-bytes") {
+                extractor.set_min_bytes(*min_bytes);
+            }
+            extractor.set_column_unit(column_unit);
+            if let Some(encoding) = matches.get_one::<&'static Encoding>("encoding") {
+                extractor.set_encoding(encoding);
+            }
+            if let Some(separator) = matches.get_one::<char>("path-separator") {
+                extractor.set_path_separator(*separator);
+            }
+
+            Some(extractor)
+        };
+
+        Ok(Extractors {
+            queries: out,
+            regex,
+            failed_grammars: failed_grammars.into_iter().map(|(lang, _)| lang).collect(),
+        })
+    }
+
+    /// The regex given for `--text-match` or `--text-not-match` (`arg`), with
+    /// `--ignore-case` applied.
+    fn text_regex(matches: &ArgMatches, arg: &str) -> Result<Option<Regex>> {
+        matches
+            .get_one::<String>(arg)
+            .map(|raw| {
+                RegexBuilder::new(raw)
+                    .case_insensitive(matches.get_flag("ignore-case"))
+                    .build()
+                    .with_context(|| format!("could not parse --{} regex", arg))
+            })
+            .transpose()
+    }
+
+    fn paths(matches: &ArgMatches) -> Result<Vec<PathBuf>> {
+        match matches.get_many::<String>("PATHS") {
+            Some(values) =>
+                values
+                    .map(|raw_path| PathBuf::from_str(raw_path).with_context(|| format!("could not parse a path from {}", raw_path)))
+                    .collect(),
+
+            None => bail!("at least one path was required but not provided. This indicates an internal errors and you should report it!"),
+        }
+    }
+}
+
+/// Take the targets whose language's grammar doesn't `load` out of
+/// `targets`, so a search for several languages can go on with the ones that
+/// work (say, when a grammar was generated for a version of tree-sitter we
+/// don't support.) Returns the languages we took out, with why. It's only an
+/// error if none of the grammars load. Languages we don't know are left for
+/// `compile_target` to complain about.
+fn drop_unloadable_grammars(
+    targets: &mut Vec<(Cow<str>, Cow<str>)>,
+    load: impl Fn(Language) -> std::result::Result<(), String>,
+) -> Result<Vec<(Language, String)>> {
+    let mut failed: Vec<(Language, String)> = Vec::new();
+    targets.retain(|(raw_lang, _)| {
+        let lang = match Language::from_str(raw_lang) {
+            Ok(lang) => lang,
+            Err(_) => return true,
+        };
+        if failed.iter().any(|(failed_lang, _)| *failed_lang == lang) {
+            return false;
+        }
+
+        match load(lang) {
+            Ok(()) => true,
+            Err(problem) => {
+                failed.push((lang, problem));
+                false
+            }
+        }
+    });
+
+    if targets.is_empty() {
+        if let Some((lang, problem)) = failed.first() {
+            bail!("could not load the {} grammar: {}", lang, problem);
+        }
+    }
+
+    Ok(failed)
+}
+
+/// The "language" for `-t` targets that are regexes instead of queries.
+pub const REGEX_TARGET: &str = "regex";
+
+/// Options that only the extractors for queries have, which it's an error to
+/// give when there are only `-t regex` targets, since nothing would use them.
+const QUERY_ONLY_ARGS: &[&str] = &[
+    "coalesce-captures",
+    "merge-adjacent",
+    "no-dedup",
+    "with-pattern-index",
+    "skip-errors",
+    "with-leading-comments",
+    "expand-to",
+    "with-sexp",
+    "sexp-max-chars",
+    "chunk-strategy",
+    "chunk-lookbehind",
+    "decode-chunks",
+    "debug-chunks",
+];
+
+/// The extractors for the `-t` targets, from `Invocation::extractors`.
+struct Extractors {
+    queries: Vec<Extractor>,
+    /// One extractor for every `-t regex` target.
+    regex: Option<RegexExtractor>,
+    /// Languages we have queries for but couldn't load the grammar of.
+    failed_grammars: Vec<Language>,
+}
+
+pub fn compile_regex_target(raw_regex: &str) -> Result<bytes::Regex> {
+    bytes::Regex::new(raw_regex).context("could not parse regex")
+}
+
+/// One regex that matches whatever any of `raw_regexes` would, like queries
+/// for the same language get joined into one.
+fn join_regexes(raw_regexes: &[String]) -> Result<bytes::Regex> {
+    // checking them one at a time points at the one that's wrong.
+    for raw_regex in raw_regexes {
+        compile_regex_target(raw_regex)
+            .with_context(|| format!("the regex {:?} is invalid", raw_regex))?;
+    }
+
+    compile_regex_target(
+        &raw_regexes
+            .iter()
+            .map(|raw_regex| format!("(?:{})", raw_regex))
+            .collect::<Vec<_>>()
+            .join("|"),
+    )
+}
+
+/// Compile a single query for its language, the same way we would before
+/// searching.
+pub fn compile_target(raw_lang: &str, raw_query: &str) -> Result<(Language, Query)> {
+    let lang = Language::from_str(raw_lang).context("could not parse language")?;
+
+    let query = lang
+        .parse_query(raw_query)
+        .map_err(|err| point_at_query_error(err, raw_query, "could not parse query"))?;
+
+    // tree-sitter is happy to compile a query with no patterns in it (like an
+    // empty string or only comments) but it will never match anything, and
+    // appending `@query` when we combine queries would turn it into a
+    // confusing syntax error. Better to say what's wrong up front.
+    if query.pattern_count() == 0 {
+        bail!(
+            "the {} query {:?} doesn't have any patterns in it. Is it empty or only comments?",
+            lang,
+            raw_query
+        )
+    }
+
+    Ok((lang, query))
+}
+
+/// Add `@capture` after every pattern in `raw_query`, not just the last one.
+/// The capture goes on its own line so it still applies when a pattern ends
+/// in a comment.
+fn capture_every_pattern(raw_query: &str, query: &Query, capture: &str) -> String {
+    let mut out = String::with_capacity(raw_query.len());
+    out.push_str(&raw_query[..query.start_byte_for_pattern(0)]);
+
+    for pattern in 0..query.pattern_count() {
+        let start = query.start_byte_for_pattern(pattern);
+        let end = if pattern + 1 < query.pattern_count() {
+            query.start_byte_for_pattern(pattern + 1)
+        } else {
+            raw_query.len()
+        };
+
+        out.push_str(&raw_query[start..end]);
+        out.push_str("\n@");
+        out.push_str(capture);
+        out.push('\n');
+    }
+
+    out
+}
+
+/// The environment variable that names the model to use when there's no
+/// `--model`.
+const MODEL_ENV: &str = "CROSSGREP_MODEL";
+
+/// The model to use without a `--model`: the one in `MODEL_ENV` (given here
+/// as `from_env`) if it's set to anything, and codebert if not.
+fn default_model(from_env: Option<String>) -> String {
+    from_env
+        .map(|model| model.trim().to_string())
+        .filter(|model| !model.is_empty())
+        .unwrap_or_else(|| String::from("codebert"))
+}
+
+/// The query `--kind` stands for, which matches every node of `kind`. We check
+/// that the grammar has that kind first, so a typo gets some suggestions
+/// instead of tree-sitter's "invalid node type" error.
+fn kind_query(raw_lang: &str, kind: &str) -> Result<String> {
+    let lang = Language::from_str(raw_lang).context("could not parse language")?;
+    let ts_language = lang.language();
+
+    let kinds: BTreeSet<&str> = (0..ts_language.node_kind_count() as u16)
+        .filter(|id| ts_language.node_kind_is_named(*id) && ts_language.node_kind_is_visible(*id))
+        .filter_map(|id| ts_language.node_kind_for_id(id))
+        .collect();
+
+    if kinds.contains(kind) {
+        return Ok(format!("({}) @match", kind));
+    }
+
+    let mut suggestions: Vec<(f64, &str)> = kinds
+        .iter()
+        .map(|candidate| (strsim::jaro_winkler(kind, candidate), *candidate))
+        // the same cutoff clap uses to suggest flags
+        .filter(|(similarity, _)| *similarity > 0.7)
+        .collect();
+    suggestions.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    if suggestions.is_empty() {
+        bail!(
+            "{} doesn't have a kind of node called {}. Try --show-tree on a file to see the kinds in it.",
+            lang,
+            kind
+        )
+    } else {
+        bail!(
+            "{} doesn't have a kind of node called {}. Did you mean {}?",
+            lang,
+            kind,
+            suggestions
+                .iter()
+                .take(3)
+                .map(|(_, candidate)| *candidate)
+                .join(" or ")
+        )
+    }
+}
+
+/// tree-sitter's syntax errors already include the offending line with a caret
+/// under the problem, but errors about names (node types, fields, and
+/// captures) only say which name was wrong. Point at those too, so they're
+/// easy to find in a long query.
+fn point_at_query_error(err: Error, raw_query: &str, context: &str) -> Error {
+    let query_err = match err.downcast_ref::<QueryError>() {
+        Some(query_err) => query_err,
+        None => return err.context(context.to_string()),
+    };
+
+    let line = match query_err.kind {
+        QueryErrorKind::NodeType | QueryErrorKind::Field | QueryErrorKind::Capture => {
+            raw_query.split('\n').nth(query_err.row)
+        }
+        _ => None,
+    };
+
+    match line {
+        Some(line) => {
+            // tree-sitter gives us the column in bytes, but the caret needs to
+            // line up with characters.
+            let width = line
+                .get(..query_err.column)
+                .map(|before| before.chars().count())
+                .unwrap_or(query_err.column);
+
+            let message = format!("{}:\n\n{}\n{}^", context, line, " ".repeat(width));
+            err.context(message)
+        }
+        None => err.context(context.to_string()),
+    }
+}
+
+fn parse_encoding(raw: &str) -> Result<&'static Encoding> {
+    Encoding::for_label(raw.as_bytes()).with_context(|| format!("unknown encoding {}", raw))
+}
+
+fn parse_path_separator(raw: &str) -> Result<char> {
+    let mut chars = raw.chars();
+    match (chars.next(), chars.next()) {
+        (Some(separator), None) => Ok(separator),
+        _ => bail!(
+            "the path separator has to be a single character, not {:?}",
+            raw
+        ),
+    }
+}
+
+fn parse_filesize(raw: &str) -> Result<u64> {
+    let (digits, multiplier) = match raw.char_indices().last() {
+        Some((idx, 'K')) | Some((idx, 'k')) => (&raw[..idx], 1 << 10),
+        Some((idx, 'M')) | Some((idx, 'm')) => (&raw[..idx], 1 << 20),
+        Some((idx, 'G')) | Some((idx, 'g')) => (&raw[..idx], 1 << 30),
+        _ => (raw, 1),
+    };
+
+    let size = u64::from_str(digits)
+        .with_context(|| format!("could not parse a file size from {}", raw))?;
+
+    size.checked_mul(multiplier)
+        .with_context(|| format!("{} is too large to be a file size", raw))
+}
+
+/// Lines to keep matches in, optionally only in one file. Lines start at 1
+/// and both ends are included.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LineRange {
+    path: Option<PathBuf>,
+    start: usize,
+    end: usize,
+}
+
+impl LineRange {
+    /// Does a match from `start_line` to `end_line` in `file` touch this
+    /// range?
+    fn overlaps(&self, file: Option<&Path>, start_line: usize, end_line: usize) -> bool {
+        let right_file = match (&self.path, file) {
+            (None, _) => true,
+            (Some(path), Some(file)) => same_path(path, file),
+            (Some(_), None) => false,
+        };
+
+        right_file && start_line <= self.end && end_line >= self.start
+    }
+}
+
+/// A directory to show paths relative to, from `--relative-to`.
+#[derive(Debug)]
+pub struct RelativeTo {
+    base: PathBuf,
+    current_dir: PathBuf,
+}
+
+impl RelativeTo {
+    pub fn new(base: &Path) -> Result<Self> {
+        let current_dir = env::current_dir().context("could not get the current directory")?;
+
+        Ok(RelativeTo {
+            base: normalize(&current_dir.join(base)),
+            current_dir,
+        })
+    }
+
+    /// The base itself, as an absolute path.
+    pub fn base(&self) -> &Path {
+        &self.base
+    }
+
+    /// `path` relative to the base, or as an absolute path if it's outside
+    /// of it. This only looks at the paths, not the filesystem, so symlinks
+    /// aren't followed.
+    fn apply(&self, path: &Path) -> Result<PathBuf, PathBuf> {
+        let absolute = normalize(&self.current_dir.join(path));
+
+        match absolute.strip_prefix(&self.base) {
+            Ok(relative) => Ok(relative.to_path_buf()),
+            Err(_) => Err(absolute),
+        }
+    }
+}
+
+/// Take `.` and `..` out of an absolute path.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+
+    normalized
+}
+
+/// Compare paths without caring about `./` prefixes, since we print paths
+/// the way we were given them and people may or may not include those.
+fn same_path(a: &Path, b: &Path) -> bool {
+    let without_cur_dir = |path: &Path| {
+        path.components()
+            .filter(|component| *component != Component::CurDir)
+            .collect::<PathBuf>()
+    };
+
+    without_cur_dir(a) == without_cur_dir(b)
+}
+
+impl FromStr for LineRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // splitting from the right lets paths have colons in them
+        let mut parts = s.rsplitn(3, ':');
+        let (end, start, path) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(end), Some(start), path) => (end, start, path),
+            _ => bail!("line ranges look like START:END or PATH:START:END"),
+        };
+
+        let start = usize::from_str(start)
+            .with_context(|| format!("could not parse a start line from {:?}", start))?;
+        let end = usize::from_str(end)
+            .with_context(|| format!("could not parse an end line from {:?}", end))?;
+
+        if start == 0 {
+            bail!("lines start at 1")
+        } else if end < start {
+            bail!("the range {} ends before it starts", s)
+        }
+
+        Ok(LineRange {
+            path: path.map(PathBuf::from),
+            start,
+            end,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum QueryFormat {
+    Lines,
+    Json,
+    JsonLines,
+    JsonStreamArray,
+    JsonLinesMatches,
     PrettyJson,
+    Lsp,
+    Dot,
 }
 
 impl FromStr for QueryFormat {
@@ -241,8 +2087,335 @@ impl FromStr for QueryFormat {
             "lines" => Ok(QueryFormat::Lines),
             "json" => Ok(QueryFormat::Json),
             "json-lines" => Ok(QueryFormat::JsonLines),
+            "json-stream-array" => Ok(QueryFormat::JsonStreamArray),
+            "json-lines-matches" => Ok(QueryFormat::JsonLinesMatches),
             "pretty-json" => Ok(QueryFormat::PrettyJson),
+            "lsp" => Ok(QueryFormat::Lsp),
+            "dot" => Ok(QueryFormat::Dot),
             _ => bail!("unknown format. See --help for valid formats."),
         }
     }
 }
+
+#[derive(Debug)]
+pub enum GroupBy {
+    Capture,
+}
+
+impl FromStr for GroupBy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "capture" => Ok(GroupBy::Capture),
+            _ => bail!("unknown grouping. See --help for valid groupings."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query_error(query: &str) -> String {
+        let args = vec![
+            "crossgrep",
+            "-m",
+            "codebert",
+            "-t",
+            "rust",
+            query,
+            "--",
+            "q",
+        ];
+        let err = Invocation::from_args(args.into_iter().map(String::from).collect())
+            .err()
+            .expect("query should have been rejected");
+
+        format!("{:#}", err)
+    }
+
+    #[test]
+    fn empty_query_is_rejected() {
+        assert!(query_error("").contains("doesn't have any patterns"));
+    }
+
+    #[test]
+    fn whitespace_query_is_rejected() {
+        assert!(query_error(" \n\t ").contains("doesn't have any patterns"));
+    }
+
+    #[test]
+    fn comment_only_query_is_rejected() {
+        assert!(query_error("; just a comment").contains("doesn't have any patterns"));
+    }
+
+    #[test]
+    fn capture_less_queries_are_rejected_without_auto_capture() {
+        let args = |query: &'static str| {
+            vec![
+                "crossgrep",
+                "--no-auto-capture",
+                "-m",
+                "codebert",
+                "-t",
+                "rust",
+                query,
+                "--",
+                "q",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect()
+        };
+
+        let err = Invocation::from_args(args("(function_item)"))
+            .err()
+            .expect("query should have been rejected");
+        assert!(format!("{:#}", err).contains("doesn't capture anything"));
+        assert!(Invocation::from_args(args("(function_item) @f")).is_ok());
+    }
+
+    #[test]
+    fn every_pattern_gets_the_capture() {
+        let raw = "; functions\n(function_item) ; and structs\n(struct_item)";
+        let query = Language::Rust.parse_query(raw).unwrap();
+        let captured = capture_every_pattern(raw, &query, "query");
+
+        let query = Language::Rust.parse_query(&captured).unwrap();
+        assert_eq!(query.capture_names(), &["query"]);
+        for pattern in 0..query.pattern_count() {
+            assert!(query.capture_quantifiers(pattern)[0] == tree_sitter::CaptureQuantifier::One);
+        }
+    }
+
+    #[test]
+    fn default_model_comes_from_the_environment_then_codebert() {
+        assert_eq!(default_model(Some(String::from("noop"))), "noop");
+        assert_eq!(default_model(Some(String::from(" "))), "codebert");
+        assert_eq!(default_model(None), "codebert");
+
+        let args = vec!["crossgrep", "-t", "rust", "(function_item) @f", "--", "q"];
+        assert!(Invocation::from_args(args.into_iter().map(String::from).collect()).is_ok());
+    }
+
+    #[test]
+    fn kinds_become_queries() {
+        assert_eq!(
+            kind_query("rust", "function_item").unwrap(),
+            "(function_item) @match"
+        );
+        assert!(
+            format!("{:#}", kind_query("rust", "function_itme").unwrap_err())
+                .contains("Did you mean function_item")
+        );
+        assert!(kind_query("rust", "not_a_kind_at_all").is_err());
+    }
+
+    #[test]
+    fn file_sizes_parse_with_and_without_suffixes() {
+        assert_eq!(parse_filesize("0").unwrap(), 0);
+        assert_eq!(parse_filesize("1500").unwrap(), 1500);
+        assert_eq!(parse_filesize("10K").unwrap(), 10 * 1024);
+        assert_eq!(parse_filesize("10k").unwrap(), 10 * 1024);
+        assert_eq!(parse_filesize("1M").unwrap(), 1024 * 1024);
+        assert_eq!(parse_filesize("1G").unwrap(), 1024 * 1024 * 1024);
+
+        for invalid in ["", "M", "10KB", "10T", "1.5M", "-1K"] {
+            assert!(parse_filesize(invalid).is_err(), "{:?} parsed", invalid);
+        }
+
+        // 2^34 gigabytes is 2^64 bytes, one more than fits
+        assert!(parse_filesize("17179869183G").is_ok());
+        assert!(format!("{:#}", parse_filesize("17179869184G").unwrap_err()).contains("too large"));
+        assert!(parse_filesize("18446744073709551616").is_err());
+    }
+
+    #[test]
+    fn choices_parse_into_their_types() {
+        let parse = |flag: &str, value: &str| {
+            Invocation::from_args(
+                [
+                    "crossgrep",
+                    "-m",
+                    "codebert",
+                    "-t",
+                    "rust",
+                    "(_) @any",
+                    flag,
+                    value,
+                    "--",
+                    "q",
+                ]
+                .iter()
+                .map(|arg| arg.to_string())
+                .collect(),
+            )
+            .map(|_| ())
+            .map_err(|err| format!("{:#}", err))
+        };
+
+        for (flag, value) in [
+            ("--chunk-strategy", "line"),
+            ("--chunk-strategy", "node"),
+            ("--column-unit", "char"),
+            ("--column-unit", "utf16"),
+            ("--diagnostics", "json"),
+        ] {
+            assert_eq!(parse(flag, value), Ok(()), "{} {}", flag, value);
+        }
+        for (flag, value) in [
+            ("--chunk-strategy", "tokens"),
+            ("--column-unit", "utf8"),
+            ("--diagnostics", "xml"),
+        ] {
+            assert!(parse(flag, value)
+                .unwrap_err()
+                .contains(&format!("invalid value '{}'", value)));
+        }
+
+        assert_eq!(
+            "node".parse::<ChunkStrategy>().unwrap(),
+            ChunkStrategy::Node
+        );
+        assert_eq!("utf16".parse::<ColumnUnit>().unwrap(), ColumnUnit::Utf16);
+        assert_eq!(
+            "json".parse::<DiagnosticFormat>().unwrap(),
+            DiagnosticFormat::Json
+        );
+    }
+
+    #[test]
+    fn line_ranges_parse_with_and_without_paths() {
+        assert_eq!(
+            LineRange::from_str("10:20").unwrap(),
+            LineRange {
+                path: None,
+                start: 10,
+                end: 20
+            }
+        );
+        assert_eq!(
+            LineRange::from_str("C:/src/lib.rs:3:3").unwrap(),
+            LineRange {
+                path: Some(PathBuf::from("C:/src/lib.rs")),
+                start: 3,
+                end: 3
+            }
+        );
+        assert!(LineRange::from_str("10").is_err());
+        assert!(LineRange::from_str("0:10").is_err());
+        assert!(LineRange::from_str("20:10").is_err());
+    }
+
+    #[test]
+    fn line_ranges_include_matches_touching_their_boundaries() {
+        let range = LineRange::from_str("10:20").unwrap();
+
+        assert!(!range.overlaps(None, 1, 9));
+        assert!(range.overlaps(None, 1, 10));
+        assert!(range.overlaps(None, 20, 30));
+        assert!(range.overlaps(None, 5, 25));
+        assert!(!range.overlaps(None, 21, 30));
+    }
+
+    #[test]
+    fn line_ranges_with_paths_only_apply_to_that_file() {
+        let range = LineRange::from_str("./src/lib.rs:1:5").unwrap();
+
+        assert!(range.overlaps(Some(Path::new("src/lib.rs")), 1, 1));
+        assert!(!range.overlaps(Some(Path::new("src/main.rs")), 1, 1));
+        assert!(!range.overlaps(None, 1, 1));
+    }
+
+    #[test]
+    fn paths_are_shown_relative_to_a_base() {
+        let relative_to = RelativeTo {
+            base: PathBuf::from("/repo/crate"),
+            current_dir: PathBuf::from("/repo"),
+        };
+
+        assert_eq!(
+            relative_to.apply(Path::new("./crate/src/lib.rs")),
+            Ok(PathBuf::from("src/lib.rs"))
+        );
+        assert_eq!(
+            relative_to.apply(Path::new("/repo/crate/src/nested/../lib.rs")),
+            Ok(PathBuf::from("src/lib.rs"))
+        );
+        assert_eq!(
+            relative_to.apply(Path::new("sibling/lib.rs")),
+            Err(PathBuf::from("/repo/sibling/lib.rs"))
+        );
+        assert_eq!(
+            relative_to.apply(Path::new("crate-two/lib.rs")),
+            Err(PathBuf::from("/repo/crate-two/lib.rs"))
+        );
+    }
+
+    #[test]
+    fn paths_outside_relative_to_are_only_warned_about_once() {
+        let args = [
+            "crossgrep",
+            "-m",
+            "codebert",
+            "-t",
+            "rust",
+            "(_) @any",
+            "--relative-to",
+            ".",
+            "--",
+            "q",
+        ];
+        let opts = match Invocation::from_args(args.iter().map(|a| a.to_string()).collect()) {
+            Ok(Invocation::DoQuery(opts)) => opts,
+            _ => panic!("should have been a search"),
+        };
+        let outside = Path::new("/somewhere/else/lib.rs");
+
+        assert!(!opts.outside_relative_to_warning.is_completed());
+        assert_eq!(
+            opts.shown_path(Path::new("src/lib.rs")),
+            Path::new("src/lib.rs")
+        );
+        assert!(!opts.outside_relative_to_warning.is_completed());
+        assert_eq!(opts.shown_path(outside), outside);
+        assert!(opts.outside_relative_to_warning.is_completed());
+    }
+
+    #[test]
+    fn targets_with_grammars_that_fail_to_load_are_dropped() {
+        let load = |lang: Language| {
+            if lang == Language::Rust {
+                Err(String::from("Incompatible language version 99"))
+            } else {
+                Ok(())
+            }
+        };
+        let target = |lang: &'static str| (Cow::Borrowed(lang), Cow::Borrowed("(_) @any"));
+
+        let mut targets = vec![
+            target("rust"),
+            target("javascript"),
+            target("rust"),
+            target("cobol"),
+        ];
+        assert_eq!(
+            drop_unloadable_grammars(&mut targets, load).unwrap(),
+            vec![(
+                Language::Rust,
+                String::from("Incompatible language version 99")
+            )]
+        );
+        // unknown languages are left for compile_target to report
+        assert_eq!(targets, vec![target("javascript"), target("cobol")]);
+
+        let mut targets = vec![target("rust")];
+        assert_eq!(
+            drop_unloadable_grammars(&mut targets, load)
+                .unwrap_err()
+                .to_string(),
+            "could not load the rust grammar: Incompatible language version 99"
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use encoding_rs::{DecoderResult, Encoding};
+
+/// Source decoded to UTF-8 from some other encoding, so tree-sitter and the
+/// tokenizer have something they can work with. We keep track of where each
+/// byte of the decoded text came from so positions in results can still
+/// point into the original file.
+#[derive(Debug)]
+pub(crate) struct Decoded {
+    text: String,
+    /// The byte in the original source that each byte of `text` was decoded
+    /// from, plus one more entry for the end of the source.
+    original: Vec<usize>,
+}
+
+impl Decoded {
+    pub(crate) fn new(source: &[u8], encoding: &'static Encoding) -> Self {
+        // we decode a byte at a time so we know exactly which input bytes
+        // each character came from. BOMs are left alone (and come out as
+        // U+FEFF) so a character can never come from nothing.
+        let mut decoder = encoding.new_decoder_without_bom_handling();
+        let mut text = String::with_capacity(source.len());
+        let mut original = Vec::with_capacity(source.len() + 1);
+        let mut buffer = [0; 16];
+        let mut char_start = 0;
+
+        for (offset, byte) in source.iter().enumerate() {
+            let last = offset + 1 == source.len();
+            let mut input: &[u8] = &[*byte];
+
+            loop {
+                let (result, read, mut written) =
+                    decoder.decode_to_utf8_without_replacement(input, &mut buffer, last);
+                input = &input[read..];
+
+                // the bytes from `char_start` up to here don't make a
+                // character in this encoding. tree-sitter and the tokenizer
+                // can't do anything with them, so they become U+FFFD just
+                // like with a lossy decode.
+                if let DecoderResult::Malformed(_, _) = result {
+                    written += char::REPLACEMENT_CHARACTER
+                        .encode_utf8(&mut buffer[written..])
+                        .len();
+                }
+
+                if written > 0 {
+                    // decoders only ever write whole characters.
+                    text.push_str(std::str::from_utf8(&buffer[..written]).unwrap());
+                    original.resize(text.len(), char_start);
+                    char_start = offset + 1 - input.len();
+                }
+
+                if let DecoderResult::InputEmpty = result {
+                    break;
+                }
+            }
+        }
+
+        original.push(source.len());
+
+        Decoded { text, original }
+    }
+
+    pub(crate) fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The byte in the original source that `byte` in the decoded text came
+    /// from. The end of the text maps to the end of the source.
+    pub(crate) fn original_byte(&self, byte: usize) -> usize {
+        self.original[byte]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_map_back_to_the_original_source() {
+        // あ is two bytes in Shift_JIS, but three in UTF-8
+        let source = b"a\x82\xa0b";
+        let decoded = Decoded::new(source, encoding_rs::SHIFT_JIS);
+
+        assert_eq!(decoded.text(), "a\u{3042}b");
+        // a, then the three UTF-8 bytes of あ, then b, then the end
+        let originals: Vec<usize> = (0..=decoded.text().len())
+            .map(|byte| decoded.original_byte(byte))
+            .collect();
+        assert_eq!(originals, vec![0, 1, 1, 1, 3, 4]);
+    }
+
+    #[test]
+    fn malformed_bytes_become_replacement_characters() {
+        let decoded = Decoded::new(b"a\xffb", encoding_rs::SHIFT_JIS);
+
+        assert_eq!(decoded.text(), "a\u{FFFD}b");
+        assert_eq!(decoded.original_byte(decoded.text().len() - 1), 2);
+    }
+}
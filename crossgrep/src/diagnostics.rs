@@ -0,0 +1,88 @@
+use serde::{Serialize, Serializer};
+use std::path::Path;
+use std::str::FromStr;
+
+/// How to print warnings about problems that don't stop a search, like a file
+/// we couldn't read or one that took too long to parse.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DiagnosticFormat {
+    /// `warning: ...` lines for people to read.
+    #[default]
+    Text,
+    /// One JSON object per line, so programs reading our results can see
+    /// what was left out of them and why.
+    Json,
+}
+
+impl FromStr for DiagnosticFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "text" => Ok(DiagnosticFormat::Text),
+            "json" => Ok(DiagnosticFormat::Json),
+            _ => anyhow::bail!("unknown diagnostics format {}. Try text or json.", s),
+        }
+    }
+}
+
+/// A problem with one file (or with none in particular) that we warn about
+/// and then keep going.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct Diagnostic<'path> {
+    #[serde(serialize_with = "serialize_file")]
+    pub file: Option<&'path Path>,
+    /// A short name for what went wrong that stays the same from run to run,
+    /// like `parse-timeout`.
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl DiagnosticFormat {
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        match self {
+            DiagnosticFormat::Text => format!("warning: {}", diagnostic.message),
+            // a diagnostic only has strings in it, so this can't fail.
+            DiagnosticFormat::Json => serde_json::to_string(diagnostic).unwrap(),
+        }
+    }
+
+    /// Print `diagnostic` on stderr, out of the way of results.
+    pub fn report(&self, diagnostic: Diagnostic) {
+        eprintln!("{}", self.render(&diagnostic));
+    }
+}
+
+// paths aren't always valid UTF-8, which serde would otherwise refuse.
+fn serialize_file<S>(file: &Option<&Path>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match file {
+        Some(file) => serializer.serialize_some(&file.to_string_lossy()),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnostics_render_as_text_or_json() {
+        let diagnostic = Diagnostic {
+            file: Some(Path::new("src/lib.rs")),
+            code: "parse-timeout",
+            message: String::from("could not extract matches from src/lib.rs"),
+        };
+
+        assert_eq!(
+            DiagnosticFormat::Text.render(&diagnostic),
+            "warning: could not extract matches from src/lib.rs"
+        );
+        assert_eq!(
+            DiagnosticFormat::Json.render(&diagnostic),
+            r#"{"file":"src/lib.rs","code":"parse-timeout","message":"could not extract matches from src/lib.rs"}"#
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+use crossgrep::extractor::ExtractedFile;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// How much of a match's text goes in its node's label.
+const LABEL_CHARS: usize = 40;
+
+/// Writes results as a Graphviz graph (for `--format dot`) one file at a
+/// time: each file is a cluster and each match is a node in it, labeled with
+/// its capture name and the start of its text.
+pub struct DotGraph {
+    /// Connect matches that are in the same function, class, or module.
+    scope_edges: bool,
+    files: usize,
+}
+
+impl DotGraph {
+    pub fn new(scope_edges: bool) -> Self {
+        DotGraph {
+            scope_edges,
+            files: 0,
+        }
+    }
+
+    pub fn push(&mut self, mut out: impl Write, extracted_file: &ExtractedFile) -> Result<()> {
+        if self.files == 0 {
+            writeln!(out, "digraph crossgrep {{\n  node [shape=box];")
+                .context("could not write graph")?;
+        }
+        let file = self.files;
+        self.files += 1;
+
+        let label = extracted_file
+            .file()
+            .map(|path| path.to_string_lossy())
+            .unwrap_or_default();
+        writeln!(
+            out,
+            "  subgraph cluster_{} {{\n    label=\"{}\";",
+            file,
+            escape(&label)
+        )
+        .context("could not write graph")?;
+
+        // matches in each scope, in order. We only draw an edge from each one
+        // to the next so big scopes don't end up with an edge for every pair.
+        let mut scopes: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+
+        for (index, extracted) in extracted_file.matches().iter().enumerate() {
+            writeln!(
+                out,
+                "    m{}_{} [label=\"{}\\n{}\"];",
+                file,
+                index,
+                escape(extracted.name()),
+                escape(&truncate(extracted.text()))
+            )
+            .context("could not write graph")?;
+
+            if let Some(scope) = extracted.scope().filter(|_| self.scope_edges) {
+                scopes.entry(scope).or_default().push(index);
+            }
+        }
+
+        for (scope, indexes) in scopes {
+            for pair in indexes.windows(2) {
+                writeln!(
+                    out,
+                    "    m{}_{} -> m{}_{} [dir=none, style=dashed, tooltip=\"{}\"];",
+                    file,
+                    pair[0],
+                    file,
+                    pair[1],
+                    escape(scope)
+                )
+                .context("could not write graph")?;
+            }
+        }
+
+        writeln!(out, "  }}").context("could not write graph")
+    }
+
+    pub fn finish(self, mut out: impl Write) -> Result<()> {
+        if self.files == 0 {
+            writeln!(out, "digraph crossgrep {{").context("could not write graph")?;
+        }
+
+        writeln!(out, "}}").context("could not write graph")
+    }
+}
+
+/// The first line of `text`, cut down to `LABEL_CHARS` characters.
+fn truncate(text: &str) -> String {
+    let line = text.lines().next().unwrap_or_default().trim_end();
+    if line.chars().count() > LABEL_CHARS || line.len() < text.trim_end().len() {
+        let mut short: String = line.chars().take(LABEL_CHARS).collect();
+        short.push('…');
+        short
+    } else {
+        line.to_string()
+    }
+}
+
+/// Make `text` safe to put in a quoted DOT string.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_are_truncated_to_one_line() {
+        assert_eq!(truncate("fn f() {}"), "fn f() {}");
+        assert_eq!(truncate("fn f() {\n    x\n}"), "fn f() {…");
+        assert_eq!(truncate(&"x".repeat(50)), format!("{}…", "x".repeat(40)));
+        assert_eq!(escape(r#"say "hi\""#), r#"say \"hi\\\""#);
+    }
+}
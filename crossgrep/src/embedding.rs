@@ -0,0 +1,360 @@
+use anyhow::{bail, Error, Result};
+use std::str::FromStr;
+
+/// How to turn a model's per-token hidden states into a single vector for a
+/// chunk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Pooling {
+    /// Average the hidden states of every real (non-padding) token. This is
+    /// what CodeBERT/RoBERTa-style sentence embeddings usually use.
+    #[default]
+    Mean,
+    /// Take the hidden state of the first token (`<s>`/`[CLS]`).
+    Cls,
+}
+
+impl Pooling {
+    /// Pool `hidden_states`, a row-major `[tokens × hidden_size]` matrix, into
+    /// one `hidden_size`-long vector. Positions where `attention_mask` is 0
+    /// are padding and never contribute to the result.
+    pub fn pool(
+        &self,
+        hidden_states: &[f32],
+        hidden_size: usize,
+        attention_mask: &[u32],
+    ) -> Result<Vec<f32>> {
+        if hidden_size == 0 || hidden_states.len() != hidden_size * attention_mask.len() {
+            bail!(
+                "got {} hidden state values, which doesn't fit {} tokens of size {}",
+                hidden_states.len(),
+                attention_mask.len(),
+                hidden_size
+            )
+        }
+
+        let rows = hidden_states.chunks_exact(hidden_size);
+
+        match self {
+            Pooling::Mean => {
+                let mut pooled = vec![0.0; hidden_size];
+                let mut real_tokens = 0;
+
+                for (row, mask) in rows.zip(attention_mask) {
+                    if *mask == 0 {
+                        continue;
+                    }
+
+                    real_tokens += 1;
+                    pooled
+                        .iter_mut()
+                        .zip(row)
+                        .for_each(|(total, value)| *total += value);
+                }
+
+                if real_tokens > 0 {
+                    pooled
+                        .iter_mut()
+                        .for_each(|total| *total /= real_tokens as f32);
+                }
+
+                Ok(pooled)
+            }
+            Pooling::Cls => match rows.zip(attention_mask).next() {
+                Some((row, mask)) if *mask != 0 => Ok(row.to_vec()),
+                _ => bail!("the first token is padding, so there's no CLS embedding to use"),
+            },
+        }
+    }
+}
+
+/// Scale `vector` to unit length in place, so that cosine similarity between
+/// two normalized vectors is just their dot product. The zero vector has no
+/// direction, so it's left alone.
+pub fn l2_normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|value| value * value).sum::<f32>().sqrt();
+
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|value| *value /= norm);
+    }
+}
+
+/// How to compare a query embedding to a chunk embedding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Metric {
+    #[default]
+    Cosine,
+    Dot,
+    Euclidean,
+}
+
+impl Metric {
+    /// Get a pooled embedding ready to be scored with this metric. Query and
+    /// chunk embeddings should both go through here so they're treated the
+    /// same way.
+    pub fn prepare(&self, mut embedding: Vec<f32>) -> Vec<f32> {
+        if *self == Metric::Cosine {
+            l2_normalize(&mut embedding);
+        }
+
+        embedding
+    }
+
+    /// Score two embeddings that have been through `prepare`. Higher scores
+    /// always mean more similar, so Euclidean distance comes back negated.
+    /// Cosine scores are always in [-1, 1], even when rounding error would
+    /// push the dot product of two unit vectors a little past either end.
+    pub fn score(&self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            Metric::Cosine => dot(a, b).clamp(-1.0, 1.0),
+            Metric::Dot => dot(a, b),
+            Metric::Euclidean => -a
+                .iter()
+                .zip(b)
+                .map(|(x, y)| (x - y) * (x - y))
+                .sum::<f32>()
+                .sqrt(),
+        }
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Which scored results to keep, best first: everything scoring at least
+/// `min_score`, and then at most `top_k` of those. With neither, everything is
+/// kept (just sorted.)
+///
+/// Results with the same score come in `T`'s order, so the same search always
+/// keeps the same ones. Duplicated code (or a model like `Noop` that gives
+/// everything the same embedding) ties a lot, so `T` should be something like
+/// `(path, start byte, capture name)` that tells matches apart.
+pub fn select<T: Ord>(
+    mut scored: Vec<(T, f32)>,
+    min_score: Option<f32>,
+    top_k: Option<usize>,
+) -> Vec<(T, f32)> {
+    if let Some(min_score) = min_score {
+        scored.retain(|(_, score)| *score >= min_score);
+    }
+
+    scored.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then_with(|| a.cmp(b)));
+
+    if let Some(top_k) = top_k {
+        scored.truncate(top_k);
+    }
+
+    scored
+}
+
+impl FromStr for Metric {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cosine" => Ok(Metric::Cosine),
+            "dot" => Ok(Metric::Dot),
+            "euclidean" => Ok(Metric::Euclidean),
+            _ => bail!("unknown metric {}. Try cosine, dot, or euclidean.", s),
+        }
+    }
+}
+
+impl FromStr for Pooling {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mean" => Ok(Pooling::Mean),
+            "cls" => Ok(Pooling::Cls),
+            _ => bail!("unknown pooling strategy {}. Try mean or cls.", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    const HIDDEN_SIZE: usize = 2;
+
+    // a tiny stand-in for a real model: every token's hidden state depends
+    // only on its id, which is enough to see whether padding leaks in.
+    fn hidden_states(input_ids: &[u32]) -> Vec<f32> {
+        input_ids
+            .iter()
+            .flat_map(|id| vec![*id as f32, 1.0])
+            .collect()
+    }
+
+    #[test]
+    fn mean_pooling_ignores_padding() {
+        let model = Model::CodeBert;
+        let ids = [100, 200, 300];
+
+        let mut padded = Vec::new();
+        model.prepare_input_ids(&mut padded, &ids).unwrap();
+        let padded_mask = model.attention_mask(ids.len());
+
+        let unpadded = &padded[..ids.len() + model.special_tokens()];
+        let unpadded_mask = vec![1; unpadded.len()];
+
+        assert_eq!(
+            Pooling::Mean
+                .pool(&hidden_states(&padded), HIDDEN_SIZE, &padded_mask)
+                .unwrap(),
+            Pooling::Mean
+                .pool(&hidden_states(unpadded), HIDDEN_SIZE, &unpadded_mask)
+                .unwrap(),
+        );
+    }
+
+    #[test]
+    fn cls_pooling_takes_the_first_token() {
+        let model = Model::CodeBert;
+        let ids = [100, 200, 300];
+
+        let mut padded = Vec::new();
+        model.prepare_input_ids(&mut padded, &ids).unwrap();
+        let mask = model.attention_mask(ids.len());
+
+        assert_eq!(
+            Pooling::Cls
+                .pool(&hidden_states(&padded), HIDDEN_SIZE, &mask)
+                .unwrap(),
+            vec![0.0, 1.0],
+        );
+    }
+
+    #[test]
+    fn normalized_vectors_have_unit_norm() {
+        let mut vector = vec![3.0, -4.0, 12.0];
+        l2_normalize(&mut vector);
+
+        let norm = vector.iter().map(|value| value * value).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn normalizing_the_zero_vector_leaves_it_alone() {
+        let mut vector = vec![0.0, 0.0];
+        l2_normalize(&mut vector);
+
+        assert_eq!(vector, vec![0.0, 0.0]);
+    }
+
+    #[test]
+    fn cosine_ignores_magnitude() {
+        let metric = Metric::Cosine;
+        let query = metric.prepare(vec![1.0, 1.0]);
+        let short = metric.prepare(vec![2.0, 2.0]);
+        let long = metric.prepare(vec![200.0, 200.0]);
+
+        assert!((metric.score(&query, &short) - metric.score(&query, &long)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn euclidean_scores_closer_vectors_higher() {
+        let metric = Metric::Euclidean;
+        let query = metric.prepare(vec![0.0, 0.0]);
+        let near = metric.prepare(vec![1.0, 0.0]);
+        let far = metric.prepare(vec![5.0, 0.0]);
+
+        assert!(metric.score(&query, &near) > metric.score(&query, &far));
+    }
+
+    #[test]
+    fn cosine_scores_stay_in_range() {
+        let metric = Metric::Cosine;
+        let query = metric.prepare(vec![0.1, 0.7, 0.3]);
+
+        for other in &[vec![0.1, 0.7, 0.3], vec![-0.1, -0.7, -0.3]] {
+            let score = metric.score(&query, &metric.prepare(other.clone()));
+            assert!((-1.0..=1.0).contains(&score));
+        }
+
+        // not normalized, so the raw dot product is way out of range
+        assert_eq!(metric.score(&[3.0, 4.0], &[3.0, 4.0]), 1.0);
+    }
+
+    // scores for a few synthetic embeddings against a query along the x axis
+    fn scored() -> Vec<(&'static str, f32)> {
+        let metric = Metric::Cosine;
+        let query = metric.prepare(vec![1.0, 0.0]);
+
+        vec![
+            ("opposite", vec![-1.0, 0.0]),
+            ("same", vec![2.0, 0.0]),
+            ("close", vec![1.0, 0.25]),
+            ("orthogonal", vec![0.0, 1.0]),
+            ("diagonal", vec![1.0, 1.0]),
+        ]
+        .into_iter()
+        .map(|(name, embedding)| (name, metric.score(&query, &metric.prepare(embedding))))
+        .collect()
+    }
+
+    fn names(selected: Vec<(&'static str, f32)>) -> Vec<&'static str> {
+        selected.into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn selecting_with_a_threshold_keeps_everything_above_it() {
+        assert_eq!(
+            names(select(scored(), Some(0.7), None)),
+            vec!["same", "close", "diagonal"]
+        );
+        assert!(select(scored(), Some(1.5), None).is_empty());
+    }
+
+    #[test]
+    fn selecting_with_a_threshold_and_top_k_keeps_at_most_k_above_it() {
+        assert_eq!(
+            names(select(scored(), Some(0.7), Some(2))),
+            vec!["same", "close"]
+        );
+        assert_eq!(names(select(scored(), Some(0.99), Some(2))), vec!["same"]);
+        assert_eq!(
+            names(select(scored(), None, None)),
+            vec!["same", "close", "diagonal", "orthogonal", "opposite"]
+        );
+    }
+
+    #[test]
+    fn ties_are_broken_by_path_then_offset_then_name() {
+        let metric = Metric::Cosine;
+        let query = metric.prepare(vec![1.0, 0.0]);
+        // the same snippet in two files, and twice in one of them
+        let snippet = metric.prepare(vec![1.0, 1.0]);
+        let score = metric.score(&query, &snippet);
+
+        let found = vec![
+            (("src/b.rs", 10, "fn"), score),
+            (("src/a.rs", 40, "fn"), score),
+            (("src/b.rs", 10, "body"), score),
+            (("src/a.rs", 0, "fn"), score),
+        ];
+        let mut reversed = found.clone();
+        reversed.reverse();
+
+        for found in [found, reversed] {
+            assert_eq!(
+                select(found, None, Some(3))
+                    .into_iter()
+                    .map(|(key, _)| key)
+                    .collect::<Vec<_>>(),
+                vec![
+                    ("src/a.rs", 0, "fn"),
+                    ("src/a.rs", 40, "fn"),
+                    ("src/b.rs", 10, "body")
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn pooling_rejects_mismatched_shapes() {
+        assert!(Pooling::Mean.pool(&[1.0, 2.0, 3.0], 2, &[1, 1]).is_err());
+    }
+}
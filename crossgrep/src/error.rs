@@ -0,0 +1,126 @@
+use crossgrep_sys::Language;
+use std::fmt::{self, Display};
+use std::io;
+use tree_sitter::{LanguageError, QueryError};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything that can go wrong inside the library. The CLI wraps these in
+/// more context with anyhow, but library users can match on them to tell
+/// (for example) a bad query apart from a missing file.
+#[derive(Debug)]
+pub enum Error {
+    /// A query didn't parse, or used a node type, field, or capture name the
+    /// language doesn't have.
+    Query(QueryError),
+    /// A file couldn't be read.
+    Io(io::Error),
+    /// The tokenizer couldn't encode some source.
+    Tokenizer(tokenizers::Error),
+    /// tree-sitter couldn't load this language's grammar. This usually means
+    /// the grammar was generated for an incompatible version of tree-sitter.
+    UnsupportedLanguage(Language, LanguageError),
+    /// A tokenizer couldn't be loaded from a file, or downloaded (even after
+    /// retrying) with no usable cached copy to fall back on.
+    LoadTokenizer {
+        source: String,
+        error: tokenizers::Error,
+    },
+    /// We don't know how to embed for a model with this name.
+    UnsupportedModel(String),
+    /// A model config didn't have the fields we need, or had ones we don't
+    /// know about.
+    ModelConfig(serde_json::Error),
+    /// Source couldn't be split into chunks.
+    Chunk(String),
+    /// A model's chunks are too small to fit its special tokens and overlap
+    /// with room to spare.
+    ChunkSize {
+        chunk_size: usize,
+        chunk_overlap: usize,
+        special_tokens: usize,
+    },
+    /// The parser gave up on a file because it took longer than the timeout
+    /// set on the `Parser`. Callers will usually want to skip the file instead
+    /// of failing the whole run.
+    ParseTimeout,
+    /// The parser gave up on a file for some other reason. This shouldn't
+    /// happen, and it's a bug if it does.
+    Parse,
+    /// The file type matcher couldn't be built for the given extractors.
+    FileTypes(ignore::Error),
+    /// Two extractors were given for the same language.
+    DuplicateLanguage(Language),
+    /// A query used `#is? local` or `#is-not? local`, but we don't have a
+    /// locals query to work out scopes for this language.
+    NoLocalsQuery(Language),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Query(_) => write!(f, "could not parse query"),
+            Error::Io(_) => write!(f, "could not read file"),
+            Error::Tokenizer(err) => write!(f, "Could not encode source: {}", err),
+            Error::LoadTokenizer { source, error } => {
+                write!(f, "could not load tokenizer {}: {}", source, error)
+            }
+            Error::UnsupportedLanguage(_, _) => write!(f, "could not set language"),
+            Error::UnsupportedModel(identifier) => write!(f, "unsupported model: {}", identifier),
+            Error::ModelConfig(_) => write!(f, "invalid model config"),
+            Error::Chunk(problem) => write!(f, "could not chunk source: {}", problem),
+            Error::ChunkSize {
+                chunk_size,
+                chunk_overlap,
+                special_tokens,
+            } => write!(
+                f,
+                "a chunk size of {} tokens is too small for {} special tokens and an overlap of {} tokens",
+                chunk_size, special_tokens, chunk_overlap
+            ),
+            Error::ParseTimeout => write!(f, "parsing timed out"),
+            Error::Parse => write!(
+                f,
+                "could not parse to a tree. This is an internal error and should be reported."
+            ),
+            Error::FileTypes(_) => write!(
+                f,
+                "could not build a filetype matcher using provided extractors"
+            ),
+            Error::DuplicateLanguage(_) => write!(
+                f,
+                "got a duplicate query. This should not have happened. Please report it!"
+            ),
+            Error::NoLocalsQuery(language) => write!(
+                f,
+                "`#is? local` and `#is-not? local` aren't supported for {} yet",
+                language
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Query(err) => Some(err),
+            Error::Io(err) => Some(err),
+            Error::UnsupportedLanguage(_, err) => Some(err),
+            Error::FileTypes(err) => Some(err),
+            Error::ModelConfig(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<QueryError> for Error {
+    fn from(err: QueryError) -> Self {
+        Error::Query(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
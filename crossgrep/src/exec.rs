@@ -0,0 +1,245 @@
+use crate::template::Template;
+use anyhow::{bail, Context, Result};
+use crossgrep::diagnostics::{Diagnostic, DiagnosticFormat};
+use crossgrep::extractor::FileMatch;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use tempfile::TempDir;
+
+/// A command to run for matches, from `--exec` or `--exec-batch`. Each word
+/// can use the same placeholders as `--template`, plus `{}` for the path of
+/// a file with the match's text in it.
+#[derive(Debug)]
+pub struct ExecCommand {
+    words: Vec<Template>,
+    /// Run the command once with the arguments for every match, instead of
+    /// once per match.
+    batch: bool,
+}
+
+impl ExecCommand {
+    pub fn parse(raw: &str, batch: bool) -> Result<Self> {
+        let words = split_words(raw)?
+            .iter()
+            .map(|word| Template::command_word(word))
+            .collect::<Result<Vec<_>>>()?;
+        if words.is_empty() {
+            bail!("the command to run is empty");
+        }
+
+        Ok(ExecCommand { words, batch })
+    }
+}
+
+/// Runs an `ExecCommand` as matches come in, and keeps track of how many of
+/// the commands failed. A command failing doesn't stop the search; we warn
+/// about it and fail once everything's done.
+pub struct Exec<'opts> {
+    command: &'opts ExecCommand,
+    diagnostics: DiagnosticFormat,
+    /// Where the files for `{}` go, removed when this is dropped. Only
+    /// created if the command uses `{}`.
+    scratch: Option<TempDir>,
+    text_files: usize,
+    /// With `--exec-batch`, the arguments for each word so far.
+    batch_args: Vec<Vec<String>>,
+    commands: usize,
+    failures: usize,
+}
+
+impl<'opts> Exec<'opts> {
+    pub fn new(command: &'opts ExecCommand, diagnostics: DiagnosticFormat) -> Self {
+        Exec {
+            command,
+            diagnostics,
+            scratch: None,
+            text_files: 0,
+            batch_args: vec![Vec::new(); command.words.len()],
+            commands: 0,
+            failures: 0,
+        }
+    }
+
+    pub fn push(&mut self, mut out: impl Write, file_match: &FileMatch) -> Result<()> {
+        let text_file = if self.command.words.iter().any(Template::uses_text_file) {
+            Some(self.write_text_file(file_match)?)
+        } else {
+            None
+        };
+
+        let args: Vec<String> = self
+            .command
+            .words
+            .iter()
+            .map(|word| word.render_with_text_file(file_match, text_file.as_deref()))
+            .collect();
+
+        if self.command.batch {
+            for (word_args, arg) in self.batch_args.iter_mut().zip(args) {
+                // so `{path}` only passes each file once
+                if !word_args.contains(&arg) {
+                    word_args.push(arg);
+                }
+            }
+            return Ok(());
+        }
+
+        let result = self.run(&mut out, &args, file_match.file());
+        if let Some(text_file) = text_file {
+            let _ = fs::remove_file(text_file);
+        }
+
+        result
+    }
+
+    /// Run the batched command, if there is one, and fail if any command
+    /// didn't succeed.
+    pub fn finish(mut self, mut out: impl Write) -> Result<()> {
+        if self.command.batch && self.batch_args.iter().any(|args| !args.is_empty()) {
+            let args: Vec<String> = std::mem::take(&mut self.batch_args)
+                .into_iter()
+                .flatten()
+                .collect();
+
+            self.run(&mut out, &args, None)?;
+        }
+
+        if self.failures > 0 {
+            bail!("{} of {} commands failed", self.failures, self.commands);
+        }
+
+        Ok(())
+    }
+
+    fn write_text_file(&mut self, file_match: &FileMatch) -> Result<PathBuf> {
+        let scratch = match &mut self.scratch {
+            Some(scratch) => scratch,
+            // somewhere only we can write to, with a name nobody can guess
+            // and get there first with.
+            None => self.scratch.insert(
+                tempfile::Builder::new()
+                    .prefix("crossgrep-exec-")
+                    .tempdir()
+                    .context("could not create a directory for match text")?,
+            ),
+        };
+
+        // keep the extension, so tools that go by it know what's inside.
+        let mut name = format!("match-{}", self.text_files);
+        if let Some(extension) = file_match.file().and_then(Path::extension) {
+            name.push('.');
+            name.push_str(&extension.to_string_lossy());
+        }
+        self.text_files += 1;
+
+        let path = scratch.path().join(name);
+        fs::write(&path, file_match.extracted().text())
+            .with_context(|| format!("could not write {}", path.display()))?;
+
+        Ok(path)
+    }
+
+    fn run(&mut self, mut out: impl Write, args: &[String], file: Option<&Path>) -> Result<()> {
+        self.commands += 1;
+
+        let problem = match Command::new(&args[0]).args(&args[1..]).output() {
+            Ok(Output {
+                status,
+                stdout,
+                stderr,
+            }) => {
+                out.write_all(&stdout)
+                    .context("could not write command output")?;
+                // there's nothing better to do with a command's errors than
+                // show them where it would have.
+                let _ = io::stderr().write_all(&stderr);
+
+                if status.success() {
+                    None
+                } else {
+                    Some(status.to_string())
+                }
+            }
+            Err(err) => Some(err.to_string()),
+        };
+
+        if let Some(problem) = problem {
+            self.failures += 1;
+            self.diagnostics.report(Diagnostic {
+                file,
+                code: "exec-failed",
+                message: format!("`{}` failed: {}", args.join(" "), problem),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Split a command into words like a shell would, minus everything but
+/// quoting: `'...'` keeps everything in it as-is, `"..."` keeps spaces, and
+/// `\` escapes the next character anywhere but in single quotes.
+fn split_words(raw: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // so `''` is an empty word instead of nothing
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = raw.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), c) => word.push(c),
+            (_, '\\') => match chars.next() {
+                Some(escaped) => {
+                    word.push(escaped);
+                    in_word = true;
+                }
+                None => bail!("the command ends with a \\ with nothing after it"),
+            },
+            (Some(_), c) => word.push(c),
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+
+    if let Some(quote) = quote {
+        bail!("unclosed {} in the command", quote);
+    }
+    if in_word {
+        words.push(word);
+    }
+
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_split_like_a_shell() {
+        assert_eq!(
+            split_words(r#"rustfmt  --check {} 'a b' "c \"d\"" e\ f ''"#).unwrap(),
+            vec!["rustfmt", "--check", "{}", "a b", "c \"d\"", "e f", ""]
+        );
+        assert!(split_words("echo 'oops").is_err());
+        assert!(ExecCommand::parse("  ", false).is_err());
+        assert!(ExecCommand::parse("echo {nope}", false).is_err());
+    }
+}
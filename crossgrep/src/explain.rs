@@ -0,0 +1,195 @@
+use crate::cli::QueryOpts;
+use crossgrep_sys::Language;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Whether walking the paths being searched gets to a file.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Walk {
+    Found,
+    /// The walk skipped `path` (the file, or a directory on the way to it)
+    /// for `reason`.
+    Skipped {
+        path: PathBuf,
+        reason: String,
+    },
+    /// The file isn't inside any of the paths being searched.
+    Outside,
+}
+
+/// Everything that decides whether a file gets searched, for `--explain`.
+#[derive(Debug)]
+pub struct Explanation<'path> {
+    pub path: &'path Path,
+    pub walk: Walk,
+    pub archive: bool,
+    /// The languages of the extractors that claim the file.
+    pub languages: Vec<Language>,
+    /// Whether `-t regex` searches the file, since no language claims it.
+    pub regex: bool,
+    pub size: u64,
+    pub max_filesize: Option<u64>,
+    pub binary: bool,
+    pub search_binary: bool,
+    /// Whether the file passes `--path-regex`, if it was given.
+    pub path_regex: Option<bool>,
+    /// Whether the size and binary checks skip the file, straight from the
+    /// walk's own check.
+    pub skipped: bool,
+}
+
+impl Explanation<'_> {
+    pub fn searched(&self) -> bool {
+        self.walk == Walk::Found
+            && (self.archive
+                || (!self.skipped
+                    && (self.regex || !self.languages.is_empty())
+                    && self.path_regex != Some(false)))
+    }
+}
+
+impl Display for Explanation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "path: {}", self.path.display())?;
+
+        match &self.walk {
+            Walk::Found => writeln!(f, "walk: found it")?,
+            Walk::Skipped { path, reason } => {
+                writeln!(f, "walk: skipped {}: {}", path.display(), reason)?
+            }
+            Walk::Outside => writeln!(f, "walk: not inside any of the paths being searched")?,
+        }
+
+        // what's inside an archive gets checked file by file instead.
+        if self.archive {
+            writeln!(f, "archive: yes, so the files inside it are searched")?;
+        } else {
+            if self.regex {
+                writeln!(f, "language: none claim it, so the regex searches it")?;
+            } else if self.languages.is_empty() {
+                writeln!(
+                    f,
+                    "language: none of the queries are for a language that claims it"
+                )?;
+            } else {
+                let languages: Vec<String> =
+                    self.languages.iter().map(Language::to_string).collect();
+                writeln!(f, "language: {}", languages.join(", "))?;
+            }
+
+            match self.max_filesize {
+                Some(max_filesize) if self.size > max_filesize => writeln!(
+                    f,
+                    "size: {} bytes, over --max-filesize ({})",
+                    self.size, max_filesize
+                )?,
+                _ => writeln!(f, "size: {} bytes", self.size)?,
+            }
+
+            match (self.binary, self.search_binary) {
+                (false, _) => writeln!(f, "binary: no")?,
+                (true, false) => writeln!(f, "binary: yes, so it's skipped (see --binary)")?,
+                (true, true) => writeln!(
+                    f,
+                    "binary: yes, but it's searched anyway (with --binary, or a UTF-16 --encoding)"
+                )?,
+            }
+
+            match self.path_regex {
+                Some(true) => writeln!(f, "path regex: matches")?,
+                Some(false) => writeln!(f, "path regex: doesn't match")?,
+                None => {}
+            }
+        }
+
+        writeln!(
+            f,
+            "searched: {}",
+            if self.searched() { "yes" } else { "no" }
+        )
+    }
+}
+
+/// Why the walk skipped `path` (canonical, and inside `root`.) This checks
+/// things in the same order the walker does, but it can't see inside the
+/// walker, so it falls back to saying it doesn't know.
+pub fn why_skipped(path: &Path, root: &Path, opts: &QueryOpts) -> String {
+    let is_dir = path.is_dir();
+
+    if let Some(rule) = ignore_rule(path, is_dir, root, opts) {
+        return rule;
+    }
+
+    let name = path.file_name().unwrap_or_default();
+    if name.to_string_lossy().starts_with('.') {
+        return "it's hidden".to_string();
+    }
+
+    if is_dir && opts.exclude_dirs.iter().any(|excluded| excluded == name) {
+        return format!("--exclude-dir {} skips it", name.to_string_lossy());
+    }
+
+    let is_symlink = fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_symlink());
+    if is_symlink && !opts.follow_links {
+        return "it's a symlink, which isn't followed without --follow".to_string();
+    }
+
+    "the walker skipped it for a reason we can't tell".to_string()
+}
+
+/// The ignore file rule that hides `path`, if any. Like the walker, `.ignore`
+/// files beat `.gitignore` files, which beat `.git/info/exclude`, which beats
+/// git's global excludes file. Within each of those, the closest file to
+/// `path` wins, and git's files only count inside a git repository.
+fn ignore_rule(path: &Path, is_dir: bool, root: &Path, opts: &QueryOpts) -> Option<String> {
+    let dirs: Vec<&Path> = path
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| opts.ignore_parents || dir.starts_with(root))
+        .collect();
+    let repo_top = dirs.iter().position(|dir| dir.join(".git").exists());
+
+    let mut matchers: Vec<Gitignore> = dirs
+        .iter()
+        .filter_map(|dir| ignore_file(dir, ".ignore"))
+        .collect();
+    if let (true, Some(top)) = (opts.git_ignore, repo_top) {
+        // git's own files stop at the top of the repository.
+        let repo = &dirs[..=top];
+        matchers.extend(repo.iter().filter_map(|dir| ignore_file(dir, ".gitignore")));
+        matchers.extend(
+            repo.iter()
+                .filter_map(|dir| ignore_file(dir, ".git/info/exclude")),
+        );
+        matchers.push(Gitignore::global().0);
+    }
+
+    for matcher in matchers {
+        match matcher.matched(path, is_dir) {
+            Match::None => continue,
+            Match::Ignore(glob) => {
+                return Some(match glob.from() {
+                    Some(from) => format!("`{}` in {} ignores it", glob.original(), from.display()),
+                    None => format!("`{}` ignores it", glob.original()),
+                })
+            }
+            Match::Whitelist(_) => return None,
+        }
+    }
+
+    None
+}
+
+fn ignore_file(dir: &Path, name: &str) -> Option<Gitignore> {
+    let path = dir.join(name);
+    if !path.is_file() {
+        return None;
+    }
+
+    let mut builder = GitignoreBuilder::new(dir);
+    builder.add(path);
+    builder.build().ok()
+}
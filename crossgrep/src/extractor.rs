@@ -1,28 +1,209 @@
-use anyhow::{Context, Result};
+use crossgrep_sys::Language;
+use encoding_rs::{Encoding, UTF_8};
+use regex::Regex;
 use serde::ser::{SerializeStruct, Serializer};
 use serde::Serialize;
-use crossgrep_sys::Language;
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{self, Display};
 use std::fs;
-use std::path::{Path, PathBuf};
-use tree_sitter::{Parser, Point, Query, QueryCursor};
+use std::ops::Range;
+use std::path::{is_separator, Path, PathBuf, MAIN_SEPARATOR};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tree_sitter::{
+    InputEdit, Node, Parser, Point, Query, QueryCapture, QueryCursor, QueryMatch, Tree,
+};
 
-use crate::chunker::{Chunker, ExtractedChunk};
+use crate::cancellation::CancellationToken;
+use crate::chunker::{ChunkStrategy, Chunker, Chunks};
+use crate::decoded::Decoded;
+use crate::diagnostics::{Diagnostic, DiagnosticFormat};
+use crate::error::{Error, Result};
+use crate::locals::Locals;
 use crate::model::Model;
 
+/// How to count columns in the positions we report. tree-sitter counts bytes,
+/// but editors often count characters, and the language server protocol
+/// counts UTF-16 code units.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColumnUnit {
+    #[default]
+    Byte,
+    Char,
+    Utf16,
+}
+
+impl FromStr for ColumnUnit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "byte" => Ok(ColumnUnit::Byte),
+            "char" => Ok(ColumnUnit::Char),
+            "utf16" => Ok(ColumnUnit::Utf16),
+            _ => anyhow::bail!("unknown column unit {}. Try byte, char, or utf16.", s),
+        }
+    }
+}
+
+impl ColumnUnit {
+    /// Convert the byte column of `point` (which is at `byte` in `source`)
+    /// into this unit. If `source` was decoded from another encoding, byte
+    /// columns count the original file's bytes.
+    fn column(self, source: &[u8], decoded: Option<&Decoded>, point: Point, byte: usize) -> usize {
+        let before = &source[byte - point.column..byte];
+
+        match self {
+            ColumnUnit::Byte => match decoded {
+                Some(decoded) => {
+                    decoded.original_byte(byte) - decoded.original_byte(byte - point.column)
+                }
+                None => point.column,
+            },
+            ColumnUnit::Char => String::from_utf8_lossy(before).chars().count(),
+            ColumnUnit::Utf16 => String::from_utf8_lossy(before).encode_utf16().count(),
+        }
+    }
+
+    pub(crate) fn point(
+        self,
+        source: &[u8],
+        decoded: Option<&Decoded>,
+        point: Point,
+        byte: usize,
+    ) -> Point {
+        Point {
+            row: point.row,
+            column: self.column(source, decoded, point, byte),
+        }
+    }
+}
+
+/// The chunkers for the models an extractor chunks matches for.
+#[derive(Debug)]
+pub(crate) enum Chunkers {
+    One(Box<Chunker>),
+    PerModel(Vec<(String, Chunker)>),
+}
+
+impl Chunkers {
+    pub(crate) fn for_model(model: Model) -> Result<Chunkers> {
+        Ok(Chunkers::One(Box::new(Chunker::from_model(model)?)))
+    }
+
+    pub(crate) fn for_models(models: Vec<(String, Model)>) -> Result<Chunkers> {
+        let chunkers = models
+            .into_iter()
+            .map(|(name, model)| Ok((name, Chunker::from_model(model)?)))
+            .collect::<Result<_>>()?;
+
+        Ok(Chunkers::PerModel(chunkers))
+    }
+
+    /// Chunk text that didn't come from a tree. See `Chunker::chunk_text`.
+    pub(crate) fn chunk_text(
+        &self,
+        source: &[u8],
+        start_byte: usize,
+        start: Point,
+    ) -> Result<Chunks> {
+        match self {
+            Chunkers::One(chunker) => Ok(Chunks::One(
+                chunker.chunk_text_at(source, start_byte, start)?,
+            )),
+            Chunkers::PerModel(chunkers) => Ok(Chunks::PerModel(
+                chunkers
+                    .iter()
+                    .map(|(model, chunker)| {
+                        Ok((
+                            model.clone(),
+                            chunker.chunk_text_at(source, start_byte, start)?,
+                        ))
+                    })
+                    .collect::<Result<_>>()?,
+            )),
+        }
+    }
+
+    fn chunk_source(
+        &self,
+        source: &[u8],
+        start_byte: usize,
+        start: Point,
+        node: Node,
+    ) -> Result<Chunks> {
+        match self {
+            Chunkers::One(chunker) => Ok(Chunks::One(
+                chunker.chunk_source(source, start_byte, start, node)?,
+            )),
+            Chunkers::PerModel(chunkers) => Ok(Chunks::PerModel(
+                chunkers
+                    .iter()
+                    .map(|(model, chunker)| {
+                        Ok((
+                            model.clone(),
+                            chunker.chunk_source(source, start_byte, start, node)?,
+                        ))
+                    })
+                    .collect::<Result<_>>()?,
+            )),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Extractor {
     language: Language,
     ts_language: tree_sitter::Language,
     query: Query,
-    chunker: Chunker,
+    chunkers: Chunkers,
     captures: Vec<String>,
     ignores: HashSet<usize>,
+    cancellation: Option<CancellationToken>,
+    coalesce_captures: bool,
+    dedup: bool,
+    sexp_limit: Option<usize>,
+    pattern_index: bool,
+    skip_errors: bool,
+    leading_comments: bool,
+    expand_to: Option<String>,
+    text_match: Option<Regex>,
+    text_not_match: Option<Regex>,
+    min_lines: usize,
+    min_bytes: usize,
+    merge_gap: Option<usize>,
+    files_with_parse_errors: AtomicUsize,
+    /// How many matches each capture (by index, like `captures`) has had.
+    capture_hits: Vec<AtomicUsize>,
+    column_unit: ColumnUnit,
+    encoding: Option<&'static Encoding>,
+    path_separator: Option<char>,
+    diagnostic_format: DiagnosticFormat,
+    trees: Option<TreeCache>,
+    /// Only set when some pattern uses `#is? local` or `#is-not? local`,
+    /// since working out scopes means running a second query over each file.
+    locals: Option<Locals>,
 }
 
 impl Extractor {
-    pub fn new(language: Language, query: Query, model: Model) -> Extractor {
+    pub fn new(language: Language, query: Query, model: Model) -> Result<Extractor> {
+        Self::with_chunkers(language, query, Chunkers::for_model(model)?)
+    }
+
+    /// Like `new`, but chunk each match for every one of `models` so they
+    /// can be compared. Matches get a list of chunks for each model, keyed by
+    /// the name it's given here.
+    pub fn with_models(
+        language: Language,
+        query: Query,
+        models: Vec<(String, Model)>,
+    ) -> Result<Extractor> {
+        Self::with_chunkers(language, query, Chunkers::for_models(models)?)
+    }
+
+    fn with_chunkers(language: Language, query: Query, chunkers: Chunkers) -> Result<Extractor> {
         let captures = query.capture_names().to_vec();
 
         let mut ignores = HashSet::default();
@@ -32,30 +213,292 @@ impl Extractor {
             }
         });
 
+        let capture_hits = captures.iter().map(|_| AtomicUsize::new(0)).collect();
+
         if captures.len() == ignores.len() {
-            eprintln!("Warning: query only has ignored captures. No results will be printed.");
+            tracing::warn!("query only has ignored captures. No results will be printed.");
         }
 
-        Extractor {
+        let uses_locals = (0..query.pattern_count()).any(|pattern| {
+            query
+                .property_predicates(pattern)
+                .iter()
+                .any(|(property, _)| &*property.key == "local")
+        });
+        let locals = if uses_locals {
+            Some(Locals::new(language)?)
+        } else {
+            None
+        };
+
+        Ok(Extractor {
             ts_language: language.language(),
-            chunker: Chunker::from_model(model),
+            chunkers,
             language,
             query,
             captures,
             ignores,
-        }
+            cancellation: None,
+            coalesce_captures: false,
+            dedup: true,
+            sexp_limit: None,
+            pattern_index: false,
+            skip_errors: false,
+            leading_comments: false,
+            expand_to: None,
+            text_match: None,
+            text_not_match: None,
+            min_lines: 0,
+            min_bytes: 0,
+            merge_gap: None,
+            files_with_parse_errors: AtomicUsize::new(0),
+            capture_hits,
+            column_unit: ColumnUnit::default(),
+            encoding: None,
+            path_separator: None,
+            diagnostic_format: DiagnosticFormat::default(),
+            trees: None,
+            locals,
+        })
     }
 
     pub fn language(&self) -> &Language {
         &self.language
     }
 
+    /// Stop extracting once `token` is cancelled. Files that haven't been
+    /// parsed yet (or are in the middle of parsing) come back as `Ok(None)`,
+    /// so anything extracted before cancellation is still usable.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation = Some(token);
+    }
+
+    /// When a quantified capture like `(comment)* @docs` binds several nodes
+    /// in one match, emit a single result spanning all of them instead of one
+    /// result per node.
+    pub fn set_coalesce_captures(&mut self, coalesce_captures: bool) {
+        self.coalesce_captures = coalesce_captures;
+    }
+
+    /// Merge matches in the same file that overlap or have at most `gap` lines
+    /// between them into one match covering all of them, so a run of
+    /// statements becomes one block. With a gap of 0, matches on consecutive
+    /// lines merge. Merged matches keep the first match's capture name and
+    /// list all of them in `names`. `None` (the default) leaves matches alone.
+    pub fn set_merge_adjacent(&mut self, gap: Option<usize>) {
+        self.merge_gap = gap;
+    }
+
+    /// Queries for the same language get combined into one, so when two of
+    /// them match the same node with the same capture name we'd report it
+    /// twice. By default we only keep the first; turn this off to get every
+    /// match tree-sitter reports.
+    pub fn set_dedup(&mut self, dedup: bool) {
+        self.dedup = dedup;
+    }
+
+    /// Include the S-expression of each matched node in the results, cut
+    /// off with an ellipsis after `max_chars` characters. This is mostly
+    /// useful for figuring out why a query matched what it did.
+    pub fn set_sexp_limit(&mut self, max_chars: Option<usize>) {
+        self.sexp_limit = max_chars;
+    }
+
+    /// Include the index of the pattern that matched in the results. Queries
+    /// for the same language are joined together in the order they were
+    /// given, so this can be used to tell which of them a match came from.
+    pub fn set_pattern_index(&mut self, pattern_index: bool) {
+        self.pattern_index = pattern_index;
+    }
+
+    /// tree-sitter gives us a tree even for files with syntax errors, marking
+    /// the parts it couldn't make sense of with ERROR nodes. Queries can still
+    /// match inside those; turn this on to drop those matches.
+    pub fn set_skip_errors(&mut self, skip_errors: bool) {
+        self.skip_errors = skip_errors;
+    }
+
+    /// Stretch each match back over the comments right before it (like a
+    /// function's doc comment), so they're part of its text and chunks. Only
+    /// comments with no blank line between them and the match count, and a
+    /// comment at the end of another line of code belongs to that code.
+    pub fn set_leading_comments(&mut self, leading_comments: bool) {
+        self.leading_comments = leading_comments;
+    }
+
+    /// Extract the closest node of `kind` around each match (the match
+    /// itself, if it's already one) instead of the match, like the function
+    /// a `TODO` comment is in. Matches with nothing of that kind around them
+    /// are dropped, and a node around several matches is only extracted
+    /// once.
+    pub fn set_expand_to(&mut self, kind: Option<String>) {
+        self.expand_to = kind;
+    }
+
+    /// Only keep matches whose text matches `regex`, for textual constraints
+    /// that are awkward to write in a query. Matches are checked before
+    /// they're chunked, so this saves tokenizing the ones we drop.
+    pub fn set_text_match(&mut self, regex: Regex) {
+        self.text_match = Some(regex);
+    }
+
+    /// Drop matches whose text matches `regex`.
+    pub fn set_text_not_match(&mut self, regex: Regex) {
+        self.text_not_match = Some(regex);
+    }
+
+    /// Drop matches that span fewer than `min_lines` lines, counting the
+    /// lines they start and end on. Like `set_text_match`, this happens
+    /// before chunking.
+    pub fn set_min_lines(&mut self, min_lines: usize) {
+        self.min_lines = min_lines;
+    }
+
+    /// Drop matches shorter than `min_bytes` bytes.
+    pub fn set_min_bytes(&mut self, min_bytes: usize) {
+        self.min_bytes = min_bytes;
+    }
+
+    /// Keep the last tree for each file we extract from, and re-parse only
+    /// the parts that changed the next time we see that file. This speeds up
+    /// searching the same files over and over (like in watch mode) at the
+    /// cost of holding on to files' sources and trees, up to
+    /// `TREE_CACHE_BYTES` of source.
+    pub fn set_incremental(&mut self, incremental: bool) {
+        self.trees = if incremental {
+            Some(TreeCache::default())
+        } else {
+            None
+        };
+    }
+
+    /// Forget the files with parse errors and capture matches counted so
+    /// far, so the next search (like each one in watch mode) counts from
+    /// zero.
+    pub fn reset_counts(&self) {
+        self.files_with_parse_errors.store(0, Ordering::Relaxed);
+        for hits in &self.capture_hits {
+            hits.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// How many files this extractor has parsed that had syntax errors in
+    /// them, whether or not anything in them matched.
+    pub fn files_with_parse_errors(&self) -> usize {
+        self.files_with_parse_errors.load(Ordering::Relaxed)
+    }
+
+    /// The captures that haven't matched anything in any file this extractor
+    /// has searched, in the order they're in the query. Ignored captures
+    /// (starting with `_`) are left out, since they never match on their own.
+    pub fn unmatched_captures(&self) -> Vec<&str> {
+        self.captures
+            .iter()
+            .zip(&self.capture_hits)
+            .enumerate()
+            .filter(|(index, (_, hits))| {
+                !self.ignores.contains(index) && hits.load(Ordering::Relaxed) == 0
+            })
+            .map(|(_, (name, _))| name.as_str())
+            .collect()
+    }
+
+    /// How to count the columns of the positions in results. See
+    /// `ColumnUnit`.
+    pub fn set_column_unit(&mut self, column_unit: ColumnUnit) {
+        self.column_unit = column_unit;
+    }
+
+    /// Decode sources from `encoding` before parsing them, instead of taking
+    /// them to be UTF-8. Results have the decoded (UTF-8) text, but byte
+    /// offsets and byte columns point into the original source.
+    pub fn set_encoding(&mut self, encoding: &'static Encoding) {
+        self.encoding = Some(encoding).filter(|encoding| *encoding != UTF_8);
+    }
+
+    /// Show file paths with `separator` between their components instead of
+    /// the platform's own (like `/` on Windows, for tools that expect it.)
+    /// This applies to every output format, since they all get the path from
+    /// the `ExtractedFile`.
+    pub fn set_path_separator(&mut self, separator: char) {
+        self.path_separator = Some(separator).filter(|separator| *separator != MAIN_SEPARATOR);
+    }
+
+    /// How to print warnings about matches we had to leave out. See
+    /// `DiagnosticFormat`.
+    pub fn set_diagnostic_format(&mut self, diagnostic_format: DiagnosticFormat) {
+        self.diagnostic_format = diagnostic_format;
+    }
+
+    /// How to split matches that are too long for the model. See
+    /// `ChunkStrategy`.
+    pub fn set_chunk_strategy(&mut self, strategy: ChunkStrategy) {
+        match &mut self.chunkers {
+            Chunkers::One(chunker) => chunker.set_strategy(strategy),
+            Chunkers::PerModel(chunkers) => {
+                for (_, chunker) in chunkers {
+                    chunker.set_strategy(strategy);
+                }
+            }
+        }
+    }
+
+    /// Include each chunk's ids decoded back into text in its `text`. See
+    /// `Chunker::set_decode`.
+    pub fn set_decode_chunks(&mut self, decode: bool) {
+        match &mut self.chunkers {
+            Chunkers::One(chunker) => chunker.set_decode(decode),
+            Chunkers::PerModel(chunkers) => {
+                for (_, chunker) in chunkers {
+                    chunker.set_decode(decode);
+                }
+            }
+        }
+    }
+
+    /// Count how many tokens each chunk shares with its neighbors. See
+    /// `Chunker::set_overlaps`.
+    pub fn set_chunk_overlaps(&mut self, overlaps: bool) {
+        match &mut self.chunkers {
+            Chunkers::One(chunker) => chunker.set_overlaps(overlaps),
+            Chunkers::PerModel(chunkers) => {
+                for (_, chunker) in chunkers {
+                    chunker.set_overlaps(overlaps);
+                }
+            }
+        }
+    }
+
+    /// How far back to look for a boundary to end each chunk at. See
+    /// `Chunker::set_lookbehind`.
+    pub fn set_chunk_lookbehind(&mut self, lookbehind: Option<usize>) {
+        match &mut self.chunkers {
+            Chunkers::One(chunker) => chunker.set_lookbehind(lookbehind),
+            Chunkers::PerModel(chunkers) => {
+                for (_, chunker) in chunkers {
+                    chunker.set_lookbehind(lookbehind);
+                }
+            }
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .map(|token| token.is_cancelled())
+            .unwrap_or(false)
+    }
+
     pub fn extract_from_file(
         &self,
         path: &Path,
         parser: &mut Parser,
-    ) -> Result<Option<ExtractedFile>> {
-        let source = fs::read(path).context("could not read file")?;
+    ) -> Result<Option<ExtractedFile<'_>>> {
+        if self.is_cancelled() {
+            return Ok(None);
+        }
+
+        let source = fs::read(path)?;
 
         self.extract_from_text(Some(path), &source, parser)
     }
@@ -65,191 +508,1875 @@ impl Extractor {
         path: Option<&Path>,
         source: &[u8],
         parser: &mut Parser,
-    ) -> Result<Option<ExtractedFile>> {
-        parser
-            .set_language(self.ts_language)
-            .context("could not set language")?;
-
-        let tree = parser
-            .parse(source, None)
-            // note: this could be a timeout or cancellation, but we don't set
-            // that so we know it's always a language error. Buuuut we also
-            // always set the language above so if this happens we also know
-            // it's an internal error.
-            .context(
-                "could not parse to a tree. This is an internal error and should be reported.",
-            )?;
+    ) -> Result<Option<ExtractedFile<'_>>> {
+        if self.is_cancelled() {
+            return Ok(None);
+        }
+
+        let decoded = self.encoding.map(|encoding| Decoded::new(source, encoding));
+        let source = decoded
+            .as_ref()
+            .map_or(source, |decoded| decoded.text().as_bytes());
+        let original_byte = |byte| {
+            decoded
+                .as_ref()
+                .map_or(byte, |decoded| decoded.original_byte(byte))
+        };
+
+        // setting the language resets the parser, so skip it when the last
+        // file this parser saw was in the same language.
+        if parser.language() != Some(self.ts_language) {
+            parser
+                .set_language(self.ts_language)
+                .map_err(|err| Error::UnsupportedLanguage(self.language, err))?;
+        }
+
+        let tree = match self.parse(path, source, parser) {
+            Some(tree) => tree,
+            // if we don't reset in these two cases, the next call to `parse`
+            // will try to resume this parse with a different file's source!
+            None if self.is_cancelled() => {
+                parser.reset();
+                return Ok(None);
+            }
+            None if parser.timeout_micros() > 0 => {
+                parser.reset();
+                return Err(Error::ParseTimeout);
+            }
+            // note: we always set the language above so if this happens we
+            // know it's an internal error.
+            None => return Err(Error::Parse),
+        };
+
+        if tree.root_node().has_error() {
+            self.files_with_parse_errors.fetch_add(1, Ordering::Relaxed);
+        }
 
         let mut cursor = QueryCursor::new();
+        let mut seen: HashSet<(Range<usize>, Option<u32>)> = HashSet::new();
+
+        let local_nodes = self
+            .locals
+            .as_ref()
+            .map(|locals| locals.local_nodes(tree.root_node(), source));
 
-        let extracted_matches = cursor
+        let mut spans = cursor
             .matches(&self.query, tree.root_node(), source)
-            .flat_map(|query_match| query_match.captures)
+            .filter(|query_match| {
+                local_nodes
+                    .as_ref()
+                    .is_none_or(|local_nodes| self.locals_allow(query_match, local_nodes))
+            })
+            .flat_map(|query_match| self.spans(query_match.pattern_index, query_match.captures))
             // note: the casts here could potentially break if run on a 16-bit
             // microcontroller. I don't think this is a huge problem, though,
             // since even the gnarliest queries I've written have something on
             // the order of 20 matches. Nowhere close to 2^16!
-            .filter(|capture| !self.ignores.contains(&(capture.index as usize)))
-            .filter_map(|capture| {
-                let name = &self.captures[capture.index as usize];
-                let node = capture.node;
-                let node_source = &source[node.byte_range()];
+            .filter(|span| !self.ignores.contains(&(span.index as usize)))
+            // capture indexes are per-name, so this is the same as checking
+            // the name. Expanded matches are the same node whatever captured
+            // them, though.
+            .filter(|span| {
+                let index = if self.expand_to.is_some() {
+                    None
+                } else {
+                    Some(span.index)
+                };
+                !self.dedup || seen.insert((span.byte_range.clone(), index))
+            })
+            .collect::<Vec<Span<'_>>>();
+        if let Some(gap) = self.merge_gap {
+            spans = merge_adjacent(spans, gap);
+        }
+
+        let mut extracted_matches = spans
+            .into_iter()
+            // merged matches are only as small as all of them together
+            .filter(|span| {
+                span.end.row - span.start.row + 1 >= self.min_lines
+                    && span.byte_range.len() >= self.min_bytes
+            })
+            .filter_map(|span| {
+                let name = &self.captures[span.index as usize];
+                let span_source = &source[span.byte_range.clone()];
+                let span_node = span.node(&tree);
+                if self.skip_errors && in_error(span_node) {
+                    return None;
+                }
+                if !keeps_text(
+                    span_source,
+                    self.text_match.as_ref(),
+                    self.text_not_match.as_ref(),
+                ) {
+                    return None;
+                }
 
-                let chunks = match self.chunker.chunk_node(node_source, &node) {
+                let mut chunks = match self.chunkers.chunk_source(
+                    span_source,
+                    span.byte_range.start,
+                    span.start,
+                    span_node,
+                ) {
                     Ok(chunks) => chunks,
                     Err(e) => {
-                        eprintln!(
-                            "warning: tokenization for {} failed: {}",
-                            path.map(|p| p.to_path_buf().into_os_string().into_string().unwrap())
-                                .unwrap_or(String::from("stdin")),
-                            e
+                        tracing::debug!(
+                            path = path.map(|path| tracing::field::display(path.display())),
+                            capture = name.as_str(),
+                            bytes = ?span.byte_range,
+                            error = %e,
+                            "tokenization failed"
                         );
+                        self.diagnostic_format.report(Diagnostic {
+                            file: path,
+                            code: "tokenization-failed",
+                            message: format!(
+                                "tokenization for {} failed: {}",
+                                path.map(|p| p.to_string_lossy())
+                                    .unwrap_or(Cow::Borrowed(NO_FILE)),
+                                e
+                            ),
+                        });
                         return None;
                     }
                 };
 
+                chunks.map_bytes(original_byte);
+
                 // we already check for valid utf-8 in chunker
-                let text = std::str::from_utf8(node_source).unwrap().to_string();
+                let text = std::str::from_utf8(span_source).unwrap().to_string();
+
+                let indexes = if span.merged.is_empty() {
+                    std::slice::from_ref(&span.index)
+                } else {
+                    &span.merged
+                };
+                for index in indexes {
+                    self.capture_hits[*index as usize].fetch_add(1, Ordering::Relaxed);
+                }
 
                 Some(ExtractedMatch {
-                    kind: node.kind(),
+                    kind: span.kind,
                     name,
+                    names: span
+                        .merged
+                        .iter()
+                        .map(|index| self.captures[*index as usize].as_str())
+                        .collect(),
                     text,
-                    start: node.start_position(),
-                    end: node.end_position(),
+                    start: self.column_unit.point(
+                        source,
+                        decoded.as_ref(),
+                        span.start,
+                        span.byte_range.start,
+                    ),
+                    end: self.column_unit.point(
+                        source,
+                        decoded.as_ref(),
+                        span.end,
+                        span.byte_range.end,
+                    ),
+                    start_byte: original_byte(span.byte_range.start),
+                    end_byte: original_byte(span.byte_range.end),
+                    pattern: Some(span.pattern).filter(|_| self.pattern_index),
+                    metadata: self.metadata(&span),
+                    scope: self.scope(span_node, &span.byte_range, source),
                     chunks,
+                    sexp: self
+                        .sexp_limit
+                        .map(|max_chars| truncate(span_node.to_sexp(), max_chars)),
                 })
             })
             .collect::<Vec<ExtractedMatch>>();
+        // the cursor hands back captures in match order, which isn't always
+        // source order when several patterns (or nested ones) match.
+        extracted_matches.sort();
+        tracing::trace!(
+            path = path.map(|path| tracing::field::display(path.display())),
+            language = %self.language,
+            matches = extracted_matches.len(),
+            "extracted matches"
+        );
 
         if extracted_matches.is_empty() {
             Ok(None)
         } else {
             Ok(Some(ExtractedFile {
-                file: path.map(|p| p.to_owned()),
+                file: path.map(|p| with_separator(p, self.path_separator)),
                 file_type: self.language.to_string(),
+                language_version: self.ts_language.version(),
                 matches: extracted_matches,
             }))
         }
     }
-}
 
-#[derive(Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
-pub struct ExtractedFile<'query> {
-    file: Option<PathBuf>,
-    file_type: String,
-    matches: Vec<ExtractedMatch<'query>>,
-}
+    /// Whether `query_match` passes its pattern's `#is? local` and `#is-not?
+    /// local` predicates. A predicate naming a capture (like `#is? @id local`)
+    /// checks that capture's nodes; otherwise it checks all of them.
+    fn locals_allow(&self, query_match: &QueryMatch, local_nodes: &HashSet<usize>) -> bool {
+        self.query
+            .property_predicates(query_match.pattern_index)
+            .iter()
+            .filter(|(property, _)| &*property.key == "local")
+            .all(|(property, is_positive)| {
+                query_match
+                    .captures
+                    .iter()
+                    .filter(|capture| {
+                        property
+                            .capture_id
+                            .is_none_or(|id| capture.index as usize == id)
+                    })
+                    .all(|capture| local_nodes.contains(&capture.node.id()) == *is_positive)
+            })
+    }
 
-impl<'query> Display for ExtractedFile<'query> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // TODO: is there a better way to do this unwrapping? This implementation
-        // turns non-UTF-8 paths into "NON-UTF8 FILENAME". I don't know exactly
-        // what circumstances that could happen in... maybe we should just wait
-        // for bug reports?
-        let filename = self
-            .file
-            .as_ref()
-            .map(|f| f.to_str().unwrap_or("NON-UTF8 FILENAME"))
-            .unwrap_or("NO FILE");
+    fn spans<'tree>(&self, pattern: usize, captures: &[QueryCapture<'tree>]) -> Vec<Span<'tree>> {
+        let mut spans: Vec<Span<'tree>> = Vec::with_capacity(captures.len());
+
+        for capture in captures {
+            let node = match &self.expand_to {
+                Some(kind) => match std::iter::once(capture.node)
+                    .chain(ancestors(capture.node))
+                    .find(|node| node.kind() == kind)
+                {
+                    Some(node) => node,
+                    None => continue,
+                },
+                None => capture.node,
+            };
+
+            if self.coalesce_captures {
+                if let Some(span) = spans.iter_mut().find(|span| span.index == capture.index) {
+                    span.extend(&node);
+                    continue;
+                }
+            }
 
-        for extraction in &self.matches {
-            writeln!(
-                f,
-                "{}:{}:{}:{}:{}",
-                filename,
-                extraction.start.row + 1,
-                extraction.start.column + 1,
-                extraction.name,
-                extraction.text
-            )?
+            let mut span = Span::from_node(pattern, capture.index, node);
+            if self.leading_comments {
+                if let Some(comment) = self.first_leading_comment(node) {
+                    span.extend(&comment);
+                }
+            }
+            spans.push(span);
         }
 
-        Ok(())
+        spans
+    }
+
+    /// The first of the comments right before `node`, if there are any. See
+    /// `set_leading_comments`.
+    fn first_leading_comment<'tree>(&self, node: Node<'tree>) -> Option<Node<'tree>> {
+        let comment_kinds = self.language.comment_kinds();
+
+        let mut first = None;
+        let mut current = node;
+        while let Some(prev) = current.prev_sibling() {
+            let trails_code = prev
+                .prev_sibling()
+                .is_some_and(|before| before.end_position().row == prev.start_position().row);
+            if !comment_kinds.contains(&prev.kind())
+                || prev.end_position().row + 1 < current.start_position().row
+                || trails_code
+            {
+                break;
+            }
+
+            first = Some(prev);
+            current = prev;
+        }
+
+        first
+    }
+
+    /// What the `#set!` directives in `span`'s pattern say about it. Ones
+    /// aimed at a capture (like `(#set! @fn severity warning)`) only apply
+    /// to that capture.
+    fn metadata(&self, span: &Span<'_>) -> BTreeMap<&str, Option<&str>> {
+        self.query
+            .property_settings(span.pattern)
+            .iter()
+            .filter(|property| {
+                property.capture_id.is_none() || property.capture_id == Some(span.index as usize)
+            })
+            .map(|property| (&*property.key, property.value.as_deref()))
+            .collect()
+    }
+
+    /// The name of the closest declaration (from the language's
+    /// `scope_kinds`) around `node`. Captured declarations (and their names)
+    /// report the scope they're in, not themselves.
+    fn scope(&self, node: Node, byte_range: &Range<usize>, source: &[u8]) -> Option<String> {
+        let scope_kinds = self.language.scope_kinds();
+        if scope_kinds.is_empty() {
+            return None;
+        }
+
+        for ancestor in ancestors(node) {
+            if ancestor.byte_range() == *byte_range {
+                continue;
+            }
+
+            let field = match scope_kinds
+                .iter()
+                .find(|(kind, _)| *kind == ancestor.kind())
+            {
+                Some((_, field)) => field,
+                None => continue,
+            };
+            let mut name = match ancestor.child_by_field_name(field) {
+                Some(name) => name,
+                None => continue,
+            };
+            while let Some(inner) = name.child_by_field_name("declarator") {
+                name = inner;
+            }
+            // the match is this declaration's name, so it isn't inside it
+            if name.byte_range() == *byte_range {
+                continue;
+            }
+
+            return std::str::from_utf8(&source[name.byte_range()])
+                .ok()
+                .map(str::to_string);
+        }
+
+        None
+    }
+
+    fn parse(&self, path: Option<&Path>, source: &[u8], parser: &mut Parser) -> Option<Tree> {
+        // without a path we can't tell whether we've seen this source before,
+        // so those always get a full parse.
+        let cached = match (&self.trees, path) {
+            (Some(trees), Some(path)) => Some((trees, path)),
+            _ => None,
+        };
+        let old_tree = cached.and_then(|(trees, path)| trees.take(path, source));
+
+        let tree = match &self.cancellation {
+            Some(token) => {
+                // safety: the flag lives as long as `token`, which we borrow
+                // from `self` for the whole parse, and we take it back out of
+                // the parser before returning so it never outlives the borrow.
+                unsafe { parser.set_cancellation_flag(Some(token.flag())) };
+                let tree = parser.parse(source, old_tree.as_ref());
+                unsafe { parser.set_cancellation_flag(None) };
+
+                tree
+            }
+            None => parser.parse(source, old_tree.as_ref()),
+        };
+
+        if let (Some((trees, path)), Some(tree)) = (cached, &tree) {
+            trees.insert(path, source, tree.clone());
+        }
+
+        tree
     }
 }
 
-#[derive(Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
-pub struct ExtractedMatch<'query> {
-    kind: &'static str,
-    name: &'query str,
-    text: String,
-    #[serde(serialize_with = "serialize_point")]
-    start: Point,
-    #[serde(serialize_with = "serialize_point")]
-    end: Point,
-    chunks: Vec<ExtractedChunk>,
+/// How much source an incremental extractor keeps trees for. Trees take a
+/// few times as much memory as their source, so this keeps the cache to a
+/// few hundred MB at most while still covering most projects.
+const TREE_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+/// The last source and tree for the files an incremental extractor has seen
+/// most recently, up to `budget` bytes of source. The ones used longest ago
+/// are dropped first to make room.
+struct TreeCache {
+    budget: usize,
+    files: Mutex<CachedTrees>,
 }
 
-fn serialize_point<S>(point: &Point, sz: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    let mut out = sz.serialize_struct("Point", 2)?;
-    out.serialize_field("row", &(point.row + 1))?;
-    out.serialize_field("column", &(point.column + 1))?;
-    out.end()
+#[derive(Default)]
+struct CachedTrees {
+    /// Each file's source and tree, and when it was last used.
+    trees: HashMap<PathBuf, (Vec<u8>, Tree, u64)>,
+    bytes: usize,
+    clock: u64,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crossgrep_sys::Language;
-    use tree_sitter::Parser;
+impl Default for TreeCache {
+    fn default() -> Self {
+        TreeCache::with_budget(TREE_CACHE_BYTES)
+    }
+}
 
-    #[test]
-    fn test_matches_are_extracted() {
-        let lang = Language::Elm;
-        let query = lang
-            .parse_query("(import_clause (upper_case_qid)@import)")
-            .unwrap();
-        let extractor = Extractor::new(lang, query, Model::Noop);
+impl TreeCache {
+    fn with_budget(budget: usize) -> Self {
+        TreeCache {
+            budget,
+            files: Mutex::default(),
+        }
+    }
 
-        let extracted = extractor
-            .extract_from_text(None, b"import Html.Styled", &mut Parser::new())
-            // From Result<Option<ExtractedFile>>
-            .unwrap()
-            // From Option<ExtractedFile>
-            .unwrap();
+    /// Take the tree we have for `path`, edited to line up with `source` so
+    /// tree-sitter can re-use the parts that didn't change.
+    fn take(&self, path: &Path, source: &[u8]) -> Option<Tree> {
+        let mut files = self.files.lock().ok()?;
+        let (old_source, mut tree, _) = files.trees.remove(path)?;
+        files.bytes -= old_source.len();
+        tree.edit(&input_edit(&old_source, source));
 
-        assert_eq!(extracted.matches.len(), 1);
-        assert_eq!(extracted.matches[0].name, "import");
-        assert_eq!(extracted.matches[0].text, "Html.Styled");
+        Some(tree)
     }
 
-    #[test]
-    fn test_underscore_names_are_ignored() {
-        let lang = Language::Elm;
-        let query = lang
-            .parse_query("(import_clause (upper_case_qid)@_import)")
-            .unwrap();
-        let extractor = Extractor::new(lang, query, Model::Noop);
+    fn insert(&self, path: &Path, source: &[u8], tree: Tree) {
+        if source.len() > self.budget {
+            return;
+        }
+        let mut files = match self.files.lock() {
+            Ok(files) => files,
+            Err(_) => return,
+        };
 
-        let extracted = extractor
-            .extract_from_text(None, b"import Html.Styled", &mut Parser::new())
-            // From Result<Option<ExtractedFile>>
-            .unwrap();
+        files.clock += 1;
+        let clock = files.clock;
+        files.bytes += source.len();
+        if let Some((old_source, _, _)) = files
+            .trees
+            .insert(path.to_owned(), (source.to_vec(), tree, clock))
+        {
+            files.bytes -= old_source.len();
+        }
 
-        assert_eq!(extracted, None);
+        while files.bytes > self.budget {
+            let oldest = files
+                .trees
+                .iter()
+                .min_by_key(|(_, (_, _, used))| *used)
+                .map(|(path, _)| path.clone());
+            match oldest.and_then(|oldest| files.trees.remove(&oldest)) {
+                Some((old_source, _, _)) => files.bytes -= old_source.len(),
+                None => break,
+            }
+        }
     }
+}
 
-    #[test]
-    fn test_underscore_names_can_still_be_used_in_matchers() {
-        let lang = Language::JavaScript;
-        let query = lang
-            .parse_query("(call_expression (identifier)@_fn (arguments . (string)@import .) (#eq? @_fn require))")
-            .unwrap();
-        let extractor = Extractor::new(lang, query, Model::Noop);
+impl fmt::Debug for TreeCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (files, bytes) = self
+            .files
+            .lock()
+            .map(|files| (files.trees.len(), files.bytes))
+            .unwrap_or((0, 0));
+        f.debug_struct("TreeCache")
+            .field("files", &files)
+            .field("bytes", &bytes)
+            .field("budget", &self.budget)
+            .finish()
+    }
+}
 
-        let extracted = extractor
-            .extract_from_text(None, b"let foo = require(\"foo.js\")", &mut Parser::new())
-            // From Result<Option<ExtractedFile>>
-            .unwrap()
-            // From Option<ExtractedFile>
-            .unwrap();
+/// File changes don't tell us what was edited, so we take everything between
+/// the longest common prefix and suffix of the old and new source as one
+/// edit. That's exact for the typical single change between saves, and still
+/// correct (just less of a speedup) when a save changes several places.
+fn input_edit(old: &[u8], new: &[u8]) -> InputEdit {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
 
-        assert_eq!(extracted.matches.len(), 1);
-        assert_eq!(extracted.matches[0].name, "import");
-        assert_eq!(extracted.matches[0].text, "\"foo.js\"");
+    InputEdit {
+        start_byte: prefix,
+        old_end_byte: old.len() - suffix,
+        new_end_byte: new.len() - suffix,
+        start_position: point_at(old, prefix),
+        old_end_position: point_at(old, old.len() - suffix),
+        new_end_position: point_at(new, new.len() - suffix),
+    }
+}
+
+/// Does `text` pass the text filters (see `Extractor::set_text_match`)? Text
+/// that isn't UTF-8 can't be checked, so we let it through and leave the
+/// complaining to the chunker.
+pub(crate) fn keeps_text(
+    text: &[u8],
+    text_match: Option<&Regex>,
+    text_not_match: Option<&Regex>,
+) -> bool {
+    match std::str::from_utf8(text) {
+        Ok(text) => {
+            text_match.iter().all(|regex| regex.is_match(text))
+                && !text_not_match.iter().any(|regex| regex.is_match(text))
+        }
+        Err(_) => true,
+    }
+}
+
+fn point_at(source: &[u8], byte: usize) -> Point {
+    let before = &source[..byte];
+    let line_start = before
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |newline| newline + 1);
+
+    Point {
+        row: before.iter().filter(|&&b| b == b'\n').count(),
+        column: byte - line_start,
+    }
+}
+
+/// Merge spans that overlap or have at most `gap` lines between them. See
+/// `Extractor::set_merge_adjacent`.
+fn merge_adjacent(mut spans: Vec<Span<'_>>, gap: usize) -> Vec<Span<'_>> {
+    spans.sort_by_key(|span| (span.byte_range.start, span.byte_range.end));
+
+    let mut merged: Vec<Span<'_>> = Vec::with_capacity(spans.len());
+    for span in spans {
+        match merged.last_mut() {
+            Some(last) if span.start.row <= last.end.row + gap + 1 => last.merge(span),
+            _ => merged.push(span),
+        }
+    }
+
+    merged
+}
+
+/// The part of the source a capture covers. Usually this is a single node, but
+/// when coalescing quantified captures it can stretch across several.
+struct Span<'tree> {
+    pattern: usize,
+    index: u32,
+    kind: &'static str,
+    /// The node that was captured, unless the span has been stretched to
+    /// cover more than it.
+    node: Option<Node<'tree>>,
+    byte_range: Range<usize>,
+    start: Point,
+    end: Point,
+    /// The capture indexes of every span merged into this one, in order.
+    /// Empty unless it's been merged with another.
+    merged: Vec<u32>,
+}
+
+impl<'tree> Span<'tree> {
+    fn from_node(pattern: usize, index: u32, node: Node<'tree>) -> Span<'tree> {
+        Span {
+            pattern,
+            index,
+            kind: node.kind(),
+            node: Some(node),
+            byte_range: node.byte_range(),
+            start: node.start_position(),
+            end: node.end_position(),
+            merged: Vec::new(),
+        }
+    }
+
+    /// The node the span covers. That's the captured node unless the span
+    /// covers several, in which case it's the smallest one around all of
+    /// them.
+    fn node(&self, tree: &'tree Tree) -> Node<'tree> {
+        self.node.unwrap_or_else(|| {
+            tree.root_node()
+                .descendant_for_byte_range(self.byte_range.start, self.byte_range.end)
+                .unwrap_or_else(|| tree.root_node())
+        })
+    }
+
+    fn merge(&mut self, other: Span<'tree>) {
+        if self.merged.is_empty() {
+            self.merged.push(self.index);
+        }
+        self.merged.push(other.index);
+        self.node = None;
+
+        if other.byte_range.end > self.byte_range.end {
+            self.byte_range.end = other.byte_range.end;
+            self.end = other.end;
+        }
+    }
+
+    fn extend(&mut self, node: &Node) {
+        if node.start_byte() < self.byte_range.start {
+            self.byte_range.start = node.start_byte();
+            self.start = node.start_position();
+            self.node = None;
+        }
+
+        if node.end_byte() > self.byte_range.end {
+            self.byte_range.end = node.end_byte();
+            self.end = node.end_position();
+            self.node = None;
+        }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExtractedFile<'query> {
+    file: Option<PathBuf>,
+    file_type: String,
+    /// The ABI version of the grammar that parsed the file, so anything that
+    /// keeps results around can tell when they came from a different grammar.
+    language_version: usize,
+    matches: Vec<ExtractedMatch<'query>>,
+}
+
+impl<'query> ExtractedFile<'query> {
+    /// Matches found with a regex, which have no grammar (or language) to
+    /// speak of.
+    pub(crate) fn from_regex(file: Option<PathBuf>, matches: Vec<ExtractedMatch<'query>>) -> Self {
+        ExtractedFile {
+            file,
+            file_type: "regex".to_string(),
+            language_version: 0,
+            matches,
+        }
+    }
+
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    pub fn matches(&self) -> &[ExtractedMatch<'query>] {
+        &self.matches
+    }
+
+    /// Add the matches another extractor found in the same file, keeping them
+    /// sorted.
+    pub fn extend(&mut self, other: ExtractedFile<'query>) {
+        self.matches.extend(other.matches);
+        self.matches.sort();
+    }
+
+    /// Throw away the matches `keep` says no to.
+    pub fn retain_matches(&mut self, keep: impl FnMut(&ExtractedMatch<'query>) -> bool) {
+        self.matches.retain(keep);
+    }
+
+    /// Each match paired up with the file it came from, for when matches
+    /// need to stand on their own away from the rest of the file.
+    pub fn file_matches(&self) -> impl Iterator<Item = FileMatch<'_, 'query>> {
+        self.matches.iter().map(move |extracted| FileMatch {
+            file: self.file.as_deref(),
+            file_type: &self.file_type,
+            extracted,
+        })
+    }
+
+    /// Show the matches under a header with the filename (like ripgrep does)
+    /// instead of repeating the filename on every line.
+    pub fn with_heading(&self) -> WithHeading<'_, 'query> {
+        WithHeading(self)
+    }
+}
+
+impl<'query> Display for ExtractedFile<'query> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for file_match in self.file_matches() {
+            Display::fmt(&file_match, f)?;
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// An `ExtractedFile` displayed with a heading. See
+/// `ExtractedFile::with_heading`.
+pub struct WithHeading<'file, 'query>(&'file ExtractedFile<'query>);
+
+impl<'file, 'query> Display for WithHeading<'file, 'query> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", filename(self.0.file.as_deref()))?;
+        for extracted in &self.0.matches {
+            Display::fmt(extracted, f)?;
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A single match along with the file it was found in. This serializes as a
+/// flat record with the file's fields next to the match's.
+#[derive(Debug, Serialize)]
+pub struct FileMatch<'file, 'query> {
+    file: Option<&'file Path>,
+    file_type: &'file str,
+    #[serde(flatten)]
+    extracted: &'file ExtractedMatch<'query>,
+}
+
+impl<'file, 'query> FileMatch<'file, 'query> {
+    pub fn file(&self) -> Option<&'file Path> {
+        self.file
+    }
+
+    /// The file's path as we print it in text output.
+    pub fn filename(&self) -> &'file str {
+        filename(self.file)
+    }
+
+    pub fn file_type(&self) -> &'file str {
+        self.file_type
+    }
+
+    pub fn extracted(&self) -> &'file ExtractedMatch<'query> {
+        self.extracted
+    }
+}
+
+fn filename(file: Option<&Path>) -> &str {
+    // TODO: is there a better way to do this unwrapping? This implementation
+    // turns non-UTF-8 paths into "NON-UTF8 FILENAME". I don't know exactly
+    // what circumstances that could happen in... maybe we should just wait
+    // for bug reports?
+    file.map(|f| f.to_str().unwrap_or("NON-UTF8 FILENAME"))
+        .unwrap_or(NO_FILE)
+}
+
+/// How we show the file for source that didn't come from one, like
+/// `--text`.
+const NO_FILE: &str = "NO FILE";
+
+impl<'file, 'query> Display for FileMatch<'file, 'query> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.filename())?;
+        Display::fmt(self.extracted, f)
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ExtractedMatch<'query> {
+    kind: &'static str,
+    name: &'query str,
+    /// The capture names of every match merged into this one, in order, when
+    /// merging adjacent matches.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    names: Vec<&'query str>,
+    text: String,
+    #[serde(serialize_with = "serialize_point")]
+    start: Point,
+    #[serde(serialize_with = "serialize_point")]
+    end: Point,
+    #[serde(skip)]
+    start_byte: usize,
+    #[serde(skip)]
+    end_byte: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pattern: Option<usize>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<&'query str, Option<&'query str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    chunks: Chunks,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sexp: Option<String>,
+}
+
+// Matches sort by where they are in the file (and then by capture name)
+// instead of by their first field, which is what results are ordered by.
+impl Ord for ExtractedMatch<'_> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.start
+            .cmp(&other.start)
+            .then_with(|| self.name.cmp(other.name))
+            .then_with(|| self.end.cmp(&other.end))
+            // the rest only keep this consistent with `Eq`
+            .then_with(|| self.kind.cmp(other.kind))
+            .then_with(|| self.names.cmp(&other.names))
+            .then_with(|| self.text.cmp(&other.text))
+            .then_with(|| self.start_byte.cmp(&other.start_byte))
+            .then_with(|| self.end_byte.cmp(&other.end_byte))
+            .then_with(|| self.pattern.cmp(&other.pattern))
+            .then_with(|| self.metadata.cmp(&other.metadata))
+            .then_with(|| self.scope.cmp(&other.scope))
+            .then_with(|| self.chunks.cmp(&other.chunks))
+            .then_with(|| self.sexp.cmp(&other.sexp))
+    }
+}
+
+impl PartialOrd for ExtractedMatch<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'query> ExtractedMatch<'query> {
+    /// A match found with a regex instead of a query. There's no node behind
+    /// it, so its kind is `regex`.
+    pub(crate) fn from_regex(
+        name: &'query str,
+        text: String,
+        byte_range: Range<usize>,
+        start: Point,
+        end: Point,
+        chunks: Chunks,
+    ) -> Self {
+        ExtractedMatch {
+            kind: "regex",
+            name,
+            names: Vec::new(),
+            text,
+            start,
+            end,
+            start_byte: byte_range.start,
+            end_byte: byte_range.end,
+            pattern: None,
+            metadata: BTreeMap::new(),
+            scope: None,
+            chunks,
+            sexp: None,
+        }
+    }
+
+    pub fn name(&self) -> &'query str {
+        self.name
+    }
+
+    pub fn kind(&self) -> &'static str {
+        self.kind
+    }
+
+    /// See `Extractor::set_merge_adjacent`.
+    pub fn names(&self) -> &[&'query str] {
+        &self.names
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Where the match starts in the file. Rows and columns are 0-based.
+    pub fn start(&self) -> Point {
+        self.start
+    }
+
+    /// Where the match ends in the file. Rows and columns are 0-based.
+    pub fn end(&self) -> Point {
+        self.end
+    }
+
+    /// The bytes the match covers in the file.
+    pub fn byte_range(&self) -> Range<usize> {
+        self.start_byte..self.end_byte
+    }
+
+    pub fn chunks(&self) -> &Chunks {
+        &self.chunks
+    }
+
+    /// The name of the nearest function, class, module, etc. the match is
+    /// inside of, if its language has any of those and there is one.
+    pub fn scope(&self) -> Option<&str> {
+        self.scope.as_deref()
+    }
+
+    /// The value `#set!` gave `key` in the pattern that matched, if any.
+    /// Keys set without a value (like `(#set! deprecated)`) have an empty one.
+    pub fn metadata(&self, key: &str) -> Option<&'query str> {
+        self.metadata
+            .get(key)
+            .map(|value| value.unwrap_or_default())
+    }
+}
+
+// With `{:#}`, the text goes on lines of its own, each after its line number
+// in the file, like `  12 | fn foo() {`. That's `--number-lines`. The types
+// that hold matches pass the flag on by calling this `fmt` with their own
+// formatter.
+impl<'query> Display for ExtractedMatch<'query> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}:",
+            self.start.row + 1,
+            self.start.column + 1,
+            self.name,
+        )?;
+
+        if f.alternate() {
+            // a match ending in a newline would otherwise get an empty line
+            // numbered after its last one.
+            let text = self.text.strip_suffix('\n').unwrap_or(&self.text);
+            let last = self.start.row + 1 + text.matches('\n').count();
+            let width = last.to_string().len();
+            for (row, line) in (self.start.row + 1..).zip(text.split('\n')) {
+                write!(f, "\n  {:>width$} | {}", row, line, width = width)?;
+            }
+        } else {
+            write!(f, "{}", self.text)?;
+        }
+
+        if let Some(sexp) = &self.sexp {
+            write!(f, "\n  {}", sexp)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// `path` with every separator replaced by `separator`, if we were given one.
+/// Windows takes both `\` and `/`, so we replace whichever the path has.
+pub(crate) fn with_separator(path: &Path, separator: Option<char>) -> PathBuf {
+    match separator {
+        Some(separator) => PathBuf::from(
+            path.to_string_lossy()
+                .chars()
+                .map(|c| if is_separator(c) { separator } else { c })
+                .collect::<String>(),
+        ),
+        None => path.to_owned(),
+    }
+}
+
+/// The nodes around `node`, closest first.
+fn ancestors<'tree>(node: Node<'tree>) -> impl Iterator<Item = Node<'tree>> {
+    std::iter::successors(node.parent(), Node::parent)
+}
+
+/// Is `node` an ERROR node, or inside one?
+fn in_error(node: Node) -> bool {
+    let mut current = Some(node);
+    while let Some(node) = current {
+        if node.is_error() {
+            return true;
+        }
+        current = node.parent();
+    }
+
+    false
+}
+
+fn truncate(mut text: String, max_chars: usize) -> String {
+    if let Some((cutoff, _)) = text.char_indices().nth(max_chars) {
+        text.truncate(cutoff);
+        text.push('…');
+    }
+
+    text
+}
+
+fn serialize_point<S>(point: &Point, sz: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let mut out = sz.serialize_struct("Point", 2)?;
+    out.serialize_field("row", &(point.row + 1))?;
+    out.serialize_field("column", &(point.column + 1))?;
+    out.end()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{ModelConfig, BYTES_TOKENIZER};
+    use crossgrep_sys::Language;
+    use tree_sitter::Parser;
+
+    #[test]
+    fn test_matches_are_extracted() {
+        let lang = Language::Elm;
+        let query = lang
+            .parse_query("(import_clause (upper_case_qid)@import)")
+            .unwrap();
+        let extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+        let extracted = extractor
+            .extract_from_text(None, b"import Html.Styled", &mut Parser::new())
+            // From Result<Option<ExtractedFile>>
+            .unwrap()
+            // From Option<ExtractedFile>
+            .unwrap();
+
+        assert_eq!(extracted.matches.len(), 1);
+        assert_eq!(extracted.matches[0].name, "import");
+        assert_eq!(extracted.matches[0].text, "Html.Styled");
+    }
+
+    #[test]
+    fn test_underscore_names_are_ignored() {
+        let lang = Language::Elm;
+        let query = lang
+            .parse_query("(import_clause (upper_case_qid)@_import)")
+            .unwrap();
+        let extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+        let extracted = extractor
+            .extract_from_text(None, b"import Html.Styled", &mut Parser::new())
+            // From Result<Option<ExtractedFile>>
+            .unwrap();
+
+        assert_eq!(extracted, None);
+    }
+
+    #[test]
+    fn test_underscore_names_can_still_be_used_in_matchers() {
+        let lang = Language::JavaScript;
+        let query = lang
+            .parse_query("(call_expression (identifier)@_fn (arguments . (string)@import .) (#eq? @_fn require))")
+            .unwrap();
+        let extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+        let extracted = extractor
+            .extract_from_text(None, b"let foo = require(\"foo.js\")", &mut Parser::new())
+            // From Result<Option<ExtractedFile>>
+            .unwrap()
+            // From Option<ExtractedFile>
+            .unwrap();
+
+        assert_eq!(extracted.matches.len(), 1);
+        assert_eq!(extracted.matches[0].name, "import");
+        assert_eq!(extracted.matches[0].text, "\"foo.js\"");
+    }
+
+    #[test]
+    fn test_quantified_captures_are_separate_by_default() {
+        let lang = Language::JavaScript;
+        let query = lang
+            .parse_query("((comment)+ @docs . (function_declaration))")
+            .unwrap();
+        let extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+        let extracted = extractor
+            .extract_from_text(
+                None,
+                b"// one\n// two\nfunction foo() {}",
+                &mut Parser::new(),
+            )
+            // From Result<Option<ExtractedFile>>
+            .unwrap()
+            // From Option<ExtractedFile>
+            .unwrap();
+
+        assert_eq!(extracted.matches.len(), 2);
+    }
+
+    #[test]
+    fn test_quantified_captures_can_be_coalesced() {
+        let lang = Language::JavaScript;
+        let query = lang
+            .parse_query("((comment)+ @docs . (function_declaration))")
+            .unwrap();
+        let mut extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+        extractor.set_coalesce_captures(true);
+
+        let extracted = extractor
+            .extract_from_text(
+                None,
+                b"// one\n// two\nfunction foo() {}",
+                &mut Parser::new(),
+            )
+            // From Result<Option<ExtractedFile>>
+            .unwrap()
+            // From Option<ExtractedFile>
+            .unwrap();
+
+        assert_eq!(extracted.matches.len(), 1);
+        assert_eq!(extracted.matches[0].name, "docs");
+        assert_eq!(extracted.matches[0].text, "// one\n// two");
+        assert_eq!(extracted.matches[0].start, Point::new(0, 0));
+        assert_eq!(extracted.matches[0].end, Point::new(1, 6));
+    }
+
+    #[test]
+    fn test_zero_or_more_captures_can_be_coalesced() {
+        let lang = Language::JavaScript;
+        let query = lang
+            .parse_query("((comment)* @docs . (function_declaration) @fn)")
+            .unwrap();
+        let mut extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+        extractor.set_coalesce_captures(true);
+
+        let extracted = extractor
+            .extract_from_text(
+                None,
+                b"// one\n// two\nfunction foo() {}\nfunction bar() {}",
+                &mut Parser::new(),
+            )
+            // From Result<Option<ExtractedFile>>
+            .unwrap()
+            // From Option<ExtractedFile>
+            .unwrap();
+
+        // `bar` has no comments, so `@docs` captures nothing for it
+        assert_eq!(
+            extracted
+                .matches
+                .iter()
+                .map(|m| (m.name, m.text.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("docs", "// one\n// two"),
+                ("fn", "function foo() {}"),
+                ("fn", "function bar() {}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_overlapping_queries_are_deduplicated() {
+        let lang = Language::JavaScript;
+        let query = lang
+            .parse_query("(identifier)@id (call_expression (identifier)@id)")
+            .unwrap();
+        let extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+        let extracted = extractor
+            .extract_from_text(None, b"foo(bar)", &mut Parser::new())
+            // From Result<Option<ExtractedFile>>
+            .unwrap()
+            // From Option<ExtractedFile>
+            .unwrap();
+
+        assert_eq!(
+            extracted
+                .matches
+                .iter()
+                .map(|m| m.text.as_str())
+                .collect::<Vec<_>>(),
+            vec!["foo", "bar"]
+        );
+    }
+
+    #[test]
+    fn test_deduplication_can_be_turned_off() {
+        let lang = Language::JavaScript;
+        let query = lang
+            .parse_query("(identifier)@id (call_expression (identifier)@id)")
+            .unwrap();
+        let mut extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+        extractor.set_dedup(false);
+
+        let extracted = extractor
+            .extract_from_text(None, b"foo(bar)", &mut Parser::new())
+            // From Result<Option<ExtractedFile>>
+            .unwrap()
+            // From Option<ExtractedFile>
+            .unwrap();
+
+        assert_eq!(extracted.matches.len(), 3);
+    }
+
+    #[test]
+    fn test_sexp_is_included_and_truncated() {
+        let lang = Language::JavaScript;
+        let query = lang.parse_query("(call_expression)@call").unwrap();
+        let mut extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+        extractor.set_sexp_limit(Some(100));
+        let extracted = extractor
+            .extract_from_text(None, b"foo(bar)", &mut Parser::new())
+            // From Result<Option<ExtractedFile>>
+            .unwrap()
+            // From Option<ExtractedFile>
+            .unwrap();
+        assert_eq!(
+            extracted.matches[0].sexp.as_deref(),
+            Some("(call_expression function: (identifier) arguments: (arguments (identifier)))")
+        );
+
+        extractor.set_sexp_limit(Some(16));
+        let extracted = extractor
+            .extract_from_text(None, b"foo(bar)", &mut Parser::new())
+            // From Result<Option<ExtractedFile>>
+            .unwrap()
+            // From Option<ExtractedFile>
+            .unwrap();
+        assert_eq!(
+            extracted.matches[0].sexp.as_deref(),
+            Some("(call_expression…")
+        );
+    }
+
+    #[test]
+    fn test_sexp_is_the_captured_nodes() {
+        // the statement and the call in it cover the same bytes
+        let sexp = |query: &str, coalesce: bool| {
+            let lang = Language::Python;
+            let query = lang.parse_query(query).unwrap();
+            let mut extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+            extractor.set_sexp_limit(Some(200));
+            extractor.set_coalesce_captures(coalesce);
+
+            extractor
+                .extract_from_text(None, b"foo(bar)\nbaz()", &mut Parser::new())
+                .unwrap()
+                .unwrap()
+                .matches[0]
+                .sexp
+                .clone()
+                .unwrap()
+        };
+
+        assert_eq!(
+            sexp("(expression_statement) @s", false),
+            "(expression_statement (call function: (identifier) arguments: (argument_list (identifier))))"
+        );
+        assert_eq!(
+            sexp("(call) @c", false),
+            "(call function: (identifier) arguments: (argument_list (identifier)))"
+        );
+        // spans over several nodes fall back to the node around them all
+        assert!(sexp("(module (expression_statement)+ @s)", true).starts_with("(module "));
+    }
+
+    #[test]
+    fn test_pattern_index_is_recorded_when_asked_for() {
+        let lang = Language::JavaScript;
+        let query = lang
+            .parse_query("(identifier)@name (property_identifier)@name")
+            .unwrap();
+        let mut extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+        let patterns = |extractor: &Extractor| {
+            extractor
+                .extract_from_text(None, b"console.log", &mut Parser::new())
+                .unwrap()
+                .unwrap()
+                .matches
+                .iter()
+                .map(|extracted| extracted.pattern)
+                .collect::<Vec<Option<usize>>>()
+        };
+
+        assert_eq!(patterns(&extractor), vec![None, None]);
+
+        extractor.set_pattern_index(true);
+        assert_eq!(patterns(&extractor), vec![Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn test_matches_in_errors_can_be_skipped() {
+        let lang = Language::JavaScript;
+        let query = lang.parse_query("(identifier)@id").unwrap();
+        let mut extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+        let source = b"good;\nif (broken {";
+
+        let names = |extractor: &Extractor| {
+            extractor
+                .extract_from_text(None, source, &mut Parser::new())
+                .unwrap()
+                .unwrap()
+                .matches
+                .iter()
+                .map(|extracted| extracted.text.clone())
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(names(&extractor), vec!["good", "broken"]);
+        assert_eq!(extractor.files_with_parse_errors(), 1);
+
+        extractor.set_skip_errors(true);
+        assert_eq!(names(&extractor), vec!["good"]);
+        assert_eq!(extractor.files_with_parse_errors(), 2);
+
+        extractor.reset_counts();
+        assert_eq!(extractor.files_with_parse_errors(), 0);
+    }
+
+    #[test]
+    fn test_unmatched_captures_are_reported() {
+        let lang = Language::JavaScript;
+        let query = lang
+            .parse_query("(identifier) @id (class_declaration) @class (number) @_num")
+            .unwrap();
+        let extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+        assert_eq!(extractor.unmatched_captures(), vec!["id", "class"]);
+
+        extractor
+            .extract_from_text(None, b"let x = 1;", &mut Parser::new())
+            .unwrap();
+
+        assert_eq!(extractor.unmatched_captures(), vec!["class"]);
+
+        extractor.reset_counts();
+        assert_eq!(extractor.unmatched_captures(), vec!["id", "class"]);
+    }
+
+    #[test]
+    fn test_chunks_have_file_positions() {
+        let lang = Language::JavaScript;
+        let query = lang.parse_query("(function_declaration)@fn").unwrap();
+        let extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+        let extracted = extractor
+            .extract_from_text(
+                None,
+                b"let x = 1;\n\nfunction foo() {\n  return x;\n}\n",
+                &mut Parser::new(),
+            )
+            // From Result<Option<ExtractedFile>>
+            .unwrap()
+            // From Option<ExtractedFile>
+            .unwrap();
+
+        let chunks = match &extracted.matches[0].chunks {
+            Chunks::One(chunks) => chunks,
+            Chunks::PerModel(_) => panic!("expected a single model's chunks"),
+        };
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].start_byte, 12);
+        assert_eq!(chunks[0].end_byte, 42);
+        assert_eq!(chunks[0].start_line, 2);
+        assert_eq!(chunks[0].end_line, 4);
+    }
+
+    #[test]
+    fn test_set_directives_become_metadata() {
+        let lang = Language::JavaScript;
+        let query = lang
+            .parse_query(
+                r#"((string) @str (#set! severity "warning") (#set! @str message "a string"))
+                ((number) @num (#set! deprecated))"#,
+            )
+            .unwrap();
+        let extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+        let extracted = extractor
+            .extract_from_text(None, b"f('hi', 1)", &mut Parser::new())
+            .unwrap()
+            .unwrap();
+
+        let string = &extracted.matches[0];
+        assert_eq!(string.metadata("severity"), Some("warning"));
+        assert_eq!(string.metadata("message"), Some("a string"));
+        assert_eq!(
+            serde_json::to_value(string).unwrap()["metadata"],
+            serde_json::json!({"message": "a string", "severity": "warning"})
+        );
+
+        let number = &extracted.matches[1];
+        assert_eq!(number.metadata("deprecated"), Some(""));
+        assert_eq!(number.metadata("severity"), None);
+    }
+
+    #[test]
+    fn test_files_have_language_version() {
+        let lang = Language::JavaScript;
+        let query = lang.parse_query("(string)@str").unwrap();
+        let extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+        let extracted = extractor
+            .extract_from_text(None, b"'hi'", &mut Parser::new())
+            .unwrap()
+            .unwrap();
+
+        let json = serde_json::to_value(&extracted).unwrap();
+        assert_eq!(json["file_type"], "javascript");
+        assert_eq!(json["language_version"], lang.language().version());
+    }
+
+    #[test]
+    fn test_encoding_maps_back_to_original_bytes() {
+        let lang = Language::JavaScript;
+        let query = lang.parse_query("(string)@str").unwrap();
+        let mut extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+        extractor.set_encoding(encoding_rs::WINDOWS_1252);
+
+        // é is one byte in Latin-1 but two in UTF-8
+        let extracted = extractor
+            .extract_from_text(None, b"x = '\xe9';\ny = 'caf\xe9';", &mut Parser::new())
+            .unwrap()
+            .unwrap();
+
+        let second = &extracted.matches[1];
+        assert_eq!(second.text(), "'caf\u{e9}'");
+        assert_eq!(second.byte_range(), 13..19);
+        assert_eq!(second.start(), Point { row: 1, column: 4 });
+        assert_eq!(second.end(), Point { row: 1, column: 10 });
+
+        let chunks = second.chunks().for_model("").unwrap();
+        assert_eq!(chunks[0].start_byte, 13);
+        assert_eq!(chunks[0].end_byte, 19);
+    }
+
+    #[test]
+    fn test_chunks_per_model() {
+        let lang = Language::JavaScript;
+        let query = lang.parse_query("(function_declaration)@fn").unwrap();
+        let extractor = Extractor::with_models(
+            lang,
+            query,
+            vec![
+                (String::from("wide"), Model::Noop),
+                // CodeBERT's chunking, without downloading its tokenizer
+                (
+                    String::from("codebert"),
+                    Model::Custom(ModelConfig {
+                        tokenizer_source: String::from(BYTES_TOKENIZER),
+                        ..Model::CodeBert.config()
+                    }),
+                ),
+            ],
+        )
+        .unwrap();
+
+        let extracted = extractor
+            .extract_from_text(None, b"function foo() {}", &mut Parser::new())
+            .unwrap()
+            .unwrap();
+
+        let chunks = extracted.matches[0].chunks();
+        assert_eq!(chunks.for_model("wide").unwrap().len(), 1);
+        assert_eq!(chunks.for_model("codebert").unwrap().len(), 1);
+        assert!(chunks.for_model("other").is_none());
+
+        // keyed by model, in the order the models were given
+        let json = serde_json::to_string(&extracted.matches[0]).unwrap();
+        assert!(json.contains(r#""chunks":{"wide":[{"ids":"#));
+        assert!(json.find(r#""wide""#) < json.find(r#""codebert""#));
+    }
+
+    #[test]
+    fn test_parse_timeout_is_distinguishable() {
+        let lang = Language::JavaScript;
+        let query = lang.parse_query("(identifier)@id").unwrap();
+        let extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+        let mut parser = Parser::new();
+        parser.set_timeout_micros(1);
+
+        let source = "let foo = bar(baz);\n".repeat(10_000);
+        let err = extractor
+            .extract_from_text(None, source.as_bytes(), &mut parser)
+            .unwrap_err();
+
+        assert!(matches!(err, Error::ParseTimeout));
+    }
+
+    #[test]
+    fn test_missing_file_is_an_io_error() {
+        let lang = Language::JavaScript;
+        let query = lang.parse_query("(identifier)@id").unwrap();
+        let extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+        let err = extractor
+            .extract_from_file(Path::new("does-not-exist.js"), &mut Parser::new())
+            .unwrap_err();
+
+        assert!(matches!(err, Error::Io(_)));
+    }
+
+    #[test]
+    fn test_cancelled_extractor_returns_nothing() {
+        let lang = Language::Elm;
+        let query = lang
+            .parse_query("(import_clause (upper_case_qid)@import)")
+            .unwrap();
+        let mut extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+        let token = CancellationToken::new();
+        extractor.set_cancellation_token(token.clone());
+        token.cancel();
+
+        let extracted = extractor
+            .extract_from_text(None, b"import Html.Styled", &mut Parser::new())
+            // From Result<Option<ExtractedFile>>
+            .unwrap();
+
+        assert_eq!(extracted, None);
+    }
+
+    #[test]
+    fn test_columns_are_counted_in_the_chosen_unit() {
+        let column = |column_unit| {
+            let lang = Language::JavaScript;
+            let query = lang.parse_query("(identifier)@id").unwrap();
+            let mut extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+            extractor.set_column_unit(column_unit);
+
+            let extracted = extractor
+                .extract_from_text(None, "\"é😀\"; x".as_bytes(), &mut Parser::new())
+                .unwrap()
+                .unwrap();
+
+            (
+                extracted.matches[0].start.column,
+                extracted.matches[0].end.column,
+            )
+        };
+
+        assert_eq!(column(ColumnUnit::Byte), (10, 11));
+        assert_eq!(column(ColumnUnit::Char), (6, 7));
+        assert_eq!(column(ColumnUnit::Utf16), (7, 8));
+    }
+
+    #[test]
+    fn test_incremental_reparse_matches_full_parse() {
+        let lang = Language::JavaScript;
+        let extract = |extractor: &Extractor, source: &str| {
+            extractor
+                .extract_from_text(
+                    Some(Path::new("a.js")),
+                    source.as_bytes(),
+                    &mut Parser::new(),
+                )
+                .unwrap()
+                .unwrap()
+                .matches
+                .iter()
+                .map(|m| (m.text.clone(), m.start, m.end, m.start_byte))
+                .collect::<Vec<_>>()
+        };
+
+        let mut incremental = Extractor::new(
+            lang,
+            lang.parse_query("(identifier)@id").unwrap(),
+            Model::Noop,
+        )
+        .unwrap();
+        incremental.set_incremental(true);
+        let full = Extractor::new(
+            lang,
+            lang.parse_query("(identifier)@id").unwrap(),
+            Model::Noop,
+        )
+        .unwrap();
+
+        extract(&incremental, "let a = b;\nlet c = d;\n");
+        for source in &[
+            "let a = b;\nlet cat = d;\n",
+            "let a = b;\nfoo(a);\nlet cat = d;\n",
+            "let cat = d;\n",
+        ] {
+            assert_eq!(extract(&incremental, source), extract(&full, source));
+        }
+    }
+
+    #[test]
+    fn test_tree_cache_drops_the_least_recently_used_trees() {
+        let mut parser = Parser::new();
+        parser
+            .set_language(Language::JavaScript.language())
+            .unwrap();
+        let source = b"let a = b;";
+        let tree = parser.parse(source, None).unwrap();
+
+        // room for two of these sources, but not three
+        let trees = TreeCache::with_budget(25);
+        trees.insert(Path::new("a.js"), source, tree.clone());
+        trees.insert(Path::new("b.js"), source, tree.clone());
+        let a = trees.take(Path::new("a.js"), source).unwrap();
+        trees.insert(Path::new("a.js"), source, a);
+        trees.insert(Path::new("c.js"), source, tree.clone());
+
+        assert!(trees.take(Path::new("b.js"), source).is_none());
+        assert!(trees.take(Path::new("a.js"), source).is_some());
+        assert!(trees.take(Path::new("c.js"), source).is_some());
+
+        // too big to keep at all
+        trees.insert(Path::new("big.js"), &[b' '; 26], tree);
+        assert!(trees.take(Path::new("big.js"), source).is_none());
+    }
+
+    #[test]
+    fn test_input_edit_covers_the_changed_bytes() {
+        let edit = input_edit(b"ab\ncd\nef", b"ab\ncXXd\nef");
+
+        assert_eq!(edit.start_byte, 4);
+        assert_eq!(edit.old_end_byte, 4);
+        assert_eq!(edit.new_end_byte, 6);
+        assert_eq!(edit.start_position, Point::new(1, 1));
+        assert_eq!(edit.new_end_position, Point::new(1, 3));
+    }
+
+    #[test]
+    fn test_alternations_report_the_kind_that_matched() {
+        let lang = Language::JavaScript;
+        let query = lang
+            .parse_query("[(function_declaration) (arrow_function)] @callable")
+            .unwrap();
+        let extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+        let extracted = extractor
+            .extract_from_text(
+                None,
+                b"function f() {}\nconst g = () => {};",
+                &mut Parser::new(),
+            )
+            .unwrap()
+            .unwrap();
+
+        let kinds: Vec<&str> = extracted.matches.iter().map(|m| m.kind).collect();
+        assert_eq!(kinds, vec!["function_declaration", "arrow_function"]);
+    }
+
+    #[test]
+    fn test_matches_know_their_enclosing_scope() {
+        let scopes = |lang: Language, source: &str| {
+            let query = lang.parse_query("(identifier) @id").unwrap();
+            let extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+            extractor
+                .extract_from_text(None, source.as_bytes(), &mut Parser::new())
+                .unwrap()
+                .unwrap()
+                .matches
+                .iter()
+                .map(|m| (m.text.clone(), m.scope.clone()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            scopes(Language::Rust, "mod m { impl S { fn f() { x } } }"),
+            vec![
+                ("m".to_string(), None),
+                ("f".to_string(), Some("S".to_string())),
+                ("x".to_string(), Some("f".to_string())),
+            ]
+        );
+        // C function names are nested inside declarators
+        assert_eq!(
+            scopes(Language::C, "int *f(void) { return x; }"),
+            vec![
+                ("f".to_string(), None),
+                ("x".to_string(), Some("f".to_string())),
+            ]
+        );
+        // languages we don't know the scopes of never have one
+        assert_eq!(scopes(Language::Nix, "{ x = y; }")[0].1, None);
+    }
+
+    #[test]
+    fn test_lines_can_be_numbered() {
+        let lang = Language::Rust;
+        let query = lang.parse_query("(function_item) @fn").unwrap();
+        let extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+        let source = "\n".repeat(8) + "fn f() {\n    g();\n}";
+        let extracted = extractor
+            .extract_from_text(None, source.as_bytes(), &mut Parser::new())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            extracted.matches[0].to_string(),
+            "9:1:fn:fn f() {\n    g();\n}"
+        );
+        assert_eq!(
+            format!("{:#}", extracted.matches[0]),
+            "9:1:fn:\n   9 | fn f() {\n  10 |     g();\n  11 | }"
+        );
+        assert_eq!(
+            format!("{:#}", extracted.with_heading()),
+            "NO FILE\n9:1:fn:\n   9 | fn f() {\n  10 |     g();\n  11 | }\n"
+        );
+    }
+
+    #[test]
+    fn test_leading_comments_are_included() {
+        let source = "let x = 1; // about x\n\n// unrelated\n\n/// Does f.\n/// Really.\nfn f() {}\nfn g() {}";
+        let texts = |leading_comments| {
+            let lang = Language::Rust;
+            let query = lang.parse_query("(function_item) @fn").unwrap();
+            let mut extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+            extractor.set_leading_comments(leading_comments);
+
+            extractor
+                .extract_from_text(None, source.as_bytes(), &mut Parser::new())
+                .unwrap()
+                .unwrap()
+                .matches
+                .iter()
+                .map(|m| (m.text.clone(), m.start.row))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            texts(true),
+            vec![
+                ("/// Does f.\n/// Really.\nfn f() {}".to_string(), 4),
+                ("fn g() {}".to_string(), 7),
+            ]
+        );
+        assert_eq!(texts(false)[0], ("fn f() {}".to_string(), 6));
+    }
+
+    #[test]
+    fn test_trailing_comments_stay_with_their_line() {
+        let lang = Language::JavaScript;
+        let query = lang.parse_query("(function_declaration) @fn").unwrap();
+        let mut extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+        extractor.set_leading_comments(true);
+
+        let extracted = extractor
+            .extract_from_text(
+                None,
+                b"let x = 1; // about x\nfunction f() {}",
+                &mut Parser::new(),
+            )
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(extracted.matches[0].text, "function f() {}");
+    }
+
+    #[test]
+    fn test_matches_are_sorted_by_position() {
+        let lang = Language::JavaScript;
+        let query = lang
+            .parse_query("(call_expression function: (_) @fn (arguments (call_expression) @inner)) (identifier) @id")
+            .unwrap();
+        let extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+        let extracted = extractor
+            .extract_from_text(None, b"foo(bar(baz))", &mut Parser::new())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            extracted
+                .matches
+                .iter()
+                .map(|m| (m.start.column, m.name))
+                .collect::<Vec<_>>(),
+            vec![(0, "fn"), (0, "id"), (4, "id"), (4, "inner"), (8, "id")]
+        );
+    }
+
+    #[test]
+    fn test_small_matches_can_be_dropped() {
+        let lang = Language::JavaScript;
+        let source = b"function f() {}\nfunction g() {\n}\nfunction h() {\n\n}";
+        let texts = |min_lines: usize, min_bytes: usize| {
+            let query = lang.parse_query("(function_declaration) @fn").unwrap();
+            let mut extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+            extractor.set_min_lines(min_lines);
+            extractor.set_min_bytes(min_bytes);
+
+            extractor
+                .extract_from_text(None, source, &mut Parser::new())
+                .unwrap()
+                .map(|extracted| {
+                    extracted
+                        .matches
+                        .iter()
+                        .map(|m| m.text.len())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        };
+
+        assert_eq!(texts(0, 0), vec![15, 16, 17]);
+        assert_eq!(texts(1, 15), vec![15, 16, 17]);
+        assert_eq!(texts(2, 0), vec![16, 17]);
+        assert_eq!(texts(3, 0), vec![17]);
+        assert_eq!(texts(0, 16), vec![16, 17]);
+        assert_eq!(texts(4, 0), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_adjacent_matches_can_be_merged() {
+        let lang = Language::JavaScript;
+        let source = b"a();\nb();\n\nc();\n\n\nd();";
+        let merged = |gap: Option<usize>| {
+            let query = lang
+                .parse_query(
+                    "(expression_statement (call_expression function: (identifier) @call))",
+                )
+                .unwrap();
+            let mut extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+            extractor.set_merge_adjacent(gap);
+
+            extractor
+                .extract_from_text(None, source, &mut Parser::new())
+                .unwrap()
+                .unwrap()
+                .matches
+                .iter()
+                .map(|m| (m.text.clone(), m.names.len()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            merged(None),
+            vec![
+                ("a".to_string(), 0),
+                ("b".to_string(), 0),
+                ("c".to_string(), 0),
+                ("d".to_string(), 0)
+            ]
+        );
+        assert_eq!(
+            merged(Some(0)),
+            vec![
+                ("a();\nb".to_string(), 2),
+                ("c".to_string(), 0),
+                ("d".to_string(), 0)
+            ]
+        );
+        assert_eq!(
+            merged(Some(1)),
+            vec![("a();\nb();\n\nc".to_string(), 3), ("d".to_string(), 0)]
+        );
+        assert_eq!(
+            merged(Some(2)),
+            vec![("a();\nb();\n\nc();\n\n\nd".to_string(), 4)]
+        );
+    }
+
+    #[test]
+    fn test_local_predicates() {
+        let lang = Language::JavaScript;
+        let source = b"function f(a) { return a + b; }";
+        let names = |predicate: &str| {
+            let query = lang
+                .parse_query(&format!("((identifier) @id ({} local))", predicate))
+                .unwrap();
+            let extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+
+            extractor
+                .extract_from_text(None, source, &mut Parser::new())
+                .unwrap()
+                .unwrap()
+                .matches
+                .iter()
+                .map(|m| m.text.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names("#is?"), vec!["a", "a"]);
+        assert_eq!(names("#is-not?"), vec!["f", "b"]);
+
+        let query = Language::C
+            .parse_query("((identifier) @id (#is? local))")
+            .unwrap();
+        assert!(matches!(
+            Extractor::new(Language::C, query, Model::Noop),
+            Err(Error::NoLocalsQuery(Language::C))
+        ));
+    }
+
+    #[test]
+    fn test_matches_expand_to_the_node_around_them() {
+        let lang = Language::Rust;
+        let query = lang
+            .parse_query(r#"((line_comment) @todo (#match? @todo "TODO"))"#)
+            .unwrap();
+        let mut extractor = Extractor::new(lang, query, Model::Noop).unwrap();
+        extractor.set_expand_to(Some("function_item".to_string()));
+
+        let extracted = extractor
+            .extract_from_text(
+                None,
+                b"// TODO: top\nfn f() {\n    // TODO: a\n    // TODO: b\n}\nfn g() { // TODO: c\n}",
+                &mut Parser::new(),
+            )
+            .unwrap()
+            .unwrap();
+
+        // the two in `f` only give us `f` once, and the one outside any
+        // function is dropped.
+        assert_eq!(
+            extracted
+                .matches
+                .iter()
+                .map(|m| (m.kind, m.text.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "function_item",
+                    "fn f() {\n    // TODO: a\n    // TODO: b\n}"
+                ),
+                ("function_item", "fn g() { // TODO: c\n}"),
+            ]
+        );
     }
 }
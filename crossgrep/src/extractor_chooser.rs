@@ -1,52 +1,350 @@
+use crate::error::{Error, Result};
 use crate::extractor::Extractor;
-use anyhow::{bail, Context, Result};
+use crate::gitattributes::LanguageOverrides;
+use crate::regex_extractor::RegexExtractor;
+use crossgrep_sys::Language;
 use ignore::types::{Types, TypesBuilder};
-use ignore::DirEntry;
-use std::collections::HashMap;
+use std::path::Path;
 
 pub struct ExtractorChooser<'extractor> {
-    matcher: Types,
-    extractors: HashMap<&'extractor str, &'extractor Extractor>,
+    /// A matcher for each language's file type, with its extractors, in the
+    /// order the extractors were given.
+    matchers: Vec<(Types, Vec<&'extractor Extractor>)>,
+    overlaps: Vec<Overlap>,
+    gitattributes: Option<LanguageOverrides>,
+    fallback: Option<&'extractor RegexExtractor>,
+}
+
+/// Files that more than one extractor's file type claims, like `*.h` for C
+/// and C++. See `ExtractorChooser::overlaps`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overlap {
+    /// A glob for the files, like `*.h`.
+    pub glob: String,
+    /// Every language that claims files like these, in the order their
+    /// extractors were given. The first one gets them.
+    pub languages: Vec<Language>,
 }
 
 impl<'extractor> ExtractorChooser<'extractor> {
-    pub fn from_extractors(extractors: &[Extractor]) -> Result<ExtractorChooser> {
-        let mut types_builder = TypesBuilder::new();
-        types_builder.add_defaults();
+    /// Build a chooser for `extractors`. If more than one of them claims a
+    /// file (because their file types overlap) the one that comes first in
+    /// `extractors` gets it, so put the ones you'd rather have first.
+    pub fn from_extractors(extractors: &[Extractor]) -> Result<ExtractorChooser<'_>> {
+        Self::build(extractors, false)
+    }
+
+    /// Like `from_extractors`, but more than one extractor can be for the
+    /// same language. Files get all of them, in the order they're given; see
+    /// `extractors_for`.
+    pub fn from_separate_extractors(extractors: &[Extractor]) -> Result<ExtractorChooser<'_>> {
+        Self::build(extractors, true)
+    }
 
-        let mut names_to_extractors = HashMap::with_capacity(extractors.len());
+    fn build(extractors: &[Extractor], separate: bool) -> Result<ExtractorChooser<'_>> {
+        let mut matchers: Vec<(Types, Vec<&Extractor>)> = Vec::with_capacity(extractors.len());
 
         for extractor in extractors {
-            let name = extractor.language().name_for_types_builder();
-            types_builder.select(name);
-
-            // a little reminder: insert returns the old value if the key was
-            // already present
-            if names_to_extractors.insert(name, extractor).is_some() {
-                bail!("got a duplicate query. This should not have happened. Please report it!")
+            if let Some((_, existing)) = matchers
+                .iter_mut()
+                .find(|(_, existing)| existing[0].language() == extractor.language())
+            {
+                if !separate {
+                    return Err(Error::DuplicateLanguage(*extractor.language()));
+                }
+                existing.push(extractor);
+                continue;
             }
+
+            let mut types_builder = TypesBuilder::new();
+            types_builder.add_defaults();
+            types_builder.select(extractor.language().name_for_types_builder());
+
+            matchers.push((
+                types_builder.build().map_err(Error::FileTypes)?,
+                vec![extractor],
+            ));
         }
 
+        let overlaps = find_overlaps(&matchers);
+
         Ok(ExtractorChooser {
-            matcher: types_builder
-                .build()
-                .context("could not build a filetype matcher using provided extractors")?,
-            extractors: names_to_extractors,
+            matchers,
+            overlaps,
+            gitattributes: None,
+            fallback: None,
         })
     }
 
-    pub fn extractor_for(&self, entry: &DirEntry) -> Option<&Extractor> {
-        let is_dir = entry.file_type().map(|ft| ft.is_dir()).unwrap_or(true);
-        let matched = self.matcher.matched(entry.path(), is_dir);
+    /// Let `linguist-language` attributes in `.gitattributes` files pick a
+    /// file's language before its name does. An attribute naming a language
+    /// we don't have an extractor for is ignored.
+    pub fn set_gitattributes(&mut self, gitattributes: bool) {
+        self.gitattributes = if gitattributes {
+            Some(LanguageOverrides::default())
+        } else {
+            None
+        };
+    }
+
+    /// Search files that none of the extractors claim with `fallback`
+    /// instead of skipping them. See `fallback_for`.
+    pub fn set_fallback(&mut self, fallback: &'extractor RegexExtractor) {
+        self.fallback = Some(fallback);
+    }
+
+    /// The regex extractor to search a file with, if there's one and none of
+    /// the other extractors claim the file.
+    pub fn fallback_for(&self, path: &Path) -> Option<&'extractor RegexExtractor> {
+        self.fallback
+            .filter(|_| self.extractors_for(path).is_empty())
+    }
+
+    /// Pick the extractor for a file based on its name (or its
+    /// `.gitattributes`, if they're turned on). Directories never get an
+    /// extractor, so callers should filter them out before asking.
+    pub fn extractor_for(&self, path: &Path) -> Option<&Extractor> {
+        self.extractors_for(path).first().copied()
+    }
+
+    /// Every extractor for a file's language, which is only ever more than
+    /// one if this was built with `from_separate_extractors`. Empty if no
+    /// extractor claims the file.
+    pub fn extractors_for(&self, path: &Path) -> &[&'extractor Extractor] {
+        let overridden = self
+            .gitattributes
+            .as_ref()
+            .and_then(|overrides| overrides.language_for(path))
+            .and_then(|language| {
+                self.matchers
+                    .iter()
+                    .find(|(_, extractors)| *extractors[0].language() == language)
+            });
+        if let Some((_, extractors)) = overridden {
+            tracing::trace!(
+                path = %path.display(),
+                language = %extractors[0].language(),
+                "using the language from .gitattributes"
+            );
+            return extractors;
+        }
+
+        self.matchers
+            .iter()
+            .find(|(matcher, _)| matcher.matched(path, false).is_whitelist())
+            .map_or(&[], |(_, extractors)| extractors.as_slice())
+    }
+
+    /// The kinds of files more than one extractor claims, and which languages
+    /// claim them. Only the first of those languages searches them.
+    ///
+    /// This checks file types' extension globs (like `*.h` or `*.[ch]pp`)
+    /// against each other, so it can miss overlaps between fancier globs.
+    pub fn overlaps(&self) -> &[Overlap] {
+        &self.overlaps
+    }
+}
+
+fn find_overlaps(matchers: &[(Types, Vec<&Extractor>)]) -> Vec<Overlap> {
+    let mut overlaps: Vec<Overlap> = Vec::new();
+
+    for (matcher, extractors) in matchers {
+        let name = extractors[0].language().name_for_types_builder();
+        for def in matcher
+            .definitions()
+            .iter()
+            .filter(|def| def.name() == name)
+        {
+            for extension in def.globs().iter().flat_map(|glob| extensions(glob)) {
+                let glob = format!("*.{}", extension);
+                if overlaps.iter().any(|overlap| overlap.glob == glob) {
+                    continue;
+                }
+
+                let example = format!("example.{}", extension);
+                let languages: Vec<Language> = matchers
+                    .iter()
+                    .filter(|(other, _)| other.matched(&example, false).is_whitelist())
+                    .map(|(_, extractors)| *extractors[0].language())
+                    .collect();
+
+                if languages.len() > 1 {
+                    overlaps.push(Overlap { glob, languages });
+                }
+            }
+        }
+    }
+
+    overlaps
+}
+
+/// The extensions an extension glob matches, like `c`, `h`, and `H` for
+/// `*.[chH]`. Globs that aren't just an extension, or that match too many to
+/// list, have none.
+fn extensions(glob: &str) -> Vec<String> {
+    let mut extensions = vec![String::new()];
+    let mut chars = match glob.strip_prefix("*.") {
+        Some(rest) => rest.chars(),
+        None => return Vec::new(),
+    };
 
-        if !matched.is_whitelist() {
-            return None;
+    while let Some(c) = chars.next() {
+        let choices: Vec<char> = match c {
+            '[' => chars.by_ref().take_while(|c| *c != ']').collect(),
+            '*' | '?' | '{' | '}' | '\\' | ']' => return Vec::new(),
+            c => vec![c],
+        };
+        // ranges and negated classes
+        if choices.len() > 1 && choices.iter().any(|c| "-!^".contains(*c)) {
+            return Vec::new();
         }
 
-        matched
-            .inner()
-            .and_then(|glob| glob.file_type_def())
-            .and_then(|def| self.extractors.get(def.name()))
-            .copied()
+        extensions = extensions
+            .iter()
+            .flat_map(|prefix| choices.iter().map(move |c| format!("{}{}", prefix, c)))
+            .collect();
+    }
+
+    extensions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    fn extractor(language: Language) -> Extractor {
+        let query = language.parse_query("(_) @any").unwrap();
+        Extractor::new(language, query, Model::Noop).unwrap()
+    }
+
+    #[test]
+    fn the_first_overlapping_extractor_wins() {
+        let extractors = vec![extractor(Language::C), extractor(Language::Cpp)];
+        let chooser = ExtractorChooser::from_extractors(&extractors).unwrap();
+
+        let language_for = |path: &str| {
+            chooser
+                .extractor_for(Path::new(path))
+                .map(|e| *e.language())
+        };
+        assert_eq!(language_for("main.c"), Some(Language::C));
+        assert_eq!(language_for("main.cpp"), Some(Language::Cpp));
+        assert_eq!(language_for("main.h"), Some(Language::C));
+        assert_eq!(language_for("main.py"), None);
+        assert!(chooser.overlaps().contains(&Overlap {
+            glob: String::from("*.h"),
+            languages: vec![Language::C, Language::Cpp],
+        }));
+
+        let extractors = vec![extractor(Language::Cpp), extractor(Language::C)];
+        let chooser = ExtractorChooser::from_extractors(&extractors).unwrap();
+
+        assert_eq!(
+            chooser
+                .extractor_for(Path::new("main.h"))
+                .map(|e| *e.language()),
+            Some(Language::Cpp)
+        );
+    }
+
+    #[test]
+    fn extension_globs_expand() {
+        assert_eq!(extensions("*.rs"), vec!["rs"]);
+        assert_eq!(extensions("*.[ch]pp"), vec!["cpp", "hpp"]);
+        assert!(extensions("CMakeLists.txt").is_empty());
+        assert!(extensions("*.tar.*").is_empty());
+        assert!(extensions("*.[a-z]").is_empty());
+    }
+
+    #[test]
+    fn gitattributes_override_extensions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        std::fs::write(
+            root.join(".gitattributes"),
+            "*.foo linguist-language=C\n*.h linguist-language=C++\n*.bar linguist-language=Python\n",
+        )
+        .unwrap();
+
+        let extractors = vec![extractor(Language::C), extractor(Language::Cpp)];
+        let mut chooser = ExtractorChooser::from_extractors(&extractors).unwrap();
+        let language_for = |chooser: &ExtractorChooser, name: &str| {
+            chooser
+                .extractor_for(&root.join(name))
+                .map(|e| *e.language())
+        };
+
+        let without = (
+            language_for(&chooser, "a.foo"),
+            language_for(&chooser, "a.h"),
+        );
+        chooser.set_gitattributes(true);
+        let with = (
+            language_for(&chooser, "a.foo"),
+            language_for(&chooser, "a.h"),
+            language_for(&chooser, "a.bar"),
+            language_for(&chooser, "a.c"),
+        );
+
+        assert_eq!(without, (None, Some(Language::C)));
+        assert_eq!(
+            with,
+            (
+                Some(Language::C),
+                Some(Language::Cpp),
+                // no Python extractor, so the extension decides
+                None,
+                Some(Language::C)
+            )
+        );
+    }
+
+    #[test]
+    fn languages_without_overlaps_have_none() {
+        let extractors = vec![extractor(Language::Rust), extractor(Language::Python)];
+        let chooser = ExtractorChooser::from_extractors(&extractors).unwrap();
+
+        assert!(chooser.overlaps().is_empty());
+    }
+
+    #[test]
+    fn separate_extractors_share_files() {
+        let extractors = vec![
+            extractor(Language::Rust),
+            extractor(Language::Python),
+            extractor(Language::Rust),
+        ];
+        let chooser = ExtractorChooser::from_separate_extractors(&extractors).unwrap();
+
+        let chosen = chooser.extractors_for(Path::new("main.rs"));
+        assert_eq!(chosen.len(), 2);
+        assert!(std::ptr::eq(chosen[0], &extractors[0]));
+        assert!(std::ptr::eq(chosen[1], &extractors[2]));
+        assert_eq!(chooser.extractors_for(Path::new("main.py")).len(), 1);
+        assert!(chooser.extractors_for(Path::new("main.c")).is_empty());
+    }
+
+    #[test]
+    fn duplicate_languages_are_rejected() {
+        let extractors = vec![extractor(Language::Rust), extractor(Language::Rust)];
+
+        assert!(matches!(
+            ExtractorChooser::from_extractors(&extractors),
+            Err(Error::DuplicateLanguage(Language::Rust))
+        ));
+    }
+
+    #[test]
+    fn the_fallback_only_gets_files_nothing_else_claims() {
+        let extractors = vec![extractor(Language::Rust)];
+        let fallback =
+            RegexExtractor::new(regex::bytes::Regex::new("TODO").unwrap(), Model::Noop).unwrap();
+        let mut chooser = ExtractorChooser::from_extractors(&extractors).unwrap();
+        assert!(chooser.fallback_for(Path::new("notes.txt")).is_none());
+
+        chooser.set_fallback(&fallback);
+        assert!(chooser.fallback_for(Path::new("notes.txt")).is_some());
+        assert!(chooser.fallback_for(Path::new("lib.rs")).is_none());
     }
 }
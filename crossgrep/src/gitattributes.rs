@@ -0,0 +1,151 @@
+use crossgrep_sys::Language;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// The languages `.gitattributes` files give files with `linguist-language`,
+/// like `*.foo linguist-language=C`. We read each directory's file the first
+/// time we look up something under it, and keep it for next time.
+#[derive(Debug, Default)]
+pub(crate) struct LanguageOverrides {
+    dirs: Mutex<HashMap<PathBuf, Arc<Vec<Rule>>>>,
+}
+
+/// One `linguist-language` line from a `.gitattributes` file.
+#[derive(Debug)]
+struct Rule {
+    matcher: Gitignore,
+    /// `None` when the line unsets the attribute (or names a language we don't
+    /// have a grammar for), which puts the file back to being picked by its
+    /// extension.
+    language: Option<Language>,
+}
+
+impl LanguageOverrides {
+    /// The language `path`'s `.gitattributes` files give it, if any. Like
+    /// git, later lines win over earlier ones, and files in deeper directories
+    /// win over ones above them. We only look as far up as `path` goes, so a
+    /// relative path doesn't see the files above the current directory.
+    pub(crate) fn language_for(&self, path: &Path) -> Option<Language> {
+        let dirs: Vec<&Path> = path.ancestors().skip(1).collect();
+
+        let mut language = None;
+        for dir in dirs.into_iter().rev() {
+            for rule in self.rules_in(dir).iter() {
+                if rule.matcher.matched(path, false).is_ignore() {
+                    language = rule.language;
+                }
+            }
+        }
+
+        language
+    }
+
+    fn rules_in(&self, dir: &Path) -> Arc<Vec<Rule>> {
+        if let Some(rules) = self.lock().get(dir) {
+            return rules.clone();
+        }
+
+        // a missing or unreadable file just doesn't override anything.
+        let rules = Arc::new(
+            fs::read_to_string(dir.join(".gitattributes"))
+                .map(|contents| parse(dir, &contents))
+                .unwrap_or_default(),
+        );
+        self.lock().insert(dir.to_path_buf(), rules.clone());
+
+        rules
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, Arc<Vec<Rule>>>> {
+        self.dirs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Pull the `linguist-language` rules out of the `.gitattributes` in `dir`.
+fn parse(dir: &Path, contents: &str) -> Vec<Rule> {
+    let mut rules = Vec::new();
+
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let pattern = match fields.next() {
+            // quoted patterns can have spaces in them, and we don't handle
+            // those yet.
+            Some(pattern) if !pattern.starts_with('"') => pattern,
+            _ => continue,
+        };
+
+        for attribute in fields {
+            let language = if let Some(name) = attribute.strip_prefix("linguist-language=") {
+                // linguist's names are capitalized (like `JavaScript`) but
+                // ours aren't. `C++` is one of our aliases.
+                Language::from_str(&name.to_lowercase()).ok()
+            } else if attribute == "-linguist-language" || attribute == "!linguist-language" {
+                None
+            } else {
+                continue;
+            };
+
+            let mut builder = GitignoreBuilder::new(dir);
+            if builder.add_line(None, pattern).is_err() {
+                continue;
+            }
+            if let Ok(matcher) = builder.build() {
+                rules.push(Rule { matcher, language });
+            }
+        }
+    }
+
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn later_and_deeper_rules_win() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let nested = root.join("nested");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(
+            root.join(".gitattributes"),
+            "# overrides\n*.foo linguist-language=C\n*.bar linguist-language=Rust\n*.bar linguist-language=C++ text\n*.baz linguist-language=Cobol\n",
+        )
+        .unwrap();
+        fs::write(nested.join(".gitattributes"), "*.foo -linguist-language\n").unwrap();
+
+        let overrides = LanguageOverrides::default();
+        let language_for = |path: PathBuf| overrides.language_for(&path);
+        let results = (
+            language_for(root.join("a.foo")),
+            language_for(root.join("a.bar")),
+            language_for(root.join("a.baz")),
+            language_for(nested.join("a.foo")),
+            language_for(nested.join("a.bar")),
+            language_for(root.join("a.rs")),
+        );
+
+        assert_eq!(
+            results,
+            (
+                Some(Language::C),
+                Some(Language::Cpp),
+                None,
+                None,
+                Some(Language::Cpp),
+                None
+            )
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::{Context, Result};
+use crossgrep::extractor::ExtractedFile;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// How many matches there were of each node kind, for `--count-by-kind`.
+#[derive(Debug, Default)]
+pub struct KindCounts {
+    counts: HashMap<&'static str, usize>,
+}
+
+#[derive(Serialize)]
+struct KindCount {
+    kind: &'static str,
+    count: usize,
+}
+
+impl KindCounts {
+    pub fn add(&mut self, extracted_file: &ExtractedFile) {
+        for extracted in extracted_file.matches() {
+            *self.counts.entry(extracted.kind()).or_default() += 1;
+        }
+    }
+
+    /// The most common kinds first, and kinds with the same count in
+    /// alphabetical order.
+    fn sorted(&self) -> Vec<KindCount> {
+        let mut sorted: Vec<KindCount> = self
+            .counts
+            .iter()
+            .map(|(kind, count)| KindCount {
+                kind,
+                count: *count,
+            })
+            .collect();
+        sorted.sort_unstable_by(|a, b| b.count.cmp(&a.count).then_with(|| a.kind.cmp(b.kind)));
+
+        sorted
+    }
+
+    /// Write a table with a line for each kind, like `   12 function_item`.
+    pub fn write_table(&self, mut out: impl Write) -> Result<()> {
+        let sorted = self.sorted();
+        let width = sorted
+            .first()
+            .map_or(1, |first| first.count.to_string().len());
+
+        for kind_count in sorted {
+            writeln!(
+                out,
+                "{:>width$} {}",
+                kind_count.count,
+                kind_count.kind,
+                width = width
+            )
+            .context("could not write kind counts")?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a JSON array of `{"kind": ..., "count": ...}` objects on one
+    /// line, in the same order as the table.
+    pub fn write_json(&self, mut out: impl Write) -> Result<()> {
+        serde_json::to_writer(&mut out, &self.sorted()).context("could not write kind counts")?;
+        writeln!(out).context("could not write kind counts")
+    }
+}
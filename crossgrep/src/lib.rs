@@ -0,0 +1,16 @@
+pub mod cancellation;
+pub mod chunker;
+mod decoded;
+pub mod diagnostics;
+pub mod embedding;
+mod error;
+pub mod extractor;
+pub mod extractor_chooser;
+mod gitattributes;
+mod locals;
+pub mod model;
+pub mod parser_pool;
+pub mod regex_extractor;
+pub mod tree_view;
+
+pub use error::{Error, Result};
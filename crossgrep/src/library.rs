@@ -0,0 +1,175 @@
+use anyhow::{bail, Context, Result};
+use crossgrep_sys::Language;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// A file of queries with names, for `--from-library`. Each pattern is named
+/// with a `; @name NAME` comment before it, and patterns are for the language
+/// in the `; @language LANGUAGE` comment above them (or the language the file
+/// is named after, like `rust.scm`):
+///
+/// ```scheme
+/// ; @language javascript
+///
+/// ; @name imports
+/// (import_statement) @import
+///
+/// ; @name exports
+/// (export_statement) @export
+/// ```
+///
+/// Anything before the first `@name` isn't part of any pattern.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Library {
+    patterns: Vec<NamedPattern>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct NamedPattern {
+    name: String,
+    language: String,
+    query: String,
+}
+
+impl Library {
+    pub fn load(path: &Path) -> Result<Self> {
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("could not read a query library from {}", path.display()))?;
+
+        let language = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .filter(|stem| Language::from_str(stem).is_ok());
+
+        Self::parse(&raw, language)
+            .with_context(|| format!("could not parse the query library in {}", path.display()))
+    }
+
+    fn parse(raw: &str, default_language: Option<&str>) -> Result<Self> {
+        let mut patterns: Vec<NamedPattern> = Vec::new();
+        let mut language = default_language.map(str::to_string);
+        // whether lines go in the last pattern, or are between patterns
+        let mut in_pattern = false;
+
+        for (index, line) in raw.lines().enumerate() {
+            match annotation(line) {
+                Some(("language", value)) => {
+                    Language::from_str(value).with_context(|| {
+                        format!("line {} names a language we don't know", index + 1)
+                    })?;
+                    language = Some(value.to_string());
+                    in_pattern = false;
+                }
+                Some(("name", value)) => {
+                    let language = language.clone().with_context(|| {
+                        format!(
+                            "the pattern named {} on line {} doesn't have a language. Add a `; @language LANGUAGE` line above it.",
+                            value,
+                            index + 1
+                        )
+                    })?;
+                    patterns.push(NamedPattern {
+                        name: value.to_string(),
+                        language,
+                        query: String::new(),
+                    });
+                    in_pattern = true;
+                }
+                _ if in_pattern => {
+                    // `in_pattern` is only ever set after pushing one
+                    let query = &mut patterns.last_mut().unwrap().query;
+                    query.push_str(line);
+                    query.push('\n');
+                }
+                _ => (),
+            }
+        }
+
+        Ok(Library { patterns })
+    }
+
+    /// The `(language, query)` pairs for the patterns named `names`, in the
+    /// same shape as the values of `-t`. Patterns that share a name all come
+    /// along.
+    pub fn targets(&self, names: &[&str]) -> Result<Vec<(&str, &str)>> {
+        for name in names {
+            if !self.patterns.iter().any(|pattern| pattern.name == *name) {
+                let mut known: Vec<&str> = self
+                    .patterns
+                    .iter()
+                    .map(|pattern| pattern.name.as_str())
+                    .collect();
+                known.sort_unstable();
+                known.dedup();
+
+                bail!(
+                    "there's no pattern named {} in the library. I know about: {}",
+                    name,
+                    known.join(", ")
+                )
+            }
+        }
+
+        Ok(self
+            .patterns
+            .iter()
+            .filter(|pattern| names.contains(&pattern.name.as_str()))
+            .map(|pattern| (pattern.language.as_str(), pattern.query.as_str()))
+            .collect())
+    }
+}
+
+/// The key and value of a `; @key value` comment.
+fn annotation(line: &str) -> Option<(&str, &str)> {
+    let rest = line
+        .trim()
+        .strip_prefix(';')?
+        .trim_start_matches(';')
+        .trim();
+    let (key, value) = rest.strip_prefix('@')?.split_once(char::is_whitespace)?;
+
+    Some((key, value.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIBRARY: &str = "; shared by everything\n(comment) @comment\n\n; @name imports\n(import_statement) @import\n\n;; @name exports\n(export_statement\n  declaration: (_) @export)\n\n; @language rust\n; @name imports\n(use_declaration) @use\n";
+
+    #[test]
+    fn named_patterns_are_selected() {
+        let library = Library::parse(LIBRARY, Some("javascript")).unwrap();
+
+        assert_eq!(
+            library.targets(&["imports"]).unwrap(),
+            vec![
+                ("javascript", "(import_statement) @import\n\n"),
+                ("rust", "(use_declaration) @use\n")
+            ]
+        );
+        assert_eq!(
+            library.targets(&["exports"]).unwrap(),
+            vec![(
+                "javascript",
+                "(export_statement\n  declaration: (_) @export)\n\n"
+            )]
+        );
+    }
+
+    #[test]
+    fn missing_names_and_languages_are_errors() {
+        let library = Library::parse(LIBRARY, Some("javascript")).unwrap();
+        assert_eq!(
+            library
+                .targets(&["imports", "todos"])
+                .unwrap_err()
+                .to_string(),
+            "there's no pattern named todos in the library. I know about: exports, imports"
+        );
+
+        assert!(Library::parse(LIBRARY, None).is_err());
+        assert!(Library::parse("; @language cobol\n", None).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use crate::error::{Error, Result};
+use crossgrep_sys::Language;
+use std::collections::HashSet;
+use tree_sitter::{Node, Query, QueryCursor};
+
+/// Works out which nodes are local variables, for `#is? local` and
+/// `#is-not? local` in queries. This follows tree-sitter's `locals.scm`
+/// conventions: a node is local if it's a definition, or a reference to a
+/// definition with the same name that came before it in a scope around it.
+/// The whole file counts as a scope too, so top-level variables are local.
+#[derive(Debug)]
+pub(crate) struct Locals {
+    query: Query,
+    scope: u32,
+    definition: u32,
+    reference: u32,
+}
+
+/// A scope we're inside of while walking the captures, with the names
+/// defined in it so far.
+struct Scope<'source> {
+    end_byte: usize,
+    names: HashSet<&'source [u8]>,
+}
+
+impl Locals {
+    pub(crate) fn new(language: Language) -> Result<Locals> {
+        let raw = language
+            .locals_query()
+            .ok_or(Error::NoLocalsQuery(language))?;
+        let query = Query::new(language.language(), raw).map_err(Error::Query)?;
+
+        let index = |name: &str| {
+            // the locals queries are tested to have all three captures.
+            query.capture_index_for_name(name).unwrap()
+        };
+
+        Ok(Locals {
+            scope: index("local.scope"),
+            definition: index("local.definition"),
+            reference: index("local.reference"),
+            query,
+        })
+    }
+
+    /// The IDs of the local nodes under `root`.
+    pub(crate) fn local_nodes(&self, root: Node, source: &[u8]) -> HashSet<usize> {
+        let mut local = HashSet::new();
+        let mut scopes = vec![Scope {
+            end_byte: usize::MAX,
+            names: HashSet::new(),
+        }];
+
+        let mut cursor = QueryCursor::new();
+        for (query_match, index) in cursor.captures(&self.query, root, source) {
+            let capture = query_match.captures[index];
+            let node = capture.node;
+
+            // captures come in order, so once one starts after a scope ends,
+            // nothing else will be in that scope either.
+            while scopes.len() > 1 && scopes[scopes.len() - 1].end_byte <= node.start_byte() {
+                scopes.pop();
+            }
+
+            let name = &source[node.byte_range()];
+            if capture.index == self.scope {
+                scopes.push(Scope {
+                    end_byte: node.end_byte(),
+                    names: HashSet::new(),
+                });
+            } else if capture.index == self.definition {
+                // there's always the file's scope at the bottom
+                scopes.last_mut().unwrap().names.insert(name);
+                local.insert(node.id());
+            } else if capture.index == self.reference
+                && scopes.iter().any(|scope| scope.names.contains(name))
+            {
+                local.insert(node.id());
+            }
+        }
+
+        local
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tree_sitter::Parser;
+
+    #[test]
+    fn references_resolve_to_definitions_in_enclosing_scopes() {
+        let source = "let top = 1;\nfunction f(a) { let b = a; return b + c + top; }\nfunction g() { return a; }";
+        let mut parser = Parser::new();
+        parser
+            .set_language(Language::JavaScript.language())
+            .unwrap();
+        let tree = parser.parse(source, None).unwrap();
+
+        let locals = Locals::new(Language::JavaScript).unwrap();
+        let local = locals.local_nodes(tree.root_node(), source.as_bytes());
+
+        let mut names: Vec<(usize, &str)> = Vec::new();
+        let mut cursor = tree.walk();
+        let mut stack = vec![tree.root_node()];
+        while let Some(node) = stack.pop() {
+            if node.kind() == "identifier" && local.contains(&node.id()) {
+                names.push((
+                    node.start_byte(),
+                    node.utf8_text(source.as_bytes()).unwrap(),
+                ));
+            }
+            stack.extend(node.children(&mut cursor));
+        }
+        names.sort_unstable();
+
+        // `c`, the function names, and `a` in `g` aren't defined anywhere
+        // they can be seen from.
+        assert_eq!(
+            names.into_iter().map(|(_, name)| name).collect::<Vec<_>>(),
+            vec!["top", "a", "b", "a", "b", "top"]
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+
+/// How much we log when neither `--log-level` nor `RUST_LOG` says.
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::WARN;
+
+/// Prints `tracing` events as lines like `debug: skipping file path=a.bin
+/// size=123`, out of the way of results. We only need events, so spans are
+/// accepted but otherwise ignored.
+pub struct Logger<W> {
+    level: LevelFilter,
+    out: Mutex<W>,
+    next_span: AtomicU64,
+}
+
+impl<W: Write> Logger<W> {
+    pub fn new(level: LevelFilter, out: W) -> Self {
+        Logger {
+            level,
+            out: Mutex::new(out),
+            next_span: AtomicU64::new(1),
+        }
+    }
+}
+
+/// Send events at `level` and more important to stderr for the rest of the
+/// program. Only the first call does anything, since there's only one place
+/// for them to go.
+pub fn init(level: LevelFilter) {
+    let _ = tracing::subscriber::set_global_default(Logger::new(level, io::stderr()));
+}
+
+/// The level `RUST_LOG` asks for. Besides a plain level like `debug`, we take
+/// the level of a `crossgrep=LEVEL` directive, since that's what people used
+/// to other programs' `RUST_LOG` will try. Other directives are ignored.
+pub fn level_from_env(raw: &str) -> Option<LevelFilter> {
+    // later directives win, like they do for everyone else.
+    raw.split(',')
+        .rev()
+        .find_map(|directive| match directive.trim().split_once('=') {
+            None => LevelFilter::from_str(directive.trim()).ok(),
+            Some((target, level)) if target.trim() == "crossgrep" => {
+                LevelFilter::from_str(level.trim()).ok()
+            }
+            Some(_) => None,
+        })
+}
+
+impl<W: Write + 'static> Subscriber for Logger<W> {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= &self.level
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.level)
+    }
+
+    fn new_span(&self, _span: &Attributes<'_>) -> Id {
+        Id::from_u64(self.next_span.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut line = Line::default();
+        event.record(&mut line);
+
+        let level = match *event.metadata().level() {
+            Level::ERROR => "error",
+            Level::WARN => "warning",
+            Level::INFO => "info",
+            Level::DEBUG => "debug",
+            Level::TRACE => "trace",
+        };
+
+        // there's nowhere left to report a failure to write a log line.
+        if let Ok(mut out) = self.out.lock() {
+            let _ = writeln!(out, "{}: {}{}", level, line.message, line.fields);
+        }
+    }
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+/// An event's message, and the rest of its fields as ` name=value`.
+#[derive(Default)]
+struct Line {
+    message: String,
+    fields: String,
+}
+
+impl Visit for Line {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Dispatch;
+
+    #[test]
+    fn events_are_lines_with_fields() {
+        let dispatch = Dispatch::new(Logger::new(LevelFilter::INFO, Vec::new()));
+        tracing::dispatcher::with_default(&dispatch, || {
+            tracing::warn!(path = "a.rs", bytes = ?(1..3), "tokenization failed");
+            tracing::info!(matches = 2, "searched a file");
+            tracing::debug!("too quiet to see");
+        });
+
+        let logger = dispatch.downcast_ref::<Logger<Vec<u8>>>().unwrap();
+        let out = logger.out.lock().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "warning: tokenization failed path=\"a.rs\" bytes=1..3\ninfo: searched a file matches=2\n"
+        );
+    }
+
+    #[test]
+    fn rust_log_takes_levels_and_our_directive() {
+        assert_eq!(level_from_env("debug"), Some(LevelFilter::DEBUG));
+        assert_eq!(
+            level_from_env("ignore=trace,crossgrep=info"),
+            Some(LevelFilter::INFO)
+        );
+        assert_eq!(level_from_env("ignore=trace"), None);
+        assert_eq!(level_from_env("loud"), None);
+    }
+}
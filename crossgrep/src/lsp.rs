@@ -0,0 +1,58 @@
+use anyhow::{Context, Result};
+use crossgrep::extractor::FileMatch;
+use serde::Serialize;
+use std::path::Path;
+use tree_sitter::Point;
+use url::Url;
+
+/// A match as a location in the language server protocol, so editor plugins
+/// can use our results directly. Lines and characters start at 0 here, unlike
+/// in the rest of our output.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Location {
+    uri: String,
+    range: Range,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct Range {
+    start: Position,
+    end: Position,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct Position {
+    line: usize,
+    character: usize,
+}
+
+impl From<Point> for Position {
+    fn from(point: Point) -> Self {
+        Position {
+            line: point.row,
+            character: point.column,
+        }
+    }
+}
+
+impl Location {
+    /// URIs have to be absolute, so relative paths are taken to be relative to
+    /// `current_dir`.
+    pub fn new(file_match: &FileMatch, current_dir: &Path) -> Result<Self> {
+        let file = file_match
+            .file()
+            .context("can only give locations for matches in files")?;
+
+        let uri = Url::from_file_path(current_dir.join(file))
+            .ok()
+            .with_context(|| format!("could not make a URI for {}", file.display()))?;
+
+        Ok(Location {
+            uri: uri.into(),
+            range: Range {
+                start: file_match.extracted().start().into(),
+                end: file_match.extracted().end().into(),
+            },
+        })
+    }
+}
@@ -1,18 +1,47 @@
-mod chunker;
+mod archive;
 mod cli;
-mod extractor;
-mod extractor_chooser;
-mod model;
-mod tree_view;
+mod dot;
+mod exec;
+mod explain;
+mod kinds;
+mod library;
+mod logging;
+mod lsp;
+mod output;
+mod presets;
+mod progress;
+mod remote;
+mod stats;
+mod template;
+mod watch;
 
 use anyhow::{bail, Context, Result};
-use cli::{Invocation, QueryFormat, QueryOpts, TreeOpts};
+use archive::ArchiveKind;
+use cli::{GroupBy, Invocation, QueryFormat, QueryOpts, TreeInput, TreeOpts};
 use crossbeam::channel;
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use crossgrep::cancellation::CancellationToken;
+use crossgrep::diagnostics::Diagnostic;
+use crossgrep::extractor::{ExtractedFile, Extractor, FileMatch};
+use crossgrep::extractor_chooser::ExtractorChooser;
+use crossgrep::tree_view;
 use crossgrep_sys::Language;
+use dot::DotGraph;
+use explain::{Explanation, Walk};
+use kinds::KindCounts;
+use output::{MatchWriter, ResultWriter};
+use progress::Progress;
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelRefIterator, ParallelBridge, ParallelIterator,
+};
+use serde::Serialize;
+use stats::Stats;
+use std::collections::BTreeMap;
 use std::env;
-use std::fs;
-use std::io::{self, BufWriter, Write};
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
 use tree_sitter::Parser;
 
 // #[global_allocator]
@@ -23,62 +52,186 @@ fn main() {
 
     tokenizers::utils::parallelism::set_parallelism(false);
 
-    if let Err(error) = try_main(env::args().collect(), &mut buffer) {
-        if let Some(err) = error.downcast_ref::<io::Error>() {
-            // a broken pipe is totally normal and fine. It's what we get when
-            // we pipe to something like `head` that only takes a certain number
-            // of lines.
-            if err.kind() == io::ErrorKind::BrokenPipe {
-                std::process::exit(0);
+    let result = try_main(
+        env::args().collect(),
+        &mut buffer,
+        io::stdout().is_terminal(),
+    );
+
+    match &result {
+        Ok(_) => buffer.flush().expect("failed to flush buffer!"),
+        Err(error) if is_broken_pipe(error) => (),
+        Err(error) => {
+            // show whatever we got through before things went wrong (like
+            // the queries that did compile for --check.) We're already
+            // reporting an error, so there's no point complaining about
+            // another one here.
+            let _ = buffer.flush();
+
+            if let Some(clap_error) = error.downcast_ref::<clap::Error>() {
+                // Clap errors (--help or misuse) are already well-formatted,
+                // so we don't have to do any additional work.
+                eprint!("{}", clap_error);
+            } else {
+                eprintln!("{:?}", error);
             }
         }
+    }
+
+    std::process::exit(exit_code(&result));
+}
 
-        if let Some(clap_error) = error.downcast_ref::<clap::Error>() {
-            // Clap errors (--help or misuse) are already well-formatted,
-            // so we don't have to do any additional work.
-            eprint!("{}", clap_error);
-            if clap_error.kind() == clap::error::ErrorKind::DisplayHelp
-                || clap_error.kind() == clap::error::ErrorKind::DisplayVersion
+/// We follow grep here: 0 if we found something, 1 if we didn't, and 2 if
+/// something went wrong.
+fn exit_code(result: &Result<bool>) -> i32 {
+    match result {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(error) if is_broken_pipe(error) => 0,
+        Err(error) => match error.downcast_ref::<clap::Error>() {
+            Some(clap_error)
+                if clap_error.kind() == clap::error::ErrorKind::DisplayHelp
+                    || clap_error.kind() == clap::error::ErrorKind::DisplayVersion =>
             {
-                std::process::exit(0);
+                0
             }
-        } else {
-            eprintln!("{:?}", error);
-        }
-
-        std::process::exit(1);
+            _ => 2,
+        },
     }
+}
 
-    buffer.flush().expect("failed to flush buffer!");
+// a broken pipe is totally normal and fine. It's what we get when we pipe to
+// something like `head` that only takes a certain number of lines.
+fn is_broken_pipe(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<io::Error>()
+        .map(|err| err.kind() == io::ErrorKind::BrokenPipe)
+        .unwrap_or(false)
 }
 
-fn try_main(args: Vec<String>, out: impl Write) -> Result<()> {
-    let invocation = Invocation::from_args(args)
+/// Run crossgrep with the given arguments, returning whether anything
+/// matched. Things that don't search (like `--languages`) count as matching.
+/// `out_is_terminal` says whether a person is going to be reading `out`, which
+/// changes some output defaults.
+fn try_main(args: Vec<String>, out: impl Write, out_is_terminal: bool) -> Result<bool> {
+    let matches = Invocation::parse_args(args)
+        .context("couldn't get a valid configuration from the command-line options")?;
+    // before building anything else, so setting up extractors can log too.
+    logging::init(Invocation::log_level(&matches)?);
+    let invocation = Invocation::from_matches(matches)
         .context("couldn't get a valid configuration from the command-line options")?;
 
     match invocation {
         Invocation::DoQuery(query_opts) => {
-            do_query(query_opts, out).context("couldn't perform the query")
+            do_query(*query_opts, out, out_is_terminal).context("couldn't perform the query")
         }
-        Invocation::ShowLanguages => {
-            show_languages(out).context("couldn't show the list of languages")
+        Invocation::ShowLanguages(format) => show_languages(&format, out)
+            .map(|()| true)
+            .context("couldn't show the list of languages"),
+        Invocation::ShowTree(tree_opts) => show_tree(tree_opts, out)
+            .map(|()| true)
+            .context("couldn't show the tree"),
+        Invocation::CheckQueries(targets) => check_queries(&targets, out).map(|()| true),
+        Invocation::Explain(query_opts, path) => explain(&query_opts, &path, out)
+            .with_context(|| format!("couldn't explain {}", path.display())),
+    }
+}
+
+fn show_languages(format: &QueryFormat, mut out: impl Write) -> Result<()> {
+    match format {
+        QueryFormat::Lines => {
+            for language in Language::all() {
+                writeln!(out, "{}", language).context("couldn't print a language")?;
+            }
         }
-        Invocation::ShowTree(tree_opts) => {
-            show_tree(tree_opts, out).context("couldn't show the tree")
+        QueryFormat::Json | QueryFormat::PrettyJson => {
+            let infos = LanguageInfo::all()?;
+            if let QueryFormat::PrettyJson = format {
+                serde_json::to_writer_pretty(&mut out, &infos)
+            } else {
+                serde_json::to_writer(&mut out, &infos)
+            }
+            .context("couldn't write the languages as JSON")?;
+            writeln!(out).context("couldn't print a newline")?;
         }
+        _ => bail!("--languages can only be shown as lines, json, or pretty-json"),
+    }
+
+    Ok(())
+}
+
+/// What `--languages --format json` says about each language.
+#[derive(Debug, Serialize)]
+struct LanguageInfo {
+    name: String,
+    aliases: &'static [&'static str],
+    /// Extensions (without the dot) of the files we search with this
+    /// language, from the same file type definitions the search uses.
+    extensions: Vec<String>,
+    abi_version: usize,
+}
+
+impl LanguageInfo {
+    fn all() -> Result<Vec<LanguageInfo>> {
+        let mut types_builder = ignore::types::TypesBuilder::new();
+        types_builder.add_defaults();
+        let definitions = types_builder.definitions();
+
+        Language::all()
+            .iter()
+            .map(|language| {
+                let definition = definitions
+                    .iter()
+                    .find(|def| def.name() == language.name_for_types_builder())
+                    .with_context(|| format!("no file type for {}", language))?;
+
+                Ok(LanguageInfo {
+                    name: language.to_string(),
+                    aliases: language.aliases(),
+                    extensions: definition
+                        .globs()
+                        .iter()
+                        .filter_map(|glob| glob.strip_prefix("*."))
+                        .map(String::from)
+                        .collect(),
+                    abi_version: language.language().version(),
+                })
+            })
+            .collect()
     }
 }
 
-fn show_languages(mut out: impl Write) -> Result<()> {
-    for language in Language::all() {
-        writeln!(out, "{}", language).context("couldn't print a language")?;
+fn check_queries(targets: &[(String, String)], mut out: impl Write) -> Result<()> {
+    let mut failed = 0;
+
+    for (lang, query) in targets {
+        let compiled = if lang == cli::REGEX_TARGET {
+            cli::compile_regex_target(query).map(|_| ())
+        } else {
+            cli::compile_target(lang, query).map(|_| ())
+        };
+        match compiled {
+            Ok(()) => writeln!(out, "ok: {} {:?}", lang, query),
+            Err(err) => {
+                failed += 1;
+                writeln!(out, "error: {} {:?}: {:?}", lang, query, err)
+            }
+        }
+        .context("couldn't print a check result")?;
+    }
+
+    if failed > 0 {
+        bail!("{} of {} queries didn't compile", failed, targets.len())
     }
 
     Ok(())
 }
 
 fn show_tree(opts: TreeOpts, out: impl Write) -> Result<()> {
-    let source = fs::read_to_string(opts.path).context("could not read target file")?;
+    let source = match opts.input {
+        TreeInput::Path(path) => fs::read_to_string(path).context("could not read target file")?,
+        TreeInput::Text(text) => text,
+    };
 
     let mut parser = Parser::new();
     parser
@@ -92,73 +245,748 @@ fn show_tree(opts: TreeOpts, out: impl Write) -> Result<()> {
     tree_view::tree_view(&tree, source.as_bytes(), out)
 }
 
-fn do_query(opts: QueryOpts, mut out: impl Write) -> Result<()> {
+fn do_query(mut opts: QueryOpts, mut out: impl Write, out_is_terminal: bool) -> Result<bool> {
+    let heading = opts.heading(out_is_terminal);
+
+    let stats = Stats::default();
+
+    if let Some(remote) = &opts.remote {
+        let checkout = remote
+            .checkout()
+            .with_context(|| format!("could not clone {}", remote.url))?;
+        opts.paths = opts
+            .paths
+            .iter()
+            .map(|path| checkout.join(path))
+            .collect::<Result<_>>()?;
+        opts.checkout = Some(checkout);
+    }
+
+    // with --quiet we only need to know whether anything matched, and with
+    // --max-total only need so many matches, so we stop extracting from the
+    // rest of the files as soon as we have enough.
+    let stop = CancellationToken::new();
+    if opts.quiet || opts.max_total.is_some() {
+        for extractor in opts.extractors.iter_mut() {
+            extractor.set_cancellation_token(stop.clone());
+        }
+        if let Some(regex) = &mut opts.regex {
+            regex.set_cancellation_token(stop.clone());
+        }
+    }
+
+    let show_progress = opts.progress && io::stderr().is_terminal();
+    // --quiet only needs one match (and --max-total only so many), so we
+    // search files as the walk finds them and stop walking as soon as we have
+    // enough. Everything else walks first, since that's faster overall (see
+    // below.)
+    let search_while_walking = (opts.quiet || opts.max_total.is_some())
+        && !show_progress
+        && opts.text.is_none()
+        && opts.files_from.is_none();
+
     // You might think "why not use ParallelBridge here?" Well, the quick answer
     // is that I benchmarked it and having things separated here and handling
     // their own errors actually speeds up this part of the code by like 20%!
-    let items: Vec<ignore::DirEntry> =
-        find_files(&opts).context("had a problem while walking the filesystem")?;
+    let items: Vec<PathBuf> = match (&opts.text, &opts.files_from) {
+        (Some(_), _) => Vec::new(),
+        _ if search_while_walking => Vec::new(),
+        (None, Some(list)) => files_from(list, &opts, &stats)
+            .with_context(|| format!("could not read the list of files from {}", list.display()))?,
+        (None, None) => {
+            find_files(&opts, &stats).context("had a problem while walking the filesystem")?
+        }
+    };
 
     let chooser = opts
         .extractor_chooser()
         .context("couldn't construct a filetype matcher")?;
 
-    let mut extracted_files = items
-        .par_iter()
-        .filter_map(|entry| {
-            chooser
-                .extractor_for(entry)
-                .map(|extractor| (entry, extractor))
-        })
-        .map_init(Parser::new, |parser, (entry, extractor)| {
-            extractor
-                .extract_from_file(entry.path(), parser)
-                .with_context(|| {
-                    format!("could not extract matches from {}", entry.path().display())
-                })
-        })
-        .filter_map(|result_containing_option| match result_containing_option {
-            Ok(None) => None,
-            Ok(Some(extraction)) => Some(Ok(extraction)),
-            Err(err) => Some(Err(err)),
+    let found = if let Some(text) = &opts.text {
+        let result = search_text(text, &opts, &stats, &stop)?;
+        write_output(&mut out, &opts, heading, result.into_iter())?
+    } else if show_progress {
+        stats.will_search(
+            items
+                .iter()
+                .filter(|path| will_search_file(path, &opts, &chooser))
+                .count(),
+        );
+
+        // results can't go to the terminal while we're drawing progress
+        // there, so we hold on to them until the search is done.
+        let extracted_files = Progress::new(&stats).show_while(|| {
+            extract_all(
+                items.par_iter().cloned().enumerate(),
+                &opts,
+                &chooser,
+                &stats,
+                &stop,
+                |extracted| extracted.collect::<Vec<_>>(),
+            )
+        });
+        write_output(&mut out, &opts, heading, extracted_files.into_iter())?
+    } else if search_while_walking {
+        thread::scope(|scope| {
+            let (sender, receiver) = channel::unbounded();
+            let walk = scope.spawn(|| walk_files(&opts, &stats, &stop, sender));
+
+            let found = extract_all(
+                receiver.into_iter().enumerate().par_bridge(),
+                &opts,
+                &chooser,
+                &stats,
+                &stop,
+                |extracted| write_output(&mut out, &opts, heading, extracted),
+            );
+
+            walk.join()
+                .expect("the walk panicked")
+                .context("had a problem while walking the filesystem")?;
+            found
+        })?
+    } else {
+        extract_all(
+            items.par_iter().cloned().enumerate(),
+            &opts,
+            &chooser,
+            &stats,
+            &stop,
+            |extracted| write_output(&mut out, &opts, heading, extracted),
+        )?
+    };
+
+    report_search(&mut out, &opts, &stats)?;
+
+    if opts.watch {
+        out.flush().context("could not flush output")?;
+
+        let watch_paths = match opts.files_from {
+            Some(_) => items.clone(),
+            None => opts.paths.clone(),
+        };
+
+        watch::watch(&watch_paths, |changed| {
+            let stats = Stats::default();
+            for extractor in &opts.extractors {
+                extractor.reset_counts();
+            }
+            let changed = changed_files_to_search(changed, &opts, &stats);
+
+            if opts.watch_clear {
+                // clear the screen and move the cursor to the top left
+                write!(out, "\x1B[2J\x1B[H").context("could not clear the screen")?;
+            }
+
+            let written = extract_all(
+                changed.par_iter().cloned().enumerate(),
+                &opts,
+                &chooser,
+                &stats,
+                &stop,
+                |extracted| write_output(&mut out, &opts, heading, extracted),
+            );
+            match written {
+                Ok(_) => (),
+                Err(err) if is_broken_pipe(&err) => return Err(err),
+                // a file can be half-written when we see it change, so
+                // problems here shouldn't stop us from watching for the
+                // next change.
+                Err(err) => opts.diagnostics.report(Diagnostic {
+                    file: None,
+                    code: "search-failed",
+                    message: format!("{:?}", err),
+                }),
+            }
+
+            report_search(&mut out, &opts, &stats)?;
+            out.flush().context("could not flush output")
+        })?;
+    }
+
+    Ok(found)
+}
+
+/// Print what `--stats` and `--report-unmatched-captures` ask for once a
+/// search is done.
+fn report_search(out: &mut impl Write, opts: &QueryOpts, stats: &Stats) -> Result<()> {
+    if opts.stats {
+        // so the stats come after all the results when both go to a terminal
+        out.flush().context("could not flush output")?;
+        stats.set_files_with_parse_errors(
+            opts.extractors
+                .iter()
+                .map(Extractor::files_with_parse_errors)
+                .sum(),
+        );
+        let mut loaded: Vec<Language> = Vec::new();
+        for extractor in &opts.extractors {
+            if !loaded.contains(extractor.language()) {
+                loaded.push(*extractor.language());
+            }
+        }
+        stats.set_grammars(loaded, opts.failed_grammars.clone());
+        eprint!("{}", stats);
+    }
+
+    if opts.report_unmatched_captures {
+        out.flush().context("could not flush output")?;
+        for extractor in &opts.extractors {
+            for name in extractor.unmatched_captures() {
+                opts.diagnostics.report(Diagnostic {
+                    file: None,
+                    code: "unmatched-capture",
+                    message: format!(
+                        "@{} in the {} query never matched anything",
+                        name,
+                        extractor.language()
+                    ),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// How many paths' worth of results can be waiting to be written before we
+/// stop extracting more. This keeps memory use flat on big searches when
+/// writing is slower than searching.
+const IN_FLIGHT: usize = 64;
+
+/// Extract matches from files (and archives) in parallel, handing them to
+/// `consume` as they're found. `paths` come numbered, and their results come
+/// out in that order, whichever order they finish in. If `consume` stops
+/// reading early, or `stop` is cancelled, we stop extracting.
+fn extract_all<'extractor, T>(
+    paths: impl ParallelIterator<Item = (usize, PathBuf)>,
+    opts: &QueryOpts,
+    chooser: &'extractor ExtractorChooser,
+    stats: &Stats,
+    stop: &CancellationToken,
+    consume: impl FnOnce(Extracted<'extractor, '_>) -> T,
+) -> T {
+    thread::scope(|scope| {
+        let (sender, receiver) = channel::bounded(IN_FLIGHT);
+
+        scope.spawn(move || {
+            // an error here means `consume` is done with the results, so
+            // there's no point in extracting any more of them.
+            let _ = paths.try_for_each_init(
+                || (opts.parser(), sender.clone()),
+                |(parser, sender), (index, path)| {
+                    // once we've found everything we need, the rest of the
+                    // files don't get searched (or counted as searched.) They
+                    // still need a place in line, even if it's empty.
+                    if stop.is_cancelled() {
+                        return sender.send((index, Vec::new()));
+                    }
+
+                    let results = match archive_kind(&path, opts) {
+                        Some(kind) => search_archive(&path, kind, opts, chooser, stats, parser),
+                        None => vec![search_file(&path, opts, chooser, stats, parser)],
+                    };
+
+                    let results = results
+                        .into_iter()
+                        .filter_map(|result| keep_result(Some(&path), result, opts, stop))
+                        .collect();
+                    sender.send((index, results))
+                },
+            );
+        });
+
+        consume(Extracted {
+            receiver,
+            next_index: 0,
+            pending: BTreeMap::new(),
+            current: Vec::new().into_iter(),
+            limit: Limit::new(opts, stats, stop),
         })
-        .collect::<Result<Vec<extractor::ExtractedFile>>>()
-        .context("couldn't extract matches from files")?;
+    })
+}
+
+/// Decide what to do with the result of searching one file (or one file in
+/// the archive at `path`): pass it on, or drop it (with a warning, if it's
+/// worth one.)
+fn keep_result<'extractor>(
+    path: Option<&Path>,
+    result: Result<Option<ExtractedFile<'extractor>>>,
+    opts: &QueryOpts,
+    stop: &CancellationToken,
+) -> Option<Result<ExtractedFile<'extractor>>> {
+    match result {
+        Ok(None) => None,
+        Ok(Some(mut extraction)) => {
+            if !opts.line_ranges.is_empty() {
+                let file = extraction.file().map(Path::to_path_buf);
+                extraction
+                    .retain_matches(|extracted| opts.in_line_ranges(file.as_deref(), extracted));
+                if extraction.matches().is_empty() {
+                    return None;
+                }
+            }
+
+            if opts.quiet {
+                stop.cancel();
+            }
+            Some(Ok(extraction))
+        }
+        // a file that takes too long to parse shouldn't take the whole
+        // search down with it, so we skip it and keep going.
+        Err(err)
+            if matches!(
+                err.downcast_ref::<crossgrep::Error>(),
+                Some(crossgrep::Error::ParseTimeout)
+            ) =>
+        {
+            opts.diagnostics.report(Diagnostic {
+                file: path,
+                code: "parse-timeout",
+                message: format!("{:#}", err),
+            });
+            None
+        }
+        Err(err) => Some(Err(err)),
+    }
+}
 
+type PathResults<'extractor> = Vec<Result<ExtractedFile<'extractor>>>;
+
+/// The results of `extract_all`, put back in the order of the paths they
+/// came from.
+struct Extracted<'extractor, 'run> {
+    receiver: channel::Receiver<(usize, PathResults<'extractor>)>,
+    next_index: usize,
+    // results for paths that finished before the ones ahead of them
+    pending: BTreeMap<usize, PathResults<'extractor>>,
+    current: std::vec::IntoIter<Result<ExtractedFile<'extractor>>>,
+    limit: Limit<'run>,
+}
+
+impl<'extractor> Iterator for Extracted<'extractor, '_> {
+    type Item = Result<ExtractedFile<'extractor>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.limit.reached() {
+                return None;
+            }
+
+            if let Some(result) = self.current.next() {
+                return Some(
+                    result
+                        .map(|file| self.limit.hand_out(file))
+                        .context("couldn't extract matches from files"),
+                );
+            }
+
+            match self.pending.remove(&self.next_index) {
+                Some(results) => {
+                    self.next_index += 1;
+                    self.current = results.into_iter();
+                }
+                None => {
+                    let (index, results) = self.receiver.recv().ok()?;
+                    self.pending.insert(index, results);
+                }
+            }
+        }
+    }
+}
+
+/// Keeps track of the matches we hand out: at most `--max-total` of them, all
+/// counted in the stats. Matches past the limit are never printed, so they
+/// aren't counted either.
+struct Limit<'run> {
+    // how many more matches we can hand out. Once that's none, we stop `stop`
+    // so the walk and the workers give up on the rest.
+    remaining: Option<usize>,
+    stats: &'run Stats,
+    stop: CancellationToken,
+}
+
+impl<'run> Limit<'run> {
+    fn new(opts: &QueryOpts, stats: &'run Stats, stop: &CancellationToken) -> Self {
+        Limit {
+            remaining: opts.max_total,
+            stats,
+            stop: stop.clone(),
+        }
+    }
+
+    fn reached(&self) -> bool {
+        self.remaining == Some(0)
+    }
+
+    /// Trim `file` to the matches we can still hand out, and count them.
+    fn hand_out<'extractor>(
+        &mut self,
+        mut file: ExtractedFile<'extractor>,
+    ) -> ExtractedFile<'extractor> {
+        if let Some(remaining) = &mut self.remaining {
+            let mut kept = 0;
+            file.retain_matches(|_| {
+                kept += 1;
+                kept <= *remaining
+            });
+            *remaining -= file.matches().len();
+
+            if *remaining == 0 {
+                self.stop.cancel();
+            }
+        }
+
+        self.stats.found_matches(file.matches().len());
+        file
+    }
+}
+
+/// Write results in whatever format we were asked for, as they come in where
+/// we can. Returns whether there were any.
+fn write_output<'extractor>(
+    mut out: impl Write,
+    opts: &QueryOpts,
+    heading: bool,
+    mut extracted: impl Iterator<Item = Result<ExtractedFile<'extractor>>>,
+) -> Result<bool> {
+    if opts.quiet {
+        // there's nothing to print, and one match is all we need to see.
+        return Ok(extracted.next().transpose()?.is_some());
+    }
+
+    // grouping has to see every match before it can print any of them
+    if let Some(GroupBy::Capture) = opts.group_by {
+        let extracted_files = extracted.collect::<Result<Vec<ExtractedFile>>>()?;
+        let groups = CaptureGroup::group(&extracted_files);
+        let found = !extracted_files.is_empty();
+
+        match MatchWriter::for_opts(opts)? {
+            Some(mut writer) => {
+                for file_match in groups.iter().flat_map(|group| &group.matches) {
+                    writer.write(&mut out, file_match)?;
+                }
+                writer.finish(&mut out)?;
+            }
+            None => {
+                let mut writer = ResultWriter::new(&opts.format, opts.number_lines);
+                for group in &groups {
+                    writer.write(&mut out, group)?;
+                }
+                writer.finish(&mut out)?;
+            }
+        }
+
+        if opts.count_by_kind {
+            let mut kind_counts = KindCounts::default();
+            for extracted_file in &extracted_files {
+                kind_counts.add(extracted_file);
+            }
+            write_kind_counts(&mut out, opts, &kind_counts, found)?;
+        }
+
+        return Ok(found);
+    }
+
+    let heading = heading && matches!(opts.format, QueryFormat::Lines);
+    let mut match_writer = MatchWriter::for_opts(opts)?;
+    let mut result_writer = ResultWriter::new(&opts.format, opts.number_lines);
+    let mut dot_graph = match opts.format {
+        QueryFormat::Dot => Some(DotGraph::new(opts.dot_scope_edges)),
+        _ => None,
+    };
+    let mut kind_counts = KindCounts::default();
+    let mut found = false;
+    let mut total = 0;
+
+    for extracted_file in extracted {
+        let extracted_file = extracted_file?;
+
+        if opts.count_by_kind {
+            kind_counts.add(&extracted_file);
+        }
+
+        if opts.count_matches {
+            total += extracted_file.matches().len();
+        } else if let Some(writer) = &mut match_writer {
+            for file_match in extracted_file.file_matches() {
+                writer.write(&mut out, &file_match)?;
+            }
+        } else if heading {
+            if found {
+                writeln!(out).context("could not write lines")?;
+            }
+            output::write_lines(&mut out, &extracted_file.with_heading(), opts.number_lines)?;
+        } else if let Some(graph) = &mut dot_graph {
+            graph.push(&mut out, &extracted_file)?;
+        } else {
+            result_writer.write(&mut out, &extracted_file)?;
+        }
+
+        found = true;
+    }
+
+    if opts.count_matches && opts.count_by_kind {
+        // the counts are all we print
+        return write_kind_counts(&mut out, opts, &kind_counts, false).map(|()| found);
+    } else if opts.count_matches {
+        writeln!(out, "{}", total).context("could not write match count")?;
+    } else if let Some(writer) = match_writer {
+        writer.finish(&mut out)?;
+    } else if let Some(graph) = dot_graph {
+        graph.finish(&mut out)?;
+    } else {
+        result_writer.finish(&mut out)?;
+    }
+
+    if opts.count_by_kind {
+        write_kind_counts(&mut out, opts, &kind_counts, found)?;
+    }
+
+    Ok(found)
+}
+
+/// Print the `--count-by-kind` counts: as JSON for the JSON formats, and
+/// otherwise as a table set off from any results above it by a blank line.
+/// Only the lines format has results above it; the others need
+/// `--count-matches`, so they stay one document.
+fn write_kind_counts(
+    mut out: impl Write,
+    opts: &QueryOpts,
+    kind_counts: &KindCounts,
+    after_results: bool,
+) -> Result<()> {
     match opts.format {
-        QueryFormat::Lines => {
-            for extracted_file in extracted_files {
-                write!(out, "{}", extracted_file).context("could not write lines")?;
+        QueryFormat::Lines | QueryFormat::Dot => {
+            if after_results {
+                writeln!(out).context("could not write kind counts")?;
+            }
+            kind_counts.write_table(out)
+        }
+        _ => kind_counts.write_json(out),
+    }
+}
+
+/// Whether `search_file` would search `path` rather than skip it, without
+/// reading it. Archives are never searched this way.
+fn will_search_file(path: &Path, opts: &QueryOpts, chooser: &ExtractorChooser) -> bool {
+    let claimed = !chooser.extractors_for(path).is_empty() || chooser.fallback_for(path).is_some();
+
+    archive_kind(path, opts).is_none() && claimed && opts.file_path_matches(path)
+}
+
+fn search_file<'extractor>(
+    path: &Path,
+    opts: &QueryOpts,
+    chooser: &'extractor ExtractorChooser,
+    stats: &Stats,
+    parser: &mut Parser,
+) -> Result<Option<ExtractedFile<'extractor>>> {
+    let extractors = chooser.extractors_for(path);
+    let fallback = chooser.fallback_for(path);
+    if extractors.is_empty() && fallback.is_none() {
+        return Ok(None);
+    }
+
+    // after picking an extractor, so we only warn about paths outside
+    // --relative-to for files we'd search.
+    let shown = opts.shown_path(path);
+    if !opts.path_matches(&shown) {
+        return Ok(None);
+    }
+
+    stats.searched_file();
+
+    if let Some(regex) = fallback {
+        return fs::read(path)
+            .map_err(crossgrep::Error::Io)
+            .and_then(|source| regex.extract_from_text(Some(&shown), &source))
+            .with_context(|| format!("could not extract matches from {}", shown.display()));
+    }
+
+    match extractors {
+        [extractor] if shown == path => extractor.extract_from_file(path, parser),
+        _ => fs::read(path)
+            .map_err(crossgrep::Error::Io)
+            .and_then(|source| extract_with_all(extractors, Some(&shown), &source, parser)),
+    }
+    .with_context(|| format!("could not extract matches from {}", shown.display()))
+}
+
+/// Extract matches from `source` with each of `extractors` (which are all for
+/// its language) and put them together, for `--separate-queries`.
+fn extract_with_all<'extractor>(
+    extractors: &[&'extractor Extractor],
+    path: Option<&Path>,
+    source: &[u8],
+    parser: &mut Parser,
+) -> crossgrep::Result<Option<ExtractedFile<'extractor>>> {
+    let mut all: Option<ExtractedFile> = None;
+
+    for extractor in extractors {
+        if let Some(extracted) = extractor.extract_from_text(path, source, parser)? {
+            match &mut all {
+                Some(all) => all.extend(extracted),
+                None => all = Some(extracted),
             }
         }
+    }
 
-        QueryFormat::Json => {
-            serde_json::to_writer(out, &extracted_files).context("could not write JSON output")?;
+    Ok(all)
+}
+
+/// Search the source given with `--text` as if it were a file with no name.
+/// Without a name to pick a language with, there has to be only one (but
+/// there can be several queries for it with `--separate-queries`.)
+fn search_text<'extractor>(
+    text: &str,
+    opts: &'extractor QueryOpts,
+    stats: &Stats,
+    stop: &CancellationToken,
+) -> Result<Option<Result<ExtractedFile<'extractor>>>> {
+    let extractors: Vec<&Extractor> = opts.extractors.iter().collect();
+    let result = match (extractors.as_slice(), &opts.regex) {
+        ([], Some(regex)) => {
+            stats.searched_file();
+            regex.extract_from_text(None, text.as_bytes())
         }
+        ([first, rest @ ..], None)
+            if rest.iter().all(|other| other.language() == first.language()) =>
+        {
+            stats.searched_file();
+            extract_with_all(&extractors, None, text.as_bytes(), &mut opts.parser())
+        }
+        _ => bail!("--text needs queries for exactly one language (or only regexes), since there's no file name to pick one with"),
+    }
+    .context("could not extract matches from --text");
 
-        QueryFormat::JsonLines => {
-            for extracted_file in extracted_files {
-                writeln!(
-                    out,
-                    "{}",
-                    serde_json::to_string(&extracted_file)
-                        .context("could not write JSON output")?
-                )
-                .context("could not write line")?;
+    let mut limit = Limit::new(opts, stats, stop);
+    Ok(keep_result(None, result, opts, stop).map(|result| result.map(|file| limit.hand_out(file))))
+}
+
+/// Search the files inside an archive as if they were on disk, with paths like
+/// `archive.zip!src/lib.rs`. Since we can't look at their metadata before
+/// reading them, we check their size and whether they're binary as we go.
+fn search_archive<'extractor>(
+    path: &Path,
+    kind: ArchiveKind,
+    opts: &QueryOpts,
+    chooser: &'extractor ExtractorChooser,
+    stats: &Stats,
+    parser: &mut Parser,
+) -> Vec<Result<Option<ExtractedFile<'extractor>>>> {
+    let mut results = Vec::new();
+    let shown = opts.shown_path(path);
+
+    let searched = archive::for_each_file(path, kind, |inner, reader| {
+        let inner = archive::virtual_path(&shown, inner);
+        if !opts.path_matches(&inner) {
+            return Ok(());
+        }
+
+        let extractors = chooser.extractors_for(&inner);
+        let fallback = chooser.fallback_for(&inner);
+        if extractors.is_empty() && fallback.is_none() {
+            return Ok(());
+        }
+
+        let contents = match archive::read_limited(reader, opts.max_filesize)
+            .with_context(|| format!("could not read {}", inner.display()))?
+        {
+            Some(contents) => contents,
+            None => {
+                tracing::debug!(path = %inner.display(), "skipping a file bigger than --max-filesize");
+                stats.skipped_too_large();
+                return Ok(());
             }
+        };
+
+        if opts.skips_binary() && looks_binary(&contents) {
+            tracing::debug!(path = %inner.display(), "skipping a binary file");
+            stats.skipped_binary();
+            return Ok(());
         }
 
-        QueryFormat::PrettyJson => {
-            serde_json::to_writer_pretty(out, &extracted_files)
-                .context("could not write JSON output")?;
+        stats.will_search(1);
+        stats.searched_file();
+
+        let result = match fallback {
+            Some(regex) => regex.extract_from_text(Some(&inner), &contents),
+            None => extract_with_all(extractors, Some(&inner), &contents, parser),
+        };
+        results.push(
+            result.with_context(|| format!("could not extract matches from {}", inner.display())),
+        );
+
+        Ok(())
+    });
+
+    // a broken archive is one bad file, like a file we can't parse, so it
+    // shouldn't stop the search (or lose the matches we found before it broke.)
+    if let Err(err) = searched {
+        opts.diagnostics.report(Diagnostic {
+            file: Some(path),
+            code: "search-failed",
+            message: format!("could not search {}: {:#}", path.display(), err),
+        });
+    }
+
+    results
+}
+
+fn archive_kind(path: &Path, opts: &QueryOpts) -> Option<ArchiveKind> {
+    if opts.archives {
+        ArchiveKind::for_path(path)
+    } else {
+        None
+    }
+}
+
+/// All the matches for one capture name, across every file.
+#[derive(Serialize)]
+struct CaptureGroup<'file, 'query> {
+    capture: &'query str,
+    matches: Vec<FileMatch<'file, 'query>>,
+}
+
+impl<'file, 'query> CaptureGroup<'file, 'query> {
+    fn group(extracted_files: &'file [ExtractedFile<'query>]) -> Vec<Self> {
+        let mut groups: BTreeMap<&'query str, Vec<FileMatch<'file, 'query>>> = BTreeMap::new();
+
+        for file_match in extracted_files.iter().flat_map(|file| file.file_matches()) {
+            groups
+                .entry(file_match.extracted().name())
+                .or_default()
+                .push(file_match);
         }
+
+        groups
+            .into_iter()
+            .map(|(capture, matches)| CaptureGroup { capture, matches })
+            .collect()
     }
+}
 
-    Ok(())
+impl<'file, 'query> Display for CaptureGroup<'file, 'query> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for file_match in &self.matches {
+            // so `{:#}` numbers lines, like it does for a file's matches
+            Display::fmt(file_match, f)?;
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
 }
 
-fn find_files(opts: &QueryOpts) -> Result<Vec<ignore::DirEntry>> {
-    let mut builder = match opts.paths.split_first() {
+/// A walker over `roots` that skips what `opts` says to. With `toward` (a
+/// canonical path), it only goes into the directories on the way there.
+fn walk_builder(
+    roots: &[PathBuf],
+    opts: &QueryOpts,
+    toward: Option<PathBuf>,
+) -> Result<ignore::WalkBuilder> {
+    let mut builder = match roots.split_first() {
         Some((first, rest)) => {
             let mut builder = ignore::WalkBuilder::new(first);
             for path in rest {
@@ -170,25 +998,2029 @@ fn find_files(opts: &QueryOpts) -> Result<Vec<ignore::DirEntry>> {
         None => bail!("I need at least one file or directory to walk!"),
     };
 
-    let (root_sender, receiver) = channel::unbounded();
+    if !opts.exclude_dirs.is_empty() || toward.is_some() {
+        let exclude_dirs = opts.exclude_dirs.clone();
+        // the walker doesn't filter the paths it starts from, so asking for
+        // an excluded directory by name still searches it.
+        builder.filter_entry(move |entry| {
+            let excluded = entry.file_type().is_some_and(|ft| ft.is_dir())
+                && exclude_dirs.iter().any(|name| name == entry.file_name());
+            let on_the_way = toward.as_ref().is_none_or(|toward| {
+                fs::canonicalize(entry.path()).is_ok_and(|path| toward.starts_with(path))
+            });
+
+            !excluded && on_the_way
+        });
+    }
 
     builder
         .git_ignore(opts.git_ignore)
         .git_exclude(opts.git_ignore)
         .git_global(opts.git_ignore)
+        .parents(opts.ignore_parents)
+        .follow_links(opts.follow_links);
+
+    Ok(builder)
+}
+
+fn find_files(opts: &QueryOpts, stats: &Stats) -> Result<Vec<PathBuf>> {
+    let (sender, receiver) = channel::unbounded();
+    walk_files(opts, stats, &CancellationToken::new(), sender)?;
+
+    Ok(receiver.iter().collect())
+}
+
+/// Walk `opts.paths`, sending each file worth searching to `root_sender` as
+/// we find it. We stop early if `stop` is cancelled.
+fn walk_files(
+    opts: &QueryOpts,
+    stats: &Stats,
+    stop: &CancellationToken,
+    root_sender: channel::Sender<PathBuf>,
+) -> Result<()> {
+    walk_builder(&opts.paths, opts, None)?
         .build_parallel()
         .run(|| {
             let sender = root_sender.clone();
             Box::new(move |entry_result| match entry_result {
-                Ok(entry) => match sender.send(entry) {
+                _ if stop.is_cancelled() => ignore::WalkState::Quit,
+                Ok(entry) if entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false) => {
+                    ignore::WalkState::Continue
+                }
+                // without --follow, the walker hands us symlinks as they are,
+                // including ones that point at nothing.
+                Ok(entry) if entry.path_is_symlink() && !entry.path().exists() => {
+                    opts.diagnostics.report(Diagnostic {
+                        file: Some(entry.path()),
+                        code: "unreadable-path",
+                        message: format!(
+                            "skipping {}: it's a symlink to something that doesn't exist",
+                            entry.path().display()
+                        ),
+                    });
+                    ignore::WalkState::Continue
+                }
+                // the path is cheap to check, so we do it before anything
+                // that has to open the file.
+                Ok(entry) if !opts.file_path_matches(entry.path()) => ignore::WalkState::Continue,
+                // we check the size from the directory entry's metadata so we
+                // never have to read a giant file just to throw it away.
+                Ok(entry)
+                    if entry
+                        .metadata()
+                        .map(|metadata| is_skipped(entry.path(), &metadata, opts, stats))
+                        .unwrap_or(false) =>
+                {
+                    ignore::WalkState::Continue
+                }
+                Ok(entry) => match sender.send(entry.into_path()) {
                     Ok(()) => ignore::WalkState::Continue,
                     Err(_) => ignore::WalkState::Quit,
                 },
-                Err(_) => ignore::WalkState::Quit,
+                // the walker notices loops by comparing each directory to the
+                // ones above it (by device and inode), so we can skip just
+                // the symlink that closes the loop.
+                Err(err) if is_symlink_loop(&err) => ignore::WalkState::Continue,
+                // one path we can't read (like a broken symlink we were told
+                // to follow, or a directory we don't have permission for)
+                // shouldn't stop the rest of the walk.
+                Err(err) => {
+                    opts.diagnostics.report(Diagnostic {
+                        file: walk_error_path(&err),
+                        code: "unreadable-path",
+                        message: format!("skipping {}", err),
+                    });
+                    ignore::WalkState::Continue
+                }
             })
         });
 
-    drop(root_sender);
+    Ok(())
+}
 
-    Ok(receiver.iter().collect())
+/// Read the files to search from a list with one path per line (or from stdin
+/// if the list is `-`) instead of walking directories. Paths in the list that
+/// don't exist get a warning instead of stopping the whole search, since lists
+/// like this are usually generated and can go stale.
+fn files_from(list: &Path, opts: &QueryOpts, stats: &Stats) -> Result<Vec<PathBuf>> {
+    let raw = if list == Path::new("-") {
+        let mut raw = String::new();
+        io::stdin()
+            .read_to_string(&mut raw)
+            .context("could not read stdin")?;
+        raw
+    } else {
+        fs::read_to_string(list).context("could not read file")?
+    };
+
+    let mut paths = Vec::new();
+    for line in raw.lines().filter(|line| !line.trim().is_empty()) {
+        let path = PathBuf::from(line);
+
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                opts.diagnostics.report(Diagnostic {
+                    file: Some(&path),
+                    code: "unreadable-path",
+                    message: format!("skipping {}: {}", path.display(), err),
+                });
+                continue;
+            }
+        };
+
+        if metadata.is_dir() {
+            opts.diagnostics.report(Diagnostic {
+                file: Some(&path),
+                code: "is-a-directory",
+                message: format!("skipping {}: is a directory", path.display()),
+            });
+            continue;
+        }
+
+        if opts.file_path_matches(&path) && !is_skipped(&path, &metadata, opts, stats) {
+            paths.push(path);
+        }
+    }
+
+    Ok(paths)
+}
+
+fn is_symlink_loop(err: &ignore::Error) -> bool {
+    match err {
+        ignore::Error::Loop { .. } => true,
+        ignore::Error::WithPath { err, .. } | ignore::Error::WithDepth { err, .. } => {
+            is_symlink_loop(err)
+        }
+        _ => false,
+    }
+}
+
+/// The files out of `changed` that a search would have searched. We're told
+/// about every change under the watched paths, including ones in ignored,
+/// hidden, or excluded directories, so each one has to get past the same
+/// rules the walk applies.
+fn changed_files_to_search(changed: &[PathBuf], opts: &QueryOpts, stats: &Stats) -> Vec<PathBuf> {
+    changed
+        .iter()
+        .filter(|path| {
+            // files from a list were asked for by name, not walked to
+            let walked =
+                opts.files_from.is_some() || matches!(walk_to(opts, path), Ok(Walk::Found));
+
+            walked
+                && opts.file_path_matches(path)
+                && fs::metadata(path)
+                    .map(|metadata| !is_skipped(path, &metadata, opts, stats))
+                    .unwrap_or(false)
+        })
+        .cloned()
+        .collect()
+}
+
+/// The path a walk error is about, if it's about one.
+fn walk_error_path(err: &ignore::Error) -> Option<&Path> {
+    match err {
+        ignore::Error::WithPath { path, .. } => Some(path),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            walk_error_path(err)
+        }
+        ignore::Error::Loop { child, .. } => Some(child),
+        _ => None,
+    }
+}
+
+/// Why `is_skipped` skips a file.
+enum Skip {
+    TooLarge,
+    Binary,
+}
+
+/// Check a file against `--max-filesize` and `--binary`, counting it in the
+/// stats if it gets skipped.
+fn is_skipped(path: &Path, metadata: &fs::Metadata, opts: &QueryOpts, stats: &Stats) -> bool {
+    match skip_reason(path, metadata, opts) {
+        Some(Skip::TooLarge) => {
+            tracing::debug!(
+                path = %path.display(),
+                size = metadata.len(),
+                "skipping a file bigger than --max-filesize"
+            );
+            stats.skipped_too_large();
+            true
+        }
+        Some(Skip::Binary) => {
+            tracing::debug!(path = %path.display(), "skipping a binary file");
+            stats.skipped_binary();
+            true
+        }
+        None => false,
+    }
+}
+
+fn skip_reason(path: &Path, metadata: &fs::Metadata, opts: &QueryOpts) -> Option<Skip> {
+    if !metadata.is_file() {
+        return None;
+    }
+
+    // archives are (almost always) binary, and big ones can be full of small
+    // source files. We check the files inside them instead.
+    if archive_kind(path, opts).is_some() {
+        return None;
+    }
+
+    if opts
+        .max_filesize
+        .is_some_and(|max_filesize| metadata.len() > max_filesize)
+    {
+        return Some(Skip::TooLarge);
+    }
+
+    // binaries would give us garbage trees at best and trip over our UTF-8
+    // assumptions at worst, so we sniff for them here before anything reads
+    // the whole file. If we can't read the file here, we'll find out again
+    // (with a better error message) when we try to extract matches from it.
+    if opts.skips_binary() && sniff_binary(path).unwrap_or(false) {
+        return Some(Skip::Binary);
+    }
+
+    None
+}
+
+/// Say whether `path` would be searched, and why or why not, for
+/// `--explain`. Returns whether it would be.
+fn explain(opts: &QueryOpts, path: &Path, mut out: impl Write) -> Result<bool> {
+    let metadata = fs::metadata(path).context("could not read its metadata")?;
+    if metadata.is_dir() {
+        bail!("it's a directory, and --explain only explains files")
+    }
+
+    let chooser = opts
+        .extractor_chooser()
+        .context("couldn't construct a filetype matcher")?;
+
+    let explanation = Explanation {
+        path,
+        walk: walk_to(opts, path)?,
+        archive: archive_kind(path, opts).is_some(),
+        languages: chooser
+            .extractors_for(path)
+            .iter()
+            .map(|extractor| *extractor.language())
+            .collect(),
+        regex: chooser.fallback_for(path).is_some(),
+        size: metadata.len(),
+        max_filesize: opts.max_filesize,
+        binary: sniff_binary(path).unwrap_or(false),
+        search_binary: !opts.skips_binary(),
+        path_regex: opts
+            .path_regex
+            .as_ref()
+            .map(|_| opts.path_matches(&opts.shown_path(path))),
+        skipped: skip_reason(path, &metadata, opts).is_some(),
+    };
+
+    write!(out, "{}", explanation).context("could not write the explanation")?;
+    Ok(explanation.searched())
+}
+
+/// Walk toward `path` the same way `find_files` walks everything, to see
+/// whether the walk gets there.
+fn walk_to(opts: &QueryOpts, path: &Path) -> Result<Walk> {
+    let target = fs::canonicalize(path).context("could not find its canonical path")?;
+    let (root, canonical_root) = match opts.paths.iter().find_map(|root| {
+        fs::canonicalize(root)
+            .ok()
+            .filter(|canonical| target.starts_with(canonical))
+            .map(|canonical| (root, canonical))
+    }) {
+        Some(found) => found,
+        None => return Ok(Walk::Outside),
+    };
+
+    // the deepest directory on the way to `path` that the walk got to, as
+    // the walker shows it and canonically.
+    let mut reached = (root.clone(), canonical_root.clone());
+    let walk = walk_builder(std::slice::from_ref(root), opts, Some(target.clone()))?.build();
+    let mut errors = Vec::new();
+    for entry in walk {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // in case one of these is where the walk stopped
+            Err(err) => {
+                errors.push(err);
+                continue;
+            }
+        };
+        let canonical = match fs::canonicalize(entry.path()) {
+            Ok(canonical) => canonical,
+            Err(_) => continue,
+        };
+        if canonical == target {
+            return Ok(Walk::Found);
+        }
+        if canonical.starts_with(&reached.1) {
+            reached = (entry.into_path(), canonical);
+        }
+    }
+
+    // `target` is inside `reached` and isn't it, so there's always a next
+    // step.
+    let next = target
+        .strip_prefix(&reached.1)
+        .ok()
+        .and_then(|rest| rest.components().next())
+        .context("could not work out where the walk stopped")?;
+    let stopped = reached.1.join(next);
+
+    // an error reading where the walk got to (or the next step) is what
+    // stopped it, which is better than any reason we could work out.
+    let error = errors.into_iter().find(|err| {
+        walk_error_path(err)
+            .and_then(|path| fs::canonicalize(path).ok())
+            .is_some_and(|path| path == reached.1 || path == stopped)
+    });
+    Ok(Walk::Skipped {
+        path: reached.0.join(next),
+        reason: match error {
+            Some(err) => format!("the walker couldn't get past an error: {}", err),
+            None => explain::why_skipped(&stopped, &canonical_root, opts),
+        },
+    })
+}
+
+// this is the same heuristic git and ripgrep use: if there's a NUL byte in the
+// first few KB, we're probably not looking at source code.
+const BINARY_SNIFF_BYTES: u64 = 8 * 1024;
+
+fn sniff_binary(path: &Path) -> io::Result<bool> {
+    let mut head = Vec::with_capacity(BINARY_SNIFF_BYTES as usize);
+    File::open(path)?
+        .take(BINARY_SNIFF_BYTES)
+        .read_to_end(&mut head)?;
+
+    Ok(looks_binary(&head))
+}
+
+fn looks_binary(contents: &[u8]) -> bool {
+    let head = &contents[..contents.len().min(BINARY_SNIFF_BYTES as usize)];
+
+    head.contains(&0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn try_run(args: &[&str]) -> (Result<bool>, String) {
+        let mut out = Vec::new();
+        let result = try_main(
+            args.iter().map(|arg| arg.to_string()).collect(),
+            &mut out,
+            false,
+        );
+
+        (result, String::from_utf8(out).unwrap())
+    }
+
+    fn query_opts(args: &[&str]) -> QueryOpts {
+        match Invocation::from_args(args.iter().map(|arg| arg.to_string()).collect()) {
+            Ok(Invocation::DoQuery(opts)) => *opts,
+            Ok(_) => panic!("{:?} isn't a search", args),
+            Err(err) => panic!("{:#}", err),
+        }
+    }
+
+    /// A directory for a test's files, removed when it's dropped (even if
+    /// the test fails before it gets to the end.)
+    fn test_dir() -> tempfile::TempDir {
+        tempfile::Builder::new()
+            .prefix("crossgrep-test-")
+            .tempdir()
+            .unwrap()
+    }
+
+    fn run(args: &[&str]) -> String {
+        let (result, out) = try_run(args);
+        result.unwrap();
+
+        out
+    }
+
+    #[test]
+    fn json_output_is_an_array_even_when_empty() {
+        assert_eq!(
+            run(&[
+                "crossgrep",
+                "-m",
+                "codebert",
+                "-f",
+                "json",
+                "-t",
+                "javascript",
+                "(debugger_statement)",
+                "../tests/cmd/hello-world.js",
+                "--",
+                "query",
+            ]),
+            "[]"
+        );
+    }
+
+    #[test]
+    fn json_lines_output_is_empty_when_empty() {
+        assert_eq!(
+            run(&[
+                "crossgrep",
+                "-m",
+                "codebert",
+                "-f",
+                "json-lines",
+                "-t",
+                "javascript",
+                "(debugger_statement)",
+                "../tests/cmd/hello-world.js",
+                "--",
+                "query",
+            ]),
+            ""
+        );
+    }
+
+    #[test]
+    fn json_lines_matches_has_a_record_per_match() {
+        let out = run(&[
+            "crossgrep",
+            "-m",
+            "codebert",
+            "-f",
+            "json-lines-matches",
+            "-t",
+            "javascript",
+            "(identifier) @id (property_identifier) @prop",
+            "../tests/cmd/hello-world.js",
+            "--",
+            "query",
+        ]);
+
+        let records: Vec<serde_json::Value> = out
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        for (record, text) in records.iter().zip(&["console", "log"]) {
+            assert_eq!(record["file"], "../tests/cmd/hello-world.js");
+            assert_eq!(record["file_type"], "javascript");
+            assert_eq!(record["text"], *text);
+            assert!(record["chunks"].is_array());
+        }
+    }
+
+    #[test]
+    fn group_by_capture_collects_matches_by_name() {
+        let out = run(&[
+            "crossgrep",
+            "-m",
+            "codebert",
+            "-f",
+            "json",
+            "--group-by",
+            "capture",
+            "-t",
+            "javascript",
+            "(identifier) @id (string) @str",
+            "../tests/cmd/hello-world.js",
+            "--",
+            "query",
+        ]);
+
+        let groups: serde_json::Value = serde_json::from_str(&out).unwrap();
+        let names: Vec<&str> = groups
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|group| group["capture"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["id", "str"]);
+
+        for group in groups.as_array().unwrap() {
+            for file_match in group["matches"].as_array().unwrap() {
+                assert_eq!(file_match["name"], group["capture"]);
+                assert_eq!(file_match["file"], "../tests/cmd/hello-world.js");
+            }
+        }
+    }
+
+    #[test]
+    fn files_from_searches_listed_files_and_skips_missing_ones() {
+        let tmp = test_dir();
+        let list = tmp.path().join("files");
+        fs::write(
+            &list,
+            "../tests/cmd/hello-world.js\n\n../tests/cmd/does-not-exist.js\n",
+        )
+        .unwrap();
+
+        let out = run(&[
+            "crossgrep",
+            "-m",
+            "codebert",
+            "-t",
+            "javascript",
+            "(string) @str",
+            "--files-from",
+            list.to_str().unwrap(),
+            "--",
+            "query",
+        ]);
+
+        assert_eq!(
+            out,
+            "../tests/cmd/hello-world.js:1:13:str:\"Hello, World!\"\n"
+        );
+    }
+
+    #[test]
+    fn path_regex_filters_files_before_searching() {
+        let search = |regex| {
+            run(&[
+                "crossgrep",
+                "-m",
+                "codebert",
+                "-t",
+                "javascript",
+                "(string) @str",
+                "--path-regex",
+                regex,
+                "../tests/cmd/hello-world.js",
+                "--",
+                "query",
+            ])
+        };
+
+        assert_eq!(
+            search(r"hello-\w+\.js$"),
+            "../tests/cmd/hello-world.js:1:13:str:\"Hello, World!\"\n"
+        );
+        assert_eq!(search("goodbye"), "");
+    }
+
+    #[test]
+    fn path_regex_skips_files_before_reading_them() {
+        let tmp = test_dir();
+        fs::write(tmp.path().join("kept.js"), "a;\n").unwrap();
+        fs::write(tmp.path().join("binary.js"), b"\0\0\0").unwrap();
+
+        let find = |regex: &str| {
+            let opts = query_opts(&[
+                "crossgrep",
+                "-m",
+                "codebert",
+                "-t",
+                "javascript",
+                "(identifier) @id",
+                "--path-regex",
+                regex,
+                tmp.path().to_str().unwrap(),
+                "--",
+                "query",
+            ]);
+            let stats = Stats::default();
+            let files = find_files(&opts, &stats).unwrap();
+            (files, stats.to_string())
+        };
+
+        // the binary file is filtered out before anything sniffs it
+        let (files, stats) = find(r"kept\.js$");
+        assert_eq!(files, vec![tmp.path().join("kept.js")]);
+        assert!(stats.contains("binary files skipped: 0\n"), "{}", stats);
+
+        let (files, stats) = find(r"\.js$");
+        assert_eq!(files, vec![tmp.path().join("kept.js")]);
+        assert!(stats.contains("binary files skipped: 1\n"), "{}", stats);
+    }
+
+    #[test]
+    fn progress_only_counts_files_a_language_claims() {
+        let tmp = test_dir();
+        for name in ["a.js", "b.js", "notes.txt", "c.py"] {
+            fs::write(tmp.path().join(name), "a;\n").unwrap();
+        }
+        let opts = query_opts(&[
+            "crossgrep",
+            "-m",
+            "codebert",
+            "-t",
+            "javascript",
+            "(identifier) @id",
+            tmp.path().to_str().unwrap(),
+            "--",
+            "query",
+        ]);
+        let chooser = opts.extractor_chooser().unwrap();
+        let stats = Stats::default();
+
+        let paths = find_files(&opts, &stats).unwrap();
+        stats.will_search(
+            paths
+                .iter()
+                .filter(|path| will_search_file(path, &opts, &chooser))
+                .count(),
+        );
+        extract_all(
+            paths.par_iter().cloned().enumerate(),
+            &opts,
+            &chooser,
+            &stats,
+            &CancellationToken::new(),
+            |extracted| extracted.count(),
+        );
+
+        assert_eq!(paths.len(), 4);
+        assert_eq!(
+            Progress::new(&stats).to_string(),
+            "searched 2/2 files (100%)"
+        );
+    }
+
+    #[test]
+    fn count_matches_prints_the_total() {
+        let count = |query| {
+            run(&[
+                "crossgrep",
+                "-m",
+                "codebert",
+                "--count-matches",
+                "-t",
+                "javascript",
+                query,
+                "../tests/cmd/hello-world.js",
+                "--",
+                "query",
+            ])
+        };
+
+        assert_eq!(count("(identifier) @id (string) @str"), "2\n");
+        assert_eq!(count("(string) @str (string) @_ignored"), "1\n");
+        assert_eq!(count("(debugger_statement) @debugger"), "0\n");
+    }
+
+    #[test]
+    fn number_lines_only_changes_the_lines_format() {
+        let search = |extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-t",
+                "rust",
+                "(function_item) @fn",
+                "--text",
+                "\nfn a() {\n}",
+                "--number-lines",
+            ];
+            args.extend_from_slice(extra);
+            args.extend_from_slice(&["--", "query"]);
+            run(&args)
+        };
+
+        let numbered = "NO FILE:2:1:fn:\n  2 | fn a() {\n  3 | }\n";
+        assert_eq!(search(&[]), numbered);
+        assert_eq!(search(&["--group-by", "capture"]), numbered);
+        assert_eq!(
+            search(&["--heading"]),
+            "NO FILE\n2:1:fn:\n  2 | fn a() {\n  3 | }\n"
+        );
+        assert!(search(&["--format", "json-lines"]).contains("\"text\":\"fn a() {\\n}\""));
+    }
+
+    #[test]
+    fn exit_code_says_whether_anything_matched() {
+        let exit_code_for = |query| {
+            exit_code(
+                &try_run(&[
+                    "crossgrep",
+                    "-m",
+                    "codebert",
+                    "-t",
+                    "javascript",
+                    query,
+                    "../tests/cmd/hello-world.js",
+                    "--",
+                    "query",
+                ])
+                .0,
+            )
+        };
+
+        assert_eq!(exit_code_for("(string) @str"), 0);
+        assert_eq!(exit_code_for("(debugger_statement) @debugger"), 1);
+        assert_eq!(exit_code_for("(not_a_node) @oops"), 2);
+    }
+
+    #[test]
+    fn quiet_prints_nothing_but_still_reports_matches() {
+        let quiet = |query| {
+            try_run(&[
+                "crossgrep",
+                "-m",
+                "codebert",
+                "--quiet",
+                "-t",
+                "javascript",
+                query,
+                "../tests/cmd/hello-world.js",
+                "--",
+                "query",
+            ])
+        };
+
+        let (found, out) = quiet("(string) @str");
+        assert!(found.unwrap());
+        assert_eq!(out, "");
+
+        let (found, out) = quiet("(debugger_statement) @debugger");
+        assert!(!found.unwrap());
+        assert_eq!(out, "");
+    }
+
+    #[test]
+    fn nothing_is_walked_or_searched_once_stopped() {
+        let tmp = test_dir();
+        for name in ["a.js", "b.js", "c.js"] {
+            fs::write(tmp.path().join(name), "a;\n").unwrap();
+        }
+        let opts = query_opts(&[
+            "crossgrep",
+            "-m",
+            "codebert",
+            "--quiet",
+            "-t",
+            "javascript",
+            "(identifier) @id",
+            tmp.path().to_str().unwrap(),
+            "--",
+            "query",
+        ]);
+        let chooser = opts.extractor_chooser().unwrap();
+        let stats = Stats::default();
+        let stop = CancellationToken::new();
+        stop.cancel();
+
+        let (sender, receiver) = channel::unbounded();
+        walk_files(&opts, &stats, &stop, sender).unwrap();
+        assert_eq!(receiver.iter().count(), 0);
+
+        let paths = find_files(&opts, &stats).unwrap();
+        let found = extract_all(
+            paths.par_iter().cloned().enumerate(),
+            &opts,
+            &chooser,
+            &stats,
+            &stop,
+            |extracted| extracted.count(),
+        );
+        assert_eq!(paths.len(), 3);
+        assert_eq!(found, 0);
+        assert!(stats.to_string().starts_with("files searched: 0\n"));
+    }
+
+    #[test]
+    fn files_inside_archives_are_searched() {
+        let tmp = test_dir();
+        let dir = tmp.path();
+        let archive = dir.join("deps.tar");
+
+        let source = b"console.log(\"in an archive\");\n";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(source.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(File::create(&archive).unwrap());
+        builder
+            .append_data(&mut header, "src/index.js", &source[..])
+            .unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+
+        let search = |extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-m",
+                "codebert",
+                "-t",
+                "javascript",
+                "(string) @str",
+            ];
+            args.extend(extra);
+            args.extend([archive.to_str().unwrap(), "--", "query"]);
+            run(&args)
+        };
+
+        assert_eq!(
+            search(&[]),
+            format!(
+                "{}!src/index.js:1:13:str:\"in an archive\"\n",
+                archive.display()
+            )
+        );
+        assert_eq!(search(&["--no-archives"]), "");
+    }
+
+    #[test]
+    fn broken_archives_dont_stop_the_search() {
+        let tmp = test_dir();
+        let dir = tmp.path();
+        fs::write(dir.join("index.js"), "console.log(\"not archived\");\n").unwrap();
+
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        zip.start_file("src/index.js", Default::default()).unwrap();
+        zip.write_all(b"console.log(\"in an archive\");\n").unwrap();
+        let zipped = zip.finish().unwrap().into_inner();
+        fs::write(dir.join("deps.zip"), &zipped[..zipped.len() / 2]).unwrap();
+
+        let (result, out) = try_run(&[
+            "crossgrep",
+            "-m",
+            "codebert",
+            "-t",
+            "javascript",
+            "(string) @str",
+            dir.to_str().unwrap(),
+            "--",
+            "query",
+        ]);
+
+        assert!(result.unwrap());
+        assert_eq!(
+            out,
+            format!(
+                "{}:1:13:str:\"not archived\"\n",
+                dir.join("index.js").display()
+            )
+        );
+    }
+
+    #[test]
+    fn ignore_files_can_be_turned_off() {
+        let tmp = test_dir();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join(".gitignore"), "*.js\n").unwrap();
+        fs::write(dir.join("src/index.js"), "console.log(\"ignored\");\n").unwrap();
+        let src = dir.join("src");
+
+        let search = |extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-m",
+                "codebert",
+                "-t",
+                "javascript",
+                "(string) @str",
+            ];
+            args.extend(extra);
+            args.extend([src.to_str().unwrap(), "--", "query"]);
+            run(&args)
+        };
+
+        let found = format!("{}:1:13:str:\"ignored\"\n", src.join("index.js").display());
+        assert_eq!(search(&[]), "");
+        assert_eq!(search(&["--no-gitignore"]), found);
+        assert_eq!(search(&["--no-ignore-parent"]), found);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follow_searches_symlinked_directories_once() {
+        let tmp = test_dir();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("real")).unwrap();
+        fs::write(dir.join("real/index.js"), "console.log(\"hi\");\n").unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("linked")).unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("real/loop")).unwrap();
+
+        let search = |extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-m",
+                "codebert",
+                "-t",
+                "javascript",
+                "(string) @str",
+            ];
+            args.extend(extra);
+            args.extend([dir.to_str().unwrap(), "--", "query"]);
+
+            let mut lines: Vec<String> = run(&args).lines().map(String::from).collect();
+            lines.sort();
+            lines
+        };
+
+        let followed = search(&["--follow"]);
+        let not_followed = search(&[]);
+
+        // the file shows up once under each way of getting to it, and the
+        // loops back into `real` don't find it again.
+        let found = |path: &str| format!("{}:1:13:str:\"hi\"", dir.join(path).display());
+        assert_eq!(
+            followed,
+            vec![found("linked/index.js"), found("real/index.js")]
+        );
+        assert_eq!(not_followed, vec![found("real/index.js")]);
+    }
+
+    #[test]
+    fn utf16_files_arent_skipped_as_binary() {
+        let tmp = test_dir();
+        let source: Vec<u8> = "console.log(\"hi\");\n"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        fs::write(tmp.path().join("index.js"), source).unwrap();
+
+        let search = |extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-m",
+                "codebert",
+                "-t",
+                "javascript",
+                "(string) @str",
+            ];
+            args.extend(extra);
+            args.extend([tmp.path().to_str().unwrap(), "--", "query"]);
+            run(&args)
+        };
+
+        assert_eq!(search(&[]), "");
+        assert!(search(&["--encoding", "utf-16le"]).ends_with(":str:\"hi\"\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn broken_symlinks_dont_stop_the_walk() {
+        let tmp = test_dir();
+        let dir = tmp.path();
+        fs::write(dir.join("index.js"), "console.log(\"hi\");\n").unwrap();
+        std::os::unix::fs::symlink(dir.join("missing.js"), dir.join("broken.js")).unwrap();
+
+        let search = |extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-m",
+                "codebert",
+                "-t",
+                "javascript",
+                "(string) @str",
+            ];
+            args.extend(extra);
+            args.extend([dir.to_str().unwrap(), "--", "query"]);
+            let (result, out) = try_run(&args);
+            (result.map_err(|err| format!("{:#}", err)), out)
+        };
+
+        let found = format!("{}:1:13:str:\"hi\"\n", dir.join("index.js").display());
+        assert_eq!(search(&["--follow"]), (Ok(true), found.clone()));
+        assert_eq!(search(&[]), (Ok(true), found));
+    }
+
+    #[test]
+    fn several_models_chunk_each_match_for_each() {
+        let tmp = test_dir();
+        let config = tmp.path().join("models.json");
+        fs::write(&config, r#"{"chunk_size": 8, "chunk_overlap": 0}"#).unwrap();
+
+        let search = |models: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-f",
+                "json",
+                "-t",
+                "javascript",
+                "(string) @str",
+            ];
+            args.extend(models);
+            args.extend(["../tests/cmd/hello-world.js", "--", "query"]);
+            try_run(&args)
+        };
+
+        let (result, out) = search(&[
+            "-m",
+            "codebert",
+            "-m",
+            "codebert",
+            "--model-config",
+            config.to_str().unwrap(),
+        ]);
+        let (duplicate, _) = search(&["-m", "codebert", "-m", "codebert"]);
+
+        result.unwrap();
+        let results: serde_json::Value = serde_json::from_str(&out).unwrap();
+        let chunks = &results[0]["matches"][0]["chunks"];
+        assert_eq!(chunks["codebert"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            chunks[format!("codebert:{}", config.display())]
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+        assert!(duplicate.is_err());
+    }
+
+    #[test]
+    fn tokenizer_opts_are_checked_and_applied() {
+        let search = |option: &str| {
+            try_run(&[
+                "crossgrep",
+                "-f",
+                "json",
+                "-m",
+                "codebert",
+                "-t",
+                "javascript",
+                "(property_identifier) @prop",
+                "--tokenizer-opt",
+                option,
+                "../tests/cmd/hello-world.js",
+                "--",
+                "query",
+            ])
+        };
+
+        let ids = |option: &str| {
+            let (result, out) = search(option);
+            result.unwrap();
+            let results: serde_json::Value = serde_json::from_str(&out).unwrap();
+            results[0]["matches"][0]["chunks"][0]["ids"].clone()
+        };
+        let error = |option: &str| format!("{:#}", search(option).0.unwrap_err());
+
+        // `log`, framed by <s> and </s> only when the tokenizer adds them
+        assert_eq!(
+            ids("add_special_tokens=false"),
+            serde_json::json!([79, 82, 74])
+        );
+        assert_eq!(
+            ids("add_special_tokens=true"),
+            serde_json::json!([0, 79, 82, 74, 2])
+        );
+
+        assert!(error("add_special_tokenz=true").contains("unknown field `add_special_tokenz`"));
+        assert!(error("add_special_tokens").contains("should look like KEY=VALUE"));
+    }
+
+    #[test]
+    fn dump_tokens_shows_each_token_of_each_match() {
+        let search = |extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-m",
+                "codebert",
+                "-t",
+                "javascript",
+                "(property_identifier) @prop",
+            ];
+            args.extend(extra);
+            args.extend(["../tests/cmd/hello-world.js", "--", "query"]);
+            try_run(&args)
+        };
+
+        let (result, out) = search(&["--debug", "--dump-tokens"]);
+        result.unwrap();
+        assert_eq!(
+            out,
+            "../tests/cmd/hello-world.js:1:9:prop:log\n  79\t0..1\t\"l\"\n  82\t1..2\t\"o\"\n  74\t2..3\t\"g\"\n"
+        );
+
+        // it's only for debugging, so it has to be asked for on purpose
+        assert!(search(&["--dump-tokens"]).0.is_err());
+    }
+
+    #[test]
+    fn lsp_locations_are_0_based_file_uris() {
+        let out = run(&[
+            "crossgrep",
+            "-m",
+            "codebert",
+            "-f",
+            "lsp",
+            "-t",
+            "javascript",
+            "(string) @str",
+            "../tests/cmd/hello-world.js",
+            "--",
+            "query",
+        ]);
+
+        let uri = url::Url::from_file_path(
+            env::current_dir()
+                .unwrap()
+                .join("../tests/cmd/hello-world.js"),
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            format!(
+                r#"[{{"uri":"{}","range":{{"start":{{"line":0,"character":12}},"end":{{"line":0,"character":27}}}}}}]"#,
+                uri
+            )
+        );
+    }
+
+    #[test]
+    fn check_reports_each_query() {
+        let (result, out) = try_run(&[
+            "crossgrep",
+            "--check",
+            "-t",
+            "javascript",
+            "(identifier) @id",
+            "-t",
+            "javascript",
+            "(nope) @nope",
+        ]);
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "1 of 2 queries didn't compile"
+        );
+        assert!(out.starts_with("ok: javascript \"(identifier) @id\"\nerror: javascript \"(nope) @nope\": could not parse query"));
+    }
+
+    #[test]
+    fn check_succeeds_when_every_query_compiles() {
+        let (result, out) = try_run(&["crossgrep", "--check", "-t", "rust", "(identifier) @id"]);
+
+        assert!(result.unwrap());
+        assert_eq!(out, "ok: rust \"(identifier) @id\"\n");
+    }
+
+    #[test]
+    fn only_matching_prints_just_the_text() {
+        let only_matching = |extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-m",
+                "codebert",
+                "-o",
+                "-t",
+                "rust",
+                "(block) @block",
+            ];
+            args.extend(extra);
+            args.extend(["../tests/cmd/only-matching.rs", "--", "query"]);
+            run(&args)
+        };
+
+        assert_eq!(only_matching(&[]), "{\n    one();\n    two();\n}\n");
+        assert_eq!(
+            only_matching(&["--only-matching-trim"]),
+            "{ one(); two(); }\n"
+        );
+    }
+
+    #[test]
+    fn template_lays_out_lines() {
+        assert_eq!(
+            run(&[
+                "crossgrep",
+                "-m",
+                "codebert",
+                "--template",
+                "{path}:{line} [{name}] {kind} {byte_start}-{byte_end} {text}",
+                "-t",
+                "javascript",
+                "(string) @str",
+                "../tests/cmd/hello-world.js",
+                "--",
+                "query",
+            ]),
+            "../tests/cmd/hello-world.js:1 [str] string 12-27 \"Hello, World!\"\n"
+        );
+    }
+
+    #[test]
+    fn heading_shows_the_filename_once() {
+        assert_eq!(
+            run(&[
+                "crossgrep",
+                "-m",
+                "codebert",
+                "--heading",
+                "-t",
+                "javascript",
+                "(identifier) @id (property_identifier) @prop",
+                "../tests/cmd/hello-world.js",
+                "--",
+                "query",
+            ]),
+            "../tests/cmd/hello-world.js\n1:1:id:console\n1:9:prop:log\n"
+        );
+    }
+
+    #[test]
+    fn no_heading_overrides_heading() {
+        assert_eq!(
+            run(&[
+                "crossgrep",
+                "-m",
+                "codebert",
+                "--heading",
+                "--no-heading",
+                "-t",
+                "javascript",
+                "(identifier) @id (property_identifier) @prop",
+                "../tests/cmd/hello-world.js",
+                "--",
+                "query",
+            ]),
+            "../tests/cmd/hello-world.js:1:1:id:console\n../tests/cmd/hello-world.js:1:9:prop:log\n"
+        );
+    }
+
+    #[test]
+    fn git_url_searches_a_clone_of_the_repository() {
+        use std::process::Command;
+
+        let tmp = test_dir();
+        let root = tmp.path();
+        let repo = root.join("repo");
+        let cache = root.join("cache");
+        fs::create_dir_all(&repo).unwrap();
+        fs::copy("../tests/cmd/hello-world.js", repo.join("hello-world.js")).unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .current_dir(&repo)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "--quiet", "--initial-branch", "main"]);
+        git(&["add", "."]);
+        git(&["commit", "--quiet", "-m", "hello"]);
+
+        let url = format!("file://{}", repo.display());
+        let search = |extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-m",
+                "codebert",
+                "-t",
+                "javascript",
+                "(property_identifier) @prop",
+                "--git-url",
+                &url,
+            ];
+            args.extend(extra);
+            args.extend(["--", "query"]);
+            try_run(&args)
+        };
+
+        let (result, out) = search(&["--clone-cache", cache.to_str().unwrap()]);
+        let (missing_ref, _) = search(&["--ref", "no-such-branch"]);
+        let cached = cache.read_dir().unwrap().count();
+
+        assert!(result.unwrap());
+        assert!(out.starts_with("hello-world.js:"), "{}", out);
+        assert_eq!(cached, 1);
+        assert!(missing_ref.is_err());
+    }
+
+    #[test]
+    fn max_total_caps_matches_across_files() {
+        let tmp = test_dir();
+        let dir = tmp.path();
+        for name in &["a.js", "b.js", "c.js"] {
+            fs::write(dir.join(name), "a; b; c;\n").unwrap();
+        }
+
+        let search = |extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-m",
+                "codebert",
+                "-t",
+                "javascript",
+                "(identifier) @id",
+            ];
+            args.extend(extra);
+            args.extend([dir.to_str().unwrap(), "--", "query"]);
+            run(&args)
+        };
+
+        let capped = search(&["--max-total", "4"]);
+        let counted = search(&["--max-total", "4", "--count-matches"]);
+        let all = search(&[]);
+
+        assert_eq!(capped.lines().count(), 4);
+        assert_eq!(counted.trim(), "4");
+        assert_eq!(all.lines().count(), 9);
+    }
+
+    #[test]
+    fn max_total_stops_the_search_and_only_counts_what_it_keeps() {
+        let tmp = test_dir();
+        for name in &["a.js", "b.js", "c.js"] {
+            fs::write(tmp.path().join(name), "a; b; c;\n").unwrap();
+        }
+        let opts = query_opts(&[
+            "crossgrep",
+            "-m",
+            "codebert",
+            "-t",
+            "javascript",
+            "(identifier) @id",
+            "--max-total",
+            "4",
+            tmp.path().to_str().unwrap(),
+            "--",
+            "query",
+        ]);
+        let chooser = opts.extractor_chooser().unwrap();
+        let stats = Stats::default();
+        let stop = CancellationToken::new();
+
+        let paths = find_files(&opts, &stats).unwrap();
+        let handed_out: usize = extract_all(
+            paths.par_iter().cloned().enumerate(),
+            &opts,
+            &chooser,
+            &stats,
+            &stop,
+            |extracted| extracted.map(|file| file.unwrap().matches().len()).sum(),
+        );
+
+        assert_eq!(handed_out, 4);
+        assert!(stop.is_cancelled());
+        let stats = stats.to_string();
+        assert!(stats.contains("files with matches: 2\n"), "{}", stats);
+        assert!(stats.contains("matches: 4\n"), "{}", stats);
+    }
+
+    #[test]
+    fn text_filters_narrow_down_matches() {
+        let search = |extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-m",
+                "codebert",
+                "-t",
+                "javascript",
+                "(identifier) @id (property_identifier) @prop",
+            ];
+            args.extend(extra);
+            args.extend(["../tests/cmd/hello-world.js", "--", "query"]);
+            try_run(&args)
+        };
+        let texts = |extra: &[&str]| {
+            let (result, out) = search(extra);
+            result.unwrap();
+            out.lines()
+                .map(|line| line.rsplit(':').next().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(texts(&["--text-match", "^con"]), vec!["console"]);
+        assert!(texts(&["--text-match", "^CON"]).is_empty());
+        assert_eq!(texts(&["--text-match", "^CON", "-i"]), vec!["console"]);
+        assert_eq!(texts(&["--text-not-match", "^con"]), vec!["log"]);
+        assert!(search(&["--text-match", "("]).0.is_err());
+    }
+
+    #[test]
+    fn path_separator_applies_to_every_format() {
+        let search = |extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-m",
+                "codebert",
+                "--path-separator",
+                "|",
+                "-t",
+                "javascript",
+                "(string) @str",
+            ];
+            args.extend(extra);
+            args.extend(["../tests/cmd/hello-world.js", "--", "query"]);
+            run(&args)
+        };
+
+        assert!(search(&[]).starts_with("..|tests|cmd|hello-world.js:1:13:"));
+        let results: serde_json::Value = serde_json::from_str(&search(&["-f", "json"])).unwrap();
+        assert_eq!(results[0]["file"], "..|tests|cmd|hello-world.js");
+        assert!(try_run(&[
+            "crossgrep",
+            "-m",
+            "codebert",
+            "--path-separator",
+            "//",
+            "-t",
+            "javascript",
+            "(string) @str",
+            "../tests/cmd/hello-world.js",
+            "--",
+            "query",
+        ])
+        .0
+        .is_err());
+    }
+
+    #[test]
+    fn languages_can_be_json() {
+        assert!(run(&["crossgrep", "--languages"]).starts_with("c\ncpp\n"));
+
+        let languages: serde_json::Value =
+            serde_json::from_str(&run(&["crossgrep", "--languages", "-f", "json"])).unwrap();
+        let javascript = languages
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|lang| lang["name"] == "javascript")
+            .unwrap();
+        assert_eq!(javascript["aliases"], serde_json::json!(["js"]));
+        assert!(javascript["extensions"]
+            .as_array()
+            .unwrap()
+            .contains(&serde_json::json!("js")));
+        assert!(javascript["abi_version"].as_u64().unwrap() > 0);
+
+        assert!(try_run(&["crossgrep", "--languages", "-f", "lsp"])
+            .0
+            .is_err());
+    }
+
+    #[test]
+    fn same_language_targets_keep_their_own_captures() {
+        let out = run(&[
+            "crossgrep",
+            "-m",
+            "codebert",
+            "-t",
+            "javascript",
+            "(string) ; strings",
+            "-t",
+            "javascript",
+            "(property_identifier) @prop",
+            "../tests/cmd/hello-world.js",
+            "--",
+            "query",
+        ]);
+
+        assert!(out.contains(":query:\"Hello, World!\""), "{}", out);
+        assert!(out.contains(":prop:log"), "{}", out);
+    }
+
+    #[test]
+    fn text_is_searched_without_a_file() {
+        let out = run(&[
+            "crossgrep",
+            "-t",
+            "rust",
+            "(function_item name: (identifier) @name)",
+            "--text",
+            "fn main() {}",
+            "--",
+            "query",
+        ]);
+        assert_eq!(out, "NO FILE:1:4:name:main\n");
+
+        let tree = run(&["crossgrep", "--show-tree", "rust", "--text", "fn main() {}"]);
+        assert!(tree.contains("function_item"), "{}", tree);
+
+        assert!(try_run(&[
+            "crossgrep",
+            "-t",
+            "rust",
+            "(identifier) @id",
+            "-t",
+            "python",
+            "(identifier) @id",
+            "--text",
+            "main",
+            "--",
+            "query",
+        ])
+        .0
+        .is_err());
+    }
+
+    #[test]
+    fn lines_count_columns_in_characters() {
+        let search = |extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-t",
+                "javascript",
+                "(number) @n",
+                "--text",
+                "let café = 1;",
+            ];
+            args.extend(extra);
+            args.extend(["--", "query"]);
+            run(&args)
+        };
+
+        assert_eq!(search(&[]), "NO FILE:1:12:n:1\n");
+        assert_eq!(search(&["--column-unit", "byte"]), "NO FILE:1:13:n:1\n");
+        let json: serde_json::Value = serde_json::from_str(&search(&["-f", "json"])).unwrap();
+        assert_eq!(json[0]["matches"][0]["start"]["column"], 13);
+    }
+
+    #[test]
+    fn dot_graphs_have_a_cluster_per_file() {
+        let graph = run(&[
+            "crossgrep",
+            "-t",
+            "rust",
+            "(identifier) @id",
+            "--text",
+            "fn f() { let x = \"a\"; x }",
+            "-f",
+            "dot",
+            "--dot-scope-edges",
+            "--",
+            "query",
+        ]);
+
+        assert_eq!(
+            graph,
+            concat!(
+                "digraph crossgrep {\n",
+                "  node [shape=box];\n",
+                "  subgraph cluster_0 {\n",
+                "    label=\"\";\n",
+                "    m0_0 [label=\"id\\nf\"];\n",
+                "    m0_1 [label=\"id\\nx\"];\n",
+                "    m0_2 [label=\"id\\nx\"];\n",
+                "    m0_1 -> m0_2 [dir=none, style=dashed, tooltip=\"f\"];\n",
+                "  }\n",
+                "}\n",
+            )
+        );
+        assert_eq!(
+            run(&[
+                "crossgrep",
+                "-f",
+                "dot",
+                "-t",
+                "rust",
+                "(struct_item) @s",
+                "--text",
+                "fn f() {}",
+                "--",
+                "query"
+            ]),
+            "digraph crossgrep {\n}\n"
+        );
+        assert!(try_run(&[
+            "crossgrep",
+            "-t",
+            "rust",
+            "(identifier) @id",
+            "--dot-scope-edges",
+            "--",
+            "query"
+        ])
+        .0
+        .is_err());
+    }
+
+    #[test]
+    fn library_patterns_are_picked_by_name() {
+        let tmp = test_dir();
+        let library = tmp.path().join("library.scm");
+        fs::write(
+            &library,
+            "; @language rust\n; @name structs\n(struct_item name: (_) @struct)\n\n; @name fns\n(function_item name: (_) @fn)\n",
+        )
+        .unwrap();
+        let search = |patterns: &str| {
+            try_run(&[
+                "crossgrep",
+                "--from-library",
+                library.to_str().unwrap(),
+                "--pattern",
+                patterns,
+                "--text",
+                "struct S; fn f() {}",
+                "--",
+                "query",
+            ])
+        };
+
+        let (fns, fns_out) = search("fns");
+        let (both, both_out) = search("fns,structs");
+        let (missing, _) = search("enums");
+
+        assert!(fns.unwrap());
+        assert_eq!(fns_out, "NO FILE:1:14:fn:f\n");
+        assert!(both.unwrap());
+        assert_eq!(both_out, "NO FILE:1:8:struct:S\nNO FILE:1:14:fn:f\n");
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn separate_queries_are_not_deduplicated() {
+        let search = |extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-t",
+                "rust",
+                "(identifier) @id",
+                "-t",
+                "rust",
+                "(let_declaration pattern: (identifier) @id)",
+                "--text",
+                "let x = y;",
+            ];
+            args.extend(extra);
+            args.extend(["--", "query"]);
+            run(&args)
+        };
+
+        assert_eq!(search(&[]), "NO FILE:1:5:id:x\nNO FILE:1:9:id:y\n");
+        assert_eq!(
+            search(&["--separate-queries"]),
+            "NO FILE:1:5:id:x\nNO FILE:1:5:id:x\nNO FILE:1:9:id:y\n"
+        );
+    }
+
+    #[test]
+    fn count_by_kind_counts_each_node_kind() {
+        let count = |extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-t",
+                "rust",
+                "(function_item) @fn (struct_item) @struct",
+                "--text",
+                "struct A;\nfn a() {}\nfn b() {}",
+                "--count-by-kind",
+            ];
+            args.extend_from_slice(extra);
+            args.extend_from_slice(&["--", "query"]);
+            run(&args)
+        };
+
+        assert_eq!(
+            count(&[]),
+            "NO FILE:1:1:struct:struct A;\nNO FILE:2:1:fn:fn a() {}\nNO FILE:3:1:fn:fn b() {}\n\n2 function_item\n1 struct_item\n"
+        );
+        assert_eq!(
+            count(&["--count-matches"]),
+            "2 function_item\n1 struct_item\n"
+        );
+        let json = count(&["--count-matches", "--format", "json"]);
+        assert_eq!(
+            json,
+            "[{\"kind\":\"function_item\",\"count\":2},{\"kind\":\"struct_item\",\"count\":1}]\n"
+        );
+        assert!(serde_json::from_str::<serde_json::Value>(&json).is_ok());
+    }
+
+    #[test]
+    fn count_by_kind_doesnt_follow_json_results() {
+        for format in ["json", "json-lines", "pretty-json", "dot"] {
+            let (result, out) = try_run(&[
+                "crossgrep",
+                "-t",
+                "rust",
+                "(function_item) @fn",
+                "--text",
+                "fn a() {}",
+                "--count-by-kind",
+                "--format",
+                format,
+                "--",
+                "query",
+            ]);
+
+            let err = format!("{:#}", result.unwrap_err());
+            assert!(err.contains("Add --count-matches"), "{}: {}", format, err);
+            assert_eq!(out, "");
+        }
+    }
+
+    #[test]
+    fn exec_runs_a_command_for_matches() {
+        let exec = |flag, command| {
+            try_run(&[
+                "crossgrep",
+                "-t",
+                "rust",
+                "(function_item name: (identifier) @name)",
+                "--text",
+                "fn a() {}\nfn b() {}",
+                flag,
+                command,
+                "--",
+                "query",
+            ])
+        };
+
+        let output = |flag, command| {
+            let (result, out) = exec(flag, command);
+            result.unwrap();
+            out
+        };
+
+        assert_eq!(output("--exec", "echo {line}: {text}"), "1: a\n2: b\n");
+        assert_eq!(output("--exec", "cat {}"), "ab");
+        assert_eq!(
+            output("--exec-batch", "echo {path} {text}"),
+            "NO FILE a b\n"
+        );
+
+        // every command still runs, but the search fails at the end
+        let (result, out) = exec("--exec", "sh -c 'echo {text}; exit {line}'");
+        assert_eq!(out, "a\nb\n");
+        assert_eq!(
+            format!("{:#}", result.unwrap_err()),
+            "couldn't perform the query: 2 of 2 commands failed"
+        );
+    }
+
+    #[test]
+    fn exclude_dir_skips_directories_at_any_depth() {
+        let tmp = test_dir();
+        let dir = tmp.path();
+        for sub in ["src/node_modules/deep", "node_modules", "target"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        fs::write(dir.join("src/a.js"), "a();\n").unwrap();
+        fs::write(dir.join("src/node_modules/deep/b.js"), "b();\n").unwrap();
+        fs::write(dir.join("node_modules/c.js"), "c();\n").unwrap();
+        fs::write(dir.join("target/d.js"), "d();\n").unwrap();
+
+        let search = |path: &Path, extra: &[&str]| {
+            let mut args = vec!["crossgrep", "-t", "javascript", "(call_expression) @call"];
+            args.extend(extra);
+            args.extend([path.to_str().unwrap(), "--", "query"]);
+            let mut lines: Vec<String> = run(&args)
+                .lines()
+                .map(|line| line.rsplit(':').next().unwrap().to_string())
+                .collect();
+            lines.sort();
+            lines
+        };
+
+        assert_eq!(search(dir, &[]), vec!["a()", "b()", "c()", "d()"]);
+        assert_eq!(
+            search(
+                dir,
+                &["--exclude-dir", "node_modules", "--exclude-dir", "target"]
+            ),
+            vec!["a()"]
+        );
+        // asking for one by name still searches it
+        assert_eq!(
+            search(
+                &dir.join("node_modules"),
+                &["--exclude-dir", "node_modules"]
+            ),
+            vec!["c()"]
+        );
+    }
+
+    #[test]
+    fn watched_changes_are_filtered_like_the_walk() {
+        let tmp = test_dir();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        for sub in ["src", "node_modules", ".cache"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        fs::write(dir.join(".gitignore"), "*.min.js\n").unwrap();
+        let changed: Vec<PathBuf> = [
+            "src/a.js",
+            "src/a.min.js",
+            "src/.b.js",
+            "node_modules/c.js",
+            ".cache/d.js",
+        ]
+        .iter()
+        .map(|name| {
+            let path = dir.join(name);
+            fs::write(&path, "a();\n").unwrap();
+            path
+        })
+        .collect();
+
+        let opts = query_opts(&[
+            "crossgrep",
+            "-m",
+            "codebert",
+            "-t",
+            "javascript",
+            "(call_expression) @call",
+            "--exclude-dir",
+            "node_modules",
+            dir.to_str().unwrap(),
+            "--",
+            "query",
+        ]);
+
+        assert_eq!(
+            changed_files_to_search(&changed, &opts, &Stats::default()),
+            vec![dir.join("src/a.js")]
+        );
+    }
+
+    #[test]
+    fn explain_names_what_skips_a_file() {
+        let tmp = test_dir();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join(".gitignore"), "*.min.js\n").unwrap();
+        fs::write(dir.join("src/index.js"), "console.log(\"hi\");\n").unwrap();
+        fs::write(dir.join("src/index.min.js"), "console.log(\"hi\");\n").unwrap();
+        fs::write(dir.join("src/README"), "hi\n").unwrap();
+
+        let explain = |file: &str, extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-m",
+                "codebert",
+                "-t",
+                "javascript",
+                "(string) @str",
+                "--explain",
+                file,
+            ];
+            args.extend(extra);
+            args.push(dir.to_str().unwrap());
+            let (result, out) = try_run(&args);
+            (result.unwrap(), out)
+        };
+
+        let index = dir.join("src/index.js");
+        let (searched, out) = explain(index.to_str().unwrap(), &[]);
+        assert!(searched);
+        assert_eq!(
+            out,
+            format!(
+                "path: {}\nwalk: found it\nlanguage: javascript\nsize: 19 bytes\nbinary: no\nsearched: yes\n",
+                index.display()
+            )
+        );
+        let (searched, out) = explain(index.to_str().unwrap(), &["--max-filesize", "10"]);
+        assert!(!searched);
+        assert!(out.contains("size: 19 bytes, over --max-filesize (10)\n"));
+
+        let minified = dir.join("src/index.min.js");
+        let (searched, out) = explain(minified.to_str().unwrap(), &[]);
+        assert!(!searched);
+        assert!(out.contains(&format!(
+            "walk: skipped {}: `*.min.js` in {} ignores it\n",
+            minified.display(),
+            fs::canonicalize(dir).unwrap().join(".gitignore").display()
+        )));
+
+        let (searched, out) = explain(minified.to_str().unwrap(), &["--exclude-dir", "src"]);
+        assert!(!searched);
+        assert!(out.contains(&format!(
+            "walk: skipped {}: --exclude-dir src skips it\n",
+            dir.join("src").display()
+        )));
+
+        let readme = dir.join("src/README");
+        let (searched, out) = explain(readme.to_str().unwrap(), &[]);
+        assert!(!searched);
+        assert!(out.contains("language: none of the queries are for a language that claims it\n"));
+        let (searched, out) = explain(readme.to_str().unwrap(), &["-t", "regex", "hi"]);
+        assert!(searched);
+        assert!(out.contains("language: none claim it, so the regex searches it\n"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn explain_shows_errors_that_stop_the_walk() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = test_dir();
+        let dir = tmp.path();
+        let locked = dir.join("locked");
+        fs::create_dir_all(&locked).unwrap();
+        fs::write(locked.join("index.js"), "console.log(\"hi\");\n").unwrap();
+        // we can get to the file, but not list what's next to it
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o100)).unwrap();
+        let readable = fs::read_dir(&locked).is_ok();
+
+        let file = locked.join("index.js");
+        let (result, out) = try_run(&[
+            "crossgrep",
+            "-m",
+            "codebert",
+            "-t",
+            "javascript",
+            "(string) @str",
+            "--explain",
+            file.to_str().unwrap(),
+            dir.to_str().unwrap(),
+        ]);
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+
+        // permissions don't stop root
+        if readable {
+            assert!(result.unwrap());
+            return;
+        }
+        assert!(!result.unwrap());
+        assert!(
+            out.contains(&format!(
+                "walk: skipped {}: the walker couldn't get past an error: ",
+                file.display()
+            )),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn regex_targets_search_what_queries_dont_claim() {
+        let tmp = test_dir();
+        let dir = tmp.path();
+        fs::write(
+            dir.join("index.js"),
+            "// TODO: not this one\nlet x = \"hi\";\n",
+        )
+        .unwrap();
+        fs::write(dir.join("notes.txt"), "done\n  TODO: this one\n").unwrap();
+
+        let mut lines: Vec<String> = run(&[
+            "crossgrep",
+            "-m",
+            "codebert",
+            "-t",
+            "javascript",
+            "(string) @str",
+            "-t",
+            "regex",
+            r"TODO: \w+",
+            dir.to_str().unwrap(),
+            "--",
+            "query",
+        ])
+        .lines()
+        .map(String::from)
+        .collect();
+        lines.sort();
+
+        assert_eq!(
+            lines,
+            vec![
+                format!("{}:2:9:str:\"hi\"", dir.join("index.js").display()),
+                format!("{}:2:3:match:TODO: this", dir.join("notes.txt").display()),
+            ]
+        );
+    }
+
+    #[test]
+    fn regex_targets_take_the_options_that_apply_to_them() {
+        let search = |extra: &[&str]| {
+            let mut args = vec![
+                "crossgrep",
+                "-t",
+                "regex",
+                r"TODO\S*",
+                "--text",
+                "TODO one\nTODO(x) two\n",
+            ];
+            args.extend_from_slice(extra);
+            args.extend_from_slice(&["--", "query"]);
+            try_run(&args)
+        };
+
+        let (result, out) = search(&["--text-not-match", "x"]);
+        assert!(result.unwrap());
+        assert_eq!(out, "NO FILE:1:1:match:TODO\n");
+
+        let (result, _) = search(&["--expand-to", "function_item"]);
+        let err = format!("{:#}", result.unwrap_err());
+        assert!(
+            err.contains("--expand-to only changes what tree-sitter queries find"),
+            "{}",
+            err
+        );
+
+        // with a query too, the query gets it
+        let opts = query_opts(&[
+            "crossgrep",
+            "-t",
+            "regex",
+            "TODO",
+            "-t",
+            "rust",
+            "(identifier) @id",
+            "--expand-to",
+            "function_item",
+            "--",
+            "query",
+        ]);
+        assert!(opts.regex.is_some());
+    }
 }
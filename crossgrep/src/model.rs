@@ -1,71 +1,628 @@
-use anyhow::{bail, Result};
-use tokenizers::tokenizer::Tokenizer;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tokenizers::models::bpe::BPE;
+use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+use tokenizers::processors::roberta::RobertaProcessing;
+use tokenizers::tokenizer::{AddedToken, Tokenizer};
 
-#[derive(Clone, Copy, Debug)]
+/// The `tokenizer_source` for a tokenizer we build ourselves instead of
+/// loading: one token for every byte, with RoBERTa's special tokens. It
+/// needs no network or files, so it's what `Model::Noop` (and so most of the
+/// tests) uses.
+pub const BYTES_TOKENIZER: &str = "crossgrep:bytes";
+
+/// How many times we try to download a tokenizer before falling back to the
+/// cached copy. Hub requests fail transiently often enough that giving up on
+/// the first one would make every flaky connection a hard failure.
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// How long to wait after the first failed download. This doubles after
+/// every failure after that.
+const DOWNLOAD_BACKOFF: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug)]
 pub enum Model {
     CodeBert,
-    // wide model for testing purposes
+    // wide model for testing purposes. It tokenizes with `BYTES_TOKENIZER`,
+    // so it works offline.
     Noop,
+    /// A model described by a `ModelConfig`, usually one of the others with
+    /// some of its settings changed.
+    Custom(ModelConfig),
+}
+
+/// Everything we need to know about a model to chunk source for it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelConfig {
+    /// A tokenizer on the Hugging Face hub (like `roberta-base`), the path to
+    /// a local `tokenizer.json`, or `BYTES_TOKENIZER`.
+    pub tokenizer_source: String,
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    /// How many tokens of each chunk go to things like `<s>` and `</s>`
+    /// instead of source.
+    pub special_tokens: usize,
+    /// The tokens to wrap each chunk's content in and pad it with, if the
+    /// model wants them.
+    pub framing: Option<Framing>,
+    #[serde(default)]
+    pub tokenizer_options: TokenizerOptions,
+}
+
+/// Settings for how source gets tokenized, for tokenizers that need
+/// something other than our defaults.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenizerOptions {
+    /// Let the tokenizer add its own special tokens (like `<s>` and `</s>`)
+    /// when encoding source. We frame chunks ourselves, so this is off by
+    /// default, and turning it on means those tokens count towards each
+    /// chunk's content.
+    pub add_special_tokens: bool,
+}
+
+/// The special token ids a model expects around (and after) each chunk's
+/// content. RoBERTa uses `<s>`=0, `</s>`=2, and `<pad>`=1, while BERT uses
+/// `[CLS]`=101, `[SEP]`=102, and `[PAD]`=0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Framing {
+    pub start_token: u32,
+    pub end_token: u32,
+    pub pad_token: u32,
+}
+
+impl Framing {
+    /// How many tokens of each chunk framing takes up (not counting padding.)
+    pub const TOKENS: usize = 2;
+
+    pub const ROBERTA: Framing = Framing {
+        start_token: 0,
+        end_token: 2,
+        pad_token: 1,
+    };
+
+    pub const BERT: Framing = Framing {
+        start_token: 101,
+        end_token: 102,
+        pad_token: 0,
+    };
+}
+
+impl ModelConfig {
+    /// Replace the settings that `overrides` (a JSON object with some of our
+    /// fields) has, and keep the rest.
+    pub fn with_overrides(self, overrides: serde_json::Value) -> Result<Self> {
+        let mut config = serde_json::to_value(self).map_err(Error::ModelConfig)?;
+
+        match (&mut config, overrides) {
+            (serde_json::Value::Object(config), serde_json::Value::Object(overrides)) => {
+                config.extend(overrides)
+            }
+            _ => {
+                return Err(Error::ModelConfig(serde::de::Error::custom(
+                    "a model config has to be a JSON object",
+                )))
+            }
+        }
+
+        let config: ModelConfig = serde_json::from_value(config).map_err(Error::ModelConfig)?;
+
+        // otherwise we'd make chunks with no room for the framing, and
+        // padding them out to the chunk size would cut the end token off.
+        if config.framing.is_some() && config.special_tokens < Framing::TOKENS {
+            return Err(Error::ModelConfig(serde::de::Error::custom(format!(
+                "framing takes {} special tokens, but special_tokens is {}",
+                Framing::TOKENS,
+                config.special_tokens
+            ))));
+        }
+
+        Ok(config)
+    }
+
+    /// Set one of the `TokenizerOptions` by name, like `--tokenizer-opt`
+    /// does. `value` is read as JSON if it can be (so `true` is a boolean)
+    /// and as a string otherwise.
+    pub fn with_tokenizer_option(self, key: &str, value: &str) -> Result<Self> {
+        let value = serde_json::from_str(value)
+            .unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+
+        let mut options =
+            serde_json::to_value(self.tokenizer_options).map_err(Error::ModelConfig)?;
+        if let serde_json::Value::Object(options) = &mut options {
+            options.insert(key.to_string(), value);
+        }
+
+        self.with_overrides(serde_json::json!({ "tokenizer_options": options }))
+    }
 }
 
 impl Model {
     pub fn from_pretrained(identifier: &str) -> Result<Self> {
         match identifier {
             "codebert" => Ok(Model::CodeBert),
-            _ => bail!("unsupported model: {}", identifier),
+            _ => Err(Error::UnsupportedModel(identifier.to_string())),
         }
     }
 
-    pub fn prepare_input_ids(&self, input_ids: &mut Vec<u32>, ids: &[u32]) {
+    pub fn config(&self) -> ModelConfig {
         match self {
-            Model::CodeBert => {
-                assert!(ids.len() <= self.chunk_size() - self.special_tokens());
-                input_ids.push(0);
-                for i in ids {
-                    input_ids.push(*i);
-                }
-                input_ids.push(2);
-                for _ in 0..(self.chunk_size() - self.special_tokens() - ids.len()) {
-                    input_ids.push(1);
-                }
-                assert!(input_ids.len() == self.chunk_size());
+            Model::CodeBert => ModelConfig {
+                tokenizer_source: String::from("roberta-base"),
+                chunk_size: 512,
+                chunk_overlap: 64,
+                special_tokens: Framing::TOKENS,
+                framing: Some(Framing::ROBERTA),
+                tokenizer_options: TokenizerOptions::default(),
+            },
+            Model::Noop => ModelConfig {
+                tokenizer_source: String::from(BYTES_TOKENIZER),
+                chunk_size: usize::MAX,
+                chunk_overlap: 0,
+                special_tokens: 0,
+                framing: None,
+                tokenizer_options: TokenizerOptions::default(),
+            },
+            Model::Custom(config) => config.clone(),
+        }
+    }
+
+    fn framing(&self) -> Option<Framing> {
+        match self {
+            Model::Custom(config) => config.framing,
+            _ => self.config().framing,
+        }
+    }
+
+    /// Add `ids` to `input_ids`, framed and padded the way the model expects.
+    /// If `ids` won't fit in a chunk (which means the chunker got its sums
+    /// wrong) we return an error instead of building input the model can't
+    /// take, and leave `input_ids` alone.
+    pub fn prepare_input_ids(&self, input_ids: &mut Vec<u32>, ids: &[u32]) -> Result<()> {
+        let max_ids = self.chunk_size().saturating_sub(self.special_tokens());
+        if ids.len() > max_ids {
+            return Err(Error::Chunk(format!(
+                "got {} tokens for a chunk, but only {} fit",
+                ids.len(),
+                max_ids
+            )));
+        }
+
+        match self.framing() {
+            Some(framing) => {
+                input_ids.push(framing.start_token);
+                input_ids.extend_from_slice(ids);
+                input_ids.push(framing.end_token);
+                input_ids.resize(self.chunk_size(), framing.pad_token);
             }
-            Model::Noop => {
-                input_ids.clone_from_slice(ids);
+            None => {
+                input_ids.extend_from_slice(ids);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The attention mask for input ids built by `prepare_input_ids` from
+    /// `content_len` real token ids: 1 for every token the model should look
+    /// at, and 0 for padding.
+    pub fn attention_mask(&self, content_len: usize) -> Vec<u32> {
+        match self.framing() {
+            Some(_) => {
+                let real_tokens = content_len + Framing::TOKENS;
+                let mut mask = vec![1; real_tokens];
+                mask.resize(self.chunk_size(), 0);
+                mask
             }
+            None => vec![1; content_len],
         }
     }
 
     pub fn chunk_size(&self) -> usize {
         match self {
-            Model::CodeBert => 512,
-            Model::Noop => usize::MAX,
+            Model::Custom(config) => config.chunk_size,
+            _ => self.config().chunk_size,
         }
     }
 
     pub fn chunk_overlap(&self) -> usize {
         match self {
-            Model::CodeBert => 64,
-            Model::Noop => 0,
+            Model::Custom(config) => config.chunk_overlap,
+            _ => self.config().chunk_overlap,
+        }
+    }
+
+    pub fn tokenizer_options(&self) -> TokenizerOptions {
+        match self {
+            Model::Custom(config) => config.tokenizer_options,
+            _ => self.config().tokenizer_options,
         }
     }
 
     pub fn special_tokens(&self) -> usize {
         match self {
-            Model::CodeBert => 2,
-            Model::Noop => 0,
+            Model::Custom(config) => config.special_tokens,
+            _ => self.config().special_tokens,
         }
     }
 
     // TODO cache/share this for when there are multiple extractors
-    pub fn tokenizer(&self) -> Tokenizer {
-        Tokenizer::from_pretrained(
-            match self {
-                Model::CodeBert => "roberta-base",
-                Model::Noop => "roberta-base",
-            },
+    pub fn tokenizer(&self) -> Result<Tokenizer> {
+        let config = self.config();
+        let source = &config.tokenizer_source;
+
+        let mut tokenizer = if source == BYTES_TOKENIZER {
+            bytes_tokenizer()
+        } else if Path::new(source).is_file() {
+            Tokenizer::from_file(source)
+        } else {
+            load_pretrained(source, tokenizer_cache_path(source).as_deref())
+        }
+        .map_err(|error| Error::LoadTokenizer {
+            source: source.clone(),
+            error,
+        })?;
+
+        // the chunker does its own windowing over every token in the source,
+        // so a tokenizer that comes configured to truncate or pad would cut
+        // matches short or give us ids that aren't in the source.
+        tokenizer
+            .with_truncation(None)
+            .map_err(|error| Error::LoadTokenizer {
+                source: source.clone(),
+                error,
+            })?;
+        tokenizer.with_padding(None);
+
+        Ok(tokenizer)
+    }
+}
+
+/// Build the `BYTES_TOKENIZER`: byte-level BPE with no merges, so every
+/// byte of source is its own token.
+fn bytes_tokenizer() -> tokenizers::Result<Tokenizer> {
+    let special = ["<s>", "<pad>", "</s>", "<unk>"];
+
+    let mut alphabet: Vec<char> = ByteLevel::alphabet().into_iter().collect();
+    alphabet.sort_unstable();
+
+    let vocab: HashMap<String, u32> = special
+        .iter()
+        .map(|token| token.to_string())
+        .chain(alphabet.iter().map(|c| c.to_string()))
+        .enumerate()
+        .map(|(id, token)| (token, id as u32))
+        .collect();
+
+    let mut tokenizer = Tokenizer::new(BPE::builder().vocab_and_merges(vocab, Vec::new()).build()?);
+    tokenizer.with_pre_tokenizer(ByteLevel::new(false, true, true));
+    tokenizer.with_decoder(ByteLevel::default());
+    tokenizer.with_post_processor(RobertaProcessing::new(
+        (String::from("</s>"), 2),
+        (String::from("<s>"), 0),
+    ));
+    tokenizer.add_special_tokens(
+        &special
+            .iter()
+            .map(|token| AddedToken::from(token.to_string(), true))
+            .collect::<Vec<_>>(),
+    );
+
+    Ok(tokenizer)
+}
+
+/// Download a tokenizer from the Hugging Face hub, retrying with backoff. If
+/// every attempt fails we use the copy we saved at `cache` the last time a
+/// download worked, so crossgrep keeps working offline.
+fn load_pretrained(source: &str, cache: Option<&Path>) -> tokenizers::Result<Tokenizer> {
+    fetch_with_retries(
+        source,
+        cache,
+        DOWNLOAD_ATTEMPTS,
+        DOWNLOAD_BACKOFF,
+        |source| Tokenizer::from_pretrained(source, None),
+    )
+}
+
+/// `load_pretrained`, but with the download (`fetch`), how many times to try
+/// it, and how long to wait after the first failure passed in, so tests can
+/// fail on purpose without the network or the wait.
+fn fetch_with_retries(
+    source: &str,
+    cache: Option<&Path>,
+    attempts: u32,
+    mut backoff: Duration,
+    mut fetch: impl FnMut(&str) -> tokenizers::Result<Tokenizer>,
+) -> tokenizers::Result<Tokenizer> {
+    let mut attempt = 1;
+    let err = loop {
+        match fetch(source) {
+            Ok(tokenizer) => {
+                if let Some(cache) = cache {
+                    // a missing cache only matters once we're offline, so
+                    // failing to write it shouldn't stop this search.
+                    if let Some(parent) = cache.parent() {
+                        let _ = std::fs::create_dir_all(parent);
+                    }
+                    let _ = tokenizer.save(cache, false);
+                }
+                return Ok(tokenizer);
+            }
+            Err(err) if attempt >= attempts => break err,
+            Err(_) => {
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+        }
+    };
+
+    match cache {
+        Some(cache) if cache.is_file() => Tokenizer::from_file(cache).map_err(|cache_err| {
+            format!(
+                "{} (and the cached copy at {} didn't load either: {})",
+                err,
+                cache.display(),
+                cache_err
+            )
+            .into()
+        }),
+        _ => Err(format!("{} (and there's no cached copy to fall back on)", err).into()),
+    }
+}
+
+/// Where we keep a copy of a downloaded tokenizer, like
+/// `~/.cache/crossgrep/tokenizers/my-org--my-vocab.json`. `None` if
+/// the OS doesn't have a cache directory.
+fn tokenizer_cache_path(source: &str) -> Option<PathBuf> {
+    let name: String = source
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' {
+                c.to_string()
+            } else {
+                String::from("--")
+            }
+        })
+        .collect();
+
+    dirs::cache_dir().map(|dir| {
+        dir.join("crossgrep")
+            .join("tokenizers")
+            .join(format!("{}.json", name))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noop_input_ids_are_the_ids() {
+        let mut input_ids = Vec::new();
+        Model::Noop
+            .prepare_input_ids(&mut input_ids, &[5, 6, 7])
+            .unwrap();
+
+        assert_eq!(input_ids, vec![5, 6, 7]);
+    }
+
+    #[test]
+    fn codebert_input_ids_are_wrapped_and_padded() {
+        let mut input_ids = Vec::new();
+        Model::CodeBert
+            .prepare_input_ids(&mut input_ids, &[5, 6, 7])
+            .unwrap();
+
+        assert_eq!(input_ids.len(), Model::CodeBert.chunk_size());
+        assert_eq!(&input_ids[..6], &[0, 5, 6, 7, 2, 1]);
+        assert!(input_ids[5..].iter().all(|id| *id == 1));
+    }
+
+    #[test]
+    fn oversized_input_ids_are_an_error() {
+        let model = Model::CodeBert;
+        let ids = vec![5; model.chunk_size() - model.special_tokens() + 1];
+
+        let mut input_ids = Vec::new();
+        let err = model.prepare_input_ids(&mut input_ids, &ids).unwrap_err();
+
+        assert!(matches!(err, Error::Chunk(_)));
+        assert!(input_ids.is_empty());
+    }
+
+    #[test]
+    fn tokenizer_does_not_truncate_or_pad() {
+        let model = Model::CodeBert;
+        let tokenizer = model.tokenizer().unwrap();
+        assert!(tokenizer.get_truncation().is_none());
+        assert!(tokenizer.get_padding().is_none());
+
+        let source = "let x = 1;\n".repeat(model.chunk_size());
+        let encoding = tokenizer.encode(source.as_str(), false).unwrap();
+
+        assert!(encoding.get_ids().len() > model.chunk_size());
+        assert_eq!(encoding.get_offsets().last().unwrap().1, source.len());
+    }
+
+    #[test]
+    fn noop_tokenizes_every_byte_without_loading_anything() {
+        let tokenizer = Model::Noop.tokenizer().unwrap();
+        let encoding = tokenizer.encode("a é", false).unwrap();
+
+        assert_eq!(encoding.get_ids().len(), "a é".len());
+        assert_eq!(tokenizer.decode(encoding.get_ids(), false).unwrap(), "a é");
+        assert_eq!(
+            tokenizer.encode("a", true).unwrap().get_ids().len(),
+            1 + Framing::TOKENS
+        );
+    }
+
+    #[test]
+    fn tokenizer_cache_names_are_flat() {
+        let path = tokenizer_cache_path("my-org/my-vocab").unwrap();
+
+        assert_eq!(path.file_name().unwrap(), "my-org--my-vocab.json");
+        assert!(path.ends_with("crossgrep/tokenizers/my-org--my-vocab.json"));
+    }
+
+    /// A download that fails the first `failures` times it's tried, and
+    /// counts how many times that was.
+    fn flaky_fetch(
+        failures: u32,
+        tries: &mut u32,
+    ) -> impl FnMut(&str) -> tokenizers::Result<Tokenizer> + '_ {
+        move |_| {
+            *tries += 1;
+            if *tries <= failures {
+                Err("no network".into())
+            } else {
+                bytes_tokenizer()
+            }
+        }
+    }
+
+    #[test]
+    fn downloads_are_retried() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = tmp.path().join("tokenizers/flaky.json");
+
+        let mut tries = 0;
+        let fetched = fetch_with_retries(
+            "flaky",
+            Some(&cache),
+            3,
+            Duration::ZERO,
+            flaky_fetch(2, &mut tries),
+        );
+
+        assert!(fetched.is_ok());
+        assert_eq!(tries, 3);
+        assert!(cache.is_file());
+    }
+
+    #[test]
+    fn failed_downloads_fall_back_to_the_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = tmp.path().join("cached.json");
+        bytes_tokenizer().unwrap().save(&cache, false).unwrap();
+
+        let mut tries = 0;
+        let cached = fetch_with_retries(
+            "offline",
+            Some(&cache),
+            3,
+            Duration::ZERO,
+            flaky_fetch(u32::MAX, &mut tries),
+        );
+        assert!(cached.is_ok());
+        assert_eq!(tries, 3);
+
+        let mut tries = 0;
+        let missing = fetch_with_retries(
+            "offline",
             None,
-        )
-        .expect("could not load tokenizer")
+            2,
+            Duration::ZERO,
+            flaky_fetch(u32::MAX, &mut tries),
+        );
+        assert_eq!(tries, 2);
+        let err = missing.unwrap_err().to_string();
+        assert!(err.contains("no network"), "{}", err);
+        assert!(err.contains("no cached copy"), "{}", err);
+    }
+
+    #[test]
+    fn overrides_only_change_what_they_mention() {
+        let config = Model::CodeBert
+            .config()
+            .with_overrides(serde_json::json!({"tokenizer_source": "my-org/my-vocab"}))
+            .unwrap();
+
+        assert_eq!(config.tokenizer_source, "my-org/my-vocab");
+        assert_eq!(
+            ModelConfig {
+                tokenizer_source: String::from("roberta-base"),
+                ..config
+            },
+            Model::CodeBert.config()
+        );
+    }
+
+    #[test]
+    fn overrides_must_be_known_fields() {
+        assert!(Model::CodeBert
+            .config()
+            .with_overrides(serde_json::json!({"chunk_sise": 256}))
+            .is_err());
+    }
+
+    #[test]
+    fn tokenizer_options_can_be_set_by_name() {
+        let config = Model::CodeBert
+            .config()
+            .with_tokenizer_option("add_special_tokens", "true")
+            .unwrap();
+        assert!(config.tokenizer_options.add_special_tokens);
+
+        let err = Model::CodeBert
+            .config()
+            .with_tokenizer_option("add_special_tokenz", "true")
+            .unwrap_err();
+        assert!(matches!(err, Error::ModelConfig(_)));
+        assert!(Model::CodeBert
+            .config()
+            .with_tokenizer_option("add_special_tokens", "yes")
+            .is_err());
+    }
+
+    #[test]
+    fn framing_wraps_content_in_each_models_tokens() {
+        let small = |framing| {
+            Model::Custom(ModelConfig {
+                chunk_size: 6,
+                framing: Some(framing),
+                ..Model::CodeBert.config()
+            })
+        };
+
+        for (model, expected) in [
+            (small(Framing::ROBERTA), vec![0, 5, 6, 2, 1, 1]),
+            (small(Framing::BERT), vec![101, 5, 6, 102, 0, 0]),
+            (
+                small(Framing {
+                    start_token: 7,
+                    end_token: 8,
+                    pad_token: 9,
+                }),
+                vec![7, 5, 6, 8, 9, 9],
+            ),
+        ] {
+            let mut input_ids = Vec::new();
+            model.prepare_input_ids(&mut input_ids, &[5, 6]).unwrap();
+
+            assert_eq!(input_ids, expected);
+            assert_eq!(model.attention_mask(2), vec![1, 1, 1, 1, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn framing_needs_room_in_special_tokens() {
+        let err = Model::Noop
+            .config()
+            .with_overrides(serde_json::json!({"framing": Framing::BERT}))
+            .unwrap_err();
+
+        assert!(matches!(err, Error::ModelConfig(_)));
+        assert!(Model::Noop
+            .config()
+            .with_overrides(serde_json::json!({"framing": Framing::BERT, "special_tokens": 2}))
+            .is_ok());
     }
 }
@@ -0,0 +1,327 @@
+use crate::cli::{QueryFormat, QueryOpts};
+use crate::exec::Exec;
+use crate::lsp::Location;
+use crate::template::Template;
+use anyhow::{Context, Result};
+use crossgrep::chunker::Chunker;
+use crossgrep::extractor::FileMatch;
+use serde::Serialize;
+use std::env;
+use std::fmt::Display;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Writes a JSON array one element at a time, so we can print results as we
+/// find them instead of holding on to all of them until the end. The output
+/// is the same as serializing the whole array at once.
+pub struct JsonArray {
+    pretty: bool,
+    started: bool,
+}
+
+impl JsonArray {
+    pub fn new(pretty: bool) -> Self {
+        JsonArray {
+            pretty,
+            started: false,
+        }
+    }
+
+    pub fn push(&mut self, mut out: impl Write, value: &impl Serialize) -> Result<()> {
+        let separator = match (self.started, self.pretty) {
+            (false, false) => "[",
+            (false, true) => "[\n",
+            (true, false) => ",",
+            (true, true) => ",\n",
+        };
+        write!(out, "{}", separator).context("could not write JSON output")?;
+        self.started = true;
+
+        if self.pretty {
+            let value =
+                serde_json::to_string_pretty(value).context("could not write JSON output")?;
+            for (index, line) in value.lines().enumerate() {
+                if index > 0 {
+                    writeln!(out).context("could not write JSON output")?;
+                }
+                write!(out, "  {}", line).context("could not write JSON output")?;
+            }
+        } else {
+            serde_json::to_writer(&mut out, value).context("could not write JSON output")?;
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self, mut out: impl Write) -> Result<()> {
+        let end = match (self.started, self.pretty) {
+            (false, _) => "[]",
+            (true, false) => "]",
+            (true, true) => "\n]",
+        };
+
+        write!(out, "{}", end).context("could not write JSON output")
+    }
+}
+
+/// Write `result` in the lines format. With `number_lines`, match text goes
+/// on lines of its own after their line numbers; see `ExtractedMatch`'s
+/// `Display`.
+pub fn write_lines(mut out: impl Write, result: &impl Display, number_lines: bool) -> Result<()> {
+    if number_lines {
+        write!(out, "{:#}", result)
+    } else {
+        write!(out, "{}", result)
+    }
+    .context("could not write lines")
+}
+
+/// Writes results (whole files, or capture groups) one at a time in any
+/// format but LSP, which `MatchWriter` takes care of.
+pub struct ResultWriter<'opts> {
+    format: &'opts QueryFormat,
+    number_lines: bool,
+    array: JsonArray,
+}
+
+impl<'opts> ResultWriter<'opts> {
+    pub fn new(format: &'opts QueryFormat, number_lines: bool) -> Self {
+        ResultWriter {
+            format,
+            number_lines,
+            array: JsonArray::new(matches!(format, QueryFormat::PrettyJson)),
+        }
+    }
+
+    pub fn write<T: Display + Serialize>(&mut self, mut out: impl Write, result: &T) -> Result<()> {
+        match self.format {
+            QueryFormat::Lines => write_lines(out, result, self.number_lines),
+
+            QueryFormat::Json | QueryFormat::PrettyJson => self.array.push(out, result),
+
+            QueryFormat::JsonStreamArray => {
+                self.array.push(&mut out, result)?;
+                out.flush().context("could not flush JSON output")
+            }
+
+            QueryFormat::JsonLines => writeln!(
+                out,
+                "{}",
+                serde_json::to_string(result).context("could not write JSON output")?
+            )
+            .context("could not write line"),
+
+            QueryFormat::JsonLinesMatches | QueryFormat::Lsp => {
+                unreachable!("per-match formats are written by MatchWriter")
+            }
+
+            QueryFormat::Dot => unreachable!("graphs are written by DotGraph"),
+        }
+    }
+
+    pub fn finish(self, out: impl Write) -> Result<()> {
+        match self.format {
+            QueryFormat::Json | QueryFormat::PrettyJson | QueryFormat::JsonStreamArray => {
+                self.array.finish(out)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes matches one at a time for the kinds of output that don't care which
+/// file (or capture group) a match came from.
+pub enum MatchWriter<'opts> {
+    Tokens(&'opts [(String, Chunker)]),
+    OnlyMatching {
+        trim: bool,
+    },
+    Template(&'opts Template),
+    Exec(Exec<'opts>),
+    JsonLines,
+    Lsp {
+        current_dir: PathBuf,
+        array: JsonArray,
+    },
+}
+
+impl<'opts> MatchWriter<'opts> {
+    /// The writer `opts` asks for, if any. Everything else is written a whole
+    /// result at a time with `ResultWriter`.
+    pub fn for_opts(opts: &'opts QueryOpts) -> Result<Option<Self>> {
+        if let Some(command) = &opts.exec {
+            Ok(Some(MatchWriter::Exec(Exec::new(
+                command,
+                opts.diagnostics,
+            ))))
+        } else if !opts.dump_tokens.is_empty() {
+            Ok(Some(MatchWriter::Tokens(&opts.dump_tokens)))
+        } else if opts.only_matching {
+            Ok(Some(MatchWriter::OnlyMatching {
+                trim: opts.only_matching_trim,
+            }))
+        } else if let Some(template) = &opts.template {
+            Ok(Some(MatchWriter::Template(template)))
+        } else if let QueryFormat::JsonLinesMatches = opts.format {
+            Ok(Some(MatchWriter::JsonLines))
+        } else if let QueryFormat::Lsp = opts.format {
+            Ok(Some(MatchWriter::Lsp {
+                // the paths in results are relative to --relative-to, if
+                // it was given.
+                current_dir: match &opts.relative_to {
+                    Some(relative_to) => relative_to.base().to_path_buf(),
+                    None => env::current_dir().context("could not get the current directory")?,
+                },
+                array: JsonArray::new(false),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn write(&mut self, mut out: impl Write, file_match: &FileMatch) -> Result<()> {
+        match self {
+            MatchWriter::Tokens(chunkers) => {
+                writeln!(out, "{}", file_match).context("could not write line")?;
+
+                for (model, chunker) in chunkers.iter() {
+                    if chunkers.len() > 1 {
+                        writeln!(out, "  {}:", model).context("could not write line")?;
+                    }
+
+                    let tokens = chunker
+                        .tokens(file_match.extracted().text())
+                        .context("could not tokenize match")?;
+                    for token in tokens {
+                        writeln!(
+                            out,
+                            "  {}\t{}..{}\t{:?}",
+                            token.id, token.start_byte, token.end_byte, token.text
+                        )
+                        .context("could not write line")?;
+                    }
+                }
+
+                Ok(())
+            }
+
+            MatchWriter::OnlyMatching { trim } => {
+                let text = file_match.extracted().text();
+                if *trim {
+                    writeln!(
+                        out,
+                        "{}",
+                        text.split_whitespace().collect::<Vec<_>>().join(" ")
+                    )
+                } else {
+                    writeln!(out, "{}", text)
+                }
+                .context("could not write line")
+            }
+
+            MatchWriter::Template(template) => {
+                writeln!(out, "{}", template.render(file_match)).context("could not write line")
+            }
+
+            MatchWriter::Exec(exec) => exec.push(out, file_match),
+
+            MatchWriter::JsonLines => writeln!(
+                out,
+                "{}",
+                serde_json::to_string(file_match).context("could not write JSON output")?
+            )
+            .context("could not write line"),
+
+            MatchWriter::Lsp { current_dir, array } => {
+                array.push(out, &Location::new(file_match, current_dir)?)
+            }
+        }
+    }
+
+    pub fn finish(self, out: impl Write) -> Result<()> {
+        match self {
+            MatchWriter::Lsp { array, .. } => array.finish(out),
+            MatchWriter::Exec(exec) => exec.finish(out),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_array(pretty: bool, values: &[serde_json::Value]) -> String {
+        let mut out = Vec::new();
+        let mut array = JsonArray::new(pretty);
+        for value in values {
+            array.push(&mut out, value).unwrap();
+        }
+        array.finish(&mut out).unwrap();
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn json_arrays_match_serializing_all_at_once() {
+        let values = [
+            serde_json::json!({"file": "a.rs", "matches": [{"name": "x"}]}),
+            serde_json::json!({"file": "b.rs", "matches": []}),
+        ];
+
+        for values in [&values[..0], &values[..1], &values[..]] {
+            assert_eq!(
+                write_array(false, values),
+                serde_json::to_string(values).unwrap()
+            );
+            assert_eq!(
+                write_array(true, values),
+                serde_json::to_string_pretty(values).unwrap()
+            );
+        }
+    }
+
+    /// Keeps what's been written, and what had been written at each flush.
+    #[derive(Default)]
+    struct Flushes {
+        written: Vec<u8>,
+        flushed: Vec<String>,
+    }
+
+    impl Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushed
+                .push(String::from_utf8(self.written.clone()).unwrap());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stream_arrays_are_flushed_after_each_file() {
+        let write = |format: QueryFormat, values: &[serde_json::Value]| {
+            let mut out = Flushes::default();
+            let mut writer = ResultWriter::new(&format, false);
+            for value in values {
+                writer.write(&mut out, value).unwrap();
+            }
+            writer.finish(&mut out).unwrap();
+            out
+        };
+
+        let values = [
+            serde_json::json!({"file": "a.rs"}),
+            serde_json::json!({"file": "b.rs"}),
+        ];
+        let streamed = write(QueryFormat::JsonStreamArray, &values);
+        assert_eq!(
+            streamed.flushed,
+            vec![r#"[{"file":"a.rs"}"#, r#"[{"file":"a.rs"},{"file":"b.rs"}"#]
+        );
+        assert_eq!(streamed.written, write(QueryFormat::Json, &values).written);
+        assert_eq!(write(QueryFormat::JsonStreamArray, &[]).written, b"[]");
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::time::Duration;
+use tree_sitter::Parser;
+
+/// Parsers to reuse from file to file (and thread to thread) instead of making
+/// a new one for each. Extractors only switch a parser's language when it
+/// changes, so parsers that see runs of files in the same language don't
+/// have to be set up again between them.
+#[derive(Default)]
+pub struct ParserPool {
+    parsers: Mutex<Vec<Parser>>,
+    timeout: Option<Duration>,
+}
+
+impl ParserPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give every parser from this pool `timeout` to parse a file in. See
+    /// `Parser::set_timeout_micros`.
+    pub fn with_timeout(timeout: Option<Duration>) -> Self {
+        ParserPool {
+            parsers: Mutex::default(),
+            timeout,
+        }
+    }
+
+    /// Take a parser out of the pool, or make one if they're all in use. It
+    /// goes back in the pool when it's dropped.
+    pub fn get(&self) -> PooledParser<'_> {
+        let parser = self.lock().pop().unwrap_or_else(|| {
+            let mut parser = Parser::new();
+            if let Some(timeout) = self.timeout {
+                parser.set_timeout_micros(timeout.as_micros() as u64);
+            }
+            parser
+        });
+
+        PooledParser {
+            pool: self,
+            parser: Some(parser),
+        }
+    }
+
+    /// How many parsers are waiting in the pool to be used again.
+    pub fn idle(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Parser>> {
+        // a panic while holding the lock can't leave the list half-changed,
+        // so it's still fine to use.
+        self.parsers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl fmt::Debug for ParserPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ParserPool")
+            .field("idle", &self.idle())
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+/// A parser borrowed from a `ParserPool`. Use it anywhere a `&mut Parser` goes.
+pub struct PooledParser<'pool> {
+    pool: &'pool ParserPool,
+    // only `None` while it's being put back in `drop`
+    parser: Option<Parser>,
+}
+
+impl Deref for PooledParser<'_> {
+    type Target = Parser;
+
+    fn deref(&self) -> &Parser {
+        self.parser.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledParser<'_> {
+    fn deref_mut(&mut self) -> &mut Parser {
+        self.parser.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledParser<'_> {
+    fn drop(&mut self) {
+        if let Some(parser) = self.parser.take() {
+            self.pool.lock().push(parser);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossgrep_sys::Language;
+
+    #[test]
+    fn parsers_go_back_in_the_pool() {
+        let pool = ParserPool::with_timeout(Some(Duration::from_millis(5)));
+
+        {
+            let mut parser = pool.get();
+            parser.set_language(Language::Rust.language()).unwrap();
+            let _other = pool.get();
+            assert_eq!(pool.idle(), 0);
+        }
+        assert_eq!(pool.idle(), 2);
+
+        // we get the same parsers back, language and all
+        let parsers = [pool.get(), pool.get()];
+        assert_eq!(pool.idle(), 0);
+        assert!(parsers
+            .iter()
+            .any(|parser| parser.language() == Some(Language::Rust.language())));
+        assert!(parsers
+            .iter()
+            .all(|parser| parser.timeout_micros() == 5_000));
+    }
+}
@@ -0,0 +1,92 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Named sets of queries from a config file, so searches you run all the time
+/// don't have to be typed out every time. Each preset maps language names to
+/// queries, like this:
+///
+/// ```toml
+/// [imports]
+/// javascript = "(import_statement) @import"
+/// rust = "(use_declaration) @use"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Presets(HashMap<String, BTreeMap<String, String>>);
+
+impl Presets {
+    /// Where we look for presets if we're not told otherwise. This is
+    /// `~/.config/crossgrep/queries.toml` on Linux, and the platform
+    /// equivalent elsewhere.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|config| config.join("crossgrep").join("queries.toml"))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("could not read presets from {}", path.display()))?;
+
+        Self::from_toml(&raw)
+            .with_context(|| format!("could not parse presets from {}", path.display()))
+    }
+
+    fn from_toml(raw: &str) -> Result<Self> {
+        Ok(toml::from_str(raw)?)
+    }
+
+    /// The `(language, query)` pairs in a preset, in the same shape as the
+    /// values of `-t`.
+    pub fn targets(&self, name: &str) -> Result<impl Iterator<Item = (&str, &str)>> {
+        match self.0.get(name) {
+            Some(targets) => Ok(targets
+                .iter()
+                .map(|(lang, query)| (lang.as_str(), query.as_str()))),
+            None => {
+                let mut names: Vec<&str> = self.0.keys().map(|name| name.as_str()).collect();
+                names.sort_unstable();
+
+                bail!(
+                    "there's no preset named {}. I know about: {}",
+                    name,
+                    names.join(", ")
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_map_names_to_targets() {
+        let presets = Presets::from_toml(
+            "[imports]\njavascript = \"(import_statement) @import\"\nrust = \"(use_declaration) @use\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            presets.targets("imports").unwrap().collect::<Vec<_>>(),
+            vec![
+                ("javascript", "(import_statement) @import"),
+                ("rust", "(use_declaration) @use")
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_presets_list_the_known_ones() {
+        let presets = Presets::from_toml("[imports]\n[todos]\n").unwrap();
+
+        let err = presets.targets("exports").err().unwrap();
+
+        assert_eq!(
+            err.to_string(),
+            "there's no preset named exports. I know about: imports, todos"
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use crate::stats::Stats;
+use std::fmt::{self, Display};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+const REDRAW_EVERY: Duration = Duration::from_millis(100);
+
+/// Draws how many files have been searched out of how many will be, for
+/// `--progress`. The counts come from `Stats`, which workers bump from
+/// whatever thread they're on, and a separate thread draws them.
+#[derive(Debug)]
+pub struct Progress<'stats> {
+    stats: &'stats Stats,
+}
+
+impl<'stats> Progress<'stats> {
+    pub fn new(stats: &'stats Stats) -> Self {
+        Progress { stats }
+    }
+
+    /// Run `work`, drawing progress on stderr until it's done. The progress
+    /// line is cleared afterwards so it doesn't get mixed up with results.
+    pub fn show_while<T>(&self, work: impl FnOnce() -> T) -> T {
+        let finished = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut stderr = io::stderr();
+                while !finished.load(Ordering::Relaxed) {
+                    // there's nothing useful to do if we can't draw, and the
+                    // search itself shouldn't fail because of it.
+                    let _ = write!(stderr, "\r{}", self);
+                    let _ = stderr.flush();
+                    thread::sleep(REDRAW_EVERY);
+                }
+
+                // carriage return and clear the line
+                let _ = write!(stderr, "\r\x1B[K");
+            });
+
+            let result = work();
+            finished.store(true, Ordering::Relaxed);
+            result
+        })
+    }
+}
+
+impl Display for Progress<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (done, total) = (self.stats.files_searched(), self.stats.files_to_search());
+        let percent = (done * 100).checked_div(total).unwrap_or(100);
+
+        write!(f, "searched {}/{} files ({}%)", done, total, percent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_counts_across_threads() {
+        let stats = Stats::default();
+        stats.will_search(100);
+        let progress = Progress::new(&stats);
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..25 {
+                        stats.searched_file();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(progress.to_string(), "searched 100/100 files (100%)");
+    }
+}
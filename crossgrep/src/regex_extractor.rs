@@ -0,0 +1,286 @@
+use crate::cancellation::CancellationToken;
+use crate::decoded::Decoded;
+use crate::error::Result;
+use crate::extractor::{
+    keeps_text, with_separator, Chunkers, ColumnUnit, ExtractedFile, ExtractedMatch,
+};
+use crate::model::Model;
+use encoding_rs::{Encoding, UTF_8};
+use regex::bytes::Regex;
+use std::path::Path;
+use tree_sitter::Point;
+
+/// The capture name regex matches get, like the queries `--kind` makes.
+const NAME: &str = "match";
+
+/// Searches source with a regex instead of a tree-sitter query, for files we
+/// don't have a grammar for. Matches come out like any `Extractor`'s (with
+/// the kind `regex`, since there's no node behind them) so they work with
+/// every output format.
+#[derive(Debug)]
+pub struct RegexExtractor {
+    regex: Regex,
+    chunkers: Chunkers,
+    cancellation: Option<CancellationToken>,
+    column_unit: ColumnUnit,
+    path_separator: Option<char>,
+    text_match: Option<regex::Regex>,
+    text_not_match: Option<regex::Regex>,
+    min_lines: usize,
+    min_bytes: usize,
+    encoding: Option<&'static Encoding>,
+}
+
+impl RegexExtractor {
+    pub fn new(regex: Regex, model: Model) -> Result<RegexExtractor> {
+        Ok(Self::with_chunkers(regex, Chunkers::for_model(model)?))
+    }
+
+    /// Like `new`, but chunk each match for every one of `models`. See
+    /// `Extractor::with_models`.
+    pub fn with_models(regex: Regex, models: Vec<(String, Model)>) -> Result<RegexExtractor> {
+        Ok(Self::with_chunkers(regex, Chunkers::for_models(models)?))
+    }
+
+    fn with_chunkers(regex: Regex, chunkers: Chunkers) -> RegexExtractor {
+        RegexExtractor {
+            regex,
+            chunkers,
+            cancellation: None,
+            column_unit: ColumnUnit::default(),
+            path_separator: None,
+            text_match: None,
+            text_not_match: None,
+            min_lines: 0,
+            min_bytes: 0,
+            encoding: None,
+        }
+    }
+
+    /// See `Extractor::set_cancellation_token`.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation = Some(token);
+    }
+
+    /// See `Extractor::set_column_unit`.
+    pub fn set_column_unit(&mut self, column_unit: ColumnUnit) {
+        self.column_unit = column_unit;
+    }
+
+    /// See `Extractor::set_path_separator`.
+    pub fn set_path_separator(&mut self, separator: char) {
+        self.path_separator = Some(separator);
+    }
+
+    /// See `Extractor::set_text_match`.
+    pub fn set_text_match(&mut self, regex: regex::Regex) {
+        self.text_match = Some(regex);
+    }
+
+    /// See `Extractor::set_text_not_match`.
+    pub fn set_text_not_match(&mut self, regex: regex::Regex) {
+        self.text_not_match = Some(regex);
+    }
+
+    /// See `Extractor::set_min_lines`.
+    pub fn set_min_lines(&mut self, min_lines: usize) {
+        self.min_lines = min_lines;
+    }
+
+    /// See `Extractor::set_min_bytes`.
+    pub fn set_min_bytes(&mut self, min_bytes: usize) {
+        self.min_bytes = min_bytes;
+    }
+
+    /// See `Extractor::set_encoding`. The regex matches the decoded text.
+    pub fn set_encoding(&mut self, encoding: &'static Encoding) {
+        self.encoding = Some(encoding).filter(|encoding| *encoding != UTF_8);
+    }
+
+    pub fn extract_from_text(
+        &self,
+        path: Option<&Path>,
+        source: &[u8],
+    ) -> Result<Option<ExtractedFile<'_>>> {
+        if self.is_cancelled() {
+            return Ok(None);
+        }
+
+        let decoded = self.encoding.map(|encoding| Decoded::new(source, encoding));
+        let source = decoded
+            .as_ref()
+            .map_or(source, |decoded| decoded.text().as_bytes());
+        let original_byte = |byte| {
+            decoded
+                .as_ref()
+                .map_or(byte, |decoded| decoded.original_byte(byte))
+        };
+
+        let mut matches = Vec::new();
+        // matches come in order, so we only count the newlines between one
+        // and the next.
+        let mut counted = (0, 0);
+        for found in self.regex.find_iter(source) {
+            // empty matches (like from `x*`) are everywhere and say nothing.
+            if found.is_empty() {
+                continue;
+            }
+
+            let start = point_at(source, found.start(), &mut counted);
+            let end = point_at(source, found.end(), &mut counted);
+            if end.row - start.row + 1 < self.min_lines
+                || found.len() < self.min_bytes
+                || !keeps_text(
+                    found.as_bytes(),
+                    self.text_match.as_ref(),
+                    self.text_not_match.as_ref(),
+                )
+            {
+                continue;
+            }
+
+            let mut chunks = self
+                .chunkers
+                .chunk_text(found.as_bytes(), found.start(), start)?;
+            chunks.map_bytes(original_byte);
+
+            matches.push(ExtractedMatch::from_regex(
+                NAME,
+                String::from_utf8_lossy(found.as_bytes()).into_owned(),
+                original_byte(found.start())..original_byte(found.end()),
+                self.column_unit
+                    .point(source, decoded.as_ref(), start, found.start()),
+                self.column_unit
+                    .point(source, decoded.as_ref(), end, found.end()),
+                chunks,
+            ));
+        }
+
+        if matches.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(ExtractedFile::from_regex(
+                path.map(|path| with_separator(path, self.path_separator)),
+                matches,
+            )))
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+}
+
+/// The row and (byte) column of `byte` in `source`. `counted` is a byte at
+/// or before this one and its row, so we can count on from there instead of
+/// from the start; it's moved up to `byte` afterwards.
+fn point_at(source: &[u8], byte: usize, counted: &mut (usize, usize)) -> Point {
+    let (counted_byte, counted_row) = *counted;
+    let row = counted_row
+        + source[counted_byte..byte]
+            .iter()
+            .filter(|b| **b == b'\n')
+            .count();
+    *counted = (byte, row);
+
+    let line_start = source[..byte]
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |newline| newline + 1);
+
+    Point::new(row, byte - line_start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_have_rows_and_columns() {
+        let extractor = RegexExtractor::new(Regex::new(r"TODO\w*").unwrap(), Model::Noop).unwrap();
+        let source = "one\n  TODO here\nTODOs: é TODO\nx*";
+
+        let extracted = extractor
+            .extract_from_text(Some(Path::new("notes.txt")), source.as_bytes())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            extracted
+                .matches()
+                .iter()
+                .map(|m| (m.kind(), m.text(), m.start(), m.end()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("regex", "TODO", Point::new(1, 2), Point::new(1, 6)),
+                ("regex", "TODOs", Point::new(2, 0), Point::new(2, 5)),
+                ("regex", "TODO", Point::new(2, 10), Point::new(2, 14)),
+            ]
+        );
+
+        // nothing matching is nothing extracted, like for queries
+        assert!(
+            RegexExtractor::new(Regex::new("nope").unwrap(), Model::Noop)
+                .unwrap()
+                .extract_from_text(None, source.as_bytes())
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn matches_are_filtered_like_queries() {
+        let source = "TODO one\nTODO two\nTODO(x)\n";
+        let texts = |extractor: &RegexExtractor| -> Vec<String> {
+            extractor
+                .extract_from_text(None, source.as_bytes())
+                .unwrap()
+                .map(|extracted| {
+                    extracted
+                        .matches()
+                        .iter()
+                        .map(|m| m.text().to_string())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let extractor =
+            || RegexExtractor::new(Regex::new(r"TODO\S*").unwrap(), Model::Noop).unwrap();
+
+        let mut matching = extractor();
+        matching.set_text_match(regex::Regex::new("x").unwrap());
+        assert_eq!(texts(&matching), vec!["TODO(x)"]);
+
+        let mut not_matching = extractor();
+        not_matching.set_text_not_match(regex::Regex::new("x").unwrap());
+        assert_eq!(texts(&not_matching), vec!["TODO", "TODO"]);
+
+        let mut long = extractor();
+        long.set_min_bytes(5);
+        assert_eq!(texts(&long), vec!["TODO(x)"]);
+
+        let mut multiline = extractor();
+        multiline.set_min_lines(2);
+        assert!(texts(&multiline).is_empty());
+    }
+
+    #[test]
+    fn encoded_sources_are_decoded_before_matching() {
+        let source: Vec<u8> = "a\n  TODO"
+            .encode_utf16()
+            .flat_map(|unit| unit.to_le_bytes())
+            .collect();
+        let mut extractor = RegexExtractor::new(Regex::new("TODO").unwrap(), Model::Noop).unwrap();
+        extractor.set_encoding(encoding_rs::UTF_16LE);
+
+        let extracted = extractor.extract_from_text(None, &source).unwrap().unwrap();
+        let found = &extracted.matches()[0];
+
+        assert_eq!(found.text(), "TODO");
+        assert_eq!(found.start(), Point::new(1, 4));
+        // in the original bytes, like for queries
+        assert_eq!(found.byte_range(), 8..16);
+    }
+}
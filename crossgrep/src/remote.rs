@@ -0,0 +1,265 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+/// A git repository to search instead of the local filesystem, from
+/// `--git-url`.
+#[derive(Debug)]
+pub struct Remote {
+    pub url: String,
+    /// The branch or tag to check out. The remote's default branch if not
+    /// given.
+    pub git_ref: Option<String>,
+    /// Where to keep clones between searches. Without this we clone into a
+    /// temporary directory and remove it when we're done.
+    pub cache: Option<PathBuf>,
+}
+
+impl Remote {
+    /// Get a working tree for this remote, either from the cache or with a
+    /// fresh shallow clone.
+    pub fn checkout(&self) -> Result<Checkout> {
+        let (dir, temporary) = match &self.cache {
+            Some(cache) => (cache.join(self.cache_name()), None),
+            None => {
+                // somewhere only we can write to, with a name nobody can
+                // guess and get there first with. git clones into a
+                // directory inside it, since it wants to create that itself.
+                let temporary = tempfile::Builder::new()
+                    .prefix("crossgrep-clone-")
+                    .tempdir()
+                    .context("could not create a directory to clone into")?;
+                (temporary.path().join("repo"), Some(temporary))
+            }
+        };
+
+        // cached clones aren't updated; remove them to get a fresh one.
+        if temporary.is_none() && dir.is_dir() {
+            return Ok(Checkout {
+                dir,
+                _temporary: temporary,
+            });
+        }
+
+        if let Some(parent) = dir.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("could not create {}", parent.display()))?;
+        }
+
+        let mut command = Command::new("git");
+        command.args(["clone", "--quiet", "--depth", "1"]);
+        if let Some(git_ref) = &self.git_ref {
+            command.args(["--branch", git_ref]);
+        }
+        command.arg("--").arg(&self.url).arg(&dir);
+
+        let status = command
+            .status()
+            .context("could not run git. Is it installed?")?;
+        if !status.success() {
+            // git cleans up after itself when a clone fails, but we make sure
+            // so a half-finished clone never ends up in the cache.
+            let _ = fs::remove_dir_all(&dir);
+            bail!("git could not clone {} ({})", self.url, status);
+        }
+
+        Ok(Checkout {
+            dir,
+            _temporary: temporary,
+        })
+    }
+
+    /// A directory name for this remote (and ref) that's safe on any
+    /// filesystem, like `github.com-foo-bar@main-0123456789abcdef`. Flattening
+    /// the URL can make different URLs look the same (like `foo/bar` and
+    /// `foo-bar`), so the name ends with a hash of the URL and ref as given.
+    fn cache_name(&self) -> String {
+        let url = self
+            .url
+            .split_once("://")
+            .map_or(self.url.as_str(), |(_, rest)| rest)
+            .trim_end_matches('/')
+            .trim_end_matches(".git");
+
+        let mut name: String = url
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+                    c
+                } else {
+                    '-'
+                }
+            })
+            .collect();
+        if let Some(git_ref) = &self.git_ref {
+            name.push('@');
+            name.extend(git_ref.chars().map(|c| if c == '/' { '-' } else { c }));
+        }
+
+        let mut hashed = self.url.clone().into_bytes();
+        if let Some(git_ref) = &self.git_ref {
+            hashed.push(0);
+            hashed.extend(git_ref.bytes());
+        }
+
+        format!("{}-{:016x}", name, fnv1a(&hashed))
+    }
+}
+
+/// A 64-bit FNV-1a hash. Cache names have to stay the same from one build to
+/// the next, which std's hashers don't promise.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// A cloned working tree. Temporary ones are removed when this is dropped.
+#[derive(Debug)]
+pub struct Checkout {
+    dir: PathBuf,
+    /// The directory a temporary clone is in, which is removed along with
+    /// the clone when this is dropped. `None` for cached clones.
+    _temporary: Option<TempDir>,
+}
+
+impl Checkout {
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// `path` inside the working tree. Paths that lead out of it (like
+    /// `../other` or a symlink to somewhere else) are an error, since we
+    /// were asked to search the repository and nothing else.
+    pub fn join(&self, path: &Path) -> Result<PathBuf> {
+        let joined = self.dir.join(path);
+        let canonical = fs::canonicalize(&joined)
+            .with_context(|| format!("could not find {} in the repository", path.display()))?;
+        let dir = fs::canonicalize(&self.dir)
+            .with_context(|| format!("could not find {}", self.dir.display()))?;
+        if !canonical.starts_with(dir) {
+            bail!("{} is outside the repository", path.display());
+        }
+
+        Ok(joined)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_names_are_flat() {
+        let remote = Remote {
+            url: "https://github.com/foo/bar.git".to_string(),
+            git_ref: Some("release/1.0".to_string()),
+            cache: None,
+        };
+
+        let name = remote.cache_name();
+        assert!(
+            name.starts_with("github.com-foo-bar@release-1.0-"),
+            "{}",
+            name
+        );
+        assert_eq!(name.len(), "github.com-foo-bar@release-1.0-".len() + 16);
+    }
+
+    #[test]
+    fn cache_names_dont_collide() {
+        let name = |url: &str, git_ref: Option<&str>| {
+            Remote {
+                url: url.to_string(),
+                git_ref: git_ref.map(String::from),
+                cache: None,
+            }
+            .cache_name()
+        };
+
+        assert_ne!(
+            name("https://github.com/foo/bar", None),
+            name("https://github.com/foo-bar", None)
+        );
+        assert_ne!(
+            name("https://github.com/foo/bar", Some("a/b")),
+            name("https://github.com/foo/bar", Some("a-b"))
+        );
+        assert_eq!(
+            name("https://github.com/foo/bar", None),
+            name("https://github.com/foo/bar", None)
+        );
+    }
+
+    #[test]
+    fn paths_outside_the_checkout_are_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = tmp.path().join("repo");
+        fs::create_dir_all(repo.join("src")).unwrap();
+        fs::create_dir_all(tmp.path().join("other")).unwrap();
+        let checkout = Checkout {
+            dir: repo.clone(),
+            _temporary: None,
+        };
+
+        assert_eq!(checkout.join(Path::new("src")).unwrap(), repo.join("src"));
+        assert_eq!(checkout.join(Path::new(".")).unwrap(), repo.join("."));
+        assert!(checkout.join(Path::new("../other")).is_err());
+        assert!(checkout.join(tmp.path()).is_err());
+        assert!(checkout.join(Path::new("missing")).is_err());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(tmp.path().join("other"), repo.join("link")).unwrap();
+            assert!(checkout.join(Path::new("link")).is_err());
+        }
+    }
+
+    #[test]
+    fn temporary_clones_get_a_directory_of_their_own() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = tmp.path().join("repo");
+        fs::create_dir_all(&repo).unwrap();
+        fs::write(repo.join("lib.rs"), "fn main() {}\n").unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .current_dir(&repo)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "--quiet"]);
+        git(&["add", "."]);
+        git(&["commit", "--quiet", "-m", "init"]);
+
+        let remote = Remote {
+            url: format!("file://{}", repo.display()),
+            git_ref: None,
+            cache: None,
+        };
+        let first = remote.checkout().unwrap();
+        let second = remote.checkout().unwrap();
+        let dir = first.dir().to_path_buf();
+
+        assert!(dir.join("lib.rs").is_file());
+        assert_ne!(first.dir(), second.dir());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.parent().unwrap())
+                .unwrap()
+                .permissions()
+                .mode();
+            // nobody else can write to it
+            assert_eq!(mode & 0o022, 0, "{:o}", mode);
+        }
+
+        drop(first);
+        assert!(!dir.exists());
+        assert!(!dir.parent().unwrap().exists());
+    }
+}
@@ -0,0 +1,121 @@
+use crossgrep_sys::Language;
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Counters for `--stats`. These get bumped from both the walker and the
+/// extraction threads, so everything in here is atomic.
+#[derive(Debug, Default)]
+pub struct Stats {
+    files_searched: AtomicUsize,
+    files_to_search: AtomicUsize,
+    files_with_matches: AtomicUsize,
+    matches: AtomicUsize,
+    skipped_too_large: AtomicUsize,
+    skipped_binary: AtomicUsize,
+    files_with_parse_errors: AtomicUsize,
+    grammars: Mutex<Grammars>,
+}
+
+#[derive(Debug, Default)]
+struct Grammars {
+    loaded: Vec<Language>,
+    failed: Vec<Language>,
+}
+
+impl Stats {
+    pub fn searched_file(&self) {
+        self.files_searched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count `count` more files toward the total `--progress` shows. Files
+    /// inside archives are only counted once we open the archive.
+    pub fn will_search(&self, count: usize) {
+        self.files_to_search.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn files_searched(&self) -> usize {
+        self.files_searched.load(Ordering::Relaxed)
+    }
+
+    pub fn files_to_search(&self) -> usize {
+        self.files_to_search.load(Ordering::Relaxed)
+    }
+
+    pub fn found_matches(&self, count: usize) {
+        self.files_with_matches.fetch_add(1, Ordering::Relaxed);
+        self.matches.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn skipped_too_large(&self) {
+        self.skipped_too_large.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn skipped_binary(&self) {
+        self.skipped_binary.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Extractors keep their own count of these (since they're the ones who
+    /// see the trees) so we get the total from them once the search is done.
+    pub fn set_files_with_parse_errors(&self, count: usize) {
+        self.files_with_parse_errors.store(count, Ordering::Relaxed);
+    }
+
+    /// Which languages' grammars we searched with, and which we had to skip
+    /// because they couldn't be loaded.
+    pub fn set_grammars(&self, loaded: Vec<Language>, failed: Vec<Language>) {
+        if let Ok(mut grammars) = self.grammars.lock() {
+            *grammars = Grammars { loaded, failed };
+        }
+    }
+}
+
+fn list(languages: &[Language]) -> String {
+    if languages.is_empty() {
+        String::from("none")
+    } else {
+        languages
+            .iter()
+            .map(Language::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "files searched: {}", self.files_searched())?;
+        writeln!(
+            f,
+            "files with matches: {}",
+            self.files_with_matches.load(Ordering::Relaxed)
+        )?;
+        writeln!(f, "matches: {}", self.matches.load(Ordering::Relaxed))?;
+        writeln!(
+            f,
+            "files skipped for size: {}",
+            self.skipped_too_large.load(Ordering::Relaxed)
+        )?;
+        writeln!(
+            f,
+            "binary files skipped: {}",
+            self.skipped_binary.load(Ordering::Relaxed)
+        )?;
+        writeln!(
+            f,
+            "files with parse errors: {}",
+            self.files_with_parse_errors.load(Ordering::Relaxed)
+        )?;
+
+        let grammars = self
+            .grammars
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        writeln!(f, "grammars loaded: {}", list(&grammars.loaded))?;
+        writeln!(
+            f,
+            "grammars that failed to load: {}",
+            list(&grammars.failed)
+        )
+    }
+}
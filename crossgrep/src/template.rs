@@ -0,0 +1,258 @@
+use anyhow::{bail, Error, Result};
+use crossgrep::extractor::FileMatch;
+use std::fmt::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+/// A user-supplied layout for lines output, like `{path}:{line} [{name}]
+/// {text}`. Use `{{` and `}}` for literal braces.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder),
+    /// `{metadata.KEY}`: what the matching pattern's `#set!` gave KEY.
+    Metadata(String),
+    /// `{}`: the path of a file with the match's text in it. Only commands
+    /// for `--exec` can use this.
+    TextFile,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Placeholder {
+    Path,
+    FileType,
+    Line,
+    Column,
+    EndLine,
+    EndColumn,
+    ByteStart,
+    ByteEnd,
+    Name,
+    Kind,
+    Scope,
+    Text,
+}
+
+const PLACEHOLDERS: &[(&str, Placeholder)] = &[
+    ("path", Placeholder::Path),
+    ("file_type", Placeholder::FileType),
+    ("line", Placeholder::Line),
+    ("column", Placeholder::Column),
+    ("end_line", Placeholder::EndLine),
+    ("end_column", Placeholder::EndColumn),
+    ("byte_start", Placeholder::ByteStart),
+    ("byte_end", Placeholder::ByteEnd),
+    ("name", Placeholder::Name),
+    ("kind", Placeholder::Kind),
+    ("scope", Placeholder::Scope),
+    ("text", Placeholder::Text),
+];
+
+impl Template {
+    /// Parse one word of an `--exec` command, which can also use `{}` for a
+    /// file with the match's text in it.
+    pub fn command_word(raw: &str) -> Result<Self> {
+        Self::parse(raw, true)
+    }
+
+    /// Whether this has a `{}` in it, so it needs a file to render.
+    pub fn uses_text_file(&self) -> bool {
+        self.parts.iter().any(|part| matches!(part, Part::TextFile))
+    }
+
+    pub fn render(&self, file_match: &FileMatch) -> String {
+        self.render_with_text_file(file_match, None)
+    }
+
+    /// Like `render`, with `text_file` in place of `{}`.
+    pub fn render_with_text_file(
+        &self,
+        file_match: &FileMatch,
+        text_file: Option<&Path>,
+    ) -> String {
+        let extracted = file_match.extracted();
+        let mut out = String::new();
+
+        for part in &self.parts {
+            // writing to a String can't fail
+            let _ = match part {
+                Part::Literal(literal) => write!(out, "{}", literal),
+                Part::Placeholder(Placeholder::Path) => write!(out, "{}", file_match.filename()),
+                Part::Placeholder(Placeholder::FileType) => {
+                    write!(out, "{}", file_match.file_type())
+                }
+                Part::Placeholder(Placeholder::Line) => {
+                    write!(out, "{}", extracted.start().row + 1)
+                }
+                Part::Placeholder(Placeholder::Column) => {
+                    write!(out, "{}", extracted.start().column + 1)
+                }
+                Part::Placeholder(Placeholder::EndLine) => {
+                    write!(out, "{}", extracted.end().row + 1)
+                }
+                Part::Placeholder(Placeholder::EndColumn) => {
+                    write!(out, "{}", extracted.end().column + 1)
+                }
+                Part::Placeholder(Placeholder::ByteStart) => {
+                    write!(out, "{}", extracted.byte_range().start)
+                }
+                Part::Placeholder(Placeholder::ByteEnd) => {
+                    write!(out, "{}", extracted.byte_range().end)
+                }
+                Part::Placeholder(Placeholder::Name) => write!(out, "{}", extracted.name()),
+                Part::Placeholder(Placeholder::Kind) => write!(out, "{}", extracted.kind()),
+                Part::Placeholder(Placeholder::Scope) => {
+                    write!(out, "{}", extracted.scope().unwrap_or_default())
+                }
+                Part::Placeholder(Placeholder::Text) => write!(out, "{}", extracted.text()),
+                Part::Metadata(key) => {
+                    write!(out, "{}", extracted.metadata(key).unwrap_or_default())
+                }
+                Part::TextFile => match text_file {
+                    Some(text_file) => write!(out, "{}", text_file.display()),
+                    None => Ok(()),
+                },
+            };
+        }
+
+        out
+    }
+}
+
+impl FromStr for Template {
+    type Err = Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        Self::parse(raw, false)
+    }
+}
+
+impl Template {
+    fn parse(raw: &str, allow_text_file: bool) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = raw.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => bail!("unclosed {{ in template. Use {{{{ for a literal {{."),
+                        }
+                    }
+
+                    let part = match PLACEHOLDERS.iter().find(|(known, _)| *known == name) {
+                        Some((_, placeholder)) => Part::Placeholder(*placeholder),
+                        None if allow_text_file && name.is_empty() => Part::TextFile,
+                        // people coming from embedding search will look for
+                        // this, so it gets a better answer than "unknown".
+                        None if name == "score" => bail!(
+                            "{{score}} isn't supported: nothing in a search scores matches yet, so there's no score to show"
+                        ),
+                        None => match name.strip_prefix("metadata.") {
+                            Some(key) if !key.is_empty() => Part::Metadata(key.to_string()),
+                            _ => bail!(
+                                "unknown placeholder {{{}}} in template. Try one of: {}, or {{metadata.KEY}}",
+                                name,
+                                PLACEHOLDERS
+                                    .iter()
+                                    .map(|(known, _)| format!("{{{}}}", known))
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            ),
+                        },
+                    };
+
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(part);
+                }
+                '}' => bail!("unmatched }} in template. Use }}}} for a literal }}."),
+                _ => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Template { parts })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates_parse_placeholders_and_escapes() {
+        assert_eq!(
+            Template::from_str("{path}:{line} {{{name}}}").unwrap(),
+            Template {
+                parts: vec![
+                    Part::Placeholder(Placeholder::Path),
+                    Part::Literal(":".to_string()),
+                    Part::Placeholder(Placeholder::Line),
+                    Part::Literal(" {".to_string()),
+                    Part::Placeholder(Placeholder::Name),
+                    Part::Literal("}".to_string()),
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn templates_parse_metadata_keys() {
+        assert_eq!(
+            Template::from_str("[{metadata.severity}]").unwrap(),
+            Template {
+                parts: vec![
+                    Part::Literal("[".to_string()),
+                    Part::Metadata("severity".to_string()),
+                    Part::Literal("]".to_string()),
+                ]
+            }
+        );
+        assert!(Template::from_str("{metadata.}").is_err());
+    }
+
+    #[test]
+    fn unknown_placeholders_are_rejected() {
+        let err = Template::from_str("{path}:{nope}").unwrap_err();
+
+        assert!(err
+            .to_string()
+            .starts_with("unknown placeholder {nope} in template"));
+    }
+
+    #[test]
+    fn scores_are_rejected_with_a_reason() {
+        let err = Template::from_str("{score} {text}").unwrap_err();
+
+        assert!(err.to_string().starts_with("{score} isn't supported"));
+    }
+
+    #[test]
+    fn unmatched_braces_are_rejected() {
+        assert!(Template::from_str("{path").is_err());
+        assert!(Template::from_str("path}").is_err());
+    }
+}
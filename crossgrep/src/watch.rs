@@ -0,0 +1,63 @@
+use anyhow::{Context, Result};
+use notify_debouncer_mini::new_debouncer;
+use notify_debouncer_mini::notify::RecursiveMode;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Editors often write a file in several steps (truncate, write, rename...)
+/// so we wait for things to settle down before searching again.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Watch `paths` (recursively) and call `on_change` with the files that
+/// changed each time something does. This only returns if watching fails or
+/// `on_change` returns an error.
+pub fn watch(paths: &[PathBuf], mut on_change: impl FnMut(&[PathBuf]) -> Result<()>) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+
+    let mut debouncer =
+        new_debouncer(DEBOUNCE, sender).context("could not start watching files")?;
+    for path in paths {
+        debouncer
+            .watcher()
+            .watch(path, RecursiveMode::Recursive)
+            .with_context(|| format!("could not watch {}", path.display()))?;
+    }
+
+    // the notifier gives us absolute paths, but if we were given relative
+    // ones we want to keep showing paths the same way we did before we
+    // started watching.
+    let current_dir = if paths.iter().all(|path| path.is_relative()) {
+        Some(env::current_dir().context("could not get the current directory")?)
+    } else {
+        None
+    };
+
+    for events in receiver {
+        let events = events.context("had a problem while watching files")?;
+
+        // deleted files don't have anything left to match, so we only care
+        // about files that still exist.
+        let mut changed: Vec<PathBuf> = events
+            .into_iter()
+            .filter(|event| event.path.is_file())
+            .map(|event| match &current_dir {
+                Some(current_dir) => relative_to(&event.path, current_dir),
+                None => event.path,
+            })
+            .collect();
+        changed.sort();
+        changed.dedup();
+
+        if !changed.is_empty() {
+            on_change(&changed)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn relative_to(path: &Path, dir: &Path) -> PathBuf {
+    path.strip_prefix(dir).unwrap_or(path).to_path_buf()
+}
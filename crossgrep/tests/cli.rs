@@ -0,0 +1,84 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+// these run from the crate's directory, so paths in them start with `../`.
+#[test]
+fn exit_codes() {
+    trycmd::TestCases::new()
+        .env("RUST_BACKTRACE", "0")
+        .case("../tests/cmd/exit-codes.trycmd");
+}
+
+// git finds its global excludes file through the environment, so this runs
+// crossgrep in its own process with a home directory of its own (so nothing
+// in the real ~/.gitconfig gets in the way) instead of changing the
+// environment of every other test.
+#[test]
+fn git_excludes_are_ignored_like_gitignore() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let repo = dir.join("repo");
+    fs::create_dir_all(repo.join(".git/info")).unwrap();
+    fs::create_dir_all(dir.join("config/git")).unwrap();
+    fs::write(repo.join(".gitignore"), "gitignore.js\n").unwrap();
+    fs::write(repo.join(".git/info/exclude"), "info-exclude.js\n").unwrap();
+    fs::write(dir.join("global-excludes"), "global-exclude.js\n").unwrap();
+    fs::write(
+        dir.join("config/git/config"),
+        format!(
+            "[core]\n\texcludesFile = {}\n",
+            dir.join("global-excludes").display()
+        ),
+    )
+    .unwrap();
+    for file in [
+        "gitignore.js",
+        "info-exclude.js",
+        "global-exclude.js",
+        "kept.js",
+    ] {
+        fs::write(repo.join(file), "console.log(\"hi\");\n").unwrap();
+    }
+
+    let search = |extra: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_crossgrep"))
+            .args(["-t", "javascript", "(string) @str"])
+            .args(extra)
+            .arg(&repo)
+            .args(["--", "query"])
+            .env("HOME", dir)
+            .env("XDG_CONFIG_HOME", dir.join("config"))
+            // but keep the real cache, where the tokenizer is
+            .env("XDG_CACHE_HOME", dirs::cache_dir().unwrap())
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let mut files: Vec<String> = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let file = Path::new(line.split(':').next().unwrap());
+                file.file_name().unwrap().to_string_lossy().into_owned()
+            })
+            .collect();
+        files.sort();
+        files
+    };
+
+    assert_eq!(search(&[]), vec!["kept.js"]);
+    assert_eq!(
+        search(&["--no-gitignore"]),
+        vec![
+            "gitignore.js",
+            "global-exclude.js",
+            "info-exclude.js",
+            "kept.js"
+        ]
+    );
+}